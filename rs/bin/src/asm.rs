@@ -0,0 +1,78 @@
+use crate::cli::{catch_emit, read_input, write_json, write_output, Arg, Args, CliError, CliResult};
+use crate::format::{encode, OutputFormat};
+use avm1_emitter::asm::parse_asm;
+use avm1_emitter::emit_cfg_listing;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: avm1-emitter asm [OPTIONS] [INPUT]
+
+Assembles the AVM1 assembly source from INPUT (stdin if omitted or `-`) and
+writes the emitted AVM1 bytes.
+
+Options:
+  -o, --output <FILE>  Write the bytes to FILE instead of stdout
+      --format <FORMAT>
+                       Encoding of the output: `binary` (default), `hex`,
+                       `base64`, `rust`, `c`, `json` or `listing`
+      --cfg            Write the CFG as JSON instead of emitting it
+  -h, --help           Print this help
+";
+
+#[derive(Debug, Default)]
+pub struct AsmCommand {
+  pub input: Option<PathBuf>,
+  pub output: Option<PathBuf>,
+  pub format: OutputFormat,
+  pub cfg: bool,
+}
+
+impl AsmCommand {
+  /// Parses the options of the command. Returns `None` if the help was requested.
+  pub fn parse(mut args: Args) -> CliResult<Option<Self>> {
+    let mut command = Self::default();
+    while let Some(arg) = args.next_arg()? {
+      match arg {
+        Arg::Flag(flag) => match flag.as_str() {
+          "-h" | "--help" => return Ok(None),
+          "-o" | "--output" => command.output = Some(PathBuf::from(args.value(&flag)?)),
+          "--format" => {
+            let value = args.value(&flag)?;
+            command.format =
+              OutputFormat::parse(&value).ok_or_else(|| CliError::Usage(format!("unknown format: {}", value)))?;
+          }
+          "--cfg" => command.cfg = true,
+          _ => return Err(CliError::Usage(format!("unknown option: {}", flag))),
+        },
+        Arg::Positional(value) => {
+          if command.input.is_some() {
+            return Err(CliError::Usage(format!("unexpected argument: {}", value)));
+          }
+          command.input = Some(PathBuf::from(value));
+        }
+      }
+    }
+    if command.cfg && command.format != OutputFormat::Binary {
+      return Err(CliError::Usage(String::from("--format cannot be used with --cfg")));
+    }
+    Ok(Some(command))
+  }
+
+  pub fn run(&self) -> CliResult<()> {
+    let input = self.input.clone().unwrap_or_else(|| PathBuf::from("-"));
+    let output = self.output.clone().unwrap_or_else(|| PathBuf::from("-"));
+    let source = read_input(&input)?;
+    let source = String::from_utf8(source)
+      .map_err(|_| CliError::Other(format!("{}: source is not valid UTF-8", input.display())))?;
+    let cfg = parse_asm(&source).map_err(|e| CliError::Other(format!("{}:{}", input.display(), e)))?;
+    if self.cfg {
+      return write_json(&output, &cfg);
+    }
+    let (bytes, listing) = catch_emit(|| emit_cfg_listing(&cfg))?;
+    match encode(self.format, &bytes) {
+      Some(text) => write_output(&output, text.as_bytes()),
+      None if self.format == OutputFormat::Listing => write_output(&output, listing.to_string().as_bytes()),
+      None => write_output(&output, &bytes),
+    }
+  }
+}
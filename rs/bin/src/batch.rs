@@ -0,0 +1,289 @@
+use crate::cli::{catch_emit, read_json, write_output, Arg, Args, CliError, CliResult};
+use avm1_emitter::emit_cfg;
+use avm1_types::cfg::Cfg;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+pub const USAGE: &str = "\
+Usage: avm1-emitter batch [OPTIONS] <DIR>
+
+Emits every `cfg.json` file found in DIR (e.g. `tests/avm1`), writes the
+result next to it and compares it with the sibling `main.avm1` if present.
+Exits with a non-zero code if any output does not match.
+
+Options:
+      --output-name <NAME>  Name of the emitted files (default: local-main.rs.avm1)
+  -j, --jobs <N>            Number of threads (default: number of cores)
+  -h, --help                Print this help
+";
+
+const INPUT_NAME: &str = "cfg.json";
+const EXPECTED_NAME: &str = "main.avm1";
+
+#[derive(Debug, Clone)]
+pub struct BatchCommand {
+  pub root: PathBuf,
+  pub output_name: String,
+  pub jobs: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Status {
+  /// The output matches `main.avm1`.
+  Pass,
+  /// The output does not match `main.avm1`.
+  Fail,
+  /// There is no `main.avm1` to compare with.
+  New,
+  Error(String),
+}
+
+#[derive(Debug)]
+struct CaseResult {
+  /// Directory of the case, relative to the root.
+  name: String,
+  status: Status,
+  expected_size: Option<usize>,
+  actual_size: Option<usize>,
+}
+
+impl BatchCommand {
+  /// Parses the options of the command. Returns `None` if the help was requested.
+  pub fn parse(mut args: Args) -> CliResult<Option<Self>> {
+    let mut root: Option<PathBuf> = None;
+    let mut output_name = String::from("local-main.rs.avm1");
+    let mut jobs: Option<usize> = None;
+    while let Some(arg) = args.next_arg()? {
+      match arg {
+        Arg::Flag(flag) => match flag.as_str() {
+          "-h" | "--help" => return Ok(None),
+          "--output-name" => output_name = args.value(&flag)?,
+          "-j" | "--jobs" => {
+            let value = args.value(&flag)?;
+            match value.parse::<usize>() {
+              Ok(n) if n > 0 => jobs = Some(n),
+              _ => return Err(CliError::Usage(format!("invalid number of jobs: {}", value))),
+            }
+          }
+          _ => return Err(CliError::Usage(format!("unknown option: {}", flag))),
+        },
+        Arg::Positional(value) => {
+          if root.is_some() {
+            return Err(CliError::Usage(format!("unexpected argument: {}", value)));
+          }
+          root = Some(PathBuf::from(value));
+        }
+      }
+    }
+    let root = root.ok_or_else(|| CliError::Usage(String::from("missing directory")))?;
+    let jobs = jobs.unwrap_or_else(|| thread::available_parallelism().map(|n| n.get()).unwrap_or(1));
+    Ok(Some(Self {
+      root,
+      output_name,
+      jobs,
+    }))
+  }
+
+  pub fn run(&self) -> CliResult<()> {
+    let mut cases: Vec<PathBuf> = Vec::new();
+    find_cases(&self.root, &mut cases)?;
+    cases.sort();
+
+    let results = self.run_cases(cases);
+    print!("{}", format_results(&results));
+
+    let failed = results
+      .iter()
+      .filter(|r| matches!(r.status, Status::Fail | Status::Error(_)))
+      .count();
+    if failed > 0 {
+      Err(CliError::Other(format!("{} of {} cases failed", failed, results.len())))
+    } else {
+      Ok(())
+    }
+  }
+
+  /// Runs the cases on `self.jobs` threads, results are in the order of `cases`.
+  fn run_cases(&self, cases: Vec<PathBuf>) -> Vec<CaseResult> {
+    let count = cases.len();
+    let command = Arc::new(self.clone());
+    let cases = Arc::new(cases);
+    let next = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel::<(usize, CaseResult)>();
+    let workers: Vec<thread::JoinHandle<()>> = (0..self.jobs.min(count))
+      .map(|_| {
+        let command = Arc::clone(&command);
+        let cases = Arc::clone(&cases);
+        let next = Arc::clone(&next);
+        let sender = sender.clone();
+        thread::spawn(move || loop {
+          let i = next.fetch_add(1, Ordering::Relaxed);
+          let case = match cases.get(i) {
+            Some(case) => case,
+            None => break,
+          };
+          sender.send((i, command.run_case(case))).unwrap();
+        })
+      })
+      .collect();
+    drop(sender);
+
+    let mut results: Vec<Option<CaseResult>> = (0..count).map(|_| None).collect();
+    for (i, result) in receiver {
+      results[i] = Some(result);
+    }
+    for worker in workers {
+      worker.join().unwrap();
+    }
+    results.into_iter().map(|r| r.unwrap()).collect()
+  }
+
+  fn run_case(&self, dir: &Path) -> CaseResult {
+    let name = dir.strip_prefix(&self.root).unwrap_or(dir).display().to_string();
+    let expected = fs::read(dir.join(EXPECTED_NAME)).ok();
+    let mut result = CaseResult {
+      name,
+      status: Status::New,
+      expected_size: expected.as_ref().map(|e| e.len()),
+      actual_size: None,
+    };
+    let actual = match self.emit_case(dir) {
+      Ok(actual) => actual,
+      Err(e) => {
+        result.status = Status::Error(e.to_string());
+        return result;
+      }
+    };
+    result.actual_size = Some(actual.len());
+    result.status = match expected {
+      Some(expected) if expected == actual => Status::Pass,
+      Some(_) => Status::Fail,
+      None => Status::New,
+    };
+    result
+  }
+
+  fn emit_case(&self, dir: &Path) -> CliResult<Vec<u8>> {
+    let cfg: Cfg = read_json(&dir.join(INPUT_NAME))?;
+    let actual = catch_emit(|| emit_cfg(&cfg))?;
+    write_output(&dir.join(&self.output_name), &actual)?;
+    Ok(actual)
+  }
+}
+
+/// Collects the directories under `dir` containing a `cfg.json` file.
+/// Hidden entries are skipped.
+fn find_cases(dir: &Path, cases: &mut Vec<PathBuf>) -> CliResult<()> {
+  let io_error = |error| CliError::Io {
+    path: dir.to_path_buf(),
+    error,
+  };
+  if dir.join(INPUT_NAME).is_file() {
+    cases.push(dir.to_path_buf());
+  }
+  for entry in fs::read_dir(dir).map_err(io_error)? {
+    let entry = entry.map_err(io_error)?;
+    let hidden = entry.file_name().to_string_lossy().starts_with('.');
+    if !hidden && entry.file_type().map_err(io_error)?.is_dir() {
+      find_cases(&entry.path(), cases)?;
+    }
+  }
+  Ok(())
+}
+
+fn format_results(results: &[CaseResult]) -> String {
+  let size = |s: Option<usize>| s.map(|s| s.to_string()).unwrap_or_else(|| String::from("-"));
+  let mut out = format!(
+    "{:<6}  {:>8}  {:>8}  {:>6}  {}\n",
+    "STATUS", "EXPECTED", "ACTUAL", "DELTA", "CASE"
+  );
+  let (mut passed, mut failed, mut new, mut errors) = (0, 0, 0, 0);
+  for result in results {
+    let status = match result.status {
+      Status::Pass => {
+        passed += 1;
+        "pass"
+      }
+      Status::Fail => {
+        failed += 1;
+        "FAIL"
+      }
+      Status::New => {
+        new += 1;
+        "new"
+      }
+      Status::Error(_) => {
+        errors += 1;
+        "ERROR"
+      }
+    };
+    let delta = match (result.expected_size, result.actual_size) {
+      (Some(expected), Some(actual)) => format!("{:+}", actual as isize - expected as isize),
+      _ => String::from("-"),
+    };
+    let line = format!(
+      "{:<6}  {:>8}  {:>8}  {:>6}  {}",
+      status,
+      size(result.expected_size),
+      size(result.actual_size),
+      delta,
+      result.name
+    );
+    out.push_str(line.trim_end());
+    out.push('\n');
+    if let Status::Error(message) = &result.status {
+      out.push_str(&format!("        {}\n", message));
+    }
+  }
+  out.push_str(&format!(
+    "\n{} cases: {} passed, {} failed, {} new, {} errors\n",
+    results.len(),
+    passed,
+    failed,
+    new,
+    errors
+  ));
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_format_results() {
+    let results = vec![
+      CaseResult {
+        name: String::from("a/pass"),
+        status: Status::Pass,
+        expected_size: Some(10),
+        actual_size: Some(10),
+      },
+      CaseResult {
+        name: String::from("a/fail"),
+        status: Status::Fail,
+        expected_size: Some(10),
+        actual_size: Some(8),
+      },
+      CaseResult {
+        name: String::from("b/error"),
+        status: Status::Error(String::from("failed to emit AVM1: TargetLabelNotFound")),
+        expected_size: None,
+        actual_size: None,
+      },
+    ];
+    let expected = "\
+STATUS  EXPECTED    ACTUAL   DELTA  CASE
+pass          10        10      +0  a/pass
+FAIL          10         8      -2  a/fail
+ERROR          -         -       -  b/error
+        failed to emit AVM1: TargetLabelNotFound
+
+3 cases: 1 passed, 1 failed, 0 new, 1 errors
+";
+    assert_eq!(format_results(&results), expected);
+  }
+}
@@ -0,0 +1,226 @@
+use std::cell::Cell;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Error reported by a command.
+#[derive(Debug)]
+pub enum CliError {
+  /// Invalid command line, the usage is printed with the message.
+  Usage(String),
+  Io {
+    path: PathBuf,
+    error: io::Error,
+  },
+  Json {
+    path: PathBuf,
+    error: serde_json_v8::Error,
+  },
+  /// The library failed to emit the input.
+  Emit(String),
+  /// Any other error, already formatted.
+  Other(String),
+}
+
+impl CliError {
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      CliError::Usage(_) => 2,
+      _ => 1,
+    }
+  }
+}
+
+impl fmt::Display for CliError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      CliError::Usage(message) => f.write_str(message),
+      CliError::Io { path, error } => write!(f, "{}: {}", display_path(path), error),
+      CliError::Json { path, error } => write!(f, "{}: invalid JSON: {}", display_path(path), error),
+      CliError::Emit(message) => write!(f, "failed to emit AVM1: {}", message),
+      CliError::Other(message) => f.write_str(message),
+    }
+  }
+}
+
+pub type CliResult<T> = Result<T, CliError>;
+
+fn display_path(path: &Path) -> String {
+  if path == Path::new("-") {
+    String::from("<stdio>")
+  } else {
+    path.display().to_string()
+  }
+}
+
+/// Command line arguments, consumed from left to right.
+pub struct Args {
+  args: std::vec::IntoIter<String>,
+  /// Value attached to the last flag with `--flag=value`.
+  pending_value: Option<String>,
+}
+
+pub enum Arg {
+  Flag(String),
+  Positional(String),
+}
+
+impl Args {
+  pub fn new(args: Vec<String>) -> Self {
+    Self {
+      args: args.into_iter(),
+      pending_value: None,
+    }
+  }
+
+  pub fn next_arg(&mut self) -> CliResult<Option<Arg>> {
+    if let Some(value) = self.pending_value.take() {
+      return Err(CliError::Usage(format!("unexpected value: {}", value)));
+    }
+    let arg = match self.args.next() {
+      Some(arg) => arg,
+      None => return Ok(None),
+    };
+    if arg.len() > 1 && arg.starts_with('-') {
+      match arg.split_once('=') {
+        Some((flag, value)) if flag.starts_with("--") => {
+          self.pending_value = Some(value.to_string());
+          Ok(Some(Arg::Flag(flag.to_string())))
+        }
+        _ => Ok(Some(Arg::Flag(arg))),
+      }
+    } else {
+      Ok(Some(Arg::Positional(arg)))
+    }
+  }
+
+  /// Returns the value of `flag`.
+  pub fn value(&mut self, flag: &str) -> CliResult<String> {
+    match self.pending_value.take().or_else(|| self.args.next()) {
+      Some(value) => Ok(value),
+      None => Err(CliError::Usage(format!("missing value for {}", flag))),
+    }
+  }
+}
+
+/// Reads the file at `path`, or stdin if `path` is `-`.
+pub fn read_input(path: &Path) -> CliResult<Vec<u8>> {
+  let result = if path == Path::new("-") {
+    let stdin = io::stdin();
+    let mut bytes = Vec::new();
+    let result = stdin.lock().read_to_end(&mut bytes);
+    result.map(|_| bytes)
+  } else {
+    fs::read(path)
+  };
+  result.map_err(|error| CliError::Io {
+    path: path.to_path_buf(),
+    error,
+  })
+}
+
+/// Writes `bytes` to the file at `path`, or stdout if `path` is `-`.
+pub fn write_output(path: &Path, bytes: &[u8]) -> CliResult<()> {
+  let result = if path == Path::new("-") {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(bytes).and_then(|_| stdout.flush())
+  } else {
+    fs::write(path, bytes)
+  };
+  result.map_err(|error| CliError::Io {
+    path: path.to_path_buf(),
+    error,
+  })
+}
+
+pub fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> CliResult<T> {
+  let bytes = read_input(path)?;
+  serde_json_v8::from_slice(&bytes).map_err(|error| CliError::Json {
+    path: path.to_path_buf(),
+    error,
+  })
+}
+
+pub fn write_json<T: serde::Serialize>(path: &Path, value: &T) -> CliResult<()> {
+  let mut bytes = serde_json_v8::to_vec_pretty(value).map_err(|error| CliError::Json {
+    path: path.to_path_buf(),
+    error,
+  })?;
+  bytes.push(b'\n');
+  write_output(path, &bytes)
+}
+
+thread_local! {
+  /// Whether the current thread is running `catch_emit`.
+  static CATCHING_PANIC: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Installs a panic hook silencing the panics caught by `catch_emit`.
+pub fn install_panic_hook() {
+  let default_hook = std::panic::take_hook();
+  std::panic::set_hook(Box::new(move |info| {
+    if !CATCHING_PANIC.with(|c| c.get()) {
+      default_hook(info);
+    }
+  }));
+}
+
+/// Runs `f`, turning a panic of the emitter into an error.
+///
+/// The emitter panics on invalid graphs (e.g. a jump to a missing label).
+pub fn catch_emit<T>(f: impl FnOnce() -> io::Result<T> + std::panic::UnwindSafe) -> CliResult<T> {
+  CATCHING_PANIC.with(|c| c.set(true));
+  let result = std::panic::catch_unwind(f);
+  CATCHING_PANIC.with(|c| c.set(false));
+  match result {
+    Ok(Ok(value)) => Ok(value),
+    Ok(Err(error)) => Err(CliError::Emit(error.to_string())),
+    Err(payload) => {
+      let message = if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+      } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+      } else {
+        String::from("unknown error")
+      };
+      Err(CliError::Emit(message))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn collect(args: &[&str]) -> Vec<String> {
+    let mut args = Args::new(args.iter().map(|a| a.to_string()).collect());
+    let mut result = Vec::new();
+    while let Some(arg) = args.next_arg().unwrap() {
+      match arg {
+        Arg::Flag(flag) => {
+          let value = args.value(&flag).unwrap();
+          result.push(format!("{}={}", flag, value));
+        }
+        Arg::Positional(value) => result.push(value),
+      }
+    }
+    result
+  }
+
+  #[test]
+  fn test_args() {
+    assert_eq!(
+      collect(&["in.json", "-o", "out.avm1", "--map=map.json", "-"]),
+      vec!["in.json", "-o=out.avm1", "--map=map.json", "-"]
+    );
+  }
+
+  #[test]
+  fn test_catch_emit() {
+    let err = catch_emit(|| -> io::Result<()> { panic!("TargetLabelNotFound") }).unwrap_err();
+    assert_eq!(err.to_string(), "failed to emit AVM1: TargetLabelNotFound");
+  }
+}
@@ -0,0 +1,309 @@
+use crate::cli::{catch_emit, read_json, write_output, Arg, Args, CliError, CliResult};
+use avm1_emitter::emit_cfg_listing;
+use avm1_emitter::listing::{Listing, ListingAction, ListingLine};
+use avm1_emitter::JumpTarget;
+use avm1_types::cfg::Cfg;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: avm1-emitter diff [OPTIONS] <A> <B>
+
+Emits the CFGs in JSON from A and B and prints the differences between the
+emitted actions. Blocks are aligned by label and functions by name.
+Exits with a non-zero code if the actions differ.
+
+Options:
+  -o, --output <FILE>  Write the diff to FILE instead of stdout
+  -h, --help           Print this help
+";
+
+#[derive(Debug)]
+pub struct DiffCommand {
+  pub left: PathBuf,
+  pub right: PathBuf,
+  pub output: Option<PathBuf>,
+}
+
+/// Emitted action, described independently of its offset.
+struct DiffAction {
+  /// Mnemonic and operands, with jump offsets replaced by target labels.
+  text: String,
+  mnemonic: String,
+  target: Option<JumpTarget>,
+  size: usize,
+}
+
+impl DiffAction {
+  fn new(action: &ListingAction) -> Self {
+    let text = match &action.target {
+      Some((JumpTarget::Label(label), _)) => format!("{} -> {}", action.mnemonic, label.0),
+      Some((JumpTarget::End, _)) => format!("{} -> <end>", action.mnemonic),
+      None => format!("{} {}", action.mnemonic, action.operands)
+        .trim_end()
+        .to_string(),
+    };
+    Self {
+      text,
+      mnemonic: action.mnemonic.clone(),
+      target: action.target.as_ref().map(|(t, _)| t.clone()),
+      size: action.bytes.len(),
+    }
+  }
+}
+
+/// Actions of a listing grouped by block, blocks are identified by the
+/// path of the enclosing functions and their label.
+struct Blocks {
+  order: Vec<String>,
+  actions: HashMap<String, Vec<DiffAction>>,
+  size: usize,
+}
+
+impl Blocks {
+  fn new(listing: &Listing) -> Self {
+    let mut blocks = Self {
+      order: Vec::new(),
+      actions: HashMap::new(),
+      size: 0,
+    };
+    // Path of each scope, e.g. `f/g#2` for the second function `g` defined in `f`.
+    let mut paths: HashMap<usize, String> = HashMap::new();
+    paths.insert(0, String::new());
+    let mut name_counts: HashMap<String, usize> = HashMap::new();
+    let mut scopes: Vec<usize> = vec![0];
+    for line in listing.lines.iter() {
+      match line {
+        ListingLine::FunctionStart { scope, name, .. } => {
+          let parent = &paths[scopes.last().unwrap()];
+          let name = if name.is_empty() { "<anonymous>" } else { name.as_str() };
+          let mut path = if parent.is_empty() {
+            name.to_string()
+          } else {
+            format!("{}/{}", parent, name)
+          };
+          let count = name_counts.entry(path.clone()).or_default();
+          *count += 1;
+          if *count > 1 {
+            path = format!("{}#{}", path, count);
+          }
+          paths.insert(*scope, path);
+          scopes.push(*scope);
+        }
+        ListingLine::FunctionEnd { .. } => {
+          scopes.pop();
+        }
+        ListingLine::Action(action) => {
+          blocks.size += action.bytes.len();
+          let label = action.label.as_ref().map(|l| l.0.as_str()).unwrap_or("<end>");
+          let path = &paths[&action.scope];
+          let key = if path.is_empty() {
+            label.to_string()
+          } else {
+            format!("{}: {}", path, label)
+          };
+          if !blocks.actions.contains_key(&key) {
+            blocks.order.push(key.clone());
+          }
+          blocks.actions.entry(key).or_default().push(DiffAction::new(action));
+        }
+        _ => {}
+      }
+    }
+    blocks
+  }
+}
+
+enum Edit<'a> {
+  Same(&'a DiffAction),
+  Removed(&'a DiffAction),
+  Added(&'a DiffAction),
+  Changed(&'a DiffAction, &'a DiffAction),
+}
+
+/// Aligns two action lists with their longest common subsequence.
+fn diff_actions<'a>(left: &'a [DiffAction], right: &'a [DiffAction]) -> Vec<Edit<'a>> {
+  let same = |l: &DiffAction, r: &DiffAction| l.text == r.text && l.size == r.size;
+  // lcs[i][j]: length of the LCS of `left[i..]` and `right[j..]`
+  let mut lcs = vec![vec![0usize; right.len() + 1]; left.len() + 1];
+  for i in (0..left.len()).rev() {
+    for j in (0..right.len()).rev() {
+      lcs[i][j] = if same(&left[i], &right[j]) {
+        lcs[i + 1][j + 1] + 1
+      } else {
+        lcs[i + 1][j].max(lcs[i][j + 1])
+      };
+    }
+  }
+
+  let mut edits: Vec<Edit<'a>> = Vec::new();
+  let (mut i, mut j) = (0, 0);
+  while i < left.len() || j < right.len() {
+    if i < left.len() && j < right.len() && same(&left[i], &right[j]) {
+      edits.push(Edit::Same(&left[i]));
+      i += 1;
+      j += 1;
+    } else if i < left.len()
+      && j < right.len()
+      && lcs[i + 1][j + 1] == lcs[i][j]
+      && left[i].mnemonic == right[j].mnemonic
+    {
+      edits.push(Edit::Changed(&left[i], &right[j]));
+      i += 1;
+      j += 1;
+    } else if j == right.len() || (i < left.len() && lcs[i + 1][j] >= lcs[i][j + 1]) {
+      edits.push(Edit::Removed(&left[i]));
+      i += 1;
+    } else {
+      edits.push(Edit::Added(&right[j]));
+      j += 1;
+    }
+  }
+  edits
+}
+
+/// Returns the differences between the actions of two listings, or `None` if
+/// they emit the same actions.
+pub fn diff_listings(left: &Listing, right: &Listing, left_name: &str, right_name: &str) -> Option<String> {
+  let left = Blocks::new(left);
+  let right = Blocks::new(right);
+  let mut keys: Vec<&String> = left.order.iter().collect();
+  keys.extend(right.order.iter().filter(|k| !left.actions.contains_key(*k)));
+
+  let mut out = String::new();
+  let mut changed = false;
+  let empty: Vec<DiffAction> = Vec::new();
+  for key in keys {
+    let (l, r) = (left.actions.get(key), right.actions.get(key));
+    let edits = diff_actions(l.unwrap_or(&empty), r.unwrap_or(&empty));
+    if edits.iter().all(|e| matches!(e, Edit::Same(_))) {
+      continue;
+    }
+    changed = true;
+    let status = match (l, r) {
+      (None, _) => " (added)",
+      (_, None) => " (removed)",
+      _ => "",
+    };
+    writeln!(out, "@@ {}{} @@", key, status).unwrap();
+    for edit in edits {
+      match edit {
+        Edit::Same(a) => writeln!(out, "  {}", a.text),
+        Edit::Removed(a) => writeln!(out, "- {}", a.text),
+        Edit::Added(a) => writeln!(out, "+ {}", a.text),
+        Edit::Changed(a, b) => {
+          let mut notes: Vec<String> = Vec::new();
+          if a.target != b.target {
+            notes.push(String::from("jump target"));
+          } else if a.mnemonic == "Push" {
+            notes.push(String::from("push values"));
+          } else if a.text != b.text {
+            notes.push(String::from("operands"));
+          }
+          if a.size != b.size {
+            notes.push(format!("size {} -> {}", a.size, b.size));
+          }
+          writeln!(out, "~ {}  =>  {}  ({})", a.text, b.text, notes.join(", "))
+        }
+      }
+      .unwrap();
+    }
+  }
+
+  if !changed && left.size == right.size {
+    return None;
+  }
+  let header = format!(
+    "--- {} ({} bytes)\n+++ {} ({} bytes, {:+})\n",
+    left_name,
+    left.size,
+    right_name,
+    right.size,
+    right.size as isize - left.size as isize
+  );
+  Some(header + &out)
+}
+
+impl DiffCommand {
+  /// Parses the options of the command. Returns `None` if the help was requested.
+  pub fn parse(mut args: Args) -> CliResult<Option<Self>> {
+    let mut inputs: Vec<PathBuf> = Vec::new();
+    let mut output: Option<PathBuf> = None;
+    while let Some(arg) = args.next_arg()? {
+      match arg {
+        Arg::Flag(flag) => match flag.as_str() {
+          "-h" | "--help" => return Ok(None),
+          "-o" | "--output" => output = Some(PathBuf::from(args.value(&flag)?)),
+          _ => return Err(CliError::Usage(format!("unknown option: {}", flag))),
+        },
+        Arg::Positional(value) => inputs.push(PathBuf::from(value)),
+      }
+    }
+    if inputs.len() != 2 {
+      return Err(CliError::Usage(String::from("expected exactly two inputs")));
+    }
+    let right = inputs.pop().unwrap();
+    let left = inputs.pop().unwrap();
+    Ok(Some(Self { left, right, output }))
+  }
+
+  pub fn run(&self) -> CliResult<()> {
+    let output = self.output.clone().unwrap_or_else(|| PathBuf::from("-"));
+    let left: Cfg = read_json(&self.left)?;
+    let right: Cfg = read_json(&self.right)?;
+    let (_, left) = catch_emit(|| emit_cfg_listing(&left))?;
+    let (_, right) = catch_emit(|| emit_cfg_listing(&right))?;
+    let left_name = self.left.display().to_string();
+    let right_name = self.right.display().to_string();
+    match diff_listings(&left, &right, &left_name, &right_name) {
+      Some(diff) => {
+        write_output(&output, diff.as_bytes())?;
+        Err(CliError::Other(String::from("emitted actions differ")))
+      }
+      None => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn listing(json: &str) -> Listing {
+    let cfg: Cfg = serde_json_v8::from_str(json).unwrap();
+    emit_cfg_listing(&cfg).unwrap().1
+  }
+
+  #[test]
+  fn test_diff_listings() {
+    let left = listing(
+      r#"{"blocks": [
+        {"label": "a", "actions": [{"action": "Push", "values": [{"type": "String", "value": "x"}]}, {"action": "Trace"}],
+          "flow": {"type": "If", "true_target": "b", "false_target": null}},
+        {"label": "b", "actions": [{"action": "Stop"}], "flow": {"type": "Simple", "next": null}}
+      ]}"#,
+    );
+    let right = listing(
+      r#"{"blocks": [
+        {"label": "a", "actions": [{"action": "Push", "values": [{"type": "String", "value": "xy"}]}, {"action": "Trace"}],
+          "flow": {"type": "If", "true_target": "a", "false_target": null}},
+        {"label": "b", "actions": [{"action": "Play"}], "flow": {"type": "Simple", "next": null}}
+      ]}"#,
+    );
+    assert_eq!(diff_listings(&left, &left, "a.json", "a.json"), None);
+    let expected = "\
+--- a.json (15 bytes)
++++ b.json (16 bytes, +1)
+@@ a @@
+~ Push \"x\"  =>  Push \"xy\"  (push values, size 6 -> 7)
+  Trace
+~ If -> b  =>  If -> a  (jump target)
+  End
+@@ b @@
+- Stop
++ Play
+";
+    assert_eq!(diff_listings(&left, &right, "a.json", "b.json").unwrap(), expected);
+  }
+}
@@ -0,0 +1,246 @@
+use crate::cli::{catch_emit, read_json, write_json, write_output, Arg, Args, CliError, CliResult};
+use crate::format::{encode, OutputFormat};
+use avm1_emitter::instrument::coverage::instrument_coverage;
+use avm1_emitter::instrument::trace::instrument_function_tracing;
+use avm1_emitter::instrument::Probe;
+use avm1_emitter::opt::{optimize, Level};
+use avm1_emitter::swd::{emit_swd, DebugInfo};
+use avm1_emitter::{emit_cfg, emit_cfg_listing, emit_cfg_with_map, emit_raw_actions, EmitOptions};
+use avm1_types::cfg::Cfg;
+use avm1_types::raw;
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+Usage: avm1-emitter [OPTIONS] [INPUT]
+
+Reads a CFG in JSON from INPUT (stdin if omitted or `-`) and writes the
+emitted AVM1 bytes.
+
+Options:
+      --input-kind <KIND>         Kind of input: `cfg` (default) or `raw` for an
+                                  array of raw actions. Only --output and --format
+                                  apply to raw actions.
+  -o, --output <FILE>             Write the bytes to FILE instead of stdout
+      --format <FORMAT>           Encoding of the output: `binary` (default), `hex`,
+                                  `base64`, `rust`, `c`, `json` or `listing`
+      --map <FILE>                Write the offsets of blocks, actions and functions (JSON)
+      --listing <FILE>            Write an annotated disassembly
+      --coverage <PREFIX>         Add coverage probes reporting with `trace(PREFIX + id)`
+      --coverage-call <FUNCTION>  Add coverage probes reporting with `FUNCTION(id)`
+      --coverage-map <FILE>       Write the coverage probes (JSON)
+      --trace-functions <PREFIX>  Trace function calls with `trace`, prefixing messages
+      --trace-functions-call <FUNCTION>
+                                  Trace function calls by calling `FUNCTION(message)`
+      --debug-info <FILE>         Read source positions (JSON) and write a SWD file
+      --swd <FILE>                Path of the SWD file, required with --debug-info
+      --optimize <LEVEL>          Optimize the CFG before emitting it: `none` (default),
+                                  `size` or `speed`
+      --swf-version <VERSION>     SWF version of the movie running the code, allows
+                                  more constant folding with --optimize
+  -h, --help                      Print this help
+";
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InputKind {
+  /// Control flow graph (`cfg::Cfg`).
+  Cfg,
+  /// List of raw actions (`Vec<raw::Action>`).
+  Raw,
+}
+
+impl Default for InputKind {
+  fn default() -> Self {
+    InputKind::Cfg
+  }
+}
+
+#[derive(Debug, Default)]
+pub struct EmitCommand {
+  pub input: Option<PathBuf>,
+  pub input_kind: InputKind,
+  pub output: Option<PathBuf>,
+  pub format: OutputFormat,
+  pub map: Option<PathBuf>,
+  pub listing: Option<PathBuf>,
+  pub coverage: Option<Probe>,
+  pub coverage_map: Option<PathBuf>,
+  pub trace_functions: Option<Probe>,
+  pub debug_info: Option<PathBuf>,
+  pub swd: Option<PathBuf>,
+  pub options: EmitOptions,
+}
+
+impl EmitCommand {
+  /// Parses the options of the command. Returns `None` if the help was requested.
+  pub fn parse(mut args: Args) -> CliResult<Option<Self>> {
+    let mut command = Self::default();
+    while let Some(arg) = args.next_arg()? {
+      match arg {
+        Arg::Flag(flag) => match flag.as_str() {
+          "-h" | "--help" => return Ok(None),
+          "--input-kind" => {
+            command.input_kind = match args.value(&flag)?.as_str() {
+              "cfg" => InputKind::Cfg,
+              "raw" => InputKind::Raw,
+              kind => return Err(CliError::Usage(format!("unknown input kind: {}", kind))),
+            }
+          }
+          "-o" | "--output" => command.output = Some(PathBuf::from(args.value(&flag)?)),
+          "--format" => {
+            let value = args.value(&flag)?;
+            command.format =
+              OutputFormat::parse(&value).ok_or_else(|| CliError::Usage(format!("unknown format: {}", value)))?;
+          }
+          "--map" => command.map = Some(PathBuf::from(args.value(&flag)?)),
+          "--listing" => command.listing = Some(PathBuf::from(args.value(&flag)?)),
+          "--coverage" => {
+            command.coverage = Some(Probe::Trace {
+              prefix: args.value(&flag)?,
+            })
+          }
+          "--coverage-call" => {
+            command.coverage = Some(Probe::Call {
+              function: args.value(&flag)?,
+            })
+          }
+          "--coverage-map" => command.coverage_map = Some(PathBuf::from(args.value(&flag)?)),
+          "--trace-functions" => {
+            command.trace_functions = Some(Probe::Trace {
+              prefix: args.value(&flag)?,
+            })
+          }
+          "--trace-functions-call" => {
+            command.trace_functions = Some(Probe::Call {
+              function: args.value(&flag)?,
+            })
+          }
+          "--debug-info" => command.debug_info = Some(PathBuf::from(args.value(&flag)?)),
+          "--swd" => command.swd = Some(PathBuf::from(args.value(&flag)?)),
+          "--optimize" => {
+            command.options.level = match args.value(&flag)?.as_str() {
+              "none" => Level::None,
+              "size" => Level::Size,
+              "speed" => Level::Speed,
+              level => return Err(CliError::Usage(format!("unknown optimization level: {}", level))),
+            }
+          }
+          "--swf-version" => {
+            let value = args.value(&flag)?;
+            match value.parse::<u8>() {
+              Ok(version) => command.options.swf_version = Some(version),
+              _ => return Err(CliError::Usage(format!("invalid SWF version: {}", value))),
+            }
+          }
+          _ => return Err(CliError::Usage(format!("unknown option: {}", flag))),
+        },
+        Arg::Positional(value) => {
+          if command.input.is_some() {
+            return Err(CliError::Usage(format!("unexpected argument: {}", value)));
+          }
+          command.input = Some(PathBuf::from(value));
+        }
+      }
+    }
+    if command.coverage_map.is_some() && command.coverage.is_none() {
+      return Err(CliError::Usage(String::from(
+        "--coverage-map requires --coverage or --coverage-call",
+      )));
+    }
+    if command.debug_info.is_some() != command.swd.is_some() {
+      return Err(CliError::Usage(String::from(
+        "--debug-info and --swd must be used together",
+      )));
+    }
+    if command.input_kind == InputKind::Raw {
+      let has_cfg_options = command.map.is_some()
+        || command.listing.is_some()
+        || command.coverage.is_some()
+        || command.trace_functions.is_some()
+        || command.debug_info.is_some()
+        || command.options != EmitOptions::default()
+        || command.format == OutputFormat::Listing;
+      if has_cfg_options {
+        return Err(CliError::Usage(String::from(
+          "only --output and --format (except `listing`) can be used with `--input-kind raw`",
+        )));
+      }
+    }
+    Ok(Some(command))
+  }
+
+  pub fn run(&self) -> CliResult<()> {
+    let input = self.input.clone().unwrap_or_else(|| PathBuf::from("-"));
+    let output = self.output.clone().unwrap_or_else(|| PathBuf::from("-"));
+    if self.input_kind == InputKind::Raw {
+      let actions: Vec<raw::Action> = read_json(&input)?;
+      let bytes = catch_emit(|| emit_raw_actions(&actions))?;
+      return self.write(&output, &bytes, None);
+    }
+
+    let cfg: Cfg = read_json(&input)?;
+    let cfg = self.instrument(cfg)?;
+    // Maps and listings describe the optimized CFG.
+    let cfg = optimize(&cfg, self.options.level, self.options.swf_version);
+
+    // The bytes are taken from the first emission producing a map or a listing.
+    let mut bytes: Option<Vec<u8>> = None;
+    let map = if self.map.is_some() || self.swd.is_some() {
+      let (emitted, map) = catch_emit(|| emit_cfg_with_map(&cfg))?;
+      bytes = Some(emitted);
+      Some(map)
+    } else {
+      None
+    };
+    let listing = if self.listing.is_some() || self.format == OutputFormat::Listing {
+      let (emitted, listing) = catch_emit(|| emit_cfg_listing(&cfg))?;
+      bytes.get_or_insert(emitted);
+      Some(listing.to_string())
+    } else {
+      None
+    };
+    let bytes = match bytes {
+      Some(bytes) => bytes,
+      None => catch_emit(|| emit_cfg(&cfg))?,
+    };
+
+    if let (Some(path), Some(map)) = (&self.map, &map) {
+      write_json(path, map)?;
+    }
+    if let (Some(path), Some(listing)) = (&self.listing, &listing) {
+      write_output(path, listing.as_bytes())?;
+    }
+    if let (Some(debug_info), Some(swd), Some(map)) = (&self.debug_info, &self.swd, &map) {
+      let debug: DebugInfo = read_json(debug_info)?;
+      let bytes = emit_swd(map, &debug).map_err(|e| CliError::Other(format!("{}: {}", debug_info.display(), e)))?;
+      write_output(swd, &bytes)?;
+    }
+
+    let listing = listing.filter(|_| self.format == OutputFormat::Listing);
+    self.write(&output, &bytes, listing)
+  }
+
+  /// Writes `bytes` with the selected format.
+  fn write(&self, output: &Path, bytes: &[u8], listing: Option<String>) -> CliResult<()> {
+    match encode(self.format, bytes).or(listing) {
+      Some(text) => write_output(output, text.as_bytes()),
+      None => write_output(output, bytes),
+    }
+  }
+
+  fn instrument(&self, cfg: Cfg) -> CliResult<Cfg> {
+    let cfg = match &self.coverage {
+      Some(probe) => {
+        let (cfg, coverage_map) = instrument_coverage(&cfg, probe);
+        if let Some(path) = &self.coverage_map {
+          write_json(path, &coverage_map)?;
+        }
+        cfg
+      }
+      None => cfg,
+    };
+    Ok(match &self.trace_functions {
+      Some(probe) => instrument_function_tracing(&cfg, probe),
+      None => cfg,
+    })
+  }
+}
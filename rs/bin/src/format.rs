@@ -0,0 +1,130 @@
+use std::fmt::Write;
+
+/// Number of bytes per line in source code formats.
+const BYTES_PER_LINE: usize = 12;
+
+const BASE64_ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Encoding of the emitted bytes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+  Binary,
+  /// Lowercase hexadecimal string.
+  Hex,
+  /// Standard base64 string, with padding.
+  Base64,
+  /// Rust `&[u8]` literal.
+  Rust,
+  /// C `unsigned char` array with its length, like `xxd -i`.
+  C,
+  /// JSON array of numbers.
+  Json,
+  /// Annotated disassembly, only available for control flow graphs.
+  Listing,
+}
+
+impl Default for OutputFormat {
+  fn default() -> Self {
+    OutputFormat::Binary
+  }
+}
+
+impl OutputFormat {
+  pub fn parse(value: &str) -> Option<Self> {
+    match value {
+      "binary" => Some(OutputFormat::Binary),
+      "hex" => Some(OutputFormat::Hex),
+      "base64" => Some(OutputFormat::Base64),
+      "rust" => Some(OutputFormat::Rust),
+      "c" => Some(OutputFormat::C),
+      "json" => Some(OutputFormat::Json),
+      "listing" => Some(OutputFormat::Listing),
+      _ => None,
+    }
+  }
+}
+
+/// Encodes `bytes` in a text format. Returns `None` for `Binary` and `Listing`.
+pub fn encode(format: OutputFormat, bytes: &[u8]) -> Option<String> {
+  match format {
+    OutputFormat::Binary | OutputFormat::Listing => None,
+    OutputFormat::Hex => {
+      let mut out: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+      out.push('\n');
+      Some(out)
+    }
+    OutputFormat::Base64 => {
+      let mut out = base64(bytes);
+      out.push('\n');
+      Some(out)
+    }
+    OutputFormat::Rust => Some(format!("&[\n{}]\n", source_lines(bytes, true))),
+    OutputFormat::C => Some(format!(
+      "const unsigned char avm1[] = {{\n{}}};\nconst unsigned int avm1_len = {};\n",
+      source_lines(bytes, false),
+      bytes.len()
+    )),
+    OutputFormat::Json => {
+      let values: Vec<String> = bytes.iter().map(|b| b.to_string()).collect();
+      Some(format!("[{}]\n", values.join(",")))
+    }
+  }
+}
+
+/// Returns the bytes as indented lines of comma-separated hexadecimal literals.
+fn source_lines(bytes: &[u8], trailing_comma: bool) -> String {
+  let mut out = String::new();
+  let line_count = (bytes.len() + BYTES_PER_LINE - 1) / BYTES_PER_LINE;
+  for (i, line) in bytes.chunks(BYTES_PER_LINE).enumerate() {
+    let values: Vec<String> = line.iter().map(|b| format!("0x{:02x}", b)).collect();
+    let separator = if trailing_comma || i + 1 < line_count { "," } else { "" };
+    writeln!(out, "  {}{}", values.join(", "), separator).unwrap();
+  }
+  out
+}
+
+fn base64(bytes: &[u8]) -> String {
+  let mut out = String::with_capacity((bytes.len() + 2) / 3 * 4);
+  for chunk in bytes.chunks(3) {
+    let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+    let n = (u32::from(b[0]) << 16) | (u32::from(b[1]) << 8) | u32::from(b[2]);
+    for i in 0..4 {
+      if i <= chunk.len() {
+        out.push(char::from(BASE64_ALPHABET[((n >> (18 - 6 * i)) & 0x3f) as usize]));
+      } else {
+        out.push('=');
+      }
+    }
+  }
+  out
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_base64() {
+    assert_eq!(base64(b""), "");
+    assert_eq!(base64(b"f"), "Zg==");
+    assert_eq!(base64(b"fo"), "Zm8=");
+    assert_eq!(base64(b"foo"), "Zm9v");
+    assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+  }
+
+  #[test]
+  fn test_encode() {
+    let bytes: Vec<u8> = (0..14).collect();
+    assert_eq!(encode(OutputFormat::Hex, &bytes[..3]).unwrap(), "000102\n");
+    assert_eq!(
+      encode(OutputFormat::Rust, &bytes).unwrap(),
+      "&[\n  0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,\n  0x0c, 0x0d,\n]\n"
+    );
+    assert_eq!(
+      encode(OutputFormat::C, &bytes[10..]).unwrap(),
+      "const unsigned char avm1[] = {\n  0x0a, 0x0b, 0x0c, 0x0d\n};\nconst unsigned int avm1_len = 4;\n"
+    );
+    assert_eq!(encode(OutputFormat::Json, &bytes[..3]).unwrap(), "[0,1,2]\n");
+    assert_eq!(encode(OutputFormat::Binary, &bytes), None);
+  }
+}
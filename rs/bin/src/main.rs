@@ -1,3 +1,89 @@
+mod asm;
+mod batch;
+mod cli;
+mod diff;
+mod emit;
+mod format;
+mod size_report;
+
+use crate::asm::AsmCommand;
+use crate::batch::BatchCommand;
+use crate::cli::{install_panic_hook, Args, CliError, CliResult};
+use crate::diff::DiffCommand;
+use crate::emit::EmitCommand;
+use crate::size_report::SizeReportCommand;
+use std::process;
+
+const COMMANDS: &str = "\
+Commands:
+  asm          Assemble an AVM1 assembly source
+  batch        Emit every `cfg.json` file of a fixture directory tree
+  diff         Compare the actions emitted for two CFGs
+  size-report  Report the size of the emitted functions, blocks and opcodes
+
+Run `avm1-emitter <COMMAND> --help` for the options of a command.
+";
+
+fn run(mut args: Vec<String>) -> CliResult<()> {
+  let command = if args.is_empty() { None } else { Some(args[0].as_str()) };
+  match command {
+    Some("asm") => {
+      args.remove(0);
+      match AsmCommand::parse(Args::new(args))? {
+        Some(command) => command.run(),
+        None => {
+          print!("{}", asm::USAGE);
+          Ok(())
+        }
+      }
+    }
+    Some("batch") => {
+      args.remove(0);
+      match BatchCommand::parse(Args::new(args))? {
+        Some(command) => command.run(),
+        None => {
+          print!("{}", batch::USAGE);
+          Ok(())
+        }
+      }
+    }
+    Some("diff") => {
+      args.remove(0);
+      match DiffCommand::parse(Args::new(args))? {
+        Some(command) => command.run(),
+        None => {
+          print!("{}", diff::USAGE);
+          Ok(())
+        }
+      }
+    }
+    Some("size-report") => {
+      args.remove(0);
+      match SizeReportCommand::parse(Args::new(args))? {
+        Some(command) => command.run(),
+        None => {
+          print!("{}", size_report::USAGE);
+          Ok(())
+        }
+      }
+    }
+    _ => match EmitCommand::parse(Args::new(args))? {
+      Some(command) => command.run(),
+      None => {
+        print!("{}\n{}", emit::USAGE, COMMANDS);
+        Ok(())
+      }
+    },
+  }
+}
+
 fn main() {
-  unimplemented!();
+  install_panic_hook();
+  if let Err(error) = run(std::env::args().skip(1).collect()) {
+    eprintln!("avm1-emitter: {}", error);
+    if let CliError::Usage(_) = error {
+      eprintln!("Try `avm1-emitter --help` for more information.");
+    }
+    process::exit(error.exit_code());
+  }
 }
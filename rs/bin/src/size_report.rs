@@ -0,0 +1,311 @@
+use crate::cli::{catch_emit, read_json, write_json, write_output, Arg, Args, CliError, CliResult};
+use avm1_emitter::emit_cfg_listing;
+use avm1_emitter::listing::{Listing, ListingLine};
+use avm1_emitter::RegionKind;
+use avm1_types::cfg::Cfg;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: avm1-emitter size-report [OPTIONS] [INPUT]
+
+Emits the CFG in JSON from INPUT (stdin if omitted or `-`) and reports where
+the bytes go.
+
+Options:
+      --json           Print the report as JSON
+  -o, --output <FILE>  Write the report to FILE instead of stdout
+  -h, --help           Print this help
+";
+
+#[derive(Debug, Default)]
+pub struct SizeReportCommand {
+  pub input: Option<PathBuf>,
+  pub output: Option<PathBuf>,
+  pub json: bool,
+}
+
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct SizeReport {
+  pub total: usize,
+  /// Bytes of the top-level code, excluding function definitions.
+  pub top_level: usize,
+  /// Bytes of the top-level function definitions, including their body.
+  pub functions_total: usize,
+  /// `Try` and `With` regions, in emission order.
+  pub regions: Vec<RegionSize>,
+  /// Function definitions, in emission order.
+  pub functions: Vec<FunctionSize>,
+  /// Blocks, in emission order.
+  pub blocks: Vec<BlockSize>,
+  /// Actions grouped by opcode, largest first.
+  pub opcodes: Vec<OpcodeSize>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct RegionSize {
+  pub scope: usize,
+  pub kind: RegionKind,
+  pub offset: usize,
+  pub size: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct FunctionSize {
+  pub scope: usize,
+  /// Function name, empty string if anonymous.
+  pub name: String,
+  /// Bytes of the `DefineFunction` or `DefineFunction2` action.
+  pub header: usize,
+  /// Bytes of the body, including nested functions.
+  pub body: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct BlockSize {
+  pub scope: usize,
+  pub label: String,
+  /// Bytes of the actions of the block, excluding nested blocks and function bodies.
+  pub size: usize,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct OpcodeSize {
+  pub mnemonic: String,
+  pub count: usize,
+  pub size: usize,
+}
+
+impl SizeReport {
+  pub fn new(listing: &Listing) -> Self {
+    let mut report = Self::default();
+    let mut opcodes: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    // Size of the last action, used as the header size of function definitions.
+    let mut last_action_size: usize = 0;
+    // Scopes of the functions being listed, innermost last.
+    let mut scopes: Vec<usize> = vec![0];
+    for line in listing.lines.iter() {
+      match line {
+        ListingLine::Label { scope, label } => report.blocks.push(BlockSize {
+          scope: *scope,
+          label: label.0.clone(),
+          size: 0,
+        }),
+        ListingLine::Action(action) => {
+          let size = action.bytes.len();
+          report.total += size;
+          if action.scope == 0 {
+            report.top_level += size;
+          }
+          if let Some(label) = &action.label {
+            let block = report
+              .blocks
+              .iter_mut()
+              .rev()
+              .find(|b| b.scope == action.scope && b.label == label.0);
+            if let Some(block) = block {
+              block.size += size;
+            }
+          }
+          let opcode = opcodes.entry(action.mnemonic.as_str()).or_default();
+          opcode.0 += 1;
+          opcode.1 += size;
+          last_action_size = size;
+        }
+        ListingLine::FunctionStart { scope, name, body } => {
+          let function = FunctionSize {
+            scope: *scope,
+            name: name.clone(),
+            header: last_action_size,
+            body: body.end - body.start,
+          };
+          if scopes.last() == Some(&0) {
+            report.functions_total += function.header + function.body;
+            report.top_level -= function.header;
+          }
+          scopes.push(*scope);
+          report.functions.push(function);
+        }
+        ListingLine::RegionStart { scope, kind, range } => report.regions.push(RegionSize {
+          scope: *scope,
+          kind: *kind,
+          offset: range.start,
+          size: range.end - range.start,
+        }),
+        ListingLine::FunctionEnd { .. } => {
+          scopes.pop();
+        }
+        ListingLine::RegionEnd { .. } => {}
+      }
+    }
+    report.opcodes = opcodes
+      .into_iter()
+      .map(|(mnemonic, (count, size))| OpcodeSize {
+        mnemonic: mnemonic.to_string(),
+        count,
+        size,
+      })
+      .collect();
+    report
+      .opcodes
+      .sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.mnemonic.cmp(&b.mnemonic)));
+    report
+  }
+}
+
+impl std::fmt::Display for SizeReport {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    let percent = |size: usize| -> f64 {
+      if self.total == 0 {
+        0.0
+      } else {
+        100.0 * size as f64 / self.total as f64
+      }
+    };
+    writeln!(f, "Total: {} bytes", self.total)?;
+    writeln!(
+      f,
+      "  top-level code  {:>8}  {:>5.1}%",
+      self.top_level,
+      percent(self.top_level)
+    )?;
+    writeln!(
+      f,
+      "  functions       {:>8}  {:>5.1}%",
+      self.functions_total,
+      percent(self.functions_total)
+    )?;
+
+    if !self.regions.is_empty() {
+      writeln!(f, "\nRegions:")?;
+      writeln!(f, "  {:>5}  {:<8}  {:>6}  {:>8}", "SCOPE", "KIND", "OFFSET", "SIZE")?;
+      for region in self.regions.iter() {
+        let kind = format!("{:?}", region.kind).to_lowercase();
+        writeln!(
+          f,
+          "  {:>5}  {:<8}  {:>6}  {:>8}",
+          region.scope,
+          kind,
+          format!("{:04x}", region.offset),
+          region.size
+        )?;
+      }
+    }
+
+    if !self.functions.is_empty() {
+      writeln!(f, "\nFunctions:")?;
+      writeln!(f, "  {:>5}  {:>6}  {:>8}  NAME", "SCOPE", "HEADER", "BODY")?;
+      for function in self.functions.iter() {
+        let name = if function.name.is_empty() {
+          "<anonymous>"
+        } else {
+          function.name.as_str()
+        };
+        writeln!(
+          f,
+          "  {:>5}  {:>6}  {:>8}  {}",
+          function.scope, function.header, function.body, name
+        )?;
+      }
+    }
+
+    writeln!(f, "\nBlocks:")?;
+    writeln!(f, "  {:>5}  {:>8}  LABEL", "SCOPE", "SIZE")?;
+    for block in self.blocks.iter() {
+      writeln!(f, "  {:>5}  {:>8}  {}", block.scope, block.size, block.label)?;
+    }
+
+    writeln!(f, "\nOpcodes:")?;
+    writeln!(f, "  {:<16}  {:>6}  {:>8}  {:>6}", "MNEMONIC", "COUNT", "SIZE", "SHARE")?;
+    for opcode in self.opcodes.iter() {
+      writeln!(
+        f,
+        "  {:<16}  {:>6}  {:>8}  {:>5.1}%",
+        opcode.mnemonic,
+        opcode.count,
+        opcode.size,
+        percent(opcode.size)
+      )?;
+    }
+    Ok(())
+  }
+}
+
+impl SizeReportCommand {
+  /// Parses the options of the command. Returns `None` if the help was requested.
+  pub fn parse(mut args: Args) -> CliResult<Option<Self>> {
+    let mut command = Self::default();
+    while let Some(arg) = args.next_arg()? {
+      match arg {
+        Arg::Flag(flag) => match flag.as_str() {
+          "-h" | "--help" => return Ok(None),
+          "--json" => command.json = true,
+          "-o" | "--output" => command.output = Some(PathBuf::from(args.value(&flag)?)),
+          _ => return Err(CliError::Usage(format!("unknown option: {}", flag))),
+        },
+        Arg::Positional(value) => {
+          if command.input.is_some() {
+            return Err(CliError::Usage(format!("unexpected argument: {}", value)));
+          }
+          command.input = Some(PathBuf::from(value));
+        }
+      }
+    }
+    Ok(Some(command))
+  }
+
+  pub fn run(&self) -> CliResult<()> {
+    let input = self.input.clone().unwrap_or_else(|| PathBuf::from("-"));
+    let output = self.output.clone().unwrap_or_else(|| PathBuf::from("-"));
+    let cfg: Cfg = read_json(&input)?;
+    let (_, listing) = catch_emit(|| emit_cfg_listing(&cfg))?;
+    let report = SizeReport::new(&listing);
+    if self.json {
+      write_json(&output, &report)
+    } else {
+      write_output(&output, report.to_string().as_bytes())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_size_report() {
+    let cfg: Cfg = serde_json_v8::from_str(
+      r#"{"blocks": [{
+        "label": "l0",
+        "actions": [
+          {"action": "DefineFunction", "name": "f", "parameters": [], "body": {"blocks": [{
+            "label": "f0",
+            "actions": [{"action": "Push", "values": [{"type": "Sint32", "value": 1}]}],
+            "flow": {"type": "Return"}
+          }]}},
+          {"action": "Stop"}
+        ],
+        "flow": {"type": "Simple", "next": null}
+      }]}"#,
+    )
+    .unwrap();
+    let (bytes, listing) = emit_cfg_listing(&cfg).unwrap();
+    let report = SizeReport::new(&listing);
+    assert_eq!(report.total, bytes.len());
+    assert_eq!(
+      report.functions,
+      vec![FunctionSize {
+        scope: 1,
+        name: String::from("f"),
+        header: 9,
+        body: 9,
+      }]
+    );
+    assert_eq!(report.functions_total, 18);
+    assert_eq!(report.top_level, 2);
+    assert_eq!(report.blocks[0].size, 10);
+    assert_eq!(report.blocks[1].size, 9);
+    assert_eq!(report.opcodes[0].mnemonic, "DefineFunction");
+  }
+}
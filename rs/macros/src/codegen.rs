@@ -0,0 +1,256 @@
+//! Generation of the Rust expressions building a CFG.
+
+use avm1_types::cfg::{self, Cfg, CfgFlow, CfgLabel};
+use avm1_types::{CatchTarget, GetUrl2Method, PushValue};
+use proc_macro2::{Ident, Span, TokenStream};
+use quote::quote;
+
+pub(crate) fn cfg(value: &Cfg) -> TokenStream {
+  let blocks = value.blocks.iter().map(|block| {
+    let label = label(&block.label);
+    let actions = block.actions.iter().map(action);
+    let flow = flow(&block.flow);
+    quote!(::avm1_types::cfg::CfgBlock {
+      label: #label,
+      actions: ::std::vec![#(#actions),*],
+      flow: #flow,
+    })
+  });
+  // `Vec1` is built from a `VecDeque` so the caller does not need to depend on `vec1`
+  quote!(::avm1_types::cfg::Cfg {
+    blocks: ::std::result::Result::unwrap(::std::convert::TryFrom::try_from(::std::collections::VecDeque::from(
+      ::std::vec![#(#blocks),*]
+    ))),
+  })
+}
+
+fn label(value: &CfgLabel) -> TokenStream {
+  let value = &value.0;
+  quote!(::avm1_types::cfg::CfgLabel(::std::string::String::from(#value)))
+}
+
+fn target(value: &Option<CfgLabel>) -> TokenStream {
+  match value {
+    Some(value) => {
+      let value = label(value);
+      quote!(::std::option::Option::Some(#value))
+    }
+    None => quote!(::std::option::Option::None),
+  }
+}
+
+fn string(value: &str) -> TokenStream {
+  quote!(::std::string::String::from(#value))
+}
+
+fn flow(value: &CfgFlow) -> TokenStream {
+  match value {
+    CfgFlow::Error(_) => quote!(::avm1_types::cfg::CfgFlow::Error(::avm1_types::cfg::Error {
+      error: ::std::option::Option::None
+    })),
+    CfgFlow::If(flow) => {
+      let (true_target, false_target) = (target(&flow.true_target), target(&flow.false_target));
+      quote!(::avm1_types::cfg::CfgFlow::If(::avm1_types::cfg::If {
+        true_target: #true_target,
+        false_target: #false_target,
+      }))
+    }
+    CfgFlow::Return => quote!(::avm1_types::cfg::CfgFlow::Return),
+    CfgFlow::Simple(flow) => {
+      let next = target(&flow.next);
+      quote!(::avm1_types::cfg::CfgFlow::Simple(::avm1_types::cfg::Simple { next: #next }))
+    }
+    CfgFlow::Throw => quote!(::avm1_types::cfg::CfgFlow::Throw),
+    CfgFlow::Try(flow) => {
+      let try_body = cfg(&flow.r#try);
+      let catch = match &flow.catch {
+        Some(catch) => {
+          let target = match &catch.target {
+            CatchTarget::Register(r) => quote!(::avm1_types::CatchTarget::Register(#r)),
+            CatchTarget::Variable(v) => {
+              let v = string(v);
+              quote!(::avm1_types::CatchTarget::Variable(#v))
+            }
+          };
+          let body = cfg(&catch.body);
+          quote!(::std::option::Option::Some(::avm1_types::cfg::CatchBlock { target: #target, body: #body }))
+        }
+        None => quote!(::std::option::Option::None),
+      };
+      let finally = match &flow.finally {
+        Some(finally) => {
+          let finally = cfg(finally);
+          quote!(::std::option::Option::Some(#finally))
+        }
+        None => quote!(::std::option::Option::None),
+      };
+      quote!(::avm1_types::cfg::CfgFlow::Try(::std::boxed::Box::new(::avm1_types::cfg::Try {
+        r#try: #try_body,
+        catch: #catch,
+        finally: #finally,
+      })))
+    }
+    CfgFlow::WaitForFrame(flow) => {
+      let frame = flow.frame;
+      let (ready, loading) = (target(&flow.ready_target), target(&flow.loading_target));
+      quote!(::avm1_types::cfg::CfgFlow::WaitForFrame(::avm1_types::cfg::WaitForFrame {
+        frame: #frame,
+        ready_target: #ready,
+        loading_target: #loading,
+      }))
+    }
+    CfgFlow::WaitForFrame2(flow) => {
+      let (ready, loading) = (target(&flow.ready_target), target(&flow.loading_target));
+      quote!(::avm1_types::cfg::CfgFlow::WaitForFrame2(::avm1_types::cfg::WaitForFrame2 {
+        ready_target: #ready,
+        loading_target: #loading,
+      }))
+    }
+    CfgFlow::With(flow) => {
+      let body = cfg(&flow.body);
+      quote!(::avm1_types::cfg::CfgFlow::With(::avm1_types::cfg::With { body: #body }))
+    }
+  }
+}
+
+fn push_value(value: &PushValue) -> TokenStream {
+  match value {
+    PushValue::Boolean(v) => quote!(::avm1_types::PushValue::Boolean(#v)),
+    PushValue::Constant(v) => quote!(::avm1_types::PushValue::Constant(#v)),
+    PushValue::Float32(v) => {
+      // Bits are used to keep NaN and infinite values
+      let bits = v.to_bits();
+      quote!(::avm1_types::PushValue::Float32(::std::primitive::f32::from_bits(#bits)))
+    }
+    PushValue::Float64(v) => {
+      let bits = v.to_bits();
+      quote!(::avm1_types::PushValue::Float64(::std::primitive::f64::from_bits(#bits)))
+    }
+    PushValue::Null => quote!(::avm1_types::PushValue::Null),
+    PushValue::Register(v) => quote!(::avm1_types::PushValue::Register(#v)),
+    PushValue::Sint32(v) => quote!(::avm1_types::PushValue::Sint32(#v)),
+    PushValue::String(v) => {
+      let v = string(v);
+      quote!(::avm1_types::PushValue::String(#v))
+    }
+    PushValue::Undefined => quote!(::avm1_types::PushValue::Undefined),
+  }
+}
+
+fn action(value: &cfg::Action) -> TokenStream {
+  use avm1_types::cfg::Action;
+
+  match value {
+    Action::ConstantPool(a) => {
+      let pool = a.pool.iter().map(|s| string(s));
+      quote!(::avm1_types::cfg::Action::ConstantPool(
+        ::avm1_types::action::ConstantPool {
+          pool: ::std::vec![#(#pool),*],
+        }
+      ))
+    }
+    Action::DefineFunction(a) => {
+      let name = string(&a.name);
+      let parameters = a.parameters.iter().map(|s| string(s));
+      let body = cfg(&a.body);
+      quote!(::avm1_types::cfg::Action::DefineFunction(::std::boxed::Box::new(
+        ::avm1_types::cfg::DefineFunction {
+          name: #name,
+          parameters: ::std::vec![#(#parameters),*],
+          body: #body,
+        }
+      )))
+    }
+    Action::DefineFunction2(a) => {
+      let name = string(&a.name);
+      let register_count = a.register_count;
+      let flags = a.flags.bits();
+      let parameters = a.parameters.iter().map(|p| {
+        let register = p.register;
+        let name = string(&p.name);
+        quote!(::avm1_types::Parameter { register: #register, name: #name })
+      });
+      let body = cfg(&a.body);
+      quote!(::avm1_types::cfg::Action::DefineFunction2(::std::boxed::Box::new(
+        ::avm1_types::cfg::DefineFunction2 {
+          name: #name,
+          register_count: #register_count,
+          flags: ::avm1_types::FunctionFlags::from_bits_truncate(#flags),
+          parameters: ::std::vec![#(#parameters),*],
+          body: #body,
+        }
+      )))
+    }
+    Action::GetUrl(a) => {
+      let (url, target) = (string(&a.url), string(&a.target));
+      quote!(::avm1_types::cfg::Action::GetUrl(::std::boxed::Box::new(::avm1_types::action::GetUrl {
+        url: #url,
+        target: #target,
+      })))
+    }
+    Action::GetUrl2(a) => {
+      let method = match a.method {
+        GetUrl2Method::None => quote!(::avm1_types::GetUrl2Method::None),
+        GetUrl2Method::Get => quote!(::avm1_types::GetUrl2Method::Get),
+        GetUrl2Method::Post => quote!(::avm1_types::GetUrl2Method::Post),
+      };
+      let (load_target, load_variables) = (a.load_target, a.load_variables);
+      quote!(::avm1_types::cfg::Action::GetUrl2(::avm1_types::action::GetUrl2 {
+        method: #method,
+        load_target: #load_target,
+        load_variables: #load_variables,
+      }))
+    }
+    Action::GotoFrame(a) => {
+      let frame = a.frame;
+      quote!(::avm1_types::cfg::Action::GotoFrame(::avm1_types::action::GotoFrame { frame: #frame }))
+    }
+    Action::GotoFrame2(a) => {
+      let (play, scene_bias) = (a.play, a.scene_bias);
+      quote!(::avm1_types::cfg::Action::GotoFrame2(::avm1_types::action::GotoFrame2 {
+        play: #play,
+        scene_bias: #scene_bias,
+      }))
+    }
+    Action::GotoLabel(a) => {
+      let label = string(&a.label);
+      quote!(::avm1_types::cfg::Action::GotoLabel(::avm1_types::action::GoToLabel { label: #label }))
+    }
+    Action::Push(a) => {
+      let values = a.values.iter().map(push_value);
+      quote!(::avm1_types::cfg::Action::Push(::avm1_types::action::Push {
+        values: ::std::vec![#(#values),*],
+      }))
+    }
+    Action::Raw(a) => {
+      let (code, data) = (a.code, &a.data);
+      quote!(::avm1_types::cfg::Action::Raw(::std::boxed::Box::new(::avm1_types::action::Raw {
+        code: #code,
+        data: ::std::vec![#(#data),*],
+      })))
+    }
+    Action::SetTarget(a) => {
+      let target_name = string(&a.target_name);
+      quote!(::avm1_types::cfg::Action::SetTarget(::avm1_types::action::SetTarget {
+        target_name: #target_name,
+      }))
+    }
+    Action::StoreRegister(a) => {
+      let register = a.register;
+      quote!(::avm1_types::cfg::Action::StoreRegister(::avm1_types::action::StoreRegister {
+        register: #register,
+      }))
+    }
+    Action::StrictMode(a) => {
+      let is_strict = a.is_strict;
+      quote!(::avm1_types::cfg::Action::StrictMode(::avm1_types::action::StrictMode {
+        is_strict: #is_strict,
+      }))
+    }
+    // The remaining actions have no operands, their debug representation is the variant name
+    action => {
+      let variant = Ident::new(&format!("{:?}", action), Span::call_site());
+      quote!(::avm1_types::cfg::Action::#variant)
+    }
+  }
+}
@@ -0,0 +1,214 @@
+//! Compile-time AVM1 assembler.
+//!
+//! The macros accept the syntax of `avm1_emitter::asm` with snake case
+//! mnemonics, statements separated by semicolons and optional `label`
+//! keywords:
+//!
+//! ```
+//! use avm1_emitter_macros::{avm1, avm1_cfg};
+//!
+//! let bytes: Vec<u8> = avm1! { push "a", 1; get_variable; label l1: if l1; };
+//! let cfg: avm1_types::cfg::Cfg = avm1_cfg! { push "Hello"; trace; };
+//! ```
+//!
+//! Errors are reported at the offending token:
+//!
+//! ```compile_fail
+//! let bytes: Vec<u8> = avm1_emitter_macros::avm1! { push "a"; get_varaible; };
+//! ```
+
+mod codegen;
+
+use avm1_emitter::asm::parse_asm;
+use avm1_emitter::emit_cfg;
+use avm1_types::cfg::Cfg;
+use proc_macro2::{Delimiter, Span, TokenStream, TokenTree};
+use quote::quote;
+
+/// Assembles the source at compile time and expands to the emitted bytes, as a `Vec<u8>`.
+#[proc_macro]
+pub fn avm1(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+  let result = parse(input.into()).and_then(|cfg| match emit_cfg(&cfg) {
+    Ok(bytes) => Ok(quote!(::std::vec![#(#bytes),*])),
+    Err(e) => Err(syn::Error::new(
+      Span::call_site(),
+      format!("failed to emit AVM1: {}", e),
+    )),
+  });
+  result.unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+/// Assembles the source at compile time and expands to an `avm1_types::cfg::Cfg`.
+#[proc_macro]
+pub fn avm1_cfg(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+  let result = parse(input.into()).map(|cfg| codegen::cfg(&cfg));
+  result.unwrap_or_else(|e| e.to_compile_error()).into()
+}
+
+fn parse(input: TokenStream) -> syn::Result<Cfg> {
+  let source = AsmSource::new(input)?;
+  parse_asm(&source.text).map_err(|e| {
+    let span = source
+      .token_at(e.column)
+      .map_or_else(Span::call_site, |i| source.tokens[i].1);
+    syn::Error::new(span, e.message)
+  })
+}
+
+/// Words kept as-is at the start of a statement.
+const KEYWORDS: &[&str] = &["function", "function2", "try", "catch", "finally", "with"];
+
+/// Assembly source converted from the macro input, on a single line.
+struct AsmSource {
+  text: String,
+  /// Column and span of each token of `text`.
+  tokens: Vec<(usize, Span)>,
+}
+
+impl AsmSource {
+  fn new(input: TokenStream) -> syn::Result<Self> {
+    let mut source = Self {
+      text: String::new(),
+      tokens: Vec::new(),
+    };
+    source.push_stream(input, true)?;
+    Ok(source)
+  }
+
+  /// Returns the index of the token at `column` (1-based).
+  fn token_at(&self, column: usize) -> Option<usize> {
+    let index = self.tokens.iter().rposition(|(start, _)| *start <= column)?;
+    let end = self
+      .tokens
+      .get(index + 1)
+      .map_or(self.text.chars().count() + 1, |(start, _)| *start);
+    if column < end {
+      Some(index)
+    } else {
+      None
+    }
+  }
+
+  fn push(&mut self, text: &str, span: Span) {
+    if !self.text.is_empty() {
+      self.text.push(' ');
+    }
+    self.tokens.push((self.text.chars().count() + 1, span));
+    self.text.push_str(text);
+  }
+
+  /// Pushes the tokens of `input`, which is a list of statements if
+  /// `statements` or a list of operands otherwise (e.g. function parameters).
+  fn push_stream(&mut self, input: TokenStream, statements: bool) -> syn::Result<()> {
+    let tokens: Vec<TokenTree> = input.into_iter().collect();
+    let mut statement_start = statements;
+    let mut i = 0;
+    while i < tokens.len() {
+      let token = &tokens[i];
+      let is_colon = |i: usize| matches!(tokens.get(i), Some(TokenTree::Punct(p)) if p.as_char() == ':');
+      match token {
+        TokenTree::Ident(ident) => {
+          let mut name = ident.to_string();
+          if statement_start && name == "label" {
+            i += 1;
+            continue;
+          }
+          if statement_start && is_colon(i + 1) {
+            // Label definition, the next token starts a statement
+            self.push(&name, ident.span());
+            self.push(":", tokens[i + 1].span());
+            i += 2;
+            continue;
+          }
+          if statement_start && !KEYWORDS.contains(&name.as_str()) {
+            name = pascal_case(&name);
+          }
+          self.push(&name, ident.span());
+          statement_start = false;
+        }
+        TokenTree::Punct(punct) => match punct.as_char() {
+          ';' if statements => statement_start = true,
+          c @ (':' | ',' | '=' | '|' | '-') => self.push(&c.to_string(), punct.span()),
+          c => return Err(syn::Error::new(punct.span(), format!("unexpected character: {:?}", c))),
+        },
+        TokenTree::Literal(literal) => {
+          let text = match syn::Lit::new(literal.clone()) {
+            syn::Lit::Str(s) => format!("{:?}", s.value()),
+            syn::Lit::Int(_) | syn::Lit::Float(_) => literal.to_string().replace('_', ""),
+            _ => return Err(syn::Error::new(literal.span(), "unsupported literal")),
+          };
+          self.push(&text, literal.span());
+          statement_start = false;
+        }
+        TokenTree::Group(group) => {
+          let (open, close) = match group.delimiter() {
+            Delimiter::Brace => ("{", "}"),
+            Delimiter::Parenthesis => ("(", ")"),
+            Delimiter::None => {
+              self.push_stream(group.stream(), false)?;
+              i += 1;
+              continue;
+            }
+            Delimiter::Bracket => return Err(syn::Error::new(group.span(), "unexpected brackets")),
+          };
+          self.push(open, group.span_open());
+          let is_body = group.delimiter() == Delimiter::Brace;
+          self.push_stream(group.stream(), is_body)?;
+          self.push(close, group.span_close());
+          statement_start = is_body;
+        }
+      }
+      i += 1;
+    }
+    Ok(())
+  }
+}
+
+/// Converts `get_variable` to `GetVariable`.
+fn pascal_case(name: &str) -> String {
+  name
+    .split('_')
+    .map(|part| {
+      let mut chars = part.chars();
+      match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+      }
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_asm_source() {
+    let input: TokenStream = r#"
+      push "a\n", -1, r:1; get_variable;
+      label l1: if l1, end;
+      done: function2 f(r:1=a) flags=PRELOAD_THIS|PRELOAD_ROOT { push_duplicate; return; }
+    "#
+    .parse()
+    .unwrap();
+    let source = AsmSource::new(input).unwrap();
+    assert_eq!(
+      source.text,
+      r#"Push "a\n" , - 1 , r : 1 GetVariable l1 : If l1 , end done : function2 f ( r : 1 = a ) flags = PRELOAD_THIS | PRELOAD_ROOT { PushDuplicate Return }"#
+    );
+    assert_eq!(source.token_at(1), Some(0));
+    assert_eq!(source.token_at(5), Some(0));
+    assert_eq!(source.token_at(6), Some(1));
+    assert_eq!(source.token_at(source.text.len() + 1), None);
+  }
+
+  #[test]
+  fn test_parse_error() {
+    let input: TokenStream = "push 1; foo_bar; stop;".parse().unwrap();
+    let source = AsmSource::new(input.clone()).unwrap();
+    let error = parse_asm(&source.text).unwrap_err();
+    assert_eq!(error.message, "unknown instruction: FooBar");
+    assert_eq!(source.token_at(error.column), Some(2));
+    assert_eq!(parse(input).unwrap_err().to_string(), "unknown instruction: FooBar");
+  }
+}
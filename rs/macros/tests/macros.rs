@@ -0,0 +1,71 @@
+use avm1_emitter::emit_cfg;
+use avm1_emitter_macros::{avm1, avm1_cfg};
+use avm1_types::cfg::{Cfg, CfgFlow};
+use avm1_types::PushValue;
+
+fn sample(name: &str) -> Vec<u8> {
+  std::fs::read(format!("../../tests/avm1/local/{}/main.avm1", name)).unwrap()
+}
+
+#[test]
+fn test_avm1_samples() {
+  let if_else: Vec<u8> = avm1! {
+    push "x"; get_variable; if yes;
+    push "no"; trace; jump done;
+    label yes: push "yes"; trace;
+    label done:
+  };
+  assert_eq!(if_else, sample("if-else"));
+
+  let define_functions: Vec<u8> = avm1! {
+    function2 add(r:1=a, r:2=b) flags=SUPPRESS_THIS|SUPPRESS_ARGUMENTS|SUPPRESS_SUPER {
+      push r:1, r:2; add2; return;
+    }
+    function (x) { push "hi"; trace; }
+    push "f"; stack_swap; set_variable;
+    push 1, 2, 2, "add"; call_function; trace;
+  };
+  assert_eq!(define_functions, sample("define-functions"));
+
+  let try_catch_finally: Vec<u8> = avm1! {
+    try { push "err"; throw; } catch(r:0) { push r:0; trace; } finally { push "finally"; trace; }
+    label done:
+  };
+  assert_eq!(try_catch_finally, sample("try-catch-finally"));
+}
+
+#[test]
+fn test_avm1_cfg() {
+  let cfg: Cfg = avm1_cfg! {
+    push "a", -1.5, 2.5f32, NaN, true, null;
+    wait_for_frame frame=3 loading=skip;
+    with { play; }
+    label skip: stop;
+  };
+  let values = match &cfg.blocks.first().actions[0] {
+    avm1_types::cfg::Action::Push(push) => push.values.clone(),
+    action => panic!("unexpected action: {:?}", action),
+  };
+  assert_eq!(
+    values,
+    vec![
+      PushValue::String(String::from("a")),
+      PushValue::Float64(-1.5),
+      PushValue::Float32(2.5),
+      PushValue::Float64(f64::NAN),
+      PushValue::Boolean(true),
+      PushValue::Null,
+    ]
+  );
+  assert!(matches!(cfg.blocks[1].flow, CfgFlow::With(_)));
+  assert_eq!(cfg.blocks.len(), 3);
+  assert_eq!(
+    emit_cfg(&cfg).unwrap(),
+    avm1! {
+      push "a", -1.5, 2.5f32, NaN, true, null;
+      wait_for_frame frame=3 loading=skip;
+      with { play; }
+      label skip: stop;
+    }
+  );
+}
@@ -0,0 +1,1210 @@
+//! Plain-text assembly language for AVM1 control flow graphs.
+//!
+//! ```text
+//! ; Comments start with a semicolon
+//!   Push "x"
+//!   GetVariable
+//!   If yes              ; the false branch falls through
+//!   Push "no"
+//!   Trace
+//!   Jump done
+//! yes:
+//!   Push "yes", 1, 2.5, 1.5f32, r:1, c:0, true, null, undefined
+//!   Trace
+//! done:
+//!   function add(a, b) {
+//!     Push "a"
+//!     GetVariable
+//!     Return
+//!   }
+//!   function2 sub(r:1=a, r:2=b) registers=3 flags=SUPPRESS_THIS|SUPPRESS_SUPER {
+//!     Push r:1, r:2
+//!     Subtract
+//!     Return
+//!   }
+//!   try {
+//!     Throw
+//!   } catch(r:0) {
+//!     Trace
+//!   } finally {
+//!     Stop
+//!   }
+//!   with {
+//!     Play
+//!   }
+//! ```
+//!
+//! Mnemonics are the names of the `raw::Action` variants. Operands use the
+//! notation of the listing: `r:N` for registers, `c:N` for constants, `1`
+//! (`Sint32`), `1.0` (`Float64`) and `1.0f32` (`Float32`) for numbers. Other
+//! operands are passed as `key=value` pairs, e.g. `GotoFrame2 play=true`.
+//!
+//! Blocks start at labels, after control flow instructions (`If`, `Jump`,
+//! `Return`, `Throw`, `WaitForFrame` and `WaitForFrame2`) and after `try` and
+//! `with` blocks. A block without control flow instruction falls through to
+//! the next one. Jumps target a label of the same function, or `end`.
+
+use avm1_types::cfg::{self, Cfg, CfgBlock, CfgFlow, CfgLabel};
+use avm1_types::{action, CatchTarget, FunctionFlags, GetUrl2Method, Parameter, PushValue};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io;
+use vec1::Vec1;
+
+/// Error in the assembly source, with its 1-based position.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct AsmError {
+  pub line: usize,
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for AsmError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}: {}", self.line, self.column, self.message)
+  }
+}
+
+impl std::error::Error for AsmError {}
+
+/// Parses the assembly source into a control flow graph.
+pub fn parse_asm(source: &str) -> Result<Cfg, AsmError> {
+  let tokens = tokenize(source)?;
+  let mut parser = Parser {
+    tokens,
+    pos: 0,
+    labels: HashSet::new(),
+    scopes: vec![Scope::default()],
+  };
+  let body = parser.parse_body(false)?;
+  parser.end_scope()?;
+  let mut lowerer = Lowerer {
+    used: parser.labels,
+    next_id: 0,
+  };
+  Ok(lowerer.lower_body(body, None))
+}
+
+/// Parses the assembly source and emits it with `emit_cfg`.
+///
+/// Errors in the source are returned as `InvalidData` errors wrapping an [`AsmError`].
+pub fn emit_asm(source: &str) -> io::Result<Vec<u8>> {
+  let cfg = parse_asm(source).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  crate::emit_cfg(&cfg)
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum TokenKind {
+  Ident(String),
+  Str(String),
+  Number(String),
+  Punct(char),
+  Eof,
+}
+
+impl fmt::Display for TokenKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TokenKind::Ident(v) | TokenKind::Number(v) => write!(f, "`{}`", v),
+      TokenKind::Str(v) => write!(f, "{:?}", v),
+      TokenKind::Punct(c) => write!(f, "`{}`", c),
+      TokenKind::Eof => f.write_str("end of input"),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+  kind: TokenKind,
+  line: usize,
+  column: usize,
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, AsmError> {
+  let mut tokens: Vec<Token> = Vec::new();
+  let mut chars = source.chars().peekable();
+  let (mut line, mut column) = (1, 1);
+  while let Some(&c) = chars.peek() {
+    let (start_line, start_column) = (line, column);
+    let error = |message: String| AsmError {
+      line: start_line,
+      column: start_column,
+      message,
+    };
+    let mut bump = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+      let c = chars.next();
+      if c == Some('\n') {
+        line += 1;
+        column = 1;
+      } else {
+        column += 1;
+      }
+      c
+    };
+    let kind = if c.is_whitespace() {
+      bump(&mut chars);
+      continue;
+    } else if c == ';' {
+      while chars.peek().map_or(false, |&c| c != '\n') {
+        bump(&mut chars);
+      }
+      continue;
+    } else if c == '"' {
+      bump(&mut chars);
+      let mut value = String::new();
+      loop {
+        match bump(&mut chars) {
+          None | Some('\n') => return Err(error(String::from("unterminated string"))),
+          Some('"') => break,
+          Some('\\') => match bump(&mut chars) {
+            Some('n') => value.push('\n'),
+            Some('r') => value.push('\r'),
+            Some('t') => value.push('\t'),
+            Some('0') => value.push('\0'),
+            Some('\\') => value.push('\\'),
+            Some('"') => value.push('"'),
+            Some('u') => {
+              let mut hex = String::new();
+              if bump(&mut chars) != Some('{') {
+                return Err(error(String::from("invalid unicode escape, expected `\\u{...}`")));
+              }
+              loop {
+                match bump(&mut chars) {
+                  Some('}') => break,
+                  Some(c) if c.is_ascii_hexdigit() => hex.push(c),
+                  _ => return Err(error(String::from("invalid unicode escape, expected `\\u{...}`"))),
+                }
+              }
+              match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+                Some(c) => value.push(c),
+                None => return Err(error(format!("invalid unicode escape: {}", hex))),
+              }
+            }
+            Some(c) => return Err(error(format!("invalid escape: \\{}", c))),
+            None => return Err(error(String::from("unterminated string"))),
+          },
+          Some(c) => value.push(c),
+        }
+      }
+      TokenKind::Str(value)
+    } else if c.is_ascii_digit() {
+      let mut value = String::new();
+      while let Some(&c) = chars.peek() {
+        let exponent_sign = (c == '-' || c == '+') && value.ends_with(['e', 'E']) && !value.starts_with("0x");
+        if c.is_ascii_alphanumeric() || c == '.' || c == '_' || exponent_sign {
+          value.push(c);
+          bump(&mut chars);
+        } else {
+          break;
+        }
+      }
+      TokenKind::Number(value)
+    } else if c.is_alphabetic() || c == '_' || c == '$' {
+      let mut value = String::new();
+      while let Some(&c) = chars.peek() {
+        if c.is_alphanumeric() || c == '_' || c == '$' || c == '.' {
+          value.push(c);
+          bump(&mut chars);
+        } else {
+          break;
+        }
+      }
+      TokenKind::Ident(value)
+    } else if "{}(),:=|-".contains(c) {
+      bump(&mut chars);
+      TokenKind::Punct(c)
+    } else {
+      return Err(error(format!("unexpected character: {:?}", c)));
+    };
+    tokens.push(Token {
+      kind,
+      line: start_line,
+      column: start_column,
+    });
+  }
+  tokens.push(Token {
+    kind: TokenKind::Eof,
+    line,
+    column,
+  });
+  Ok(tokens)
+}
+
+/// Jump target, before resolution of fall-through targets.
+enum Target {
+  Label(String),
+  End,
+  /// Next block, or the block following the enclosing `try` or `with` block.
+  Next,
+}
+
+enum Flow {
+  If(Target, Target),
+  Jump(Target),
+  Return,
+  Throw,
+  WaitForFrame { frame: u16, ready: Target, loading: Target },
+  WaitForFrame2 { ready: Target, loading: Target },
+  Try(Body, Option<(CatchTarget, Body)>, Option<Body>),
+  With(Body),
+}
+
+enum Item {
+  Action(cfg::Action),
+  DefineFunction(String, Vec<String>, Body),
+  DefineFunction2(String, u8, FunctionFlags, Vec<Parameter>, Body),
+}
+
+struct Block {
+  label: Option<String>,
+  items: Vec<Item>,
+  flow: Flow,
+}
+
+type Body = Vec<Block>;
+
+/// Labels defined and referenced in a function.
+#[derive(Default)]
+struct Scope {
+  defined: HashSet<String>,
+  referenced: Vec<(String, usize, usize)>,
+}
+
+struct Parser {
+  tokens: Vec<Token>,
+  pos: usize,
+  /// All the labels defined in the source.
+  labels: HashSet<String>,
+  scopes: Vec<Scope>,
+}
+
+type ParseResult<T> = Result<T, AsmError>;
+
+impl Parser {
+  fn peek(&self) -> &Token {
+    &self.tokens[self.pos]
+  }
+
+  fn peek_kind(&self, offset: usize) -> &TokenKind {
+    &self.tokens[(self.pos + offset).min(self.tokens.len() - 1)].kind
+  }
+
+  fn next(&mut self) -> Token {
+    let token = self.tokens[self.pos].clone();
+    if self.pos + 1 < self.tokens.len() {
+      self.pos += 1;
+    }
+    token
+  }
+
+  fn error_at(token: &Token, message: String) -> AsmError {
+    AsmError {
+      line: token.line,
+      column: token.column,
+      message,
+    }
+  }
+
+  fn unexpected<T>(&self, expected: &str) -> ParseResult<T> {
+    let token = self.peek();
+    Err(Self::error_at(
+      token,
+      format!("expected {}, found {}", expected, token.kind),
+    ))
+  }
+
+  fn is_punct(&self, c: char) -> bool {
+    self.peek().kind == TokenKind::Punct(c)
+  }
+
+  fn eat_punct(&mut self, c: char) -> bool {
+    let found = self.is_punct(c);
+    if found {
+      self.next();
+    }
+    found
+  }
+
+  fn expect_punct(&mut self, c: char) -> ParseResult<()> {
+    if self.eat_punct(c) {
+      Ok(())
+    } else {
+      self.unexpected(&format!("`{}`", c))
+    }
+  }
+
+  fn is_ident(&self, name: &str) -> bool {
+    matches!(&self.peek().kind, TokenKind::Ident(v) if v == name)
+  }
+
+  fn expect_ident(&mut self) -> ParseResult<String> {
+    match &self.peek().kind {
+      TokenKind::Ident(v) => {
+        let v = v.clone();
+        self.next();
+        Ok(v)
+      }
+      _ => self.unexpected("an identifier"),
+    }
+  }
+
+  fn expect_string(&mut self) -> ParseResult<String> {
+    match &self.peek().kind {
+      TokenKind::Str(v) => {
+        let v = v.clone();
+        self.next();
+        Ok(v)
+      }
+      _ => self.unexpected("a string"),
+    }
+  }
+
+  /// Parses an identifier or a string.
+  fn expect_name(&mut self) -> ParseResult<String> {
+    match &self.peek().kind {
+      TokenKind::Str(_) => self.expect_string(),
+      _ => self.expect_ident(),
+    }
+  }
+
+  fn expect_integer<T: TryFrom<i64>>(&mut self) -> ParseResult<T> {
+    let token = self.next();
+    let value = match &token.kind {
+      TokenKind::Number(v) => match v.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok(),
+        None => v.parse::<i64>().ok(),
+      },
+      _ => {
+        return Err(Self::error_at(
+          &token,
+          format!("expected an integer, found {}", token.kind),
+        ))
+      }
+    };
+    value
+      .and_then(|v| T::try_from(v).ok())
+      .ok_or_else(|| Self::error_at(&token, format!("invalid integer: {}", token.kind)))
+  }
+
+  fn expect_bool(&mut self) -> ParseResult<bool> {
+    match &self.peek().kind {
+      TokenKind::Ident(v) if v == "true" || v == "false" => {
+        let v = v == "true";
+        self.next();
+        Ok(v)
+      }
+      _ => self.unexpected("`true` or `false`"),
+    }
+  }
+
+  /// Parses `r:N` and returns `N`.
+  fn expect_register(&mut self) -> ParseResult<u8> {
+    if !self.is_ident("r") {
+      return self.unexpected("a register");
+    }
+    self.next();
+    self.expect_punct(':')?;
+    self.expect_integer()
+  }
+
+  /// Returns the key of the `key=value` operand at the current position, if any.
+  fn peek_key(&self, keys: &[&str]) -> Option<String> {
+    match (self.peek_kind(0), self.peek_kind(1)) {
+      (TokenKind::Ident(k), TokenKind::Punct('=')) if keys.contains(&k.as_str()) => Some(k.clone()),
+      _ => None,
+    }
+  }
+
+  /// Parses `key=` and returns `key`, or `None` if there are no more operands.
+  fn next_key(&mut self, keys: &[&str], seen: &mut Vec<String>) -> ParseResult<Option<String>> {
+    let key = match self.peek_key(keys) {
+      Some(key) => key,
+      None => return Ok(None),
+    };
+    if seen.contains(&key) {
+      return Err(Self::error_at(self.peek(), format!("duplicate operand: {}", key)));
+    }
+    self.next();
+    self.next();
+    seen.push(key.clone());
+    Ok(Some(key))
+  }
+
+  fn target(&mut self) -> ParseResult<Target> {
+    let token = self.peek().clone();
+    let name = self.expect_ident()?;
+    if name == "end" {
+      return Ok(Target::End);
+    }
+    self
+      .scopes
+      .last_mut()
+      .unwrap()
+      .referenced
+      .push((name.clone(), token.line, token.column));
+    Ok(Target::Label(name))
+  }
+
+  fn push_value(&mut self) -> ParseResult<PushValue> {
+    let token = self.peek().clone();
+    let negative = self.eat_punct('-');
+    let invalid = |message: String| Err(Self::error_at(&token, message));
+    let value = match self.next().kind {
+      TokenKind::Str(v) if !negative => PushValue::String(v),
+      TokenKind::Ident(v) => match v.as_str() {
+        "true" if !negative => PushValue::Boolean(true),
+        "false" if !negative => PushValue::Boolean(false),
+        "null" if !negative => PushValue::Null,
+        "undefined" if !negative => PushValue::Undefined,
+        "r" if !negative && self.is_punct(':') => {
+          self.next();
+          PushValue::Register(self.expect_integer()?)
+        }
+        "c" if !negative && self.is_punct(':') => {
+          self.next();
+          PushValue::Constant(self.expect_integer()?)
+        }
+        "NaN" => PushValue::Float64(f64::NAN),
+        "NaNf32" => PushValue::Float32(f32::NAN),
+        "inf" if negative => PushValue::Float64(f64::NEG_INFINITY),
+        "inf" => PushValue::Float64(f64::INFINITY),
+        "inff32" if negative => PushValue::Float32(f32::NEG_INFINITY),
+        "inff32" => PushValue::Float32(f32::INFINITY),
+        _ => return invalid(format!("invalid push value: {}", v)),
+      },
+      TokenKind::Number(v) => {
+        let text = if negative { format!("-{}", v) } else { v.clone() };
+        let value = if let Some(text) = text.strip_suffix("f32") {
+          text.parse::<f32>().ok().map(PushValue::Float32)
+        } else if text.contains(['.', 'e', 'E']) && !text.contains("0x") {
+          text.parse::<f64>().ok().map(PushValue::Float64)
+        } else {
+          let integer = match text.strip_prefix("0x") {
+            Some(hex) => i64::from_str_radix(hex, 16).ok(),
+            None => text.parse::<i64>().ok(),
+          };
+          integer.and_then(|v| i32::try_from(v).ok()).map(PushValue::Sint32)
+        };
+        match value {
+          Some(value) => value,
+          None => return invalid(format!("invalid number: {}", text)),
+        }
+      }
+      kind => return invalid(format!("expected a push value, found {}", kind)),
+    };
+    Ok(value)
+  }
+
+  fn end_scope(&mut self) -> ParseResult<()> {
+    let scope = self.scopes.pop().unwrap();
+    for (label, line, column) in scope.referenced {
+      if !scope.defined.contains(&label) {
+        return Err(AsmError {
+          line,
+          column,
+          message: format!("undefined label: {}", label),
+        });
+      }
+    }
+    Ok(())
+  }
+
+  /// Parses statements until the end of the input, or until `}` if `nested`.
+  fn parse_body(&mut self, nested: bool) -> ParseResult<Body> {
+    let mut body: Body = Vec::new();
+    let mut current: Option<(Option<String>, Vec<Item>)> = None;
+    loop {
+      let token = self.peek().clone();
+      let name = match &token.kind {
+        TokenKind::Eof if nested => return self.unexpected("`}`"),
+        TokenKind::Punct('}') if nested => break,
+        TokenKind::Eof => break,
+        TokenKind::Ident(name) => name.clone(),
+        _ => return self.unexpected("a label or an instruction"),
+      };
+      if self.peek_kind(1) == &TokenKind::Punct(':') {
+        self.next();
+        self.next();
+        if name == "end" {
+          return Err(Self::error_at(
+            &token,
+            String::from("`end` is reserved and cannot be used as a label"),
+          ));
+        }
+        if !self.labels.insert(name.clone()) {
+          return Err(Self::error_at(&token, format!("duplicate label: {}", name)));
+        }
+        self.scopes.last_mut().unwrap().defined.insert(name.clone());
+        if let Some((label, items)) = current.take() {
+          body.push(Block {
+            label,
+            items,
+            flow: Flow::Jump(Target::Next),
+          });
+        }
+        current = Some((Some(name), Vec::new()));
+        continue;
+      }
+
+      let flow = match name.as_str() {
+        "function" | "function2" => {
+          let item = self.parse_function()?;
+          current.get_or_insert_with(|| (None, Vec::new())).1.push(item);
+          continue;
+        }
+        "try" => self.parse_try()?,
+        "with" => {
+          self.next();
+          Flow::With(self.parse_block()?)
+        }
+        _ => match self.parse_instruction()? {
+          Ok(action) => {
+            current
+              .get_or_insert_with(|| (None, Vec::new()))
+              .1
+              .push(Item::Action(action));
+            continue;
+          }
+          Err(flow) => flow,
+        },
+      };
+      let (label, items) = current.take().unwrap_or((None, Vec::new()));
+      body.push(Block { label, items, flow });
+    }
+    if let Some((label, items)) = current {
+      body.push(Block {
+        label,
+        items,
+        flow: Flow::Jump(Target::Next),
+      });
+    }
+    Ok(body)
+  }
+
+  /// Parses `{ ... }`.
+  fn parse_block(&mut self) -> ParseResult<Body> {
+    self.expect_punct('{')?;
+    let body = self.parse_body(true)?;
+    self.expect_punct('}')?;
+    Ok(body)
+  }
+
+  fn parse_try(&mut self) -> ParseResult<Flow> {
+    self.next();
+    let try_body = self.parse_block()?;
+    let catch = if self.is_ident("catch") {
+      self.next();
+      self.expect_punct('(')?;
+      let target = if self.is_ident("r") && self.peek_kind(1) == &TokenKind::Punct(':') {
+        CatchTarget::Register(self.expect_register()?)
+      } else {
+        CatchTarget::Variable(self.expect_name()?)
+      };
+      self.expect_punct(')')?;
+      Some((target, self.parse_block()?))
+    } else {
+      None
+    };
+    let finally = if self.is_ident("finally") {
+      self.next();
+      Some(self.parse_block()?)
+    } else {
+      None
+    };
+    Ok(Flow::Try(try_body, catch, finally))
+  }
+
+  fn parse_function(&mut self) -> ParseResult<Item> {
+    let is_function2 = self.expect_ident()? == "function2";
+    let name = if self.is_punct('(') {
+      String::new()
+    } else {
+      self.expect_name()?
+    };
+    self.expect_punct('(')?;
+    let mut parameters: Vec<Parameter> = Vec::new();
+    while !self.is_punct(')') {
+      if !parameters.is_empty() {
+        self.expect_punct(',')?;
+      }
+      let register = if is_function2 && self.is_ident("r") && self.peek_kind(1) == &TokenKind::Punct(':') {
+        let register = self.expect_register()?;
+        self.expect_punct('=')?;
+        register
+      } else {
+        0
+      };
+      parameters.push(Parameter {
+        register,
+        name: self.expect_name()?,
+      });
+    }
+    self.expect_punct(')')?;
+
+    if !is_function2 {
+      self.scopes.push(Scope::default());
+      let body = self.parse_block()?;
+      self.end_scope()?;
+      let parameters = parameters.into_iter().map(|p| p.name).collect();
+      return Ok(Item::DefineFunction(name, parameters, body));
+    }
+
+    // Defaults to one more than the highest parameter register.
+    let mut register_count = parameters
+      .iter()
+      .map(|p| p.register.saturating_add(1))
+      .max()
+      .unwrap_or(0);
+    let mut flags = FunctionFlags::empty();
+    let mut seen: Vec<String> = Vec::new();
+    while let Some(key) = self.next_key(&["registers", "flags"], &mut seen)? {
+      if key == "registers" {
+        register_count = self.expect_integer()?;
+        continue;
+      }
+      loop {
+        let token = self.peek().clone();
+        flags |= match self.expect_ident()?.as_str() {
+          "PRELOAD_THIS" => FunctionFlags::PRELOAD_THIS,
+          "SUPPRESS_THIS" => FunctionFlags::SUPPRESS_THIS,
+          "PRELOAD_ARGUMENTS" => FunctionFlags::PRELOAD_ARGUMENTS,
+          "SUPPRESS_ARGUMENTS" => FunctionFlags::SUPPRESS_ARGUMENTS,
+          "PRELOAD_SUPER" => FunctionFlags::PRELOAD_SUPER,
+          "SUPPRESS_SUPER" => FunctionFlags::SUPPRESS_SUPER,
+          "PRELOAD_ROOT" => FunctionFlags::PRELOAD_ROOT,
+          "PRELOAD_PARENT" => FunctionFlags::PRELOAD_PARENT,
+          "PRELOAD_GLOBAL" => FunctionFlags::PRELOAD_GLOBAL,
+          flag => return Err(Self::error_at(&token, format!("unknown function flag: {}", flag))),
+        };
+        if !self.eat_punct('|') {
+          break;
+        }
+      }
+    }
+    self.scopes.push(Scope::default());
+    let body = self.parse_block()?;
+    self.end_scope()?;
+    Ok(Item::DefineFunction2(name, register_count, flags, parameters, body))
+  }
+
+  /// Parses an instruction, returns the action or the flow it ends its block with.
+  fn parse_instruction(&mut self) -> ParseResult<Result<cfg::Action, Flow>> {
+    use avm1_types::cfg::Action::*;
+
+    let token = self.peek().clone();
+    let mnemonic = self.expect_ident()?;
+    let mut seen: Vec<String> = Vec::new();
+    let action = match mnemonic.as_str() {
+      "Add" => Add,
+      "Add2" => Add2,
+      "And" => And,
+      "AsciiToChar" => AsciiToChar,
+      "BitAnd" => BitAnd,
+      "BitOr" => BitOr,
+      "BitLShift" => BitLShift,
+      "BitRShift" => BitRShift,
+      "BitURShift" => BitURShift,
+      "BitXor" => BitXor,
+      "Call" => Call,
+      "CallFunction" => CallFunction,
+      "CallMethod" => CallMethod,
+      "CharToAscii" => CharToAscii,
+      "CastOp" => CastOp,
+      "CloneSprite" => CloneSprite,
+      "ConstantPool" => {
+        let mut pool: Vec<String> = Vec::new();
+        if let TokenKind::Str(_) = self.peek().kind {
+          pool.push(self.expect_string()?);
+          while self.eat_punct(',') {
+            pool.push(self.expect_string()?);
+          }
+        }
+        ConstantPool(action::ConstantPool { pool })
+      }
+      "Decrement" => Decrement,
+      "DefineLocal" => DefineLocal,
+      "DefineLocal2" => DefineLocal2,
+      "Delete" => Delete,
+      "Delete2" => Delete2,
+      "Divide" => Divide,
+      "EndDrag" => EndDrag,
+      "Enumerate" => Enumerate,
+      "Enumerate2" => Enumerate2,
+      "Equals" => Equals,
+      "Equals2" => Equals2,
+      "Extends" => Extends,
+      "FsCommand2" => FsCommand2,
+      "GetMember" => GetMember,
+      "GetProperty" => GetProperty,
+      "GetTime" => GetTime,
+      "GetUrl" => {
+        let url = self.expect_string()?;
+        self.expect_punct(',')?;
+        let target = self.expect_string()?;
+        GetUrl(Box::new(action::GetUrl { url, target }))
+      }
+      "GetUrl2" => {
+        let mut value = action::GetUrl2 {
+          method: GetUrl2Method::None,
+          load_target: false,
+          load_variables: false,
+        };
+        while let Some(key) = self.next_key(&["method", "load_target", "load_variables"], &mut seen)? {
+          match key.as_str() {
+            "method" => {
+              let method = self.peek().clone();
+              value.method = match self.expect_ident()?.as_str() {
+                "None" => GetUrl2Method::None,
+                "Get" => GetUrl2Method::Get,
+                "Post" => GetUrl2Method::Post,
+                _ => {
+                  return Err(Self::error_at(
+                    &method,
+                    String::from("expected `None`, `Get` or `Post`"),
+                  ))
+                }
+              }
+            }
+            "load_target" => value.load_target = self.expect_bool()?,
+            _ => value.load_variables = self.expect_bool()?,
+          }
+        }
+        GetUrl2(value)
+      }
+      "GetVariable" => GetVariable,
+      "GotoFrame" => GotoFrame(action::GotoFrame {
+        frame: self.expect_integer()?,
+      }),
+      "GotoFrame2" => {
+        let mut value = action::GotoFrame2 {
+          play: false,
+          scene_bias: 0,
+        };
+        while let Some(key) = self.next_key(&["play", "scene_bias"], &mut seen)? {
+          match key.as_str() {
+            "play" => value.play = self.expect_bool()?,
+            _ => value.scene_bias = self.expect_integer()?,
+          }
+        }
+        GotoFrame2(value)
+      }
+      "GotoLabel" => GotoLabel(action::GoToLabel {
+        label: self.expect_string()?,
+      }),
+      "Greater" => Greater,
+      "ImplementsOp" => ImplementsOp,
+      "Increment" => Increment,
+      "InitArray" => InitArray,
+      "InitObject" => InitObject,
+      "InstanceOf" => InstanceOf,
+      "Less" => Less,
+      "Less2" => Less2,
+      "MbAsciiToChar" => MbAsciiToChar,
+      "MbCharToAscii" => MbCharToAscii,
+      "MbStringExtract" => MbStringExtract,
+      "MbStringLength" => MbStringLength,
+      "Modulo" => Modulo,
+      "Multiply" => Multiply,
+      "NewMethod" => NewMethod,
+      "NewObject" => NewObject,
+      "NextFrame" => NextFrame,
+      "Not" => Not,
+      "Or" => Or,
+      "Play" => Play,
+      "Pop" => Pop,
+      "PrevFrame" => PrevFrame,
+      "Push" => {
+        let mut values = vec![self.push_value()?];
+        while self.eat_punct(',') {
+          values.push(self.push_value()?);
+        }
+        Push(action::Push { values })
+      }
+      "PushDuplicate" => PushDuplicate,
+      "RandomNumber" => RandomNumber,
+      "Raw" => {
+        let mut value = action::Raw {
+          code: 0,
+          data: Vec::new(),
+        };
+        while let Some(key) = self.next_key(&["code", "data"], &mut seen)? {
+          match key.as_str() {
+            "code" => value.code = self.expect_integer()?,
+            _ => {
+              let token = self.peek().clone();
+              let hex = self.expect_string()?;
+              value.data =
+                parse_hex(&hex).ok_or_else(|| Self::error_at(&token, format!("invalid hex data: {}", hex)))?;
+            }
+          }
+        }
+        if !seen.iter().any(|k| k == "code") {
+          return Err(Self::error_at(&token, String::from("missing operand: code")));
+        }
+        Raw(Box::new(value))
+      }
+      "RemoveSprite" => RemoveSprite,
+      "SetMember" => SetMember,
+      "SetProperty" => SetProperty,
+      "SetTarget" => SetTarget(action::SetTarget {
+        target_name: self.expect_string()?,
+      }),
+      "SetTarget2" => SetTarget2,
+      "SetVariable" => SetVariable,
+      "StackSwap" => StackSwap,
+      "StartDrag" => StartDrag,
+      "Stop" => Stop,
+      "StopSounds" => StopSounds,
+      "StoreRegister" => StoreRegister(action::StoreRegister {
+        register: self.expect_register()?,
+      }),
+      "StrictEquals" => StrictEquals,
+      "StrictMode" => StrictMode(action::StrictMode {
+        is_strict: self.expect_bool()?,
+      }),
+      "StringAdd" => StringAdd,
+      "StringEquals" => StringEquals,
+      "StringExtract" => StringExtract,
+      "StringGreater" => StringGreater,
+      "StringLength" => StringLength,
+      "StringLess" => StringLess,
+      "Subtract" => Subtract,
+      "TargetPath" => TargetPath,
+      "ToInteger" => ToInteger,
+      "ToNumber" => ToNumber,
+      "ToString" => ToString,
+      "ToggleQuality" => ToggleQuality,
+      "Trace" => Trace,
+      "TypeOf" => TypeOf,
+      "If" => {
+        let true_target = self.target()?;
+        let false_target = if self.eat_punct(',') {
+          self.target()?
+        } else {
+          Target::Next
+        };
+        return Ok(Err(Flow::If(true_target, false_target)));
+      }
+      "Jump" => return Ok(Err(Flow::Jump(self.target()?))),
+      "Return" => return Ok(Err(Flow::Return)),
+      "Throw" => return Ok(Err(Flow::Throw)),
+      "WaitForFrame" | "WaitForFrame2" => {
+        let keys: &[&str] = if mnemonic == "WaitForFrame" {
+          &["frame", "ready", "loading"]
+        } else {
+          &["ready", "loading"]
+        };
+        let (mut frame, mut ready, mut loading) = (None, Target::Next, Target::Next);
+        while let Some(key) = self.next_key(keys, &mut seen)? {
+          match key.as_str() {
+            "frame" => frame = Some(self.expect_integer()?),
+            "ready" => ready = self.target()?,
+            _ => loading = self.target()?,
+          }
+        }
+        let flow = match frame {
+          Some(frame) => Flow::WaitForFrame { frame, ready, loading },
+          None if mnemonic == "WaitForFrame2" => Flow::WaitForFrame2 { ready, loading },
+          None => return Err(Self::error_at(&token, String::from("missing operand: frame"))),
+        };
+        return Ok(Err(flow));
+      }
+      _ => return Err(Self::error_at(&token, format!("unknown instruction: {}", mnemonic))),
+    };
+    Ok(Ok(action))
+  }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+  if hex.len() % 2 != 0 || !hex.is_ascii() {
+    return None;
+  }
+  (0..hex.len())
+    .step_by(2)
+    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+    .collect()
+}
+
+/// Converts the parsed blocks to CFGs, naming the unlabeled blocks.
+struct Lowerer {
+  used: HashSet<String>,
+  next_id: usize,
+}
+
+impl Lowerer {
+  fn fresh_label(&mut self) -> String {
+    loop {
+      let label = format!("_{}", self.next_id);
+      self.next_id += 1;
+      if self.used.insert(label.clone()) {
+        return label;
+      }
+    }
+  }
+
+  /// Lowers a body, falling through to `next` at its end.
+  fn lower_body(&mut self, body: Body, next: Option<CfgLabel>) -> Cfg {
+    let mut labels: Vec<CfgLabel> = Vec::new();
+    for block in body.iter() {
+      let label = match &block.label {
+        Some(label) => label.clone(),
+        None => self.fresh_label(),
+      };
+      labels.push(CfgLabel(label));
+    }
+    if body.is_empty() {
+      let label = CfgLabel(self.fresh_label());
+      return Cfg {
+        blocks: Vec1::new(CfgBlock {
+          label,
+          actions: Vec::new(),
+          flow: CfgFlow::Simple(cfg::Simple { next }),
+        }),
+      };
+    }
+
+    let mut blocks: Vec<CfgBlock> = Vec::new();
+    let count = body.len();
+    for (i, (block, label)) in body.into_iter().zip(labels.iter()).enumerate() {
+      let block_next = if i + 1 < count {
+        Some(labels[i + 1].clone())
+      } else {
+        next.clone()
+      };
+      let resolve = |target: Target| match target {
+        Target::Label(label) => Some(CfgLabel(label)),
+        Target::End => None,
+        Target::Next => block_next.clone(),
+      };
+      let actions = block.items.into_iter().map(|item| self.lower_item(item)).collect();
+      let flow = match block.flow {
+        Flow::If(true_target, false_target) => CfgFlow::If(cfg::If {
+          true_target: resolve(true_target),
+          false_target: resolve(false_target),
+        }),
+        Flow::Jump(target) => CfgFlow::Simple(cfg::Simple { next: resolve(target) }),
+        Flow::Return => CfgFlow::Return,
+        Flow::Throw => CfgFlow::Throw,
+        Flow::WaitForFrame { frame, ready, loading } => CfgFlow::WaitForFrame(cfg::WaitForFrame {
+          frame,
+          ready_target: resolve(ready),
+          loading_target: resolve(loading),
+        }),
+        Flow::WaitForFrame2 { ready, loading } => CfgFlow::WaitForFrame2(cfg::WaitForFrame2 {
+          ready_target: resolve(ready),
+          loading_target: resolve(loading),
+        }),
+        Flow::Try(try_body, catch, finally) => {
+          // The `try` and `catch` bodies fall through to the `finally` body
+          let finally = finally.map(|body| self.lower_body(body, block_next.clone()));
+          let after = match &finally {
+            Some(finally) => Some(finally.blocks.first().label.clone()),
+            None => block_next.clone(),
+          };
+          CfgFlow::Try(Box::new(cfg::Try {
+            r#try: self.lower_body(try_body, after.clone()),
+            catch: catch.map(|(target, body)| cfg::CatchBlock {
+              target,
+              body: self.lower_body(body, after.clone()),
+            }),
+            finally,
+          }))
+        }
+        Flow::With(body) => CfgFlow::With(cfg::With {
+          body: self.lower_body(body, block_next.clone()),
+        }),
+      };
+      blocks.push(CfgBlock {
+        label: label.clone(),
+        actions,
+        flow,
+      });
+    }
+    Cfg {
+      blocks: Vec1::try_from_vec(blocks).unwrap(),
+    }
+  }
+
+  fn lower_item(&mut self, item: Item) -> cfg::Action {
+    match item {
+      Item::Action(action) => action,
+      Item::DefineFunction(name, parameters, body) => cfg::Action::DefineFunction(Box::new(cfg::DefineFunction {
+        name,
+        parameters,
+        body: self.lower_body(body, None),
+      })),
+      Item::DefineFunction2(name, register_count, flags, parameters, body) => {
+        cfg::Action::DefineFunction2(Box::new(cfg::DefineFunction2 {
+          name,
+          register_count,
+          flags,
+          parameters,
+          body: self.lower_body(body, None),
+        }))
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::emit_cfg;
+
+  fn assert_emits_sample(source: &str, sample: &str) {
+    let path = format!("../tests/avm1/local/{}/main.avm1", sample);
+    let expected = std::fs::read(path).unwrap();
+    assert_eq!(emit_asm(source).unwrap(), expected);
+  }
+
+  #[test]
+  fn test_emit_asm_samples() {
+    assert_emits_sample(
+      r#"
+        Push "x"
+        GetVariable
+        If yes
+        Push "no"
+        Trace
+        Jump done
+      yes:
+        Push "yes"
+        Trace
+      done:
+      "#,
+      "if-else",
+    );
+    assert_emits_sample(
+      r#"
+        Push "i", 0
+        SetVariable
+      loop:
+        Push "i"
+        GetVariable
+        Push 3
+        Less2
+        Not
+        If done
+        Push "i", "i"
+        GetVariable
+        Increment
+        SetVariable
+        Jump loop
+      done:
+      "#,
+      "while-loop",
+    );
+    assert_emits_sample(
+      r#"
+        function2 add(r:1=a, r:2=b) flags=SUPPRESS_THIS | SUPPRESS_ARGUMENTS | SUPPRESS_SUPER {
+          Push r:1, r:2
+          Add2
+          Return
+        }
+        function (x) {
+          Push "hi" ; comment
+          Trace
+        }
+        Push "f"
+        StackSwap
+        SetVariable
+        Push 1, 2, 2, "add"
+        CallFunction
+        Trace
+      "#,
+      "define-functions",
+    );
+    assert_emits_sample(
+      r#"
+        try {
+          Push "err"
+          Throw
+        } catch(r:0) {
+          Push r:0
+          Trace
+        } finally {
+          Push "finally"
+          Trace
+        }
+      done:
+      "#,
+      "try-catch-finally",
+    );
+    assert_emits_sample(
+      r#"
+        Push "o"
+        GetVariable
+        with {
+          Push "a"
+          GetVariable
+          Trace
+        }
+        Stop
+      "#,
+      "with",
+    );
+  }
+
+  #[test]
+  fn test_parse_asm_operands() {
+    let cfg = parse_asm(
+      r#"
+        Push "a\n\u{e9}", -1, 0x10, 2.5, -1e3, 1.5f32, -inf, c:3, true, null, undefined
+        GotoFrame2 play=true
+        WaitForFrame frame=2 loading=skip
+        Stop
+      skip:
+      "#,
+    )
+    .unwrap();
+    let block = cfg.blocks.first();
+    assert_eq!(block.label, CfgLabel(String::from("_0")));
+    assert_eq!(
+      block.actions,
+      vec![
+        cfg::Action::Push(action::Push {
+          values: vec![
+            PushValue::String(String::from("a\n\u{e9}")),
+            PushValue::Sint32(-1),
+            PushValue::Sint32(16),
+            PushValue::Float64(2.5),
+            PushValue::Float64(-1000.0),
+            PushValue::Float32(1.5),
+            PushValue::Float64(f64::NEG_INFINITY),
+            PushValue::Constant(3),
+            PushValue::Boolean(true),
+            PushValue::Null,
+            PushValue::Undefined,
+          ],
+        }),
+        cfg::Action::GotoFrame2(action::GotoFrame2 {
+          play: true,
+          scene_bias: 0,
+        }),
+      ]
+    );
+    assert_eq!(
+      block.flow,
+      CfgFlow::WaitForFrame(cfg::WaitForFrame {
+        frame: 2,
+        ready_target: Some(CfgLabel(String::from("_1"))),
+        loading_target: Some(CfgLabel(String::from("skip"))),
+      })
+    );
+    assert!(emit_cfg(&cfg).is_ok());
+  }
+
+  #[test]
+  fn test_parse_asm_errors() {
+    let error = |source: &str| parse_asm(source).unwrap_err().to_string();
+    assert_eq!(error("Push 1\n  Foo"), "2:3: unknown instruction: Foo");
+    assert_eq!(error("Jump nowhere"), "1:6: undefined label: nowhere");
+    assert_eq!(error("a:\na:"), "2:1: duplicate label: a");
+    assert_eq!(error("Push \"a"), "1:6: unterminated string");
+    assert_eq!(error("Push 1,"), "1:8: expected a push value, found end of input");
+    assert_eq!(error("with {\n  Stop\n"), "3:1: expected `}`, found end of input");
+    assert_eq!(error("l:\nfunction f() { Jump l }"), "2:21: undefined label: l");
+    assert_eq!(error("GetUrl2 method=Put"), "1:16: expected `None`, `Get` or `Post`");
+  }
+}
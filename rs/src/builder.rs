@@ -0,0 +1,801 @@
+//! Fluent builder for control flow graphs.
+//!
+//! ```
+//! use avm1_emitter::builder::CfgBuilder;
+//!
+//! let cfg = CfgBuilder::new();
+//! let yes = cfg.fresh_label("yes");
+//! let bytes = cfg
+//!   .block("entry", |b| b.push("x").get_variable().if_true(&yes, "no"))
+//!   .block("no", |b| b.push("no").trace().end())
+//!   .block(&yes, |b| b.push("yes").trace())
+//!   .emit()
+//!   .unwrap();
+//! ```
+//!
+//! Blocks without control flow fall through to the next block of their
+//! builder. The last block of a `try`, `catch` or `with` body falls through to
+//! the block following the region (or to the `finally` body).
+
+use avm1_types::cfg::{self, Cfg, CfgBlock, CfgFlow, CfgLabel};
+use avm1_types::{action, CatchTarget, FunctionFlags, GetUrl2Method, Parameter, PushValue};
+use std::cell::Cell;
+use std::collections::HashSet;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use vec1::Vec1;
+
+/// Error returned by [`CfgBuilder::finish`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BuildError {
+  /// The label is used by two blocks of the same function.
+  DuplicateLabel(CfgLabel),
+  /// A jump targets a label missing from its function.
+  UndefinedLabel(CfgLabel),
+  /// The control flow of the block was set twice.
+  FlowAlreadySet(CfgLabel),
+  /// An action was added to the block after its control flow.
+  ActionAfterFlow(CfgLabel),
+}
+
+impl fmt::Display for BuildError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      BuildError::DuplicateLabel(label) => write!(f, "duplicate label: {}", label.0),
+      BuildError::UndefinedLabel(label) => write!(f, "undefined label: {}", label.0),
+      BuildError::FlowAlreadySet(label) => write!(f, "control flow of block {} set twice", label.0),
+      BuildError::ActionAfterFlow(label) => write!(f, "action added after the control flow of block {}", label.0),
+    }
+  }
+}
+
+impl std::error::Error for BuildError {}
+
+/// Values accepted as block labels and jump targets.
+pub trait IntoCfgLabel {
+  fn into_cfg_label(self) -> CfgLabel;
+}
+
+impl IntoCfgLabel for CfgLabel {
+  fn into_cfg_label(self) -> CfgLabel {
+    self
+  }
+}
+
+impl IntoCfgLabel for &CfgLabel {
+  fn into_cfg_label(self) -> CfgLabel {
+    self.clone()
+  }
+}
+
+impl IntoCfgLabel for &str {
+  fn into_cfg_label(self) -> CfgLabel {
+    CfgLabel(self.to_string())
+  }
+}
+
+impl IntoCfgLabel for String {
+  fn into_cfg_label(self) -> CfgLabel {
+    CfgLabel(self)
+  }
+}
+
+/// Values accepted by [`BlockBuilder::push`].
+pub trait IntoPushValue {
+  fn into_push_value(self) -> PushValue;
+}
+
+impl IntoPushValue for PushValue {
+  fn into_push_value(self) -> PushValue {
+    self
+  }
+}
+
+impl IntoPushValue for &str {
+  fn into_push_value(self) -> PushValue {
+    PushValue::String(self.to_string())
+  }
+}
+
+impl IntoPushValue for String {
+  fn into_push_value(self) -> PushValue {
+    PushValue::String(self)
+  }
+}
+
+impl IntoPushValue for bool {
+  fn into_push_value(self) -> PushValue {
+    PushValue::Boolean(self)
+  }
+}
+
+impl IntoPushValue for i32 {
+  fn into_push_value(self) -> PushValue {
+    PushValue::Sint32(self)
+  }
+}
+
+impl IntoPushValue for f32 {
+  fn into_push_value(self) -> PushValue {
+    PushValue::Float32(self)
+  }
+}
+
+impl IntoPushValue for f64 {
+  fn into_push_value(self) -> PushValue {
+    PushValue::Float64(self)
+  }
+}
+
+/// Generator of unique labels, shared by the nested builders.
+#[derive(Clone, Debug, Default)]
+struct LabelGenerator(Rc<Cell<usize>>);
+
+impl LabelGenerator {
+  fn fresh(&self, prefix: &str) -> CfgLabel {
+    let id = self.0.get();
+    self.0.set(id + 1);
+    CfgLabel(format!("{}_{}", prefix, id))
+  }
+}
+
+/// Builder of a control flow graph, or of the body of a function or region.
+#[derive(Debug, Default)]
+pub struct CfgBuilder {
+  labels: LabelGenerator,
+  blocks: Vec<BlockBuilder>,
+}
+
+impl CfgBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Builder for a nested body, sharing the label generator.
+  fn child(&self) -> Self {
+    Self {
+      labels: self.labels.clone(),
+      blocks: Vec::new(),
+    }
+  }
+
+  /// Returns a new label `{prefix}_{n}`, unique among the fresh labels of this
+  /// builder and its nested builders.
+  pub fn fresh_label(&self, prefix: &str) -> CfgLabel {
+    self.labels.fresh(prefix)
+  }
+
+  /// Appends a block.
+  pub fn block(mut self, label: impl IntoCfgLabel, f: impl FnOnce(BlockBuilder) -> BlockBuilder) -> Self {
+    let block = BlockBuilder {
+      labels: self.labels.clone(),
+      label: label.into_cfg_label(),
+      actions: Vec::new(),
+      flow: None,
+      error: None,
+    };
+    self.blocks.push(f(block));
+    self
+  }
+
+  /// Appends a block with a fresh label.
+  pub fn fresh_block(self, f: impl FnOnce(BlockBuilder) -> BlockBuilder) -> Self {
+    let label = self.fresh_label("block");
+    self.block(label, f)
+  }
+
+  /// Builds the CFG and checks that its labels are unique and its jump
+  /// targets are defined.
+  pub fn finish(self) -> Result<Cfg, BuildError> {
+    let cfg = self.build(None)?;
+    validate(&cfg)?;
+    Ok(cfg)
+  }
+
+  /// Builds the CFG and emits it with `emit_cfg`.
+  ///
+  /// Build errors are returned as `InvalidData` errors wrapping a [`BuildError`].
+  pub fn emit(self) -> io::Result<Vec<u8>> {
+    let cfg = self
+      .finish()
+      .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    crate::emit_cfg(&cfg)
+  }
+
+  /// Builds the blocks, falling through to `next` after the last one.
+  fn build(self, next: Option<CfgLabel>) -> Result<Cfg, BuildError> {
+    if self.blocks.is_empty() {
+      return Ok(Cfg {
+        blocks: Vec1::new(CfgBlock {
+          label: self.fresh_label("empty"),
+          actions: Vec::new(),
+          flow: CfgFlow::Simple(cfg::Simple { next }),
+        }),
+      });
+    }
+    let labels: Vec<CfgLabel> = self.blocks.iter().map(|b| b.label.clone()).collect();
+    let mut blocks: Vec<CfgBlock> = Vec::new();
+    for (i, block) in self.blocks.into_iter().enumerate() {
+      if let Some(error) = block.error {
+        return Err(error);
+      }
+      let block_next = labels.get(i + 1).cloned().or_else(|| next.clone());
+      let flow = match block.flow {
+        None => CfgFlow::Simple(cfg::Simple { next: block_next }),
+        Some(PendingFlow::Flow(flow)) => flow,
+        Some(PendingFlow::Try(t)) => {
+          // The `try` and `catch` bodies fall through to the `finally` body
+          let finally = t.finally.map(|body| body.build(block_next.clone())).transpose()?;
+          let after = match &finally {
+            Some(finally) => Some(finally.blocks.first().label.clone()),
+            None => block_next,
+          };
+          let catch = match t.catch {
+            Some((target, body)) => Some(cfg::CatchBlock {
+              target,
+              body: body.build(after.clone())?,
+            }),
+            None => None,
+          };
+          CfgFlow::Try(Box::new(cfg::Try {
+            r#try: t.body.build(after)?,
+            catch,
+            finally,
+          }))
+        }
+        Some(PendingFlow::With(body)) => CfgFlow::With(cfg::With {
+          body: body.build(block_next)?,
+        }),
+      };
+      blocks.push(CfgBlock {
+        label: block.label,
+        actions: block.actions,
+        flow,
+      });
+    }
+    Ok(Cfg {
+      blocks: Vec1::try_from_vec(blocks).unwrap(),
+    })
+  }
+}
+
+#[derive(Debug)]
+enum PendingFlow {
+  Flow(CfgFlow),
+  Try(TryBuilder),
+  With(CfgBuilder),
+}
+
+/// Builder of a block, returned to the closure passed to [`CfgBuilder::block`].
+#[derive(Debug)]
+pub struct BlockBuilder {
+  labels: LabelGenerator,
+  label: CfgLabel,
+  actions: Vec<cfg::Action>,
+  flow: Option<PendingFlow>,
+  /// First misuse of the builder, reported by `finish`.
+  error: Option<BuildError>,
+}
+
+macro_rules! simple_actions {
+  ($($method:ident => $variant:ident,)*) => {
+    $(
+      #[doc = concat!("Appends a `", stringify!($variant), "` action.")]
+      pub fn $method(self) -> Self {
+        self.action(cfg::Action::$variant)
+      }
+    )*
+  };
+}
+
+#[allow(clippy::should_implement_trait)]
+impl BlockBuilder {
+  /// Label of the block.
+  pub fn label(&self) -> &CfgLabel {
+    &self.label
+  }
+
+  /// Returns a new unique label, see [`CfgBuilder::fresh_label`].
+  pub fn fresh_label(&self, prefix: &str) -> CfgLabel {
+    self.labels.fresh(prefix)
+  }
+
+  /// Appends an action.
+  pub fn action(mut self, action: cfg::Action) -> Self {
+    if self.flow.is_some() && self.error.is_none() {
+      self.error = Some(BuildError::ActionAfterFlow(self.label.clone()));
+    }
+    self.actions.push(action);
+    self
+  }
+
+  /// Appends a `Push` action with a single value.
+  pub fn push(self, value: impl IntoPushValue) -> Self {
+    self.push_values(vec![value.into_push_value()])
+  }
+
+  /// Appends a `Push` action.
+  pub fn push_values(self, values: Vec<PushValue>) -> Self {
+    self.action(cfg::Action::Push(action::Push { values }))
+  }
+
+  /// Appends a `Push` action for a register.
+  pub fn push_register(self, register: u8) -> Self {
+    self.push(PushValue::Register(register))
+  }
+
+  /// Appends a `Push` action for a constant of the constant pool.
+  pub fn push_constant(self, index: u16) -> Self {
+    self.push(PushValue::Constant(index))
+  }
+
+  pub fn constant_pool(self, pool: &[&str]) -> Self {
+    let pool = pool.iter().map(|s| s.to_string()).collect();
+    self.action(cfg::Action::ConstantPool(action::ConstantPool { pool }))
+  }
+
+  pub fn get_url(self, url: &str, target: &str) -> Self {
+    self.action(cfg::Action::GetUrl(Box::new(action::GetUrl {
+      url: url.to_string(),
+      target: target.to_string(),
+    })))
+  }
+
+  pub fn get_url2(self, method: GetUrl2Method, load_target: bool, load_variables: bool) -> Self {
+    self.action(cfg::Action::GetUrl2(action::GetUrl2 {
+      method,
+      load_target,
+      load_variables,
+    }))
+  }
+
+  pub fn goto_frame(self, frame: u16) -> Self {
+    self.action(cfg::Action::GotoFrame(action::GotoFrame { frame }))
+  }
+
+  pub fn goto_frame2(self, play: bool, scene_bias: u16) -> Self {
+    self.action(cfg::Action::GotoFrame2(action::GotoFrame2 { play, scene_bias }))
+  }
+
+  pub fn goto_label(self, label: &str) -> Self {
+    self.action(cfg::Action::GotoLabel(action::GoToLabel {
+      label: label.to_string(),
+    }))
+  }
+
+  pub fn raw(self, code: u8, data: &[u8]) -> Self {
+    self.action(cfg::Action::Raw(Box::new(action::Raw {
+      code,
+      data: data.to_vec(),
+    })))
+  }
+
+  pub fn set_target(self, target_name: &str) -> Self {
+    self.action(cfg::Action::SetTarget(action::SetTarget {
+      target_name: target_name.to_string(),
+    }))
+  }
+
+  pub fn store_register(self, register: u8) -> Self {
+    self.action(cfg::Action::StoreRegister(action::StoreRegister { register }))
+  }
+
+  pub fn strict_mode(self, is_strict: bool) -> Self {
+    self.action(cfg::Action::StrictMode(action::StrictMode { is_strict }))
+  }
+
+  /// Appends a `DefineFunction` action, its body is built by `body`.
+  pub fn define_function(self, name: &str, parameters: &[&str], body: impl FnOnce(CfgBuilder) -> CfgBuilder) -> Self {
+    let body = body(self.child()).build(None);
+    match body {
+      Ok(body) => self.action(cfg::Action::DefineFunction(Box::new(cfg::DefineFunction {
+        name: name.to_string(),
+        parameters: parameters.iter().map(|p| p.to_string()).collect(),
+        body,
+      }))),
+      Err(error) => self.fail(error),
+    }
+  }
+
+  /// Appends a `DefineFunction2` action, its body is built by `body`.
+  ///
+  /// Parameters are `(register, name)` pairs, register `0` means the
+  /// parameter is not stored in a register.
+  pub fn define_function2(
+    self,
+    name: &str,
+    register_count: u8,
+    flags: FunctionFlags,
+    parameters: &[(u8, &str)],
+    body: impl FnOnce(CfgBuilder) -> CfgBuilder,
+  ) -> Self {
+    let body = body(self.child()).build(None);
+    match body {
+      Ok(body) => self.action(cfg::Action::DefineFunction2(Box::new(cfg::DefineFunction2 {
+        name: name.to_string(),
+        register_count,
+        flags,
+        parameters: parameters
+          .iter()
+          .map(|(register, name)| Parameter {
+            register: *register,
+            name: name.to_string(),
+          })
+          .collect(),
+        body,
+      }))),
+      Err(error) => self.fail(error),
+    }
+  }
+
+  simple_actions! {
+    add => Add,
+    add2 => Add2,
+    and => And,
+    ascii_to_char => AsciiToChar,
+    bit_and => BitAnd,
+    bit_or => BitOr,
+    bit_l_shift => BitLShift,
+    bit_r_shift => BitRShift,
+    bit_u_r_shift => BitURShift,
+    bit_xor => BitXor,
+    call => Call,
+    call_function => CallFunction,
+    call_method => CallMethod,
+    char_to_ascii => CharToAscii,
+    cast_op => CastOp,
+    clone_sprite => CloneSprite,
+    decrement => Decrement,
+    define_local => DefineLocal,
+    define_local2 => DefineLocal2,
+    delete => Delete,
+    delete2 => Delete2,
+    divide => Divide,
+    end_drag => EndDrag,
+    enumerate => Enumerate,
+    enumerate2 => Enumerate2,
+    equals => Equals,
+    equals2 => Equals2,
+    extends => Extends,
+    fs_command2 => FsCommand2,
+    get_member => GetMember,
+    get_property => GetProperty,
+    get_time => GetTime,
+    get_variable => GetVariable,
+    greater => Greater,
+    implements_op => ImplementsOp,
+    increment => Increment,
+    init_array => InitArray,
+    init_object => InitObject,
+    instance_of => InstanceOf,
+    less => Less,
+    less2 => Less2,
+    mb_ascii_to_char => MbAsciiToChar,
+    mb_char_to_ascii => MbCharToAscii,
+    mb_string_extract => MbStringExtract,
+    mb_string_length => MbStringLength,
+    modulo => Modulo,
+    multiply => Multiply,
+    new_method => NewMethod,
+    new_object => NewObject,
+    next_frame => NextFrame,
+    not => Not,
+    or => Or,
+    play => Play,
+    pop => Pop,
+    prev_frame => PrevFrame,
+    push_duplicate => PushDuplicate,
+    random_number => RandomNumber,
+    remove_sprite => RemoveSprite,
+    set_member => SetMember,
+    set_property => SetProperty,
+    set_target2 => SetTarget2,
+    set_variable => SetVariable,
+    stack_swap => StackSwap,
+    start_drag => StartDrag,
+    stop => Stop,
+    stop_sounds => StopSounds,
+    strict_equals => StrictEquals,
+    string_add => StringAdd,
+    string_equals => StringEquals,
+    string_extract => StringExtract,
+    string_greater => StringGreater,
+    string_length => StringLength,
+    string_less => StringLess,
+    subtract => Subtract,
+    target_path => TargetPath,
+    to_integer => ToInteger,
+    to_number => ToNumber,
+    to_string => ToString,
+    toggle_quality => ToggleQuality,
+    trace => Trace,
+    type_of => TypeOf,
+  }
+
+  /// Ends the block with a control flow.
+  pub fn flow(self, flow: CfgFlow) -> Self {
+    self.set_flow(PendingFlow::Flow(flow))
+  }
+
+  /// Jumps to `true_target` if the popped value is true, to `false_target` otherwise.
+  pub fn if_true(self, true_target: impl IntoCfgLabel, false_target: impl IntoCfgLabel) -> Self {
+    self.flow(CfgFlow::If(cfg::If {
+      true_target: Some(true_target.into_cfg_label()),
+      false_target: Some(false_target.into_cfg_label()),
+    }))
+  }
+
+  pub fn jump(self, target: impl IntoCfgLabel) -> Self {
+    self.flow(CfgFlow::Simple(cfg::Simple {
+      next: Some(target.into_cfg_label()),
+    }))
+  }
+
+  /// Jumps to the end of the CFG.
+  pub fn end(self) -> Self {
+    self.flow(CfgFlow::Simple(cfg::Simple { next: None }))
+  }
+
+  pub fn return_(self) -> Self {
+    self.flow(CfgFlow::Return)
+  }
+
+  pub fn throw(self) -> Self {
+    self.flow(CfgFlow::Throw)
+  }
+
+  pub fn wait_for_frame(self, frame: u16, ready_target: impl IntoCfgLabel, loading_target: impl IntoCfgLabel) -> Self {
+    self.flow(CfgFlow::WaitForFrame(cfg::WaitForFrame {
+      frame,
+      ready_target: Some(ready_target.into_cfg_label()),
+      loading_target: Some(loading_target.into_cfg_label()),
+    }))
+  }
+
+  pub fn wait_for_frame2(self, ready_target: impl IntoCfgLabel, loading_target: impl IntoCfgLabel) -> Self {
+    self.flow(CfgFlow::WaitForFrame2(cfg::WaitForFrame2 {
+      ready_target: Some(ready_target.into_cfg_label()),
+      loading_target: Some(loading_target.into_cfg_label()),
+    }))
+  }
+
+  /// Ends the block with a `try` region built by `f`.
+  pub fn try_(self, f: impl FnOnce(TryBuilder) -> TryBuilder) -> Self {
+    let region = f(TryBuilder {
+      body: self.child(),
+      catch: None,
+      finally: None,
+    });
+    self.set_flow(PendingFlow::Try(region))
+  }
+
+  /// Ends the block with a `with` region, its body is built by `body`.
+  pub fn with(self, body: impl FnOnce(CfgBuilder) -> CfgBuilder) -> Self {
+    let body = body(self.child());
+    self.set_flow(PendingFlow::With(body))
+  }
+
+  fn child(&self) -> CfgBuilder {
+    CfgBuilder {
+      labels: self.labels.clone(),
+      blocks: Vec::new(),
+    }
+  }
+
+  fn set_flow(mut self, flow: PendingFlow) -> Self {
+    if self.flow.is_some() {
+      let label = self.label.clone();
+      return self.fail(BuildError::FlowAlreadySet(label));
+    }
+    self.flow = Some(flow);
+    self
+  }
+
+  fn fail(mut self, error: BuildError) -> Self {
+    if self.error.is_none() {
+      self.error = Some(error);
+    }
+    self
+  }
+}
+
+/// Builder of a `try` region, see [`BlockBuilder::try_`].
+#[derive(Debug)]
+pub struct TryBuilder {
+  body: CfgBuilder,
+  catch: Option<(CatchTarget, CfgBuilder)>,
+  finally: Option<CfgBuilder>,
+}
+
+impl TryBuilder {
+  /// Builds the `try` body.
+  pub fn body(mut self, f: impl FnOnce(CfgBuilder) -> CfgBuilder) -> Self {
+    self.body = f(self.body.child());
+    self
+  }
+
+  /// Builds the `catch` body, the error is stored in `target`.
+  pub fn catch(mut self, target: CatchTarget, f: impl FnOnce(CfgBuilder) -> CfgBuilder) -> Self {
+    self.catch = Some((target, f(self.body.child())));
+    self
+  }
+
+  /// Builds the `finally` body.
+  pub fn finally(mut self, f: impl FnOnce(CfgBuilder) -> CfgBuilder) -> Self {
+    self.finally = Some(f(self.body.child()));
+    self
+  }
+}
+
+/// Checks that the labels of each function are unique and that its jumps
+/// target one of them.
+fn validate(cfg: &Cfg) -> Result<(), BuildError> {
+  let mut defined: HashSet<&CfgLabel> = HashSet::new();
+  let mut targets: Vec<&CfgLabel> = Vec::new();
+  collect_labels(cfg, &mut defined, &mut targets)?;
+  match targets.into_iter().find(|t| !defined.contains(t)) {
+    Some(target) => Err(BuildError::UndefinedLabel(target.clone())),
+    None => Ok(()),
+  }
+}
+
+fn collect_labels<'a>(
+  cfg: &'a Cfg,
+  defined: &mut HashSet<&'a CfgLabel>,
+  targets: &mut Vec<&'a CfgLabel>,
+) -> Result<(), BuildError> {
+  for block in cfg.blocks.iter() {
+    if !defined.insert(&block.label) {
+      return Err(BuildError::DuplicateLabel(block.label.clone()));
+    }
+    for action in block.actions.iter() {
+      match action {
+        cfg::Action::DefineFunction(f) => validate(&f.body)?,
+        cfg::Action::DefineFunction2(f) => validate(&f.body)?,
+        _ => {}
+      }
+    }
+    match &block.flow {
+      CfgFlow::If(flow) => targets.extend(flow.true_target.iter().chain(flow.false_target.iter())),
+      CfgFlow::Simple(flow) => targets.extend(flow.next.iter()),
+      CfgFlow::WaitForFrame(flow) => targets.extend(flow.ready_target.iter().chain(flow.loading_target.iter())),
+      CfgFlow::WaitForFrame2(flow) => targets.extend(flow.ready_target.iter().chain(flow.loading_target.iter())),
+      CfgFlow::Try(flow) => {
+        collect_labels(&flow.r#try, defined, targets)?;
+        if let Some(catch) = &flow.catch {
+          collect_labels(&catch.body, defined, targets)?;
+        }
+        if let Some(finally) = &flow.finally {
+          collect_labels(finally, defined, targets)?;
+        }
+      }
+      CfgFlow::With(flow) => collect_labels(&flow.body, defined, targets)?,
+      CfgFlow::Error(_) | CfgFlow::Return | CfgFlow::Throw => {}
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sample(name: &str) -> Vec<u8> {
+    std::fs::read(format!("../tests/avm1/local/{}/main.avm1", name)).unwrap()
+  }
+
+  #[test]
+  fn test_builder_samples() {
+    let cfg = CfgBuilder::new();
+    let (yes, done) = (cfg.fresh_label("yes"), cfg.fresh_label("done"));
+    let if_else = cfg
+      .block("entry", |b| b.push("x").get_variable().if_true(&yes, "no"))
+      .block("no", |b| b.push("no").trace().jump(&done))
+      .block(&yes, |b| b.push("yes").trace())
+      .block(&done, |b| b)
+      .emit()
+      .unwrap();
+    assert_eq!(if_else, sample("if-else"));
+
+    let flags = FunctionFlags::SUPPRESS_THIS | FunctionFlags::SUPPRESS_ARGUMENTS | FunctionFlags::SUPPRESS_SUPER;
+    let define_functions = CfgBuilder::new()
+      .fresh_block(|b| {
+        b.define_function2("add", 3, flags, &[(1, "a"), (2, "b")], |f| {
+          f.fresh_block(|b| {
+            b.push_values(vec![PushValue::Register(1), PushValue::Register(2)])
+              .add2()
+              .return_()
+          })
+        })
+        .define_function("", &["x"], |f| f.fresh_block(|b| b.push("hi").trace()))
+        .push("f")
+        .stack_swap()
+        .set_variable()
+        .push_values(vec![
+          PushValue::Sint32(1),
+          PushValue::Sint32(2),
+          PushValue::Sint32(2),
+          PushValue::String(String::from("add")),
+        ])
+        .call_function()
+        .trace()
+      })
+      .emit()
+      .unwrap();
+    assert_eq!(define_functions, sample("define-functions"));
+
+    let try_catch_finally = CfgBuilder::new()
+      .fresh_block(|b| {
+        b.try_(|t| {
+          t.body(|c| c.fresh_block(|b| b.push("err").throw()))
+            .catch(CatchTarget::Register(0), |c| {
+              c.fresh_block(|b| b.push_register(0).trace())
+            })
+            .finally(|c| c.fresh_block(|b| b.push("finally").trace()))
+        })
+      })
+      .fresh_block(|b| b)
+      .emit()
+      .unwrap();
+    assert_eq!(try_catch_finally, sample("try-catch-finally"));
+
+    let with = CfgBuilder::new()
+      .fresh_block(|b| {
+        b.push("o")
+          .get_variable()
+          .with(|c| c.fresh_block(|b| b.push("a").get_variable().trace()))
+      })
+      .fresh_block(|b| b.stop())
+      .emit()
+      .unwrap();
+    assert_eq!(with, sample("with"));
+  }
+
+  #[test]
+  fn test_builder_fall_through() {
+    let cfg = CfgBuilder::new()
+      .block("a", |b| b.with(|c| c.block("b", |b| b.play())))
+      .block("c", |b| b.stop())
+      .finish()
+      .unwrap();
+    let with = match &cfg.blocks.first().flow {
+      CfgFlow::With(with) => with,
+      flow => panic!("unexpected flow: {:?}", flow),
+    };
+    assert_eq!(
+      with.body.blocks.first().flow,
+      CfgFlow::Simple(cfg::Simple {
+        next: Some(CfgLabel(String::from("c")))
+      })
+    );
+    assert_eq!(cfg.blocks.last().flow, CfgFlow::Simple(cfg::Simple { next: None }));
+  }
+
+  #[test]
+  fn test_builder_errors() {
+    let label = |s: &str| CfgLabel(String::from(s));
+    let finish = |builder: CfgBuilder| builder.finish().unwrap_err();
+    assert_eq!(
+      finish(CfgBuilder::new().block("a", |b| b.jump("b"))),
+      BuildError::UndefinedLabel(label("b"))
+    );
+    assert_eq!(
+      finish(CfgBuilder::new().block("a", |b| b.with(|c| c.block("a", |b| b)))),
+      BuildError::DuplicateLabel(label("a"))
+    );
+    assert_eq!(
+      finish(CfgBuilder::new().block("a", |b| b.end().throw())),
+      BuildError::FlowAlreadySet(label("a"))
+    );
+    assert_eq!(
+      finish(CfgBuilder::new().block("a", |b| b.end().stop())),
+      BuildError::ActionAfterFlow(label("a"))
+    );
+    // Function bodies have their own labels
+    assert_eq!(
+      finish(CfgBuilder::new().block("a", |b| b.define_function("f", &[], |f| f.block("b", |b| b.jump("a"))))),
+      BuildError::UndefinedLabel(label("a"))
+    );
+  }
+}
@@ -0,0 +1,1008 @@
+//! Generation of the control flow graph from the syntax tree.
+
+use super::parser::{BinaryOp, Expr, Function, Pos, Stmt, StmtKind, UnaryOp};
+use super::{CompileError, CompileOptions};
+use avm1_types::action::{Push, StoreRegister};
+use avm1_types::cfg::{self, Action, Cfg, CfgBlock, CfgFlow, CfgLabel};
+use avm1_types::{CatchTarget, FunctionFlags, Parameter, PushValue};
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use vec1::Vec1;
+
+type CodegenResult<T> = Result<T, CompileError>;
+
+/// Number of registers available outside of `DefineFunction2` bodies.
+const GLOBAL_REGISTERS: u8 = 4;
+
+/// Maximum number of registers of a `DefineFunction2` body.
+const MAX_REGISTERS: u8 = 255;
+
+/// Prefix of the variables holding temporary values before SWF 7.
+const TEMP_VARIABLE_PREFIX: &str = "__t";
+
+pub(super) fn compile_program(program: &[Stmt], options: &CompileOptions) -> CodegenResult<Cfg> {
+  let mut codegen = Codegen {
+    options,
+    next_label: 0,
+    pos: Pos { line: 1, column: 1 },
+    body: Body::default(),
+    scope: Scope::new(HashMap::new(), false, 0, GLOBAL_REGISTERS),
+  };
+  codegen.function_body(program)?;
+  Ok(codegen.finish_body(None))
+}
+
+/// Variables, registers and loops of the function being compiled.
+#[derive(Default)]
+struct Scope {
+  /// Variables stored in registers, including the preloaded `this` and `arguments`.
+  registers: HashMap<String, u8>,
+  /// Other variables are declared with `DefineLocal` in functions, `SetVariable` otherwise.
+  in_function: bool,
+  /// Next free register for temporary values.
+  next_temp: u8,
+  /// End (exclusive) of the registers available for temporary values.
+  temp_end: u8,
+  /// Number of registers used, for `DefineFunction2`.
+  register_count: u8,
+  /// Break and continue targets of the enclosing loops.
+  loops: Vec<(CfgLabel, CfgLabel)>,
+}
+
+impl Scope {
+  fn new(registers: HashMap<String, u8>, in_function: bool, next_temp: u8, temp_end: u8) -> Self {
+    Self {
+      registers,
+      in_function,
+      next_temp,
+      temp_end,
+      register_count: next_temp,
+      loops: Vec::new(),
+    }
+  }
+}
+
+/// Value computed once and pushed again later.
+#[derive(Clone)]
+enum Operand {
+  /// Literal or register, pushed as is.
+  Value(PushValue),
+  /// Temporary variable.
+  Variable(String),
+}
+
+/// Blocks of the body being compiled.
+#[derive(Default)]
+struct Body {
+  blocks: Vec<CfgBlock>,
+  /// Label and actions of the current block, `None` after an unconditional jump.
+  current: Option<(CfgLabel, Vec<Action>)>,
+}
+
+struct Codegen<'a> {
+  options: &'a CompileOptions,
+  /// Labels are unique in the whole program.
+  next_label: usize,
+  /// Position of the statement being compiled, for errors.
+  pos: Pos,
+  body: Body,
+  scope: Scope,
+}
+
+impl<'a> Codegen<'a> {
+  fn new_label(&mut self) -> CfgLabel {
+    let label = CfgLabel(format!("l{}", self.next_label));
+    self.next_label += 1;
+    label
+  }
+
+  /// Returns the actions of the current block, starting an unreachable block if needed.
+  fn actions(&mut self) -> &mut Vec<Action> {
+    if self.body.current.is_none() {
+      let label = self.new_label();
+      self.body.current = Some((label, Vec::new()));
+    }
+    &mut self.body.current.as_mut().unwrap().1
+  }
+
+  fn action(&mut self, action: Action) {
+    self.actions().push(action);
+  }
+
+  /// Pushes a value, merged with the previous `Push` action if possible.
+  fn push(&mut self, value: PushValue) {
+    let actions = self.actions();
+    match actions.last_mut() {
+      Some(Action::Push(push)) => push.values.push(value),
+      _ => actions.push(Action::Push(Push { values: vec![value] })),
+    }
+  }
+
+  fn end_block(&mut self, flow: CfgFlow) {
+    self.actions();
+    let (label, actions) = self.body.current.take().unwrap();
+    self.body.blocks.push(CfgBlock { label, actions, flow });
+  }
+
+  /// Jumps to `target`, unless the current position is unreachable.
+  fn jump(&mut self, target: CfgLabel) {
+    if self.body.current.is_some() {
+      self.end_block(CfgFlow::Simple(cfg::Simple { next: Some(target) }));
+    }
+  }
+
+  /// Ends the current block with a jump to `true_target` if the value on the stack is true.
+  fn branch(&mut self, true_target: CfgLabel, false_target: CfgLabel) {
+    self.end_block(CfgFlow::If(cfg::If {
+      true_target: Some(true_target),
+      false_target: Some(false_target),
+    }));
+  }
+
+  /// Starts a new block, the current one falls through to it.
+  fn start_block(&mut self, label: CfgLabel) {
+    self.jump(label.clone());
+    self.body.current = Some((label, Vec::new()));
+  }
+
+  /// Ends the current body, falling through to `next`.
+  fn finish_body(&mut self, next: Option<CfgLabel>) -> Cfg {
+    if self.body.current.is_some() || self.body.blocks.is_empty() {
+      self.end_block(CfgFlow::Simple(cfg::Simple { next }));
+    }
+    let blocks = mem::take(&mut self.body.blocks);
+    Cfg {
+      blocks: Vec1::try_from_vec(blocks).expect("a body has at least one block"),
+    }
+  }
+
+  /// Compiles the body of a `try`, `catch` or `finally` block.
+  fn nested_body(&mut self, entry: CfgLabel, next: CfgLabel, statements: &[Stmt]) -> CodegenResult<Cfg> {
+    let outer = mem::take(&mut self.body);
+    self.body.current = Some((entry, Vec::new()));
+    let result = self.statements(statements);
+    let body = self.finish_body(Some(next));
+    self.body = outer;
+    result.map(|()| body)
+  }
+
+  fn alloc_temp(&mut self) -> CodegenResult<Operand> {
+    // Before SWF 7, `DefineFunction` bodies share the registers of their
+    // caller: a call, a getter or a setter running between the store and the
+    // read of a temporary register may overwrite it.
+    let in_variable = self.options.swf_version < 7;
+    let index = self.scope.next_temp;
+    let end = if in_variable { u8::MAX } else { self.scope.temp_end };
+    if index >= end {
+      return Err(self.pos.error(String::from("expression needs too many registers")));
+    }
+    self.scope.next_temp += 1;
+    if in_variable {
+      return Ok(Operand::Variable(format!("{}{}", TEMP_VARIABLE_PREFIX, index)));
+    }
+    self.scope.register_count = self.scope.register_count.max(self.scope.next_temp);
+    Ok(Operand::Value(PushValue::Register(index)))
+  }
+
+  fn free_temp(&mut self) {
+    self.scope.next_temp -= 1;
+  }
+
+  /// Stores the value on top of the stack in the temporary `temp`, leaving it on the stack.
+  fn store_temp(&mut self, temp: &Operand) {
+    match temp {
+      Operand::Value(PushValue::Register(register)) => {
+        self.action(Action::StoreRegister(StoreRegister { register: *register }))
+      }
+      Operand::Variable(name) => {
+        self.action(Action::PushDuplicate);
+        self.push(PushValue::String(name.clone()));
+        self.action(Action::StackSwap);
+        self.action(if self.scope.in_function {
+          Action::DefineLocal
+        } else {
+          Action::SetVariable
+        });
+      }
+      Operand::Value(_) => unreachable!("temporary values are kept in registers or variables"),
+    }
+  }
+
+  fn push_operand(&mut self, operand: &Operand) {
+    match operand {
+      Operand::Value(value) => self.push(value.clone()),
+      Operand::Variable(name) => {
+        self.push(PushValue::String(name.clone()));
+        self.action(Action::GetVariable);
+      }
+    }
+  }
+
+  /// Compiles the statements of a program or function, with hoisted function declarations.
+  fn function_body(&mut self, statements: &[Stmt]) -> CodegenResult<()> {
+    for function in Declarations::of(statements).functions {
+      self.pos = function.pos;
+      let action = self.function(function)?;
+      self.action(action);
+    }
+    self.statements(statements)
+  }
+
+  fn function(&mut self, function: &Function) -> CodegenResult<Action> {
+    let outer_scope = mem::take(&mut self.scope);
+    let outer_body = mem::take(&mut self.body);
+    let result = if self.options.swf_version >= 7 {
+      self.function2(function)
+    } else {
+      self.scope = Scope::new(HashMap::new(), true, 0, GLOBAL_REGISTERS);
+      self.function_body(&function.body).map(|()| {
+        Action::DefineFunction(Box::new(cfg::DefineFunction {
+          name: function.name.clone(),
+          parameters: function.params.clone(),
+          body: self.finish_body(None),
+        }))
+      })
+    };
+    self.scope = outer_scope;
+    self.body = outer_body;
+    result
+  }
+
+  /// Compiles a function to `DefineFunction2`, keeping its variables in registers
+  /// unless they are used by nested functions.
+  fn function2(&mut self, function: &Function) -> CodegenResult<Action> {
+    let declarations = Declarations::of(&function.body);
+    let mut registers: HashMap<String, u8> = HashMap::new();
+    let mut next_register: usize = 1;
+    let mut allocate = |registers: &mut HashMap<String, u8>, name: &str| -> u8 {
+      if next_register >= usize::from(MAX_REGISTERS) || registers.contains_key(name) {
+        return 0;
+      }
+      let register = next_register as u8;
+      registers.insert(name.to_string(), register);
+      next_register += 1;
+      register
+    };
+
+    // Preloaded registers are assigned in the order of the flags
+    let mut flags = FunctionFlags::SUPPRESS_SUPER;
+    if declarations.uses_this {
+      flags |= FunctionFlags::PRELOAD_THIS;
+      allocate(&mut registers, "this");
+    } else {
+      flags |= FunctionFlags::SUPPRESS_THIS;
+    }
+    if declarations.uses_arguments {
+      flags |= FunctionFlags::PRELOAD_ARGUMENTS;
+      allocate(&mut registers, "arguments");
+    } else {
+      flags |= FunctionFlags::SUPPRESS_ARGUMENTS;
+    }
+
+    let parameters: Vec<Parameter> = function
+      .params
+      .iter()
+      .map(|name| Parameter {
+        register: if declarations.captured.contains(name.as_str()) {
+          0
+        } else {
+          allocate(&mut registers, name)
+        },
+        name: name.clone(),
+      })
+      .collect();
+    for name in &declarations.variables {
+      let is_function = declarations.functions.iter().any(|f| f.name == *name);
+      if !is_function && !declarations.captured.contains(name) {
+        allocate(&mut registers, name);
+      }
+    }
+
+    let next_temp = next_register as u8;
+    self.scope = Scope::new(registers, true, next_temp, MAX_REGISTERS);
+    self.function_body(&function.body)?;
+    Ok(Action::DefineFunction2(Box::new(cfg::DefineFunction2 {
+      name: function.name.clone(),
+      register_count: self.scope.register_count,
+      flags,
+      parameters,
+      body: self.finish_body(None),
+    })))
+  }
+
+  fn statements(&mut self, statements: &[Stmt]) -> CodegenResult<()> {
+    for statement in statements {
+      self.statement(statement)?;
+    }
+    Ok(())
+  }
+
+  fn statement(&mut self, statement: &Stmt) -> CodegenResult<()> {
+    self.pos = statement.pos;
+    match &statement.kind {
+      StmtKind::Var(declarations) => {
+        for (name, value) in declarations {
+          self.declare(name, value.as_ref())?;
+        }
+      }
+      // Function declarations are hoisted
+      StmtKind::Function(_) => {}
+      StmtKind::Expr(expr) => self.expr(expr, false)?,
+      StmtKind::If(condition, then, otherwise) => {
+        let (then_label, end) = (self.new_label(), self.new_label());
+        self.value(condition)?;
+        self.action(Action::Not);
+        match otherwise {
+          Some(otherwise) => {
+            let else_label = self.new_label();
+            self.branch(else_label.clone(), then_label.clone());
+            self.start_block(then_label);
+            self.statement(then)?;
+            self.jump(end.clone());
+            self.start_block(else_label);
+            self.statement(otherwise)?;
+          }
+          None => {
+            self.branch(end.clone(), then_label.clone());
+            self.start_block(then_label);
+            self.statement(then)?;
+          }
+        }
+        self.start_block(end);
+      }
+      StmtKind::While(condition, body) => {
+        let (start, body_label, end) = (self.new_label(), self.new_label(), self.new_label());
+        self.start_block(start.clone());
+        self.value(condition)?;
+        self.action(Action::Not);
+        self.branch(end.clone(), body_label.clone());
+        self.start_block(body_label);
+        self.loop_body(body, end.clone(), start.clone())?;
+        self.jump(start);
+        self.start_block(end);
+      }
+      StmtKind::DoWhile(body, condition) => {
+        let (body_label, condition_label, end) = (self.new_label(), self.new_label(), self.new_label());
+        self.start_block(body_label.clone());
+        self.loop_body(body, end.clone(), condition_label.clone())?;
+        self.start_block(condition_label);
+        self.value(condition)?;
+        self.branch(body_label, end.clone());
+        self.start_block(end);
+      }
+      StmtKind::For(init, condition, update, body) => {
+        if let Some(init) = init {
+          self.statement(init)?;
+        }
+        let (start, update_label, end) = (self.new_label(), self.new_label(), self.new_label());
+        self.start_block(start.clone());
+        if let Some(condition) = condition {
+          let body_label = self.new_label();
+          self.value(condition)?;
+          self.action(Action::Not);
+          self.branch(end.clone(), body_label.clone());
+          self.start_block(body_label);
+        }
+        self.loop_body(body, end.clone(), update_label.clone())?;
+        self.start_block(update_label);
+        if let Some(update) = update {
+          self.expr(update, false)?;
+        }
+        self.jump(start);
+        self.start_block(end);
+      }
+      StmtKind::Break | StmtKind::Continue => {
+        let (break_label, continue_label) = self.scope.loops.last().cloned().expect("checked by the parser");
+        match statement.kind {
+          StmtKind::Break => self.jump(break_label),
+          _ => self.jump(continue_label),
+        }
+      }
+      StmtKind::Return(value) => {
+        match value {
+          Some(value) => self.value(value)?,
+          None => self.push(PushValue::Undefined),
+        }
+        self.end_block(CfgFlow::Return);
+      }
+      StmtKind::Throw(value) => {
+        self.value(value)?;
+        self.end_block(CfgFlow::Throw);
+      }
+      StmtKind::Try(body, catch, finally) => self.try_statement(body, catch.as_ref(), finally.as_deref())?,
+      StmtKind::Block(statements) => self.statements(statements)?,
+      StmtKind::Empty => {}
+    }
+    Ok(())
+  }
+
+  fn loop_body(&mut self, body: &Stmt, break_label: CfgLabel, continue_label: CfgLabel) -> CodegenResult<()> {
+    self.scope.loops.push((break_label, continue_label));
+    let result = self.statement(body);
+    self.scope.loops.pop();
+    result
+  }
+
+  fn declare(&mut self, name: &str, value: Option<&Expr>) -> CodegenResult<()> {
+    match (self.scope.registers.get(name).copied(), value) {
+      (Some(register), Some(value)) => {
+        self.value(value)?;
+        self.action(Action::StoreRegister(StoreRegister { register }));
+        self.action(Action::Pop);
+      }
+      (Some(_), None) => {}
+      (None, Some(value)) => {
+        self.push(PushValue::String(name.to_string()));
+        self.value(value)?;
+        self.action(if self.scope.in_function {
+          Action::DefineLocal
+        } else {
+          Action::SetVariable
+        });
+      }
+      (None, None) => {
+        if self.scope.in_function {
+          self.push(PushValue::String(name.to_string()));
+          self.action(Action::DefineLocal2);
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn try_statement(
+    &mut self,
+    body: &[Stmt],
+    catch: Option<&(String, Vec<Stmt>)>,
+    finally: Option<&[Stmt]>,
+  ) -> CodegenResult<()> {
+    let end = self.new_label();
+    let finally_label = finally.map(|_| self.new_label());
+    let next = finally_label.clone().unwrap_or_else(|| end.clone());
+    let try_label = self.new_label();
+    let try_body = self.nested_body(try_label, next.clone(), body)?;
+    let catch = match catch {
+      Some((name, statements)) => {
+        let target = match self.scope.registers.get(name) {
+          Some(&register) => CatchTarget::Register(register),
+          None => CatchTarget::Variable(name.clone()),
+        };
+        let label = self.new_label();
+        let body = self.nested_body(label, next, statements)?;
+        Some(cfg::CatchBlock { target, body })
+      }
+      None => None,
+    };
+    let finally = match (finally, finally_label) {
+      (Some(statements), Some(label)) => Some(self.nested_body(label, end.clone(), statements)?),
+      _ => None,
+    };
+    self.end_block(CfgFlow::Try(Box::new(cfg::Try {
+      r#try: try_body,
+      catch,
+      finally,
+    })));
+    self.start_block(end);
+    Ok(())
+  }
+
+  /// Compiles an expression pushing its value.
+  fn value(&mut self, expr: &Expr) -> CodegenResult<()> {
+    self.expr(expr, true)
+  }
+
+  /// Compiles an expression, pushing its value only if it is `used`.
+  fn expr(&mut self, expr: &Expr, used: bool) -> CodegenResult<()> {
+    if let Some(value) = literal(expr) {
+      if used {
+        self.push(value);
+      }
+      return Ok(());
+    }
+    match expr {
+      Expr::Identifier(name) => {
+        if used {
+          self.get_variable(name);
+        }
+        return Ok(());
+      }
+      Expr::Assign(None, target, value) => return self.assign(target, value, used),
+      Expr::Assign(Some(op), target, value) => {
+        return self.read_modify_write(target, used, false, |codegen| {
+          codegen.value(value)?;
+          codegen.binary(*op);
+          Ok(())
+        })
+      }
+      Expr::Update {
+        increment,
+        prefix,
+        target,
+      } => {
+        let action = if *increment {
+          Action::Increment
+        } else {
+          Action::Decrement
+        };
+        return self.read_modify_write(target, used, !prefix, |codegen| {
+          codegen.action(action);
+          Ok(())
+        });
+      }
+      Expr::Call(callee, args) => return self.call(callee, args, used),
+      Expr::Conditional(condition, then, otherwise) => {
+        let (then_label, else_label, end) = (self.new_label(), self.new_label(), self.new_label());
+        self.value(condition)?;
+        self.action(Action::Not);
+        self.branch(else_label.clone(), then_label.clone());
+        self.start_block(then_label);
+        self.expr(then, used)?;
+        self.jump(end.clone());
+        self.start_block(else_label);
+        self.expr(otherwise, used)?;
+        self.start_block(end);
+        return Ok(());
+      }
+      Expr::Member(object, key) => {
+        self.value(object)?;
+        self.value(key)?;
+        self.action(Action::GetMember);
+      }
+      Expr::New(callee, args) => {
+        self.arguments(args)?;
+        match &**callee {
+          Expr::Identifier(name) if !self.scope.registers.contains_key(name) => {
+            self.push(PushValue::String(name.clone()));
+            self.action(Action::NewObject);
+          }
+          Expr::Member(object, key) => {
+            self.value(object)?;
+            self.value(key)?;
+            self.action(Action::NewMethod);
+          }
+          callee => {
+            self.value(callee)?;
+            self.push(PushValue::Undefined);
+            self.action(Action::NewMethod);
+          }
+        }
+      }
+      Expr::Unary(op, value) => self.unary(*op, value)?,
+      Expr::Binary(op @ (BinaryOp::And | BinaryOp::Or), left, right) => {
+        // The left value is the result if it is falsy (`&&`) or truthy (`||`)
+        let (right_label, end) = (self.new_label(), self.new_label());
+        self.value(left)?;
+        self.action(Action::PushDuplicate);
+        if *op == BinaryOp::And {
+          self.action(Action::Not);
+        }
+        self.branch(end.clone(), right_label.clone());
+        self.start_block(right_label);
+        self.action(Action::Pop);
+        self.value(right)?;
+        self.start_block(end);
+      }
+      Expr::Binary(op, left, right) => {
+        self.value(left)?;
+        self.value(right)?;
+        self.binary(*op);
+      }
+      Expr::Array(items) => {
+        self.arguments(items)?;
+        self.action(Action::InitArray);
+      }
+      Expr::Object(properties) => {
+        for (key, value) in properties {
+          self.push(PushValue::String(key.clone()));
+          self.value(value)?;
+        }
+        self.push(PushValue::Sint32(properties.len() as i32));
+        self.action(Action::InitObject);
+      }
+      Expr::Function(function) => {
+        let action = self.function(function)?;
+        self.action(action);
+      }
+      Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) | Expr::Null | Expr::Undefined => unreachable!(),
+    }
+    if !used {
+      self.action(Action::Pop);
+    }
+    Ok(())
+  }
+
+  fn get_variable(&mut self, name: &str) {
+    match self.scope.registers.get(name) {
+      Some(&register) => self.push(PushValue::Register(register)),
+      None => {
+        self.push(PushValue::String(name.to_string()));
+        self.action(Action::GetVariable);
+      }
+    }
+  }
+
+  /// Pushes the arguments of a call in reverse order, followed by their count.
+  fn arguments(&mut self, args: &[Expr]) -> CodegenResult<()> {
+    for arg in args.iter().rev() {
+      self.value(arg)?;
+    }
+    self.push(PushValue::Sint32(args.len() as i32));
+    Ok(())
+  }
+
+  fn call(&mut self, callee: &Expr, args: &[Expr], used: bool) -> CodegenResult<()> {
+    match callee {
+      Expr::Identifier(name) if name == "trace" && args.len() == 1 && !self.scope.registers.contains_key(name) => {
+        self.value(&args[0])?;
+        self.action(Action::Trace);
+        if used {
+          self.push(PushValue::Undefined);
+        }
+        return Ok(());
+      }
+      Expr::Identifier(name) if !self.scope.registers.contains_key(name) => {
+        self.arguments(args)?;
+        self.push(PushValue::String(name.clone()));
+        self.action(Action::CallFunction);
+      }
+      Expr::Member(object, key) => {
+        self.arguments(args)?;
+        self.value(object)?;
+        self.value(key)?;
+        self.action(Action::CallMethod);
+      }
+      callee => {
+        // Calling a method with an undefined name calls the object itself
+        self.arguments(args)?;
+        self.value(callee)?;
+        self.push(PushValue::Undefined);
+        self.action(Action::CallMethod);
+      }
+    }
+    if !used {
+      self.action(Action::Pop);
+    }
+    Ok(())
+  }
+
+  fn unary(&mut self, op: UnaryOp, value: &Expr) -> CodegenResult<()> {
+    match (op, value) {
+      (UnaryOp::Delete, Expr::Member(object, key)) => {
+        self.value(object)?;
+        self.value(key)?;
+        self.action(Action::Delete);
+      }
+      (UnaryOp::Delete, Expr::Identifier(name)) => {
+        self.push(PushValue::String(name.clone()));
+        self.action(Action::Delete2);
+      }
+      (UnaryOp::Delete, value) => {
+        self.expr(value, false)?;
+        self.push(PushValue::Boolean(true));
+      }
+      (op, value) => {
+        self.value(value)?;
+        match op {
+          UnaryOp::Neg => {
+            self.push(PushValue::Sint32(-1));
+            self.action(Action::Multiply);
+          }
+          UnaryOp::Plus => self.action(Action::ToNumber),
+          UnaryOp::Not => self.action(Action::Not),
+          UnaryOp::BitNot => {
+            self.push(PushValue::Sint32(-1));
+            self.action(Action::BitXor);
+          }
+          UnaryOp::TypeOf => self.action(Action::TypeOf),
+          UnaryOp::Delete => unreachable!(),
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Applies a binary operator to the two values on top of the stack.
+  fn binary(&mut self, op: BinaryOp) {
+    let swf6 = self.options.swf_version >= 6;
+    let actions: Vec<Action> = match op {
+      BinaryOp::Add => vec![Action::Add2],
+      BinaryOp::Sub => vec![Action::Subtract],
+      BinaryOp::Mul => vec![Action::Multiply],
+      BinaryOp::Div => vec![Action::Divide],
+      BinaryOp::Mod => vec![Action::Modulo],
+      BinaryOp::Eq => vec![Action::Equals2],
+      BinaryOp::Ne => vec![Action::Equals2, Action::Not],
+      BinaryOp::StrictEq => vec![Action::StrictEquals],
+      BinaryOp::StrictNe => vec![Action::StrictEquals, Action::Not],
+      BinaryOp::Lt => vec![Action::Less2],
+      BinaryOp::Gt if swf6 => vec![Action::Greater],
+      BinaryOp::Gt => vec![Action::StackSwap, Action::Less2],
+      BinaryOp::Le if swf6 => vec![Action::Greater, Action::Not],
+      BinaryOp::Le => vec![Action::StackSwap, Action::Less2, Action::Not],
+      BinaryOp::Ge => vec![Action::Less2, Action::Not],
+      BinaryOp::BitAnd => vec![Action::BitAnd],
+      BinaryOp::BitOr => vec![Action::BitOr],
+      BinaryOp::BitXor => vec![Action::BitXor],
+      BinaryOp::Shl => vec![Action::BitLShift],
+      BinaryOp::Shr => vec![Action::BitRShift],
+      BinaryOp::UShr => vec![Action::BitURShift],
+      BinaryOp::InstanceOf => vec![Action::InstanceOf],
+      BinaryOp::And | BinaryOp::Or => unreachable!("logical operators are compiled to branches"),
+    };
+    for action in actions {
+      self.action(action);
+    }
+  }
+
+  fn assign(&mut self, target: &Expr, value: &Expr, used: bool) -> CodegenResult<()> {
+    match target {
+      Expr::Identifier(name) => match self.scope.registers.get(name).copied() {
+        Some(register) => {
+          self.value(value)?;
+          self.action(Action::StoreRegister(StoreRegister { register }));
+          if !used {
+            self.action(Action::Pop);
+          }
+          Ok(())
+        }
+        None => {
+          self.push(PushValue::String(name.clone()));
+          self.value(value)?;
+          self.store(Action::SetVariable, used)
+        }
+      },
+      Expr::Member(object, key) => {
+        self.value(object)?;
+        self.value(key)?;
+        self.value(value)?;
+        self.store(Action::SetMember, used)
+      }
+      _ => unreachable!("checked by the parser"),
+    }
+  }
+
+  /// Runs the `SetVariable` or `SetMember` action `set`, keeping the stored value if it is `used`.
+  fn store(&mut self, set: Action, used: bool) -> CodegenResult<()> {
+    if !used {
+      self.action(set);
+      return Ok(());
+    }
+    let temp = self.alloc_temp()?;
+    self.store_temp(&temp);
+    self.action(set);
+    self.push_operand(&temp);
+    self.free_temp();
+    Ok(())
+  }
+
+  /// Compiles compound assignments and increments: `modify` replaces the old
+  /// value on top of the stack by the new one. The result is the old value if
+  /// `postfix`, the new one otherwise.
+  fn read_modify_write(
+    &mut self,
+    target: &Expr,
+    used: bool,
+    postfix: bool,
+    modify: impl FnOnce(&mut Self) -> CodegenResult<()>,
+  ) -> CodegenResult<()> {
+    let (set, temps) = match target {
+      Expr::Identifier(name) => match self.scope.registers.get(name).copied() {
+        Some(register) => {
+          self.push(PushValue::Register(register));
+          if used && postfix {
+            self.action(Action::PushDuplicate);
+          }
+          modify(self)?;
+          self.action(Action::StoreRegister(StoreRegister { register }));
+          if postfix || !used {
+            self.action(Action::Pop);
+          }
+          return Ok(());
+        }
+        None => {
+          self.push(PushValue::String(name.clone()));
+          self.get_variable(name);
+          (Action::SetVariable, 0)
+        }
+      },
+      Expr::Member(object, key) => {
+        let (object, object_temp) = self.operand(object)?;
+        let (key, key_temp) = self.operand(key)?;
+        self.push_operand(&object);
+        self.push_operand(&key);
+        self.action(Action::GetMember);
+        (Action::SetMember, usize::from(object_temp) + usize::from(key_temp))
+      }
+      _ => unreachable!("checked by the parser"),
+    };
+    let result = if used { Some(self.alloc_temp()?) } else { None };
+    let store_result = |codegen: &mut Self| {
+      if let Some(temp) = &result {
+        codegen.store_temp(temp);
+      }
+    };
+    if postfix {
+      store_result(self);
+    }
+    modify(self)?;
+    if !postfix {
+      store_result(self);
+    }
+    self.action(set);
+    if let Some(temp) = &result {
+      self.push_operand(temp);
+      self.free_temp();
+    }
+    for _ in 0..temps {
+      self.free_temp();
+    }
+    Ok(())
+  }
+
+  /// Pushes `expr` and returns the operand pushing it again: literals and
+  /// registers are pushed as is, other values are kept in a temporary.
+  /// Returns whether a temporary was allocated.
+  fn operand(&mut self, expr: &Expr) -> CodegenResult<(Operand, bool)> {
+    let value = match expr {
+      Expr::Identifier(name) => self.scope.registers.get(name).map(|&r| PushValue::Register(r)),
+      expr => literal(expr),
+    };
+    if let Some(value) = value {
+      self.push(value.clone());
+      return Ok((Operand::Value(value), false));
+    }
+    self.value(expr)?;
+    let temp = self.alloc_temp()?;
+    self.store_temp(&temp);
+    Ok((temp, true))
+  }
+}
+
+fn literal(expr: &Expr) -> Option<PushValue> {
+  let value = match expr {
+    Expr::Number(value) => {
+      let int = *value as i32;
+      if f64::from(int) == *value && !(*value == 0.0 && value.is_sign_negative()) {
+        PushValue::Sint32(int)
+      } else {
+        PushValue::Float64(*value)
+      }
+    }
+    Expr::String(value) => PushValue::String(value.clone()),
+    Expr::Boolean(value) => PushValue::Boolean(*value),
+    Expr::Null => PushValue::Null,
+    Expr::Undefined => PushValue::Undefined,
+    _ => return None,
+  };
+  Some(value)
+}
+
+/// Names declared by a function body, and names used by its nested functions.
+#[derive(Default)]
+struct Declarations<'s> {
+  /// Variables declared with `var` and by `catch` clauses.
+  variables: Vec<&'s str>,
+  functions: Vec<&'s Function>,
+  /// Names used in nested functions, which cannot be kept in registers.
+  captured: HashSet<&'s str>,
+  uses_this: bool,
+  uses_arguments: bool,
+}
+
+impl<'s> Declarations<'s> {
+  fn of(body: &'s [Stmt]) -> Self {
+    let mut declarations = Self::default();
+    for statement in body {
+      declarations.statement(statement, false);
+    }
+    declarations
+  }
+
+  fn statement(&mut self, statement: &'s Stmt, nested: bool) {
+    match &statement.kind {
+      StmtKind::Var(declarations) => {
+        for (name, value) in declarations {
+          if !nested {
+            self.variables.push(name.as_str());
+          }
+          if let Some(value) = value {
+            self.expr(value, nested);
+          }
+        }
+      }
+      StmtKind::Function(function) => {
+        if !nested {
+          self.functions.push(function);
+        }
+        self.function(function);
+      }
+      StmtKind::Expr(value) | StmtKind::Throw(value) | StmtKind::Return(Some(value)) => self.expr(value, nested),
+      StmtKind::If(condition, then, otherwise) => {
+        self.expr(condition, nested);
+        self.statement(then, nested);
+        if let Some(otherwise) = otherwise {
+          self.statement(otherwise, nested);
+        }
+      }
+      StmtKind::While(condition, body) | StmtKind::DoWhile(body, condition) => {
+        self.expr(condition, nested);
+        self.statement(body, nested);
+      }
+      StmtKind::For(init, condition, update, body) => {
+        if let Some(init) = init {
+          self.statement(init, nested);
+        }
+        for value in condition.iter().chain(update.iter()) {
+          self.expr(value, nested);
+        }
+        self.statement(body, nested);
+      }
+      StmtKind::Try(body, catch, finally) => {
+        self.statements(body, nested);
+        if let Some((name, body)) = catch {
+          if !nested {
+            self.variables.push(name.as_str());
+          }
+          self.statements(body, nested);
+        }
+        if let Some(finally) = finally {
+          self.statements(finally, nested);
+        }
+      }
+      StmtKind::Block(body) => self.statements(body, nested),
+      StmtKind::Return(None) | StmtKind::Break | StmtKind::Continue | StmtKind::Empty => {}
+    }
+  }
+
+  fn statements(&mut self, statements: &'s [Stmt], nested: bool) {
+    for statement in statements {
+      self.statement(statement, nested);
+    }
+  }
+
+  fn function(&mut self, function: &'s Function) {
+    self.statements(&function.body, true);
+  }
+
+  fn expr(&mut self, expr: &'s Expr, nested: bool) {
+    match expr {
+      Expr::Identifier(name) => {
+        if nested {
+          self.captured.insert(name.as_str());
+        } else if name == "this" {
+          self.uses_this = true;
+        } else if name == "arguments" {
+          self.uses_arguments = true;
+        }
+      }
+      Expr::Member(left, right) | Expr::Binary(_, left, right) | Expr::Assign(_, left, right) => {
+        self.expr(left, nested);
+        self.expr(right, nested);
+      }
+      Expr::Call(callee, args) | Expr::New(callee, args) => {
+        self.expr(callee, nested);
+        for arg in args {
+          self.expr(arg, nested);
+        }
+      }
+      Expr::Unary(_, value) | Expr::Update { target: value, .. } => self.expr(value, nested),
+      Expr::Conditional(condition, then, otherwise) => {
+        self.expr(condition, nested);
+        self.expr(then, nested);
+        self.expr(otherwise, nested);
+      }
+      Expr::Array(items) => {
+        for item in items {
+          self.expr(item, nested);
+        }
+      }
+      Expr::Object(properties) => {
+        for (_, value) in properties {
+          self.expr(value, nested);
+        }
+      }
+      Expr::Function(function) => self.function(function),
+      Expr::Number(_) | Expr::String(_) | Expr::Boolean(_) | Expr::Null | Expr::Undefined => {}
+    }
+  }
+}
@@ -0,0 +1,190 @@
+//! Tokenizer of the ActionScript source.
+
+use super::CompileError;
+use std::fmt;
+
+/// Punctuators, longest first so the first match is the longest one.
+const PUNCTUATORS: &[&str] = &[
+  ">>>", "===", "!==", "==", "!=", "<=", ">=", "&&", "||", "++", "--", "+=", "-=", "*=", "/=", "%=", "<<", ">>", "{",
+  "}", "(", ")", "[", "]", ";", ",", ".", ":", "?", "=", "+", "-", "*", "/", "%", "<", ">", "!", "~", "&", "|", "^",
+];
+
+#[derive(Clone, Debug, PartialEq)]
+pub(super) enum TokenKind {
+  Ident(String),
+  Str(String),
+  Number(f64),
+  Punct(&'static str),
+  Eof,
+}
+
+impl fmt::Display for TokenKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      TokenKind::Ident(v) => write!(f, "`{}`", v),
+      TokenKind::Str(v) => write!(f, "{:?}", v),
+      TokenKind::Number(v) => write!(f, "`{}`", v),
+      TokenKind::Punct(p) => write!(f, "`{}`", p),
+      TokenKind::Eof => f.write_str("end of input"),
+    }
+  }
+}
+
+#[derive(Clone, Debug)]
+pub(super) struct Token {
+  pub kind: TokenKind,
+  pub line: usize,
+  pub column: usize,
+}
+
+pub(super) fn tokenize(source: &str) -> Result<Vec<Token>, CompileError> {
+  let chars: Vec<char> = source.chars().collect();
+  let mut tokens: Vec<Token> = Vec::new();
+  let (mut pos, mut line, mut column) = (0, 1, 1);
+  let advance = |pos: &mut usize, line: &mut usize, column: &mut usize, count: usize| {
+    for _ in 0..count {
+      if chars[*pos] == '\n' {
+        *line += 1;
+        *column = 1;
+      } else {
+        *column += 1;
+      }
+      *pos += 1;
+    }
+  };
+  while pos < chars.len() {
+    let c = chars[pos];
+    let next = chars.get(pos + 1).copied();
+    let (start_line, start_column) = (line, column);
+    let error = |message: String| CompileError {
+      line: start_line,
+      column: start_column,
+      message,
+    };
+    if c.is_whitespace() {
+      advance(&mut pos, &mut line, &mut column, 1);
+      continue;
+    }
+    if c == '/' && next == Some('/') {
+      while pos < chars.len() && chars[pos] != '\n' {
+        advance(&mut pos, &mut line, &mut column, 1);
+      }
+      continue;
+    }
+    if c == '/' && next == Some('*') {
+      advance(&mut pos, &mut line, &mut column, 2);
+      loop {
+        if pos >= chars.len() {
+          return Err(error(String::from("unterminated comment")));
+        }
+        if chars[pos] == '*' && chars.get(pos + 1) == Some(&'/') {
+          advance(&mut pos, &mut line, &mut column, 2);
+          break;
+        }
+        advance(&mut pos, &mut line, &mut column, 1);
+      }
+      continue;
+    }
+    let kind = if c == '"' || c == '\'' {
+      let quote = c;
+      advance(&mut pos, &mut line, &mut column, 1);
+      let mut value = String::new();
+      loop {
+        let c = match chars.get(pos) {
+          None | Some('\n') => return Err(error(String::from("unterminated string"))),
+          Some(&c) => c,
+        };
+        advance(&mut pos, &mut line, &mut column, 1);
+        if c == quote {
+          break;
+        }
+        if c != '\\' {
+          value.push(c);
+          continue;
+        }
+        let escaped = match chars.get(pos) {
+          Some(&c) => c,
+          None => return Err(error(String::from("unterminated string"))),
+        };
+        advance(&mut pos, &mut line, &mut column, 1);
+        match escaped {
+          'n' => value.push('\n'),
+          'r' => value.push('\r'),
+          't' => value.push('\t'),
+          'b' => value.push('\u{8}'),
+          'f' => value.push('\u{c}'),
+          '0' => value.push('\0'),
+          'x' | 'u' => {
+            let len = if escaped == 'x' { 2 } else { 4 };
+            let hex: String = chars.iter().skip(pos).take(len).collect();
+            match u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32) {
+              Some(c) if hex.len() == len => value.push(c),
+              _ => return Err(error(format!("invalid escape: \\{}{}", escaped, hex))),
+            }
+            advance(&mut pos, &mut line, &mut column, len);
+          }
+          c => value.push(c),
+        }
+      }
+      TokenKind::Str(value)
+    } else if c.is_ascii_digit() || (c == '.' && next.map_or(false, |c| c.is_ascii_digit())) {
+      let start = pos;
+      let mut text = String::new();
+      if c == '0' && matches!(next, Some('x' | 'X')) {
+        advance(&mut pos, &mut line, &mut column, 2);
+        while pos < chars.len() && chars[pos].is_ascii_hexdigit() {
+          text.push(chars[pos]);
+          advance(&mut pos, &mut line, &mut column, 1);
+        }
+        match u64::from_str_radix(&text, 16) {
+          Ok(value) => TokenKind::Number(value as f64),
+          Err(_) => return Err(error(String::from("invalid hexadecimal number"))),
+        }
+      } else {
+        while pos < chars.len() {
+          let c = chars[pos];
+          let exponent_sign = (c == '-' || c == '+') && pos > start && matches!(chars[pos - 1], 'e' | 'E');
+          if c.is_ascii_alphanumeric() || c == '.' || exponent_sign {
+            text.push(c);
+            advance(&mut pos, &mut line, &mut column, 1);
+          } else {
+            break;
+          }
+        }
+        match text.parse::<f64>() {
+          Ok(value) => TokenKind::Number(value),
+          Err(_) => return Err(error(format!("invalid number: {}", text))),
+        }
+      }
+    } else if c.is_alphabetic() || c == '_' || c == '$' {
+      let mut value = String::new();
+      while pos < chars.len() && (chars[pos].is_alphanumeric() || chars[pos] == '_' || chars[pos] == '$') {
+        value.push(chars[pos]);
+        advance(&mut pos, &mut line, &mut column, 1);
+      }
+      TokenKind::Ident(value)
+    } else {
+      let punct = PUNCTUATORS
+        .iter()
+        .find(|p| p.chars().enumerate().all(|(i, c)| chars.get(pos + i) == Some(&c)));
+      match punct {
+        Some(punct) => {
+          advance(&mut pos, &mut line, &mut column, punct.len());
+          TokenKind::Punct(punct)
+        }
+        None => return Err(error(format!("unexpected character: {:?}", c))),
+      }
+    };
+    tokens.push(Token {
+      kind,
+      line: start_line,
+      column: start_column,
+    });
+  }
+  tokens.push(Token {
+    kind: TokenKind::Eof,
+    line,
+    column,
+  });
+  Ok(tokens)
+}
@@ -0,0 +1,493 @@
+//! Compiler of a small subset of ActionScript 1 and 2 to control flow graphs.
+//!
+//! ```
+//! use avm1_emitter::compiler::compile;
+//!
+//! let cfg = compile("function add(a, b) { return a + b; } trace(add(1, 2));").unwrap();
+//! let bytes = avm1_emitter::emit_cfg(&cfg).unwrap();
+//! ```
+//!
+//! The supported subset covers:
+//! - `var` declarations (with optional ActionScript 2 type annotations) and assignments, including
+//!   compound assignments (`+=`, `-=`, `*=`, `/=`, `%=`) and increments;
+//! - arithmetic, comparison, bitwise, logical and conditional operators, `typeof`, `delete` and
+//!   `instanceof`;
+//! - literals: numbers, strings, booleans, `null`, `undefined`, arrays and objects;
+//! - function calls, member access (`a.b` and `a[b]`) and `new`;
+//! - `if`, `while`, `do ... while` and `for` statements, with `break` and `continue`;
+//! - function declarations (hoisted) and expressions, `return`;
+//! - `try`/`catch`/`finally` and `throw`.
+//!
+//! `trace(value)` compiles to the `Trace` action. From SWF 7, functions are
+//! compiled to `DefineFunction2` with their parameters and local variables in
+//! registers, unless they are used by nested functions. Older versions use
+//! `DefineFunction` and named variables. As their functions share the
+//! registers of their caller, temporary values are also kept in variables,
+//! named `__t0`, `__t1`, ... (local variables in functions).
+
+mod codegen;
+mod lexer;
+mod parser;
+
+use avm1_types::cfg::Cfg;
+use std::fmt;
+use std::io;
+
+/// Error in the ActionScript source, with its 1-based position.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CompileError {
+  pub line: usize,
+  pub column: usize,
+  pub message: String,
+}
+
+impl fmt::Display for CompileError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}:{}: {}", self.line, self.column, self.message)
+  }
+}
+
+impl std::error::Error for CompileError {}
+
+/// Options of the compiler.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CompileOptions {
+  /// SWF version of the player running the code, selects the available actions.
+  pub swf_version: u8,
+}
+
+impl Default for CompileOptions {
+  fn default() -> Self {
+    Self { swf_version: 8 }
+  }
+}
+
+/// Compiles the ActionScript source into a control flow graph, with the default options.
+pub fn compile(source: &str) -> Result<Cfg, CompileError> {
+  compile_with_options(source, &CompileOptions::default())
+}
+
+/// Compiles the ActionScript source into a control flow graph.
+pub fn compile_with_options(source: &str, options: &CompileOptions) -> Result<Cfg, CompileError> {
+  let tokens = lexer::tokenize(source)?;
+  let program = parser::Parser::new(tokens, options).parse_program()?;
+  codegen::compile_program(&program, options)
+}
+
+/// Compiles the ActionScript source and emits it with `emit_cfg`.
+///
+/// Errors in the source are returned as `InvalidData` errors wrapping a [`CompileError`].
+pub fn emit_source(source: &str, options: &CompileOptions) -> io::Result<Vec<u8>> {
+  let cfg = compile_with_options(source, options).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+  crate::emit_cfg(&cfg)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm::emit_asm;
+  use crate::emit_cfg;
+  use crate::equivalence::hard_cfg_equivalent;
+  use avm1_parser::parse_cfg;
+  use avm1_types::cfg::{Action, CfgBlock, CfgFlow, CfgLabel, Simple};
+  use avm1_types::PushValue;
+  use std::collections::HashMap;
+  use vec1::Vec1;
+
+  /// Compiles and emits `source`, then checks that the CFG parsed back by
+  /// `avm1-parser` is equivalent to the compiled one.
+  fn compile_round_trip(source: &str, options: &CompileOptions) -> (Cfg, Vec<u8>) {
+    let mut cfg = compile_with_options(source, options).unwrap();
+    let bytes = emit_cfg(&cfg).unwrap();
+    let mut parsed = parse_cfg(&bytes);
+    normalize(&mut cfg);
+    normalize(&mut parsed);
+    assert!(
+      hard_cfg_equivalent(&cfg, &parsed),
+      "re-parsed CFG must be equivalent:\n{:#?}\n{:#?}",
+      cfg,
+      parsed
+    );
+    (parsed, bytes)
+  }
+
+  /// Splits the blocks the way `avm1-parser` does: jumps through empty blocks
+  /// are resolved and blocks only reached by falling through are merged into
+  /// their predecessor.
+  fn normalize(cfg: &mut Cfg) {
+    let mut forward: HashMap<CfgLabel, Option<CfgLabel>> = HashMap::new();
+    for_each_block(cfg, &mut |block| match &block.flow {
+      CfgFlow::Simple(flow) if block.actions.is_empty() => {
+        forward.insert(block.label.clone(), flow.next.clone());
+      }
+      _ => {}
+    });
+    let mut refs: HashMap<CfgLabel, usize> = HashMap::new();
+    for_each_target(cfg, &mut |target| {
+      for _ in 0..forward.len() {
+        match target.as_ref().and_then(|label| forward.get(label)) {
+          Some(next) => *target = next.clone(),
+          None => break,
+        }
+      }
+      if let Some(label) = target {
+        *refs.entry(label.clone()).or_default() += 1;
+      }
+    });
+    compact(cfg, &refs);
+  }
+
+  fn compact(cfg: &mut Cfg, refs: &HashMap<CfgLabel, usize>) {
+    let mut blocks: Vec<CfgBlock> = Vec::new();
+    for mut block in cfg.blocks.iter().cloned() {
+      let block_refs = refs.get(&block.label).copied().unwrap_or(0);
+      let is_empty = block.actions.is_empty() && matches!(block.flow, CfgFlow::Simple(_));
+      if !blocks.is_empty() && is_empty && block_refs == 0 {
+        continue;
+      }
+      for action in block.actions.iter_mut() {
+        match action {
+          Action::DefineFunction(f) => normalize(&mut f.body),
+          Action::DefineFunction2(f) => normalize(&mut f.body),
+          _ => {}
+        }
+      }
+      match &mut block.flow {
+        CfgFlow::Try(flow) => {
+          compact(&mut flow.r#try, refs);
+          if let Some(catch) = &mut flow.catch {
+            compact(&mut catch.body, refs);
+          }
+          if let Some(finally) = &mut flow.finally {
+            compact(finally, refs);
+          }
+        }
+        CfgFlow::With(flow) => compact(&mut flow.body, refs),
+        _ => {}
+      }
+      match blocks.last_mut() {
+        Some(previous)
+          if block_refs == 1
+            && previous.flow
+              == CfgFlow::Simple(Simple {
+                next: Some(block.label.clone()),
+              }) =>
+        {
+          previous.actions.extend(block.actions);
+          previous.flow = block.flow;
+        }
+        _ => blocks.push(block),
+      }
+    }
+    cfg.blocks = Vec1::try_from_vec(blocks).unwrap();
+  }
+
+  fn for_each_block(cfg: &Cfg, f: &mut impl FnMut(&CfgBlock)) {
+    for block in cfg.blocks.iter() {
+      f(block);
+      match &block.flow {
+        CfgFlow::Try(flow) => {
+          for_each_block(&flow.r#try, f);
+          if let Some(catch) = &flow.catch {
+            for_each_block(&catch.body, f);
+          }
+          if let Some(finally) = &flow.finally {
+            for_each_block(finally, f);
+          }
+        }
+        CfgFlow::With(flow) => for_each_block(&flow.body, f),
+        _ => {}
+      }
+    }
+  }
+
+  fn for_each_target(cfg: &mut Cfg, f: &mut impl FnMut(&mut Option<CfgLabel>)) {
+    for block in cfg.blocks.iter_mut() {
+      match &mut block.flow {
+        CfgFlow::If(flow) => {
+          f(&mut flow.true_target);
+          f(&mut flow.false_target);
+        }
+        CfgFlow::Simple(flow) => f(&mut flow.next),
+        CfgFlow::WaitForFrame(flow) => {
+          f(&mut flow.ready_target);
+          f(&mut flow.loading_target);
+        }
+        CfgFlow::WaitForFrame2(flow) => {
+          f(&mut flow.ready_target);
+          f(&mut flow.loading_target);
+        }
+        CfgFlow::Try(flow) => {
+          for_each_target(&mut flow.r#try, f);
+          if let Some(catch) = &mut flow.catch {
+            for_each_target(&mut catch.body, f);
+          }
+          if let Some(finally) = &mut flow.finally {
+            for_each_target(finally, f);
+          }
+        }
+        CfgFlow::With(flow) => for_each_target(&mut flow.body, f),
+        CfgFlow::Error(_) | CfgFlow::Return | CfgFlow::Throw => {}
+      }
+    }
+  }
+
+  fn assert_compiles_to(source: &str, asm: &str) {
+    let (_, bytes) = compile_round_trip(source, &CompileOptions::default());
+    assert_eq!(bytes, emit_asm(asm).unwrap());
+  }
+
+  #[test]
+  fn test_compile_hello_world() {
+    let (_, bytes) = compile_round_trip(r#"trace("Hello, World!");"#, &CompileOptions::default());
+    let expected = std::fs::read("../tests/avm1/local/hello-world/main.avm1").unwrap();
+    assert_eq!(bytes, expected);
+  }
+
+  #[test]
+  fn test_compile_expressions() {
+    assert_compiles_to(
+      "var x = 1 + 2 * 3; x += 1; o.count++; trace(-x > 0 ? 'a' : o[\"b\"].c(x, 1.5));",
+      r#"
+        Push "x", 1, 2, 3
+        Multiply
+        Add2
+        SetVariable
+        Push "x", "x"
+        GetVariable
+        Push 1
+        Add2
+        SetVariable
+        Push "o"
+        GetVariable
+        StoreRegister r:0
+        Push "count", r:0, "count"
+        GetMember
+        Increment
+        SetMember
+        Push "x"
+        GetVariable
+        Push -1
+        Multiply
+        Push 0
+        Greater
+        Not
+        If other
+        Push "a"
+        Jump done
+      other:
+        Push 1.5, "x"
+        GetVariable
+        Push 2, "o"
+        GetVariable
+        Push "b"
+        GetMember
+        Push "c"
+        CallMethod
+      done:
+        Trace
+      "#,
+    );
+  }
+
+  #[test]
+  fn test_compile_control_flow() {
+    assert_compiles_to(
+      "for (var i = 0; i < 3; i++) { if (i == 1) continue; trace(i); } while (ok && !done) { break; }",
+      r#"
+        Push "i", 0
+        SetVariable
+      loop:
+        Push "i"
+        GetVariable
+        Push 3
+        Less2
+        Not
+        If loop_end
+        Push "i"
+        GetVariable
+        Push 1
+        Equals2
+        Not
+        If print
+        Jump update
+      print:
+        Push "i"
+        GetVariable
+        Trace
+      update:
+        Push "i", "i"
+        GetVariable
+        Increment
+        SetVariable
+        Jump loop
+      loop_end:
+        Push "ok"
+        GetVariable
+        PushDuplicate
+        Not
+        If check
+        Pop
+        Push "done"
+        GetVariable
+        Not
+      check:
+        Not
+        If end
+      "#,
+    );
+  }
+
+  #[test]
+  fn test_compile_define_function2() {
+    assert_compiles_to(
+      "function add(a, b) { var c = a + b; return c; } var f = function (n) { return function () { return n; }; };",
+      r#"
+        function2 add(r:1=a, r:2=b) registers=4 flags=SUPPRESS_THIS|SUPPRESS_ARGUMENTS|SUPPRESS_SUPER {
+          Push r:1, r:2
+          Add2
+          StoreRegister r:3
+          Pop
+          Push r:3
+          Return
+        }
+        Push "f"
+        function2 (n) registers=1 flags=SUPPRESS_THIS|SUPPRESS_ARGUMENTS|SUPPRESS_SUPER {
+          function2 () registers=1 flags=SUPPRESS_THIS|SUPPRESS_ARGUMENTS|SUPPRESS_SUPER {
+            Push "n"
+            GetVariable
+            Return
+          }
+          Return
+        }
+        SetVariable
+      "#,
+    );
+  }
+
+  #[test]
+  fn test_compile_round_trip() {
+    let source = r#"
+      function Point(x:Number, y:Number) {
+        this.x = x;
+        this.y = y;
+      }
+      Point.prototype.length = function ():Number {
+        return Math.sqrt(this.x * this.x + this.y * this.y);
+      };
+      function sum(items) {
+        var total = 0;
+        for (var i = 0; i < items.length; i++) {
+          total += items[i];
+        }
+        return total;
+      }
+      var p = new Point(3, 4);
+      var values = [1, 2, p.length()];
+      var config = {name: "test", size: values.length};
+      try {
+        if (typeof p != "object" || !(p instanceof Point)) {
+          throw new Error("unexpected");
+        }
+        var n = 0;
+        do {
+          n = n + 1 | 0;
+        } while (n < 10);
+        trace(sum(values) + n);
+      } catch (e) {
+        trace(e.message);
+      } finally {
+        delete config.size;
+      }
+    "#;
+    let (cfg, _) = compile_round_trip(source, &CompileOptions::default());
+    let functions: Vec<&str> = cfg
+      .blocks
+      .iter()
+      .flat_map(|block| block.actions.iter())
+      .filter_map(|action| match action {
+        Action::DefineFunction2(f) => Some(f.name.as_str()),
+        _ => None,
+      })
+      .collect();
+    assert_eq!(functions, ["Point", "sum", ""]);
+    assert!(cfg.blocks.iter().any(|block| matches!(block.flow, CfgFlow::Try(_))));
+  }
+
+  #[test]
+  fn test_compile_swf6() {
+    let options = CompileOptions { swf_version: 6 };
+    let (cfg, _) = compile_round_trip(
+      "function f(a) { var b = a; try { b.c(); } catch (e) { return e; } }",
+      &options,
+    );
+    let function = match cfg.blocks.first().actions.first() {
+      Some(Action::DefineFunction(f)) => f,
+      action => panic!("expected DefineFunction, found {:?}", action),
+    };
+    assert_eq!(function.parameters, ["a"]);
+  }
+
+  #[test]
+  fn test_compile_swf6_temporaries() {
+    // Functions share the registers of their caller: `b()` must not overwrite
+    // the object returned by `a()`.
+    let options = CompileOptions { swf_version: 6 };
+    let source = r#"function b() { var o = {}; o.x = (o.y = 1); return "k"; } a()[b()] += 1;"#;
+    let (cfg, _) = compile_round_trip(source, &options);
+    let mut uses_registers = false;
+    for_each_block(&cfg, &mut |block| {
+      uses_registers |= block.actions.iter().any(|action| match action {
+        Action::StoreRegister(_) => true,
+        Action::Push(push) => push.values.iter().any(|v| matches!(v, PushValue::Register(_))),
+        _ => false,
+      });
+    });
+    assert!(!uses_registers);
+
+    let (_, bytes) = compile_round_trip("a()[b()] += 1;", &options);
+    let expected = r#"
+      Push 0, "a"
+      CallFunction
+      PushDuplicate
+      Push "__t0"
+      StackSwap
+      SetVariable
+      Push 0, "b"
+      CallFunction
+      PushDuplicate
+      Push "__t1"
+      StackSwap
+      SetVariable
+      Push "__t0"
+      GetVariable
+      Push "__t1"
+      GetVariable
+      GetMember
+      Push 1
+      Add2
+      SetMember
+    "#;
+    assert_eq!(bytes, emit_asm(expected).unwrap());
+  }
+
+  #[test]
+  fn test_compile_errors() {
+    let error = |source: &str| compile(source).unwrap_err().to_string();
+    assert_eq!(error("var x = ;"), "1:9: expected an expression, found `;`");
+    assert_eq!(error("x = 1;\n1 = x;"), "2:1: invalid assignment target");
+    assert_eq!(error("if (a) {\n  break;\n}"), "2:3: `break` outside of a loop");
+    assert_eq!(
+      error("while (a) { function f() { continue; } }"),
+      "1:28: `continue` outside of a loop"
+    );
+    assert_eq!(error("trace('a);"), "1:7: unterminated string");
+    assert_eq!(error("f(a b)"), "1:5: expected `,`, found `b`");
+    let options = CompileOptions { swf_version: 5 };
+    assert_eq!(
+      compile_with_options("a === b", &options).unwrap_err().to_string(),
+      "1:3: `===` requires SWF 6"
+    );
+  }
+}
@@ -0,0 +1,764 @@
+//! Recursive descent parser building the syntax tree of the source.
+
+use super::lexer::{Token, TokenKind};
+use super::{CompileError, CompileOptions};
+
+/// Position of a syntax node, 1-based.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) struct Pos {
+  pub line: usize,
+  pub column: usize,
+}
+
+impl Pos {
+  pub fn error(self, message: String) -> CompileError {
+    CompileError {
+      line: self.line,
+      column: self.column,
+      message,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub(super) struct Stmt {
+  pub kind: StmtKind,
+  pub pos: Pos,
+}
+
+#[derive(Debug)]
+pub(super) enum StmtKind {
+  Var(Vec<(String, Option<Expr>)>),
+  Function(Box<Function>),
+  Expr(Expr),
+  If(Expr, Box<Stmt>, Option<Box<Stmt>>),
+  While(Expr, Box<Stmt>),
+  DoWhile(Box<Stmt>, Expr),
+  For(Option<Box<Stmt>>, Option<Expr>, Option<Expr>, Box<Stmt>),
+  Break,
+  Continue,
+  Return(Option<Expr>),
+  Throw(Expr),
+  Try(Vec<Stmt>, Option<(String, Vec<Stmt>)>, Option<Vec<Stmt>>),
+  Block(Vec<Stmt>),
+  Empty,
+}
+
+#[derive(Debug)]
+pub(super) struct Function {
+  /// Empty if anonymous.
+  pub name: String,
+  pub params: Vec<String>,
+  pub body: Vec<Stmt>,
+  pub pos: Pos,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum UnaryOp {
+  Neg,
+  Plus,
+  Not,
+  BitNot,
+  TypeOf,
+  Delete,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum BinaryOp {
+  Add,
+  Sub,
+  Mul,
+  Div,
+  Mod,
+  Eq,
+  Ne,
+  StrictEq,
+  StrictNe,
+  Lt,
+  Gt,
+  Le,
+  Ge,
+  BitAnd,
+  BitOr,
+  BitXor,
+  Shl,
+  Shr,
+  UShr,
+  InstanceOf,
+  And,
+  Or,
+}
+
+#[derive(Debug)]
+pub(super) enum Expr {
+  Number(f64),
+  String(String),
+  Boolean(bool),
+  Null,
+  Undefined,
+  /// Variable, including `this` and `arguments`.
+  Identifier(String),
+  /// `object.name` or `object[key]`.
+  Member(Box<Expr>, Box<Expr>),
+  Call(Box<Expr>, Vec<Expr>),
+  New(Box<Expr>, Vec<Expr>),
+  Unary(UnaryOp, Box<Expr>),
+  Binary(BinaryOp, Box<Expr>, Box<Expr>),
+  Conditional(Box<Expr>, Box<Expr>, Box<Expr>),
+  /// Assignment, with the operator of compound assignments.
+  Assign(Option<BinaryOp>, Box<Expr>, Box<Expr>),
+  Update {
+    increment: bool,
+    prefix: bool,
+    target: Box<Expr>,
+  },
+  Array(Vec<Expr>),
+  Object(Vec<(String, Expr)>),
+  Function(Box<Function>),
+}
+
+const KEYWORDS: &[&str] = &[
+  "break",
+  "catch",
+  "continue",
+  "delete",
+  "do",
+  "else",
+  "false",
+  "finally",
+  "for",
+  "function",
+  "if",
+  "instanceof",
+  "new",
+  "null",
+  "return",
+  "this",
+  "throw",
+  "true",
+  "try",
+  "typeof",
+  "undefined",
+  "var",
+  "while",
+];
+
+/// Binding power of binary operators, higher binds tighter.
+fn binary_op(punct: &str) -> Option<(u8, BinaryOp)> {
+  let op = match punct {
+    "||" => (1, BinaryOp::Or),
+    "&&" => (2, BinaryOp::And),
+    "|" => (3, BinaryOp::BitOr),
+    "^" => (4, BinaryOp::BitXor),
+    "&" => (5, BinaryOp::BitAnd),
+    "==" => (6, BinaryOp::Eq),
+    "!=" => (6, BinaryOp::Ne),
+    "===" => (6, BinaryOp::StrictEq),
+    "!==" => (6, BinaryOp::StrictNe),
+    "<" => (7, BinaryOp::Lt),
+    ">" => (7, BinaryOp::Gt),
+    "<=" => (7, BinaryOp::Le),
+    ">=" => (7, BinaryOp::Ge),
+    "instanceof" => (7, BinaryOp::InstanceOf),
+    "<<" => (8, BinaryOp::Shl),
+    ">>" => (8, BinaryOp::Shr),
+    ">>>" => (8, BinaryOp::UShr),
+    "+" => (9, BinaryOp::Add),
+    "-" => (9, BinaryOp::Sub),
+    "*" => (10, BinaryOp::Mul),
+    "/" => (10, BinaryOp::Div),
+    "%" => (10, BinaryOp::Mod),
+    _ => return None,
+  };
+  Some(op)
+}
+
+fn assign_op(punct: &str) -> Option<Option<BinaryOp>> {
+  let op = match punct {
+    "=" => None,
+    "+=" => Some(BinaryOp::Add),
+    "-=" => Some(BinaryOp::Sub),
+    "*=" => Some(BinaryOp::Mul),
+    "/=" => Some(BinaryOp::Div),
+    "%=" => Some(BinaryOp::Mod),
+    _ => return None,
+  };
+  Some(op)
+}
+
+pub(super) struct Parser<'a> {
+  tokens: Vec<Token>,
+  pos: usize,
+  options: &'a CompileOptions,
+  /// Number of loops enclosing the current statement, in the current function.
+  loop_depth: usize,
+}
+
+type ParseResult<T> = Result<T, CompileError>;
+
+impl<'a> Parser<'a> {
+  pub fn new(tokens: Vec<Token>, options: &'a CompileOptions) -> Self {
+    Self {
+      tokens,
+      pos: 0,
+      options,
+      loop_depth: 0,
+    }
+  }
+
+  pub fn parse_program(&mut self) -> ParseResult<Vec<Stmt>> {
+    let mut body = Vec::new();
+    while self.peek().kind != TokenKind::Eof {
+      body.push(self.parse_statement()?);
+    }
+    Ok(body)
+  }
+
+  fn peek(&self) -> &Token {
+    &self.tokens[self.pos]
+  }
+
+  fn peek_pos(&self) -> Pos {
+    let token = self.peek();
+    Pos {
+      line: token.line,
+      column: token.column,
+    }
+  }
+
+  fn next(&mut self) -> Token {
+    let token = self.tokens[self.pos].clone();
+    if self.pos + 1 < self.tokens.len() {
+      self.pos += 1;
+    }
+    token
+  }
+
+  fn unexpected<T>(&self, expected: &str) -> ParseResult<T> {
+    Err(
+      self
+        .peek_pos()
+        .error(format!("expected {}, found {}", expected, self.peek().kind)),
+    )
+  }
+
+  fn is_punct(&self, punct: &str) -> bool {
+    matches!(self.peek().kind, TokenKind::Punct(p) if p == punct)
+  }
+
+  fn eat_punct(&mut self, punct: &str) -> bool {
+    let found = self.is_punct(punct);
+    if found {
+      self.next();
+    }
+    found
+  }
+
+  fn expect_punct(&mut self, punct: &str) -> ParseResult<()> {
+    if self.eat_punct(punct) {
+      Ok(())
+    } else {
+      self.unexpected(&format!("`{}`", punct))
+    }
+  }
+
+  fn is_keyword(&self, keyword: &str) -> bool {
+    matches!(&self.peek().kind, TokenKind::Ident(v) if v == keyword)
+  }
+
+  fn eat_keyword(&mut self, keyword: &str) -> bool {
+    let found = self.is_keyword(keyword);
+    if found {
+      self.next();
+    }
+    found
+  }
+
+  fn expect_keyword(&mut self, keyword: &str) -> ParseResult<()> {
+    if self.eat_keyword(keyword) {
+      Ok(())
+    } else {
+      self.unexpected(&format!("`{}`", keyword))
+    }
+  }
+
+  /// Parses an identifier which is not a keyword.
+  fn expect_identifier(&mut self) -> ParseResult<String> {
+    match &self.peek().kind {
+      TokenKind::Ident(v) if !KEYWORDS.contains(&v.as_str()) => {
+        let v = v.clone();
+        self.next();
+        Ok(v)
+      }
+      _ => self.unexpected("an identifier"),
+    }
+  }
+
+  /// Parses any identifier, keywords included (e.g. after a `.`).
+  fn expect_name(&mut self) -> ParseResult<String> {
+    match &self.peek().kind {
+      TokenKind::Ident(v) => {
+        let v = v.clone();
+        self.next();
+        Ok(v)
+      }
+      _ => self.unexpected("a name"),
+    }
+  }
+
+  /// Skips the ActionScript 2 type annotation (`: Type`), if any.
+  fn skip_type_annotation(&mut self) -> ParseResult<()> {
+    if self.eat_punct(":") {
+      self.expect_name()?;
+      while self.eat_punct(".") {
+        self.expect_name()?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Semicolons are optional at the end of statements.
+  fn end_statement(&mut self) {
+    self.eat_punct(";");
+  }
+
+  fn parse_statement(&mut self) -> ParseResult<Stmt> {
+    let pos = self.peek_pos();
+    let kind = if self.eat_punct("{") {
+      StmtKind::Block(self.parse_block_rest()?)
+    } else if self.eat_punct(";") {
+      StmtKind::Empty
+    } else if self.eat_keyword("var") {
+      let var = self.parse_var()?;
+      self.end_statement();
+      var
+    } else if self.is_keyword("function") {
+      let function = self.parse_function(true)?;
+      StmtKind::Function(Box::new(function))
+    } else if self.eat_keyword("if") {
+      self.expect_punct("(")?;
+      let condition = self.parse_expression()?;
+      self.expect_punct(")")?;
+      let then = self.parse_statement()?;
+      let otherwise = if self.eat_keyword("else") {
+        Some(Box::new(self.parse_statement()?))
+      } else {
+        None
+      };
+      StmtKind::If(condition, Box::new(then), otherwise)
+    } else if self.eat_keyword("while") {
+      self.expect_punct("(")?;
+      let condition = self.parse_expression()?;
+      self.expect_punct(")")?;
+      let body = self.parse_loop_body()?;
+      StmtKind::While(condition, Box::new(body))
+    } else if self.eat_keyword("do") {
+      let body = self.parse_loop_body()?;
+      self.expect_keyword("while")?;
+      self.expect_punct("(")?;
+      let condition = self.parse_expression()?;
+      self.expect_punct(")")?;
+      self.end_statement();
+      StmtKind::DoWhile(Box::new(body), condition)
+    } else if self.eat_keyword("for") {
+      self.parse_for()?
+    } else if self.is_keyword("break") || self.is_keyword("continue") {
+      let is_break = self.is_keyword("break");
+      if self.loop_depth == 0 {
+        let keyword = if is_break { "break" } else { "continue" };
+        return Err(pos.error(format!("`{}` outside of a loop", keyword)));
+      }
+      self.next();
+      self.end_statement();
+      if is_break {
+        StmtKind::Break
+      } else {
+        StmtKind::Continue
+      }
+    } else if self.eat_keyword("return") {
+      let value = if self.is_punct(";") || self.is_punct("}") || self.peek().kind == TokenKind::Eof {
+        None
+      } else {
+        Some(self.parse_expression()?)
+      };
+      self.end_statement();
+      StmtKind::Return(value)
+    } else if self.eat_keyword("throw") {
+      let value = self.parse_expression()?;
+      self.end_statement();
+      StmtKind::Throw(value)
+    } else if self.eat_keyword("try") {
+      self.parse_try()?
+    } else {
+      let expr = self.parse_expression()?;
+      self.end_statement();
+      StmtKind::Expr(expr)
+    };
+    Ok(Stmt { kind, pos })
+  }
+
+  /// Parses the statements of a block, after its `{`.
+  fn parse_block_rest(&mut self) -> ParseResult<Vec<Stmt>> {
+    let mut body = Vec::new();
+    while !self.eat_punct("}") {
+      if self.peek().kind == TokenKind::Eof {
+        return self.unexpected("`}`");
+      }
+      body.push(self.parse_statement()?);
+    }
+    Ok(body)
+  }
+
+  fn parse_block(&mut self) -> ParseResult<Vec<Stmt>> {
+    self.expect_punct("{")?;
+    self.parse_block_rest()
+  }
+
+  fn parse_loop_body(&mut self) -> ParseResult<Stmt> {
+    self.loop_depth += 1;
+    let body = self.parse_statement();
+    self.loop_depth -= 1;
+    body
+  }
+
+  /// Parses the declarations of a `var` statement, after the keyword.
+  fn parse_var(&mut self) -> ParseResult<StmtKind> {
+    let mut declarations = Vec::new();
+    loop {
+      let name = self.expect_identifier()?;
+      self.skip_type_annotation()?;
+      let value = if self.eat_punct("=") {
+        Some(self.parse_assignment()?)
+      } else {
+        None
+      };
+      declarations.push((name, value));
+      if !self.eat_punct(",") {
+        break;
+      }
+    }
+    Ok(StmtKind::Var(declarations))
+  }
+
+  fn parse_for(&mut self) -> ParseResult<StmtKind> {
+    self.expect_punct("(")?;
+    let init = if self.is_punct(";") {
+      None
+    } else {
+      let pos = self.peek_pos();
+      let kind = if self.eat_keyword("var") {
+        self.parse_var()?
+      } else {
+        StmtKind::Expr(self.parse_expression()?)
+      };
+      Some(Box::new(Stmt { kind, pos }))
+    };
+    self.expect_punct(";")?;
+    let condition = if self.is_punct(";") {
+      None
+    } else {
+      Some(self.parse_expression()?)
+    };
+    self.expect_punct(";")?;
+    let update = if self.is_punct(")") {
+      None
+    } else {
+      Some(self.parse_expression()?)
+    };
+    self.expect_punct(")")?;
+    let body = self.parse_loop_body()?;
+    Ok(StmtKind::For(init, condition, update, Box::new(body)))
+  }
+
+  fn parse_try(&mut self) -> ParseResult<StmtKind> {
+    let body = self.parse_block()?;
+    let catch = if self.eat_keyword("catch") {
+      self.expect_punct("(")?;
+      let name = self.expect_identifier()?;
+      self.skip_type_annotation()?;
+      self.expect_punct(")")?;
+      Some((name, self.parse_block()?))
+    } else {
+      None
+    };
+    let finally = if self.eat_keyword("finally") {
+      Some(self.parse_block()?)
+    } else {
+      None
+    };
+    if catch.is_none() && finally.is_none() {
+      return self.unexpected("`catch` or `finally`");
+    }
+    Ok(StmtKind::Try(body, catch, finally))
+  }
+
+  /// Parses a function, the name is required for declarations.
+  fn parse_function(&mut self, declaration: bool) -> ParseResult<Function> {
+    let pos = self.peek_pos();
+    self.expect_keyword("function")?;
+    let name = if declaration || !self.is_punct("(") {
+      self.expect_identifier()?
+    } else {
+      String::new()
+    };
+    self.expect_punct("(")?;
+    let mut params = Vec::new();
+    if !self.eat_punct(")") {
+      loop {
+        params.push(self.expect_identifier()?);
+        self.skip_type_annotation()?;
+        if self.eat_punct(")") {
+          break;
+        }
+        self.expect_punct(",")?;
+      }
+    }
+    self.skip_type_annotation()?;
+    let loop_depth = std::mem::replace(&mut self.loop_depth, 0);
+    let body = self.parse_block();
+    self.loop_depth = loop_depth;
+    Ok(Function {
+      name,
+      params,
+      body: body?,
+      pos,
+    })
+  }
+
+  fn parse_expression(&mut self) -> ParseResult<Expr> {
+    self.parse_assignment()
+  }
+
+  fn parse_assignment(&mut self) -> ParseResult<Expr> {
+    let pos = self.peek_pos();
+    let target = self.parse_conditional()?;
+    let op = match self.peek().kind {
+      TokenKind::Punct(p) => assign_op(p),
+      _ => None,
+    };
+    match op {
+      Some(op) => {
+        if !matches!(target, Expr::Identifier(_) | Expr::Member(..)) {
+          return Err(pos.error(String::from("invalid assignment target")));
+        }
+        self.next();
+        let value = self.parse_assignment()?;
+        Ok(Expr::Assign(op, Box::new(target), Box::new(value)))
+      }
+      None => Ok(target),
+    }
+  }
+
+  fn parse_conditional(&mut self) -> ParseResult<Expr> {
+    let condition = self.parse_binary(1)?;
+    if !self.eat_punct("?") {
+      return Ok(condition);
+    }
+    let then = self.parse_assignment()?;
+    self.expect_punct(":")?;
+    let otherwise = self.parse_assignment()?;
+    Ok(Expr::Conditional(
+      Box::new(condition),
+      Box::new(then),
+      Box::new(otherwise),
+    ))
+  }
+
+  /// Parses binary operators binding at least as tight as `min_power`.
+  fn parse_binary(&mut self, min_power: u8) -> ParseResult<Expr> {
+    let mut left = self.parse_unary()?;
+    loop {
+      let pos = self.peek_pos();
+      let op = match &self.peek().kind {
+        TokenKind::Punct(p) => binary_op(p),
+        TokenKind::Ident(v) if v == "instanceof" => binary_op(v),
+        _ => None,
+      };
+      let (power, op) = match op {
+        Some((power, op)) if power >= min_power => (power, op),
+        _ => return Ok(left),
+      };
+      if matches!(op, BinaryOp::StrictEq | BinaryOp::StrictNe | BinaryOp::InstanceOf) && self.options.swf_version < 6 {
+        return Err(pos.error(format!("{} requires SWF 6", self.peek().kind)));
+      }
+      self.next();
+      let right = self.parse_binary(power + 1)?;
+      left = Expr::Binary(op, Box::new(left), Box::new(right));
+    }
+  }
+
+  fn parse_unary(&mut self) -> ParseResult<Expr> {
+    let pos = self.peek_pos();
+    let op = match &self.peek().kind {
+      TokenKind::Punct("-") => Some(UnaryOp::Neg),
+      TokenKind::Punct("+") => Some(UnaryOp::Plus),
+      TokenKind::Punct("!") => Some(UnaryOp::Not),
+      TokenKind::Punct("~") => Some(UnaryOp::BitNot),
+      TokenKind::Ident(v) if v == "typeof" => Some(UnaryOp::TypeOf),
+      TokenKind::Ident(v) if v == "delete" => Some(UnaryOp::Delete),
+      TokenKind::Punct(p @ ("++" | "--")) => {
+        let increment = *p == "++";
+        self.next();
+        let target = self.parse_unary()?;
+        return Self::update(pos, increment, true, target);
+      }
+      _ => None,
+    };
+    match op {
+      Some(op) => {
+        self.next();
+        let value = self.parse_unary()?;
+        Ok(match (op, value) {
+          (UnaryOp::Neg, Expr::Number(v)) => Expr::Number(-v),
+          (op, value) => Expr::Unary(op, Box::new(value)),
+        })
+      }
+      None => self.parse_postfix(),
+    }
+  }
+
+  fn update(pos: Pos, increment: bool, prefix: bool, target: Expr) -> ParseResult<Expr> {
+    if !matches!(target, Expr::Identifier(_) | Expr::Member(..)) {
+      return Err(pos.error(String::from("invalid increment target")));
+    }
+    Ok(Expr::Update {
+      increment,
+      prefix,
+      target: Box::new(target),
+    })
+  }
+
+  fn parse_postfix(&mut self) -> ParseResult<Expr> {
+    let pos = self.peek_pos();
+    let value = self.parse_call()?;
+    if self.is_punct("++") || self.is_punct("--") {
+      let increment = self.is_punct("++");
+      self.next();
+      return Self::update(pos, increment, false, value);
+    }
+    Ok(value)
+  }
+
+  fn parse_call(&mut self) -> ParseResult<Expr> {
+    let mut value = if self.eat_keyword("new") {
+      let callee = self.parse_member()?;
+      let args = if self.is_punct("(") {
+        self.parse_arguments()?
+      } else {
+        Vec::new()
+      };
+      Expr::New(Box::new(callee), args)
+    } else {
+      self.parse_primary()?
+    };
+    loop {
+      if self.is_punct("(") {
+        let args = self.parse_arguments()?;
+        value = Expr::Call(Box::new(value), args);
+      } else if !self.parse_member_suffix(&mut value)? {
+        return Ok(value);
+      }
+    }
+  }
+
+  /// Parses a member expression, without calls (callee of `new`).
+  fn parse_member(&mut self) -> ParseResult<Expr> {
+    let mut value = self.parse_primary()?;
+    while self.parse_member_suffix(&mut value)? {}
+    Ok(value)
+  }
+
+  /// Parses `.name` or `[key]`, returns `false` if there is none.
+  fn parse_member_suffix(&mut self, value: &mut Expr) -> ParseResult<bool> {
+    let key = if self.eat_punct(".") {
+      Expr::String(self.expect_name()?)
+    } else if self.eat_punct("[") {
+      let key = self.parse_expression()?;
+      self.expect_punct("]")?;
+      key
+    } else {
+      return Ok(false);
+    };
+    let object = std::mem::replace(value, Expr::Undefined);
+    *value = Expr::Member(Box::new(object), Box::new(key));
+    Ok(true)
+  }
+
+  fn parse_arguments(&mut self) -> ParseResult<Vec<Expr>> {
+    self.expect_punct("(")?;
+    let mut args = Vec::new();
+    if self.eat_punct(")") {
+      return Ok(args);
+    }
+    loop {
+      args.push(self.parse_assignment()?);
+      if self.eat_punct(")") {
+        return Ok(args);
+      }
+      self.expect_punct(",")?;
+    }
+  }
+
+  fn parse_primary(&mut self) -> ParseResult<Expr> {
+    let value = match self.peek().kind.clone() {
+      TokenKind::Number(v) => Expr::Number(v),
+      TokenKind::Str(v) => Expr::String(v),
+      TokenKind::Punct("(") => {
+        self.next();
+        let value = self.parse_expression()?;
+        self.expect_punct(")")?;
+        return Ok(value);
+      }
+      TokenKind::Punct("[") => {
+        self.next();
+        let mut items = Vec::new();
+        while !self.eat_punct("]") {
+          items.push(self.parse_assignment()?);
+          if !self.is_punct("]") {
+            self.expect_punct(",")?;
+          }
+        }
+        return Ok(Expr::Array(items));
+      }
+      TokenKind::Punct("{") => {
+        self.next();
+        let mut properties = Vec::new();
+        while !self.eat_punct("}") {
+          let key = match self.peek().kind.clone() {
+            TokenKind::Ident(v) | TokenKind::Str(v) => v,
+            TokenKind::Number(v) => number_to_string(v),
+            _ => return self.unexpected("a property name"),
+          };
+          self.next();
+          self.expect_punct(":")?;
+          properties.push((key, self.parse_assignment()?));
+          if !self.is_punct("}") {
+            self.expect_punct(",")?;
+          }
+        }
+        return Ok(Expr::Object(properties));
+      }
+      TokenKind::Ident(v) => match v.as_str() {
+        "true" => Expr::Boolean(true),
+        "false" => Expr::Boolean(false),
+        "null" => Expr::Null,
+        "undefined" => Expr::Undefined,
+        "this" => Expr::Identifier(v),
+        "function" => return Ok(Expr::Function(Box::new(self.parse_function(false)?))),
+        _ => return Ok(Expr::Identifier(self.expect_identifier()?)),
+      },
+      _ => return self.unexpected("an expression"),
+    };
+    self.next();
+    Ok(value)
+  }
+}
+
+/// Formats a number the way property names are written, e.g. `1` rather than `1.0`.
+fn number_to_string(value: f64) -> String {
+  if value.fract() == 0.0 && value.abs() < 1e21 {
+    format!("{}", value as i64)
+  } else {
+    format!("{}", value)
+  }
+}
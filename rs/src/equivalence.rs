@@ -0,0 +1,154 @@
+//! Structural comparison of control flow graphs, ignoring label names.
+
+use avm1_types::cfg::{self, Cfg, CfgFlow, CfgLabel};
+use std::collections::HashMap;
+
+/// Perform a DFS on both control flow graphs at the same time, check if both
+/// traversal go through exactly the same actions.
+pub(crate) fn hard_cfg_equivalent(left: &Cfg, right: &Cfg) -> bool {
+  let left_labels = get_hard_cfg_labels(left);
+  let right_labels = get_hard_cfg_labels(right);
+  let left_id: HashMap<&CfgLabel, usize> = left_labels.into_iter().enumerate().map(|(i, l)| (l, i)).collect();
+  let right_id: HashMap<&CfgLabel, usize> = right_labels.into_iter().enumerate().map(|(i, l)| (l, i)).collect();
+
+  let label_eq = |left: Option<&CfgLabel>, right: Option<&CfgLabel>| -> bool {
+    match (left, right) {
+      (Some(l), Some(r)) => left_id.get(l) == right_id.get(r),
+      (l, r) => l == r,
+    }
+  };
+
+  soft_cfg_equivalent(left, right, &label_eq)
+}
+
+fn soft_cfg_equivalent(
+  left: &Cfg,
+  right: &Cfg,
+  label_eq: &impl Fn(Option<&CfgLabel>, Option<&CfgLabel>) -> bool,
+) -> bool {
+  let left_blocks = &left.blocks;
+  let right_blocks = &right.blocks;
+  if left_blocks.len() != right_blocks.len() {
+    return false;
+  }
+  for (left_block, right_block) in left_blocks.iter().zip(right_blocks.iter()) {
+    if left_block.actions.len() != right_block.actions.len() {
+      return false;
+    }
+    if !label_eq(Some(&left_block.label), Some(&right_block.label)) {
+      return false;
+    }
+    for (left_action, right_action) in left_block.actions.iter().zip(right_block.actions.iter()) {
+      if !action_equivalent(left_action, right_action) {
+        return false;
+      }
+    }
+    let flow_eq = match (&left_block.flow, &right_block.flow) {
+      (CfgFlow::If(l), CfgFlow::If(r)) => {
+        label_eq(l.true_target.as_ref(), r.true_target.as_ref())
+          && label_eq(l.false_target.as_ref(), r.false_target.as_ref())
+      }
+      (CfgFlow::Simple(l), CfgFlow::Simple(r)) => label_eq(l.next.as_ref(), r.next.as_ref()),
+      (CfgFlow::Try(l), CfgFlow::Try(r)) => try_equivalent(l, r, label_eq),
+      (CfgFlow::WaitForFrame(l), CfgFlow::WaitForFrame(r)) => {
+        l.frame == r.frame
+          && label_eq(l.ready_target.as_ref(), r.ready_target.as_ref())
+          && label_eq(l.loading_target.as_ref(), r.loading_target.as_ref())
+      }
+      (CfgFlow::WaitForFrame2(l), CfgFlow::WaitForFrame2(r)) => {
+        label_eq(l.ready_target.as_ref(), r.ready_target.as_ref())
+          && label_eq(l.loading_target.as_ref(), r.loading_target.as_ref())
+      }
+      (CfgFlow::With(l), CfgFlow::With(r)) => soft_cfg_equivalent(&l.body, &r.body, label_eq),
+      (l, r) => l == r,
+    };
+    if !flow_eq {
+      return false;
+    }
+  }
+
+  true
+}
+
+fn action_equivalent(left: &cfg::Action, right: &cfg::Action) -> bool {
+  match (left, right) {
+    (cfg::Action::DefineFunction(l), cfg::Action::DefineFunction(r)) => {
+      l.name == r.name && l.parameters == r.parameters && hard_cfg_equivalent(&l.body, &r.body)
+    }
+    (cfg::Action::DefineFunction2(l), cfg::Action::DefineFunction2(r)) => {
+      l.name == r.name
+        && l.register_count == r.register_count
+        && l.flags == r.flags
+        && l.parameters == r.parameters
+        && hard_cfg_equivalent(&l.body, &r.body)
+    }
+    (l, r) => l == r,
+  }
+}
+
+/// Checks if two `DefineFunction` or `DefineFunction2` actions define the same
+/// function, ignoring their names. Used by the deduplication pass.
+pub(crate) fn function_equivalent(left: &cfg::Action, right: &cfg::Action) -> bool {
+  match (left, right) {
+    (cfg::Action::DefineFunction(l), cfg::Action::DefineFunction(r)) => {
+      l.parameters == r.parameters && hard_cfg_equivalent(&l.body, &r.body)
+    }
+    (cfg::Action::DefineFunction2(l), cfg::Action::DefineFunction2(r)) => {
+      l.register_count == r.register_count
+        && l.flags == r.flags
+        && l.parameters == r.parameters
+        && hard_cfg_equivalent(&l.body, &r.body)
+    }
+    _ => false,
+  }
+}
+
+fn try_equivalent(
+  left: &cfg::Try,
+  right: &cfg::Try,
+  label_eq: &impl Fn(Option<&CfgLabel>, Option<&CfgLabel>) -> bool,
+) -> bool {
+  if !soft_cfg_equivalent(&left.r#try, &right.r#try, label_eq) {
+    return false;
+  }
+  let catch_eq = match (left.catch.as_ref(), right.catch.as_ref()) {
+    (Some(l), Some(r)) => l.target == r.target && soft_cfg_equivalent(&l.body, &r.body, label_eq),
+    (l, r) => l == r,
+  };
+  if !catch_eq {
+    return false;
+  }
+  match (left.finally.as_ref(), right.finally.as_ref()) {
+    (Some(l), Some(r)) => soft_cfg_equivalent(l, r, label_eq),
+    (l, r) => l == r,
+  }
+}
+
+fn get_hard_cfg_labels<'a>(hard_cfg: &'a Cfg) -> Vec<&'a CfgLabel> {
+  let mut result: Vec<&'a CfgLabel> = Vec::new();
+
+  fn visit<'a>(cfg: &'a Cfg, result: &mut Vec<&'a CfgLabel>) {
+    for block in cfg.blocks.iter() {
+      result.push(&block.label);
+      match &block.flow {
+        CfgFlow::Try(ref flow) => {
+          visit(&flow.r#try, result);
+          if let Some(catch) = &flow.catch {
+            visit(&catch.body, result);
+          }
+          if let Some(finally) = &flow.finally {
+            visit(finally, result);
+          }
+        }
+        CfgFlow::With(ref flow) => {
+          visit(&flow.body, result);
+        }
+        _ => {}
+      }
+    }
+  }
+
+  visit(hard_cfg, &mut result);
+
+  result
+}
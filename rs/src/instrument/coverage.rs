@@ -0,0 +1,169 @@
+use crate::instrument::Probe;
+use avm1_types::cfg;
+use avm1_types::cfg::{Cfg, CfgFlow, CfgLabel};
+use avm1_types::PushValue;
+use std::io;
+
+/// Sidecar describing the probes inserted by [`instrument_coverage`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CoverageMap {
+  pub probes: Vec<CoverageProbe>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CoverageProbe {
+  pub id: u32,
+  /// Label of the instrumented block.
+  pub label: CfgLabel,
+  /// Name of the innermost function containing the block (empty string if
+  /// anonymous), or `None` for top-level code.
+  pub function: Option<String>,
+}
+
+/// Returns a copy of `value` where every block starts with a probe reporting
+/// its id.
+///
+/// `Probe::Trace` reports `"{prefix}{id}"`, `Probe::Call` passes the id as an
+/// integer. Ids are assigned in emission order, starting at `0`.
+pub fn instrument_coverage(value: &Cfg, probe: &Probe) -> (Cfg, CoverageMap) {
+  let mut cfg = value.clone();
+  let mut map = CoverageMap::default();
+  instrument_cfg(&mut cfg, probe, None, &mut map);
+  (cfg, map)
+}
+
+/// Instruments `value` for coverage and emits the result.
+pub fn emit_cfg_with_coverage(value: &Cfg, probe: &Probe) -> io::Result<(Vec<u8>, CoverageMap)> {
+  let (cfg, map) = instrument_coverage(value, probe);
+  let bytes = crate::emit_cfg(&cfg)?;
+  Ok((bytes, map))
+}
+
+fn instrument_cfg(cfg: &mut Cfg, probe: &Probe, function: Option<&str>, map: &mut CoverageMap) {
+  for block in cfg.blocks.iter_mut() {
+    let id = u32::try_from(map.probes.len()).unwrap();
+    map.probes.push(CoverageProbe {
+      id,
+      label: block.label.clone(),
+      function: function.map(String::from),
+    });
+    let message = match probe {
+      Probe::Trace { prefix } => PushValue::String(format!("{}{}", prefix, id)),
+      Probe::Call { .. } => PushValue::Sint32(i32::try_from(id).unwrap()),
+    };
+    block.actions.splice(0..0, probe.actions(Some(message)));
+
+    for action in block.actions.iter_mut() {
+      match action {
+        cfg::Action::DefineFunction(ref mut f) => instrument_cfg(&mut f.body, probe, Some(&f.name), map),
+        cfg::Action::DefineFunction2(ref mut f) => instrument_cfg(&mut f.body, probe, Some(&f.name), map),
+        _ => {}
+      }
+    }
+
+    match &mut block.flow {
+      CfgFlow::Try(ref mut flow) => {
+        instrument_cfg(&mut flow.r#try, probe, function, map);
+        if let Some(catch) = flow.catch.as_mut() {
+          instrument_cfg(&mut catch.body, probe, function, map);
+        }
+        if let Some(finally) = flow.finally.as_mut() {
+          instrument_cfg(finally, probe, function, map);
+        }
+      }
+      CfgFlow::With(ref mut flow) => instrument_cfg(&mut flow.body, probe, function, map),
+      _ => {}
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use avm1_types::cfg::{CfgBlock, Simple};
+  use avm1_types::raw::Push;
+  use vec1::vec1;
+
+  fn block(label: &str, actions: Vec<cfg::Action>, next: Option<&str>) -> CfgBlock {
+    CfgBlock {
+      label: CfgLabel(label.to_string()),
+      actions,
+      flow: CfgFlow::Simple(Simple {
+        next: next.map(|l| CfgLabel(l.to_string())),
+      }),
+    }
+  }
+
+  #[test]
+  fn test_trace_probes() {
+    let body = Cfg {
+      blocks: vec1![block("f0", vec![cfg::Action::Stop], None)],
+    };
+    let cfg = Cfg {
+      blocks: vec1![
+        block(
+          "l0",
+          vec![cfg::Action::DefineFunction(Box::new(cfg::DefineFunction {
+            name: String::from("foo"),
+            parameters: Vec::new(),
+            body,
+          }))],
+          Some("l1")
+        ),
+        block("l1", Vec::new(), None),
+      ],
+    };
+    let probe = Probe::Trace {
+      prefix: String::from("cov:"),
+    };
+    let (actual, map) = instrument_coverage(&cfg, &probe);
+
+    let ids: Vec<(u32, &str, Option<&str>)> = map
+      .probes
+      .iter()
+      .map(|p| (p.id, p.label.0.as_str(), p.function.as_deref()))
+      .collect();
+    assert_eq!(ids, vec![(0, "l0", None), (1, "f0", Some("foo")), (2, "l1", None)]);
+
+    assert_eq!(
+      &actual.blocks[1].actions[..2],
+      &[
+        cfg::Action::Push(Push {
+          values: vec![PushValue::String(String::from("cov:2"))]
+        }),
+        cfg::Action::Trace,
+      ]
+    );
+    match &actual.blocks[0].actions[2] {
+      cfg::Action::DefineFunction(f) => assert_eq!(f.body.blocks[0].actions.len(), 3),
+      a => panic!("unexpected action: {:?}", a),
+    }
+  }
+
+  #[test]
+  fn test_call_probes() {
+    let cfg = Cfg {
+      blocks: vec1![block("l0", Vec::new(), None)],
+    };
+    let probe = Probe::Call {
+      function: String::from("__cov"),
+    };
+    let (actual, _) = instrument_coverage(&cfg, &probe);
+    assert_eq!(
+      actual.blocks[0].actions,
+      vec![
+        cfg::Action::Push(Push {
+          values: vec![
+            PushValue::Sint32(0),
+            PushValue::Sint32(1),
+            PushValue::String(String::from("__cov"))
+          ]
+        }),
+        cfg::Action::CallFunction,
+        cfg::Action::Pop,
+      ]
+    );
+  }
+}
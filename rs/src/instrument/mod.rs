@@ -0,0 +1,54 @@
+//! Transformations inserting extra actions into a control flow graph before it
+//! is emitted.
+
+pub mod coverage;
+pub mod trace;
+
+use avm1_types::cfg;
+use avm1_types::raw::Push;
+use avm1_types::PushValue;
+
+/// Code inserted by an instrumentation pass to report an event.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(
+  feature = "serde",
+  derive(serde::Serialize, serde::Deserialize),
+  serde(tag = "type", rename_all = "PascalCase")
+)]
+pub enum Probe {
+  /// Report the event with the `Trace` action, prefixing the message with `prefix`.
+  Trace { prefix: String },
+  /// Report the event by calling the global function `function` with the
+  /// message as its only argument. The result of the call is discarded.
+  Call { function: String },
+}
+
+impl Probe {
+  /// Returns the actions reporting an event.
+  ///
+  /// If `message` is `None`, the message must already be on top of the stack.
+  /// The probe consumes the message and leaves the rest of the stack untouched.
+  pub(crate) fn actions(&self, message: Option<PushValue>) -> Vec<cfg::Action> {
+    match self {
+      Probe::Trace { .. } => {
+        let mut actions = Vec::with_capacity(2);
+        if let Some(message) = message {
+          actions.push(cfg::Action::Push(Push { values: vec![message] }));
+        }
+        actions.push(cfg::Action::Trace);
+        actions
+      }
+      Probe::Call { function } => {
+        let mut values = Vec::with_capacity(3);
+        values.extend(message);
+        values.push(PushValue::Sint32(1));
+        values.push(PushValue::String(function.clone()));
+        vec![
+          cfg::Action::Push(Push { values }),
+          cfg::Action::CallFunction,
+          cfg::Action::Pop,
+        ]
+      }
+    }
+  }
+}
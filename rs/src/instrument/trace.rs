@@ -0,0 +1,335 @@
+use crate::instrument::Probe;
+use crate::label::{collect_labels, fresh_label};
+use avm1_types::cfg;
+use avm1_types::cfg::{Cfg, CfgBlock, CfgFlow, CfgLabel};
+use avm1_types::raw::Push;
+use avm1_types::PushValue;
+use std::collections::HashSet;
+use std::io;
+
+/// Returns a copy of `value` where every function reports when it is entered
+/// and exited.
+///
+/// On entry, the probe receives `"> name(arg0, arg1, ...)"` with the current
+/// values of the declared parameters. Before each `Return` flow and before
+/// reaching the end of the function body, it receives `"< name"`. The exit
+/// probe also runs on throw: before each `Throw` flow of the body that is not
+/// caught by a `try` of the same body, it receives `"< name (throw)"`.
+/// Exceptions thrown by called functions are not reported. The value on top of
+/// the stack is left untouched, so the returned or thrown value is preserved.
+///
+/// `Probe::Trace` prefixes the messages with its `prefix`. Top-level code is
+/// not instrumented.
+pub fn instrument_function_tracing(value: &Cfg, probe: &Probe) -> Cfg {
+  let mut cfg = value.clone();
+  instrument_functions(&mut cfg, probe);
+  cfg
+}
+
+/// Instruments functions for tracing and emits the result.
+pub fn emit_cfg_with_function_tracing(value: &Cfg, probe: &Probe) -> io::Result<Vec<u8>> {
+  crate::emit_cfg(&instrument_function_tracing(value, probe))
+}
+
+/// Instruments the functions defined in `cfg`, without instrumenting `cfg` itself.
+fn instrument_functions(cfg: &mut Cfg, probe: &Probe) {
+  for block in cfg.blocks.iter_mut() {
+    for action in block.actions.iter_mut() {
+      match action {
+        cfg::Action::DefineFunction(ref mut f) => {
+          let args: Vec<Vec<cfg::Action>> = f.parameters.iter().map(|p| get_variable(p)).collect();
+          instrument_body(&mut f.body, probe, &f.name, &args);
+        }
+        cfg::Action::DefineFunction2(ref mut f) => {
+          let args: Vec<Vec<cfg::Action>> = f
+            .parameters
+            .iter()
+            .map(|p| match p.register {
+              0 => get_variable(&p.name),
+              r => vec![push(PushValue::Register(r))],
+            })
+            .collect();
+          instrument_body(&mut f.body, probe, &f.name, &args);
+        }
+        _ => {}
+      }
+    }
+    match &mut block.flow {
+      CfgFlow::Try(ref mut flow) => {
+        instrument_functions(&mut flow.r#try, probe);
+        if let Some(catch) = flow.catch.as_mut() {
+          instrument_functions(&mut catch.body, probe);
+        }
+        if let Some(finally) = flow.finally.as_mut() {
+          instrument_functions(finally, probe);
+        }
+      }
+      CfgFlow::With(ref mut flow) => instrument_functions(&mut flow.body, probe),
+      _ => {}
+    }
+  }
+}
+
+fn instrument_body(body: &mut Cfg, probe: &Probe, name: &str, args: &[Vec<cfg::Action>]) {
+  instrument_functions(body, probe);
+
+  let name = if name.is_empty() { "<anonymous>" } else { name };
+  let prefix = match probe {
+    Probe::Trace { prefix } => prefix.as_str(),
+    Probe::Call { .. } => "",
+  };
+
+  let mut enter: Vec<cfg::Action> = vec![push(PushValue::String(format!("{}> {}(", prefix, name)))];
+  for (i, arg) in args.iter().enumerate() {
+    if i > 0 {
+      enter.push(push(PushValue::String(String::from(", "))));
+      enter.push(cfg::Action::StringAdd);
+    }
+    enter.extend(arg.iter().cloned());
+    enter.push(cfg::Action::StringAdd);
+  }
+  enter.push(push(PushValue::String(String::from(")"))));
+  enter.push(cfg::Action::StringAdd);
+  enter.extend(probe.actions(None));
+
+  let exit = probe.actions(Some(PushValue::String(format!("{}< {}", prefix, name))));
+  let throw = probe.actions(Some(PushValue::String(format!("{}< {} (throw)", prefix, name))));
+
+  let mut labels: HashSet<CfgLabel> = HashSet::new();
+  collect_labels(body, &mut labels);
+  let enter_label = fresh_label(&mut labels, "enter");
+  let exit_label = fresh_label(&mut labels, "exit");
+
+  let mut exit_block_used = false;
+  instrument_exits(body, &exit, Some(&throw), &exit_label, &mut exit_block_used);
+  if exit_block_used {
+    body.blocks.push(CfgBlock {
+      label: exit_label,
+      actions: exit,
+      flow: CfgFlow::Simple(cfg::Simple { next: None }),
+    });
+  }
+
+  // Use a dedicated entry block: the first block may be the target of a loop.
+  let first = body.blocks.first().label.clone();
+  body.blocks.insert(
+    0,
+    CfgBlock {
+      label: enter_label,
+      actions: enter,
+      flow: CfgFlow::Simple(cfg::Simple { next: Some(first) }),
+    },
+  );
+}
+
+/// Inserts `exit` before every flow leaving the function, and `throw` before
+/// the `Throw` flows if they are not caught.
+///
+/// Jumps to the end of the function that cannot be instrumented in place are
+/// redirected to `exit_label`, and `exit_label_used` is set.
+fn instrument_exits(
+  cfg: &mut Cfg,
+  exit: &[cfg::Action],
+  throw: Option<&[cfg::Action]>,
+  exit_label: &CfgLabel,
+  exit_label_used: &mut bool,
+) {
+  for block in cfg.blocks.iter_mut() {
+    match &mut block.flow {
+      CfgFlow::Return => block.actions.extend(exit.iter().cloned()),
+      CfgFlow::Throw => block.actions.extend(throw.into_iter().flatten().cloned()),
+      CfgFlow::Simple(ref flow) => {
+        if flow.next.is_none() {
+          block.actions.extend(exit.iter().cloned());
+        }
+      }
+      CfgFlow::If(ref mut flow) => {
+        redirect(&mut flow.true_target, exit_label, exit_label_used);
+        redirect(&mut flow.false_target, exit_label, exit_label_used);
+      }
+      CfgFlow::WaitForFrame(ref mut flow) => {
+        redirect(&mut flow.ready_target, exit_label, exit_label_used);
+        redirect(&mut flow.loading_target, exit_label, exit_label_used);
+      }
+      CfgFlow::WaitForFrame2(ref mut flow) => {
+        redirect(&mut flow.ready_target, exit_label, exit_label_used);
+        redirect(&mut flow.loading_target, exit_label, exit_label_used);
+      }
+      CfgFlow::Try(ref mut flow) => {
+        let try_throw = if flow.catch.is_some() { None } else { throw };
+        instrument_exits(&mut flow.r#try, exit, try_throw, exit_label, exit_label_used);
+        if let Some(catch) = flow.catch.as_mut() {
+          instrument_exits(&mut catch.body, exit, throw, exit_label, exit_label_used);
+        }
+        if let Some(finally) = flow.finally.as_mut() {
+          instrument_exits(finally, exit, throw, exit_label, exit_label_used);
+        }
+      }
+      CfgFlow::With(ref mut flow) => instrument_exits(&mut flow.body, exit, throw, exit_label, exit_label_used),
+      CfgFlow::Error(_) => {}
+    }
+  }
+}
+
+fn redirect(target: &mut Option<CfgLabel>, exit_label: &CfgLabel, exit_label_used: &mut bool) {
+  if target.is_none() {
+    *target = Some(exit_label.clone());
+    *exit_label_used = true;
+  }
+}
+
+fn push(value: PushValue) -> cfg::Action {
+  cfg::Action::Push(Push { values: vec![value] })
+}
+
+fn get_variable(name: &str) -> Vec<cfg::Action> {
+  vec![push(PushValue::String(name.to_string())), cfg::Action::GetVariable]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use avm1_types::cfg::{If, Simple};
+  use avm1_types::{FunctionFlags, Parameter};
+  use vec1::vec1;
+
+  fn s(value: &str) -> PushValue {
+    PushValue::String(value.to_string())
+  }
+
+  fn define(body: Cfg) -> Cfg {
+    Cfg {
+      blocks: vec1![CfgBlock {
+        label: CfgLabel(String::from("l0")),
+        actions: vec![cfg::Action::DefineFunction2(Box::new(cfg::DefineFunction2 {
+          name: String::from("f"),
+          register_count: 2,
+          flags: FunctionFlags::empty(),
+          parameters: vec![
+            Parameter {
+              register: 1,
+              name: String::from("a")
+            },
+            Parameter {
+              register: 0,
+              name: String::from("b")
+            },
+          ],
+          body,
+        }))],
+        flow: CfgFlow::Simple(Simple { next: None }),
+      }],
+    }
+  }
+
+  fn body(cfg: &Cfg) -> &Cfg {
+    match &cfg.blocks[0].actions[0] {
+      cfg::Action::DefineFunction2(f) => &f.body,
+      a => panic!("unexpected action: {:?}", a),
+    }
+  }
+
+  #[test]
+  fn test_enter_and_return() {
+    let cfg = define(Cfg {
+      blocks: vec1![CfgBlock {
+        label: CfgLabel(String::from("f0")),
+        actions: vec![push(PushValue::Sint32(1))],
+        flow: CfgFlow::Return,
+      }],
+    });
+    let probe = Probe::Trace { prefix: String::new() };
+    let actual = instrument_function_tracing(&cfg, &probe);
+    let expected = vec![
+      push(s("> f(")),
+      push(PushValue::Register(1)),
+      cfg::Action::StringAdd,
+      push(s(", ")),
+      cfg::Action::StringAdd,
+      push(s("b")),
+      cfg::Action::GetVariable,
+      cfg::Action::StringAdd,
+      push(s(")")),
+      cfg::Action::StringAdd,
+      cfg::Action::Trace,
+    ];
+    assert_eq!(body(&actual).blocks[0].actions, expected);
+    assert_eq!(
+      body(&actual).blocks[1].actions,
+      vec![push(PushValue::Sint32(1)), push(s("< f")), cfg::Action::Trace]
+    );
+  }
+
+  #[test]
+  fn test_throw() {
+    let cfg = define(Cfg {
+      blocks: vec1![CfgBlock {
+        label: CfgLabel(String::from("f0")),
+        actions: vec![push(s("error"))],
+        flow: CfgFlow::Throw,
+      }],
+    });
+    let probe = Probe::Trace { prefix: String::new() };
+    let actual = instrument_function_tracing(&cfg, &probe);
+    assert_eq!(
+      body(&actual).blocks[1].actions,
+      vec![push(s("error")), push(s("< f (throw)")), cfg::Action::Trace]
+    );
+
+    // Throws caught in the function body do not exit it.
+    let cfg = define(
+      crate::asm::parse_asm(
+        r#"
+          try {
+            Push "caught"
+            Throw
+          } catch(r:0) {
+            Push "uncaught"
+            Throw
+          }
+        "#,
+      )
+      .unwrap(),
+    );
+    let actual = instrument_function_tracing(&cfg, &probe);
+    let r#try = match &body(&actual).blocks[1].flow {
+      CfgFlow::Try(flow) => flow,
+      flow => panic!("unexpected flow: {:?}", flow),
+    };
+    assert_eq!(r#try.r#try.blocks[0].actions, vec![push(s("caught"))]);
+    assert_eq!(
+      r#try.catch.as_ref().unwrap().body.blocks[0].actions,
+      vec![push(s("uncaught")), push(s("< f (throw)")), cfg::Action::Trace]
+    );
+  }
+
+  #[test]
+  fn test_redirect_end_jumps() {
+    let cfg = define(Cfg {
+      blocks: vec1![CfgBlock {
+        label: CfgLabel(String::from("exit")),
+        actions: Vec::new(),
+        flow: CfgFlow::If(If {
+          true_target: None,
+          false_target: Some(CfgLabel(String::from("exit"))),
+        }),
+      }],
+    });
+    let probe = Probe::Call {
+      function: String::from("log"),
+    };
+    let actual = instrument_function_tracing(&cfg, &probe);
+    let body = body(&actual);
+    assert_eq!(body.blocks.len(), 3);
+    assert_eq!(body.blocks[0].label, CfgLabel(String::from("enter")));
+    assert_eq!(body.blocks[2].label, CfgLabel(String::from("exit1")));
+    assert_eq!(
+      body.blocks[1].flow,
+      CfgFlow::If(If {
+        true_target: Some(CfgLabel(String::from("exit1"))),
+        false_target: Some(CfgLabel(String::from("exit"))),
+      })
+    );
+    assert_eq!(body.blocks[2].actions, probe.actions(Some(s("< f"))));
+  }
+}
@@ -0,0 +1,38 @@
+//! Label helpers shared by the CFG rewriting passes.
+
+use avm1_types::cfg::{Cfg, CfgFlow, CfgLabel};
+use std::collections::HashSet;
+
+/// Returns `base`, or `base` followed by the smallest number making it absent
+/// from `labels`, and adds it to `labels`.
+pub(crate) fn fresh_label(labels: &mut HashSet<CfgLabel>, base: &str) -> CfgLabel {
+  let mut label = CfgLabel(base.to_string());
+  let mut i: usize = 0;
+  while labels.contains(&label) {
+    i += 1;
+    label = CfgLabel(format!("{}{}", base, i));
+  }
+  labels.insert(label.clone());
+  label
+}
+
+/// Adds the labels of the blocks of `cfg` and of its nested regions to
+/// `labels`, without the labels of the function bodies.
+pub(crate) fn collect_labels(cfg: &Cfg, labels: &mut HashSet<CfgLabel>) {
+  for block in cfg.blocks.iter() {
+    labels.insert(block.label.clone());
+    match &block.flow {
+      CfgFlow::Try(ref flow) => {
+        collect_labels(&flow.r#try, labels);
+        if let Some(catch) = flow.catch.as_ref() {
+          collect_labels(&catch.body, labels);
+        }
+        if let Some(finally) = flow.finally.as_ref() {
+          collect_labels(finally, labels);
+        }
+      }
+      CfgFlow::With(ref flow) => collect_labels(&flow.body, labels),
+      _ => {}
+    }
+  }
+}
@@ -0,0 +1,90 @@
+use avm1_types::cfg::CfgLabel;
+use avm1_types::raw;
+use std::ops::Range;
+
+/// Target of an emitted `If` or `Jump` action.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+  feature = "serde",
+  derive(serde::Serialize, serde::Deserialize),
+  serde(tag = "type", content = "label", rename_all = "PascalCase")
+)]
+pub enum JumpTarget {
+  Label(CfgLabel),
+  /// End of the enclosing hard CFG (script or function body).
+  End,
+}
+
+impl From<Option<CfgLabel>> for JumpTarget {
+  fn from(label: Option<CfgLabel>) -> Self {
+    match label {
+      Some(label) => JumpTarget::Label(label),
+      None => JumpTarget::End,
+    }
+  }
+}
+
+/// Kind of a nested region delimited by a `Try` or `With` action.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+  feature = "serde",
+  derive(serde::Serialize, serde::Deserialize),
+  serde(rename_all = "PascalCase")
+)]
+pub enum RegionKind {
+  Try,
+  Catch,
+  Finally,
+  With,
+}
+
+/// Everything the emitter knows about the bytes it wrote for a hard CFG.
+///
+/// All offsets are relative to the start of the hard CFG.
+pub(crate) struct Layout {
+  pub size: usize,
+  /// Emission events, in emission order.
+  pub items: Vec<LayoutItem>,
+}
+
+pub(crate) enum LayoutItem {
+  /// Start of a block. For blocks with a `Try` or `With` flow, the range
+  /// includes the nested blocks.
+  Block {
+    label: CfgLabel,
+    range: Range<usize>,
+  },
+  Action(ActionRecord),
+  /// Body of the function defined by the previous action.
+  Function(FunctionRecord),
+  RegionStart {
+    kind: RegionKind,
+    range: Range<usize>,
+  },
+  RegionEnd {
+    kind: RegionKind,
+  },
+}
+
+pub(crate) struct ActionRecord {
+  pub offset: usize,
+  /// Size of the action header and data, excluding function bodies.
+  pub size: usize,
+  /// Emitted action, with resolved offsets and sizes.
+  pub action: raw::Action,
+  /// Label of the block emitting this action, `None` for the final `End` action.
+  pub label: Option<CfgLabel>,
+  /// Index of the action in its block, `None` for actions emitted for the
+  /// block flow.
+  pub index: Option<usize>,
+  /// Target of `If` and `Jump` actions.
+  pub target: Option<JumpTarget>,
+}
+
+pub(crate) struct FunctionRecord {
+  pub name: String,
+  /// Offset of the `DefineFunction` or `DefineFunction2` action.
+  pub offset: usize,
+  pub body_offset: usize,
+  pub body: Layout,
+}
@@ -1,6 +1,24 @@
+pub mod asm;
+pub mod builder;
+pub mod compiler;
+mod equivalence;
+pub mod instrument;
+mod label;
+mod layout;
+pub mod listing;
+pub mod map;
+pub mod object;
+pub mod opt;
 mod patchable_buf_writer;
 mod primitives;
+pub mod swd;
+pub mod switch;
 
+pub use crate::layout::{JumpTarget, RegionKind};
+
+use crate::layout::{ActionRecord, FunctionRecord, Layout, LayoutItem};
+use crate::listing::Listing;
+use crate::map::EmitMap;
 use crate::patchable_buf_writer::{BufferHole, PatchableBufWriter};
 use crate::primitives::{emit_c_string, emit_le32_f64, emit_le_f32, emit_le_i16, emit_le_i32, emit_le_u16, emit_u8};
 use avm1_types::cfg::CfgLabel;
@@ -11,6 +29,7 @@ use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::io;
 use std::io::Write;
+use std::ops::Range;
 
 pub fn emit_cfg(value: &cfg::Cfg) -> io::Result<Vec<u8>> {
   let mut avm1_writer = PatchableBufWriter::new();
@@ -18,7 +37,123 @@ pub fn emit_cfg(value: &cfg::Cfg) -> io::Result<Vec<u8>> {
   Ok(avm1_writer.complete())
 }
 
-fn write_cfg(writer: &mut PatchableBufWriter, value: &cfg::Cfg) -> io::Result<()> {
+/// Options of [`emit_cfg_with_options`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EmitOptions {
+  /// Optimization preset applied before emitting, `Level::None` by default.
+  pub level: opt::Level,
+  /// SWF version of the movie running the code, if known.
+  pub swf_version: Option<u8>,
+}
+
+impl EmitOptions {
+  /// Returns the default options with the optimization preset `level`.
+  pub fn optimize(level: opt::Level) -> Self {
+    Self {
+      level,
+      ..Self::default()
+    }
+  }
+}
+
+/// Emits `value` after applying the optimizations selected by `options`.
+///
+/// With `Level::None`, the output is the same as [`emit_cfg`].
+pub fn emit_cfg_with_options(value: &cfg::Cfg, options: &EmitOptions) -> io::Result<Vec<u8>> {
+  match options.level {
+    opt::Level::None => emit_cfg(value),
+    level => emit_cfg(&opt::optimize(value, level, options.swf_version)),
+  }
+}
+
+/// Emits `value` and returns the emitted bytes with their correspondence to the CFG.
+pub fn emit_cfg_with_map(value: &cfg::Cfg) -> io::Result<(Vec<u8>, EmitMap)> {
+  let mut avm1_writer = PatchableBufWriter::new();
+  let layout = write_cfg(&mut avm1_writer, value)?;
+  Ok((avm1_writer.complete(), EmitMap::from_layout(&layout)))
+}
+
+/// Emits `value` and returns the emitted bytes with their annotated disassembly.
+pub fn emit_cfg_listing(value: &cfg::Cfg) -> io::Result<(Vec<u8>, Listing)> {
+  let mut avm1_writer = PatchableBufWriter::new();
+  let layout = write_cfg(&mut avm1_writer, value)?;
+  let bytes = avm1_writer.complete();
+  let listing = Listing::from_layout(&layout, &bytes);
+  Ok((bytes, listing))
+}
+
+/// Bytes emitted for a soft CFG, see [`emit_soft_cfg`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SoftCfgBytes {
+  pub bytes: Vec<u8>,
+  /// Offset of every block, sorted by offset.
+  pub blocks: Vec<(CfgLabel, usize)>,
+  /// Jumps leaving the CFG, left unpatched, sorted by offset.
+  pub jumps: Vec<ExternalJump>,
+}
+
+/// `If` or `Jump` action whose `i16` offset, stored at `offset`, targets a
+/// label outside of its soft CFG (`None` is the end of the hard CFG).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExternalJump {
+  pub offset: usize,
+  pub target: Option<CfgLabel>,
+}
+
+impl ExternalJump {
+  /// Patches the jump for soft CFG bytes inserted at `start` in `buffer`, so
+  /// it reaches `target_offset` in `buffer`.
+  ///
+  /// Returns the written offset, or `None` if the target is out of reach.
+  pub fn patch(&self, buffer: &mut [u8], start: usize, target_offset: usize) -> Option<i16> {
+    let offset = start + self.offset;
+    let delta = offset_delta_i16(offset + 2, target_offset)?;
+    buffer[offset..offset + 2].copy_from_slice(&delta.to_le_bytes());
+    Some(delta)
+  }
+}
+
+/// Emits `value` as a soft CFG: a part of a hard CFG, such as the body of a
+/// `Try` or `With` region, followed by the block labelled `fallthrough_next`.
+///
+/// Jumps between blocks of `value` are resolved, other jumps are returned for
+/// the caller to patch once their target is known. No `End` action is appended.
+pub fn emit_soft_cfg(value: &cfg::Cfg, fallthrough_next: Option<&CfgLabel>) -> io::Result<SoftCfgBytes> {
+  let mut writer = PatchableBufWriter::new();
+  let wi: WriteInfo = write_soft_cfg(&mut writer, value, fallthrough_next)?;
+
+  let mut jumps: Vec<ExternalJump> = Vec::new();
+  for (offset, (hole, target_label)) in wi.jumps.into_iter() {
+    match target_label.as_ref().and_then(|l| wi.blocks.get(l)) {
+      Some(target_offset) => {
+        let delta = offset_delta_i16(offset + 2, *target_offset).expect("TargetOffsetOutOfReach");
+        hole.patch(&mut writer, delta);
+      }
+      None => {
+        hole.patch(&mut writer, 0);
+        jumps.push(ExternalJump {
+          offset,
+          target: target_label,
+        });
+      }
+    }
+  }
+  jumps.sort_by_key(|j| j.offset);
+
+  let mut blocks: Vec<(CfgLabel, usize)> = wi.blocks.into_iter().collect();
+  blocks.sort_by(|l, r| l.1.cmp(&r.1).then_with(|| l.0 .0.cmp(&r.0 .0)));
+
+  Ok(SoftCfgBytes {
+    bytes: writer.complete(),
+    blocks,
+    jumps,
+  })
+}
+
+fn write_cfg(writer: &mut PatchableBufWriter, value: &cfg::Cfg) -> io::Result<Layout> {
   write_hard_cfg(writer, value, true)
 }
 
@@ -45,29 +180,47 @@ fn offset_delta_i16(source: usize, target: usize) -> Option<i16> {
   }
 }
 
-fn write_hard_cfg(writer: &mut PatchableBufWriter, value: &cfg::Cfg, append_end_action: bool) -> io::Result<()> {
-  let wi: WriteInfo = write_soft_cfg(writer, value, None)?;
+fn write_hard_cfg(writer: &mut PatchableBufWriter, value: &cfg::Cfg, append_end_action: bool) -> io::Result<Layout> {
+  let mut wi: WriteInfo = write_soft_cfg(writer, value, None)?;
   let end_offset = writer.len();
   if append_end_action {
     write_raw_action(writer, &raw::Action::End)?;
+    wi.record(end_offset..writer.len(), raw::Action::End, None, None);
   }
 
+  let mut deltas: HashMap<usize, i16> = HashMap::new();
   for (offset, (hole, target_label)) in wi.jumps.into_iter() {
     let target_offset: usize = match target_label.as_ref() {
       Some(cfg_label) => wi.blocks.get(cfg_label).cloned().expect("TargetLabelNotFound"),
       None => end_offset,
     };
+    let action_offset = offset - 3; // Size of the `If` and `Jump` action headers
     let offset = offset + 2; // Size of the offset itself inside `If` and `Jump` actions
     let delta = offset_delta_i16(offset, target_offset).expect("TargetOffsetOutOfReach");
     hole.patch(writer, delta);
+    deltas.insert(action_offset, delta);
   }
 
-  Ok(())
+  for item in wi.items.iter_mut() {
+    if let LayoutItem::Action(record) = item {
+      match record.action {
+        raw::Action::If(ref mut a) => a.offset = deltas[&record.offset],
+        raw::Action::Jump(ref mut a) => a.offset = deltas[&record.offset],
+        _ => {}
+      }
+    }
+  }
+
+  Ok(Layout {
+    size: writer.len(),
+    items: wi.items,
+  })
 }
 
 struct WriteInfo {
   jumps: HashMap<usize, (BufferHole<i16>, Option<cfg::CfgLabel>)>,
   blocks: HashMap<cfg::CfgLabel, usize>,
+  items: Vec<LayoutItem>,
 }
 
 impl WriteInfo {
@@ -75,12 +228,54 @@ impl WriteInfo {
     Self {
       jumps: HashMap::new(),
       blocks: HashMap::new(),
+      items: Vec::new(),
     }
   }
 
   pub fn extend(&mut self, wi: Self) {
-    self.jumps.extend(wi.jumps.into_iter());
-    self.blocks.extend(wi.blocks.into_iter());
+    self.jumps.extend(wi.jumps);
+    self.blocks.extend(wi.blocks);
+    self.items.extend(wi.items);
+  }
+
+  /// Extends with the blocks of a nested region spanning `range`.
+  pub fn extend_region(&mut self, wi: Self, kind: RegionKind, range: Range<usize>) {
+    self.items.push(LayoutItem::RegionStart { kind, range });
+    self.extend(wi);
+    self.items.push(LayoutItem::RegionEnd { kind });
+  }
+
+  /// Records the action written in `range`.
+  pub fn record(&mut self, range: Range<usize>, action: raw::Action, label: Option<&CfgLabel>, index: Option<usize>) {
+    self.items.push(LayoutItem::Action(ActionRecord {
+      offset: range.start,
+      size: range.end - range.start,
+      action,
+      label: label.cloned(),
+      index,
+      target: None,
+    }));
+  }
+
+  /// Records the `If` or `Jump` action whose offset hole starts at `offset`.
+  /// The jump offset is resolved with the hard CFG.
+  pub fn record_jump(
+    &mut self,
+    offset: usize,
+    hole: BufferHole<i16>,
+    action: raw::Action,
+    label: &CfgLabel,
+    target: Option<&CfgLabel>,
+  ) {
+    self.items.push(LayoutItem::Action(ActionRecord {
+      offset: offset - 3,
+      size: 5,
+      action,
+      label: Some(label.clone()),
+      index: None,
+      target: Some(JumpTarget::from(target.cloned())),
+    }));
+    self.jumps.insert(offset, (hole, target.cloned()));
   }
 }
 
@@ -109,28 +304,53 @@ fn write_block(
   fallthrough_next: Option<&cfg::CfgLabel>,
 ) -> io::Result<WriteInfo> {
   let mut res = WriteInfo::new();
+  let label = &value.label;
+  let block_start = writer.len();
 
-  res.blocks.insert(value.label.clone(), writer.len());
+  res.blocks.insert(label.clone(), block_start);
+  res.items.push(LayoutItem::Block {
+    label: label.clone(),
+    range: block_start..block_start,
+  });
 
-  for action in value.actions.iter().cloned() {
+  for (index, action) in value.actions.iter().cloned().enumerate() {
+    let start = writer.len();
     match raw::Action::try_from(action) {
-      Ok(raw) => write_raw_action(writer, &raw)?,
-      Err(FromCfgActionError::DefineFunction(action)) => write_define_function(writer, &action)?,
-      Err(FromCfgActionError::DefineFunction2(action)) => write_define_function2(writer, &action)?,
+      Ok(raw) => {
+        write_raw_action(writer, &raw)?;
+        res.record(start..writer.len(), raw, Some(label), Some(index));
+      }
+      Err(FromCfgActionError::DefineFunction(action)) => {
+        let (raw, function) = write_define_function(writer, &action)?;
+        res.record(start..function.body_offset, raw, Some(label), Some(index));
+        res.items.push(LayoutItem::Function(function));
+      }
+      Err(FromCfgActionError::DefineFunction2(action)) => {
+        let (raw, function) = write_define_function2(writer, &action)?;
+        res.record(start..function.body_offset, raw, Some(label), Some(index));
+        res.items.push(LayoutItem::Function(function));
+      }
     }
   }
 
   match &value.flow {
-    cfg::CfgFlow::Error(_) => write_error(writer)?,
+    cfg::CfgFlow::Error(_) => {
+      let start = writer.len();
+      write_error(writer)?;
+      let action = raw::Action::Error(raw::Error { error: None });
+      res.record(start..writer.len(), action, Some(label), None);
+    }
     cfg::CfgFlow::If(ref flow) => {
       let (offset, hole) = write_if(writer)?;
-      res.jumps.insert(offset, (hole, flow.true_target.clone()));
+      let action = raw::Action::If(raw::If { offset: 0 });
+      res.record_jump(offset, hole, action, label, flow.true_target.as_ref());
       if fallthrough_next != flow.false_target.as_ref() {
         if let Some(false_target) = flow.false_target.as_ref() {
           let (offset, hole) = write_jump(writer)?;
-          res.jumps.insert(offset, (hole, Some(false_target.clone())));
+          let action = raw::Action::Jump(raw::Jump { offset: 0 });
+          res.record_jump(offset, hole, action, label, Some(false_target));
         } else {
-          write_raw_action(writer, &raw::Action::End)?;
+          write_flow_action(writer, &mut res, label, raw::Action::End)?;
         }
       }
     }
@@ -138,59 +358,159 @@ fn write_block(
       if fallthrough_next != flow.next.as_ref() {
         if let Some(next) = flow.next.as_ref() {
           let (offset, hole) = write_jump(writer)?;
-          res.jumps.insert(offset, (hole, Some(next.clone())));
+          let action = raw::Action::Jump(raw::Jump { offset: 0 });
+          res.record_jump(offset, hole, action, label, Some(next));
         } else {
-          write_raw_action(writer, &raw::Action::End)?;
+          write_flow_action(writer, &mut res, label, raw::Action::End)?;
         }
       }
     }
-    cfg::CfgFlow::Return => write_raw_action(writer, &raw::Action::Return)?,
-    cfg::CfgFlow::Throw => write_raw_action(writer, &raw::Action::Throw)?,
+    cfg::CfgFlow::Return => write_flow_action(writer, &mut res, label, raw::Action::Return)?,
+    cfg::CfgFlow::Throw => write_flow_action(writer, &mut res, label, raw::Action::Throw)?,
     cfg::CfgFlow::Try(ref flow) => {
-      write_try(writer, &mut res, flow, fallthrough_next)?;
+      write_try(writer, &mut res, label, flow, fallthrough_next)?;
     }
     cfg::CfgFlow::WaitForFrame(ref flow) => {
-      write_raw_action(
+      write_flow_action(
         writer,
-        &raw::Action::WaitForFrame(raw::WaitForFrame {
+        &mut res,
+        label,
+        raw::Action::WaitForFrame(raw::WaitForFrame {
           frame: flow.frame,
           skip: 1,
         }),
       )?;
       {
         let (offset, hole) = write_jump(writer)?;
-        res.jumps.insert(offset, (hole, flow.ready_target.clone()));
+        let action = raw::Action::Jump(raw::Jump { offset: 0 });
+        res.record_jump(offset, hole, action, label, flow.ready_target.as_ref());
       }
       {
         let (offset, hole) = write_jump(writer)?;
-        res.jumps.insert(offset, (hole, flow.loading_target.clone()));
+        let action = raw::Action::Jump(raw::Jump { offset: 0 });
+        res.record_jump(offset, hole, action, label, flow.loading_target.as_ref());
       }
     }
     cfg::CfgFlow::WaitForFrame2(ref flow) => {
-      write_raw_action(writer, &raw::Action::WaitForFrame2(raw::WaitForFrame2 { skip: 1 }))?;
+      write_flow_action(
+        writer,
+        &mut res,
+        label,
+        raw::Action::WaitForFrame2(raw::WaitForFrame2 { skip: 1 }),
+      )?;
       {
         let (offset, hole) = write_jump(writer)?;
-        res.jumps.insert(offset, (hole, flow.ready_target.clone()));
+        let action = raw::Action::Jump(raw::Jump { offset: 0 });
+        res.record_jump(offset, hole, action, label, flow.ready_target.as_ref());
       }
       {
         let (offset, hole) = write_jump(writer)?;
-        res.jumps.insert(offset, (hole, flow.loading_target.clone()));
+        let action = raw::Action::Jump(raw::Jump { offset: 0 });
+        res.record_jump(offset, hole, action, label, flow.loading_target.as_ref());
       }
     }
     cfg::CfgFlow::With(ref flow) => {
-      write_with(writer, &mut res, flow, fallthrough_next)?;
+      write_with(writer, &mut res, label, flow, fallthrough_next)?;
     }
   }
 
+  if let LayoutItem::Block { ref mut range, .. } = res.items[0] {
+    range.end = writer.len();
+  }
+
   Ok(res)
 }
 
+/// Writes and records an action emitted for the flow of the block `label`.
+fn write_flow_action(
+  writer: &mut PatchableBufWriter,
+  wi: &mut WriteInfo,
+  label: &CfgLabel,
+  action: raw::Action,
+) -> io::Result<()> {
+  let start = writer.len();
+  write_raw_action(writer, &action)?;
+  wi.record(start..writer.len(), action, Some(label), None);
+  Ok(())
+}
+
 pub fn emit_raw_action(value: &raw::Action) -> io::Result<Vec<u8>> {
   let mut writer = PatchableBufWriter::new();
   write_raw_action(&mut writer, value)?;
   Ok(writer.complete())
 }
 
+/// Emits a list of raw actions, checking that the offsets and sizes they
+/// contain are consistent with the emitted bytes.
+///
+/// Jump targets, function bodies, `Try` and `With` regions must start and
+/// end on action boundaries (or at the end of the list), and `WaitForFrame`
+/// actions must not skip past the last action. Returns an `InvalidData`
+/// error otherwise.
+pub fn emit_raw_actions(value: &[raw::Action]) -> io::Result<Vec<u8>> {
+  let mut writer = PatchableBufWriter::new();
+  let mut ranges: Vec<Range<usize>> = Vec::with_capacity(value.len());
+  for (index, action) in value.iter().enumerate() {
+    if let raw::Action::Error(_) = action {
+      return Err(invalid_raw_action(
+        index,
+        0,
+        String::from("`Error` actions cannot be emitted"),
+      ));
+    }
+    let start = writer.len();
+    write_raw_action(&mut writer, action)?;
+    ranges.push(start..writer.len());
+  }
+  let bytes = writer.complete();
+
+  let is_boundary = |offset: isize| -> bool {
+    offset == bytes.len() as isize || ranges.binary_search_by_key(&offset, |r| r.start as isize).is_ok()
+  };
+  for (index, (action, range)) in value.iter().zip(ranges.iter()).enumerate() {
+    let end = range.end as isize;
+    let check = |name: &str, offset: isize| -> io::Result<()> {
+      if is_boundary(offset) {
+        Ok(())
+      } else {
+        let message = format!("{} ({}) is not on an action boundary", name, offset);
+        Err(invalid_raw_action(index, range.start, message))
+      }
+    };
+    match action {
+      raw::Action::If(a) => check("jump target", end + isize::from(a.offset))?,
+      raw::Action::Jump(a) => check("jump target", end + isize::from(a.offset))?,
+      raw::Action::DefineFunction(a) => check("function body end", end + a.body_size as isize)?,
+      raw::Action::DefineFunction2(a) => check("function body end", end + a.body_size as isize)?,
+      raw::Action::With(a) => check("`with` body end", end + a.size as isize)?,
+      raw::Action::Try(a) => {
+        let try_end = end + a.r#try as isize;
+        check("`try` body end", try_end)?;
+        let catch_end = try_end + a.catch.as_ref().map(|c| c.size).unwrap_or(0) as isize;
+        check("`catch` body end", catch_end)?;
+        check("`finally` body end", catch_end + a.finally.unwrap_or(0) as isize)?;
+      }
+      raw::Action::WaitForFrame(raw::WaitForFrame { skip, .. })
+      | raw::Action::WaitForFrame2(raw::WaitForFrame2 { skip })
+        if usize::from(*skip) >= value.len() - index =>
+      {
+        let message = format!("skip count ({}) goes past the last action", skip);
+        return Err(invalid_raw_action(index, range.start, message));
+      }
+      _ => {}
+    }
+  }
+
+  Ok(bytes)
+}
+
+fn invalid_raw_action(index: usize, offset: usize, message: String) -> io::Error {
+  io::Error::new(
+    io::ErrorKind::InvalidData,
+    format!("invalid action {} at offset {}: {}", index, offset, message),
+  )
+}
+
 fn write_raw_action(writer: &mut PatchableBufWriter, value: &raw::Action) -> io::Result<()> {
   macro_rules! raw {
     ($c: literal) => {{
@@ -524,34 +844,54 @@ fn write_action_header<W: io::Write>(writer: &mut W, value: ActionHeader) -> io:
   Ok(())
 }
 
-fn write_define_function(writer: &mut PatchableBufWriter, value: &cfg::DefineFunction) -> io::Result<()> {
+fn write_define_function(
+  writer: &mut PatchableBufWriter,
+  value: &cfg::DefineFunction,
+) -> io::Result<(raw::Action, FunctionRecord)> {
+  let offset = writer.len();
   let mut body: PatchableBufWriter = PatchableBufWriter::new();
-  write_hard_cfg(&mut body, &value.body, false)?;
-  write_raw_action(
-    writer,
-    &raw::Action::DefineFunction(Box::new(raw::DefineFunction {
-      name: value.name.clone(),
-      parameters: value.parameters.clone(),
-      body_size: body.len().try_into().unwrap(),
-    })),
-  )?;
-  writer.write_all(&body.complete())
-}
-
-fn write_define_function2(writer: &mut PatchableBufWriter, value: &cfg::DefineFunction2) -> io::Result<()> {
+  let layout = write_hard_cfg(&mut body, &value.body, false)?;
+  let action = raw::Action::DefineFunction(Box::new(raw::DefineFunction {
+    name: value.name.clone(),
+    parameters: value.parameters.clone(),
+    body_size: body.len().try_into().unwrap(),
+  }));
+  write_raw_action(writer, &action)?;
+  let body_offset = writer.len();
+  writer.write_all(&body.complete())?;
+  let function = FunctionRecord {
+    name: value.name.clone(),
+    offset,
+    body_offset,
+    body: layout,
+  };
+  Ok((action, function))
+}
+
+fn write_define_function2(
+  writer: &mut PatchableBufWriter,
+  value: &cfg::DefineFunction2,
+) -> io::Result<(raw::Action, FunctionRecord)> {
+  let offset = writer.len();
   let mut body: PatchableBufWriter = PatchableBufWriter::new();
-  write_hard_cfg(&mut body, &value.body, false)?;
-  write_raw_action(
-    writer,
-    &raw::Action::DefineFunction2(Box::new(raw::DefineFunction2 {
-      name: value.name.clone(),
-      register_count: value.register_count,
-      flags: value.flags,
-      parameters: value.parameters.clone(),
-      body_size: body.len().try_into().unwrap(),
-    })),
-  )?;
-  writer.write_all(&body.complete())
+  let layout = write_hard_cfg(&mut body, &value.body, false)?;
+  let action = raw::Action::DefineFunction2(Box::new(raw::DefineFunction2 {
+    name: value.name.clone(),
+    register_count: value.register_count,
+    flags: value.flags,
+    parameters: value.parameters.clone(),
+    body_size: body.len().try_into().unwrap(),
+  }));
+  write_raw_action(writer, &action)?;
+  let body_offset = writer.len();
+  writer.write_all(&body.complete())?;
+  let function = FunctionRecord {
+    name: value.name.clone(),
+    offset,
+    body_offset,
+    body: layout,
+  };
+  Ok((action, function))
 }
 
 fn write_error<W: io::Write>(writer: &mut W) -> io::Result<()> {
@@ -577,9 +917,11 @@ fn write_jump(writer: &mut PatchableBufWriter) -> io::Result<(usize, BufferHole<
 fn write_try(
   writer: &mut PatchableBufWriter,
   wi: &mut WriteInfo,
+  label: &CfgLabel,
   flow: &cfg::Try,
   fallthrough_next: Option<&CfgLabel>,
 ) -> io::Result<()> {
+  let start = writer.len();
   emit_u8(writer, 0x8f)?;
   let action_size_hole = writer.write_hole_le_u16();
   let action_start = writer.len();
@@ -611,6 +953,8 @@ fn write_try(
   let action_end = writer.len();
   let action_size = u16::try_from(action_end - action_start).unwrap();
   action_size_hole.patch(writer, action_size);
+  // The `Try` action is recorded once the size of its regions is known.
+  let record_index = wi.items.len();
 
   let finally_next = fallthrough_next;
   let catch_next = flow.finally.as_ref().map(|x| &x.blocks.first().label).or(finally_next);
@@ -621,14 +965,14 @@ fn write_try(
     .or(finally_next);
 
   let try_wi = write_soft_cfg(writer, &flow.r#try, try_next)?;
-  wi.extend(try_wi);
   let try_end = writer.len();
   let try_size = u16::try_from(try_end - action_end).unwrap();
   try_size_hole.patch(writer, try_size);
+  wi.extend_region(try_wi, RegionKind::Try, action_end..try_end);
 
   if let Some(catch) = flow.catch.as_ref() {
     let catch_wi = write_soft_cfg(writer, &catch.body, catch_next)?;
-    wi.extend(catch_wi);
+    wi.extend_region(catch_wi, RegionKind::Catch, try_end..writer.len());
   }
   let catch_end = writer.len();
   let catch_size = u16::try_from(catch_end - try_end).unwrap();
@@ -636,37 +980,64 @@ fn write_try(
 
   if let Some(finally) = flow.finally.as_ref() {
     let finally_wi = write_soft_cfg(writer, finally, finally_next)?;
-    wi.extend(finally_wi);
+    wi.extend_region(finally_wi, RegionKind::Finally, catch_end..writer.len());
   }
   let finally_end = writer.len();
   let finally_size = u16::try_from(finally_end - catch_end).unwrap();
   finally_size_hole.patch(writer, finally_size);
 
+  let action = raw::Action::Try(Box::new(raw::Try {
+    r#try: try_size,
+    catch: flow.catch.as_ref().map(|c| raw::CatchBlock {
+      target: c.target.clone(),
+      size: catch_size,
+    }),
+    finally: flow.finally.as_ref().map(|_| finally_size),
+  }));
+  wi.items.insert(
+    record_index,
+    LayoutItem::Action(ActionRecord {
+      offset: start,
+      size: action_end - start,
+      action,
+      label: Some(label.clone()),
+      index: None,
+      target: None,
+    }),
+  );
+
   Ok(())
 }
 
 fn write_with(
   writer: &mut PatchableBufWriter,
   wi: &mut WriteInfo,
+  label: &CfgLabel,
   flow: &cfg::With,
   fallthrough_next: Option<&CfgLabel>,
 ) -> io::Result<()> {
+  let start = writer.len();
   write_action_header(writer, ActionHeader { code: 0x94, length: 2 })?;
   let with_size_hole = writer.write_hole_le_u16();
   let body_start = writer.len();
   let with_wi = write_soft_cfg(writer, &flow.body, fallthrough_next)?;
   let body_end = writer.len();
-  with_size_hole.patch(writer, u16::try_from(body_end - body_start).unwrap());
-  wi.extend(with_wi);
+  let with_size = u16::try_from(body_end - body_start).unwrap();
+  with_size_hole.patch(writer, with_size);
+  let action = raw::Action::With(raw::With { size: with_size });
+  wi.record(start..body_start, action, Some(label), None);
+  wi.extend_region(with_wi, RegionKind::With, body_start..body_end);
   Ok(())
 }
 
 #[cfg(test)]
 mod tests {
   use super::*;
+  use crate::equivalence::hard_cfg_equivalent;
   use ::test_generator::test_resources;
-  use avm1_parser::parse_cfg;
+  use avm1_parser::{parse_action, parse_cfg};
   use avm1_types::cfg::{Cfg, CfgFlow};
+  use std::collections::HashSet;
   use std::io::Write;
   use std::path::Path;
 
@@ -675,27 +1046,8 @@ mod tests {
     use serde::Serialize;
 
     let path: &Path = Path::new(path);
-    let name_parts: Vec<&str> = path
-      .components()
-      .rev()
-      .take(2)
-      .collect::<Vec<_>>()
-      .iter()
-      .rev()
-      .map(|c| c.as_os_str().to_str().unwrap())
-      .collect();
-
-    match name_parts.join("/").as_str() {
-      "avm1-bytes/misaligned-jump" => return,
-      "samples/delta-of-dir" => return,
-      "samples/parse-data-string" => return,
-      "try/try-empty-catch-overlong-finally-err" => return,
-      "try/try-nested-return" => return,
-      "wait-for-frame/homestuck-beta2" => return,
-      "wait-for-frame/ready-increments" => return,
-      "wait-for-frame/ready-jump-increments" => return,
-      "wait-for-frame/wff2-ready-increments" => return,
-      _ => {}
+    if is_skipped_fixture(path) {
+      return;
     }
 
     let cfg_path = path.join("cfg.json");
@@ -723,136 +1075,346 @@ mod tests {
     )
   }
 
-  /// Perform a DFS on both control flow graphs at the same time, check if both
-  /// traversal go through exactly the same actions.
-  fn hard_cfg_equivalent(left: &Cfg, right: &Cfg) -> bool {
-    let left_labels = get_hard_cfg_labels(left);
-    let right_labels = get_hard_cfg_labels(right);
-    let left_id: HashMap<&CfgLabel, usize> = left_labels.into_iter().enumerate().map(|(i, l)| (l, i)).collect();
-    let right_id: HashMap<&CfgLabel, usize> = right_labels.into_iter().enumerate().map(|(i, l)| (l, i)).collect();
-
-    let label_eq = |left: Option<&CfgLabel>, right: Option<&CfgLabel>| -> bool {
-      match (left, right) {
-        (Some(l), Some(r)) => left_id.get(l) == right_id.get(r),
-        (l, r) => l == r,
-      }
-    };
-
-    soft_cfg_equivalent(left, right, &label_eq)
+  #[test_resources("../tests/avm1/[!.]*/*/")]
+  fn test_emit_cfg_with_options_fixtures(path: &str) {
+    let path: &Path = Path::new(path);
+    if is_skipped_fixture(path) {
+      return;
+    }
+    let cfg_bytes: Vec<u8> = ::std::fs::read(path.join("cfg.json")).expect("Failed to read input CFG");
+    let cfg: Cfg = ::serde_json_v8::from_slice(&cfg_bytes).expect("Failed to parse input CFG");
+    let expected = emit_cfg(&cfg).expect("Failed to convert CFG to AVM1");
+
+    let none = emit_cfg_with_options(&cfg, &EmitOptions::default()).unwrap();
+    assert_eq!(none, expected);
+    assert!(hard_cfg_equivalent(&parse_cfg(&none), &cfg));
+    for level in [opt::Level::Size, opt::Level::Speed] {
+      let optimized_cfg = opt::optimize(&cfg, level, None);
+      assert_eq!(opt::optimize(&optimized_cfg, level, None), optimized_cfg, "{:?}", level);
+      let optimized = emit_cfg_with_options(&cfg, &EmitOptions::optimize(level)).expect("Failed to emit optimized CFG");
+      assert_eq!(optimized, emit_cfg(&optimized_cfg).unwrap(), "{:?}", level);
+      assert!(optimized.len() <= expected.len(), "{:?}", level);
+      // Emitting the optimized CFG preserves its structure.
+      let mut actual_cfg = parse_cfg(&optimized);
+      let mut optimized_cfg = optimized_cfg;
+      normalize_end_jumps(&mut actual_cfg);
+      normalize_end_jumps(&mut optimized_cfg);
+      assert!(hard_cfg_equivalent(&actual_cfg, &optimized_cfg), "{:?}", level);
+    }
   }
 
-  fn soft_cfg_equivalent(
-    left: &Cfg,
-    right: &Cfg,
-    label_eq: &impl Fn(Option<&CfgLabel>, Option<&CfgLabel>) -> bool,
-  ) -> bool {
-    let left_blocks = &left.blocks;
-    let right_blocks = &right.blocks;
-    if left_blocks.len() != right_blocks.len() {
-      return false;
+  /// Replaces the jumps to empty blocks ending the hard CFG with jumps to the
+  /// `None` label, and removes these blocks from the end of `cfg`: the parser
+  /// represents the jumps to the end of the actions with such blocks.
+  fn normalize_end_jumps(cfg: &mut Cfg) {
+    let mut ends: HashSet<CfgLabel> = HashSet::new();
+    collect_end_blocks(cfg, &mut ends);
+    redirect_end_jumps(cfg, &ends);
+    if cfg.blocks.len() > 1 && ends.contains(&cfg.blocks.last().label) {
+      cfg.blocks.pop().unwrap();
     }
-    for (left_block, right_block) in left_blocks.iter().zip(right_blocks.iter()) {
-      if left_block.actions.len() != right_block.actions.len() {
-        return false;
+  }
+
+  fn collect_end_blocks(cfg: &Cfg, ends: &mut HashSet<CfgLabel>) {
+    for block in cfg.blocks.iter() {
+      if block.actions.is_empty() && block.flow == CfgFlow::Simple(cfg::Simple { next: None }) {
+        ends.insert(block.label.clone());
       }
-      if !label_eq(Some(&left_block.label), Some(&right_block.label)) {
-        return false;
+      for region in opt::regions(&block.flow) {
+        collect_end_blocks(region, ends);
       }
-      for (left_action, right_action) in left_block.actions.iter().zip(right_block.actions.iter()) {
-        if !action_equivalent(left_action, right_action) {
-          return false;
-        }
+    }
+  }
+
+  fn redirect_end_jumps(cfg: &mut Cfg, ends: &HashSet<CfgLabel>) {
+    for block in cfg.blocks.iter_mut() {
+      for body in opt::function_bodies_mut(&mut block.actions) {
+        normalize_end_jumps(body);
       }
-      let flow_eq = match (&left_block.flow, &right_block.flow) {
-        (CfgFlow::If(l), CfgFlow::If(r)) => {
-          label_eq(l.true_target.as_ref(), r.true_target.as_ref())
-            && label_eq(l.false_target.as_ref(), r.false_target.as_ref())
+      for target in opt::flow_targets_mut(&mut block.flow) {
+        if target.as_ref().map_or(false, |label| ends.contains(label)) {
+          *target = None;
         }
-        (CfgFlow::Simple(l), CfgFlow::Simple(r)) => label_eq(l.next.as_ref(), r.next.as_ref()),
-        (CfgFlow::Try(l), CfgFlow::Try(r)) => try_equivalent(l, r, label_eq),
-        (CfgFlow::WaitForFrame(l), CfgFlow::WaitForFrame(r)) => {
-          l.frame == r.frame
-            && label_eq(l.ready_target.as_ref(), r.ready_target.as_ref())
-            && label_eq(l.loading_target.as_ref(), r.loading_target.as_ref())
+      }
+      for region in opt::regions_mut(&mut block.flow) {
+        redirect_end_jumps(region, ends);
+      }
+    }
+  }
+
+  /// Checks if the fixture at `path` is not supported by the emitter tests.
+  fn is_skipped_fixture(path: &Path) -> bool {
+    let name_parts: Vec<&str> = path
+      .components()
+      .rev()
+      .take(2)
+      .collect::<Vec<_>>()
+      .iter()
+      .rev()
+      .map(|c| c.as_os_str().to_str().unwrap())
+      .collect();
+
+    matches!(
+      name_parts.join("/").as_str(),
+      "avm1-bytes/misaligned-jump"
+        | "samples/delta-of-dir"
+        | "samples/parse-data-string"
+        | "try/try-empty-catch-overlong-finally-err"
+        | "try/try-nested-return"
+        | "wait-for-frame/homestuck-beta2"
+        | "wait-for-frame/ready-increments"
+        | "wait-for-frame/ready-jump-increments"
+        | "wait-for-frame/wff2-ready-increments"
+    )
+  }
+
+  #[test_resources("../tests/avm1/[!.]*/*/")]
+  fn test_emit_cfg_with_map(path: &str) {
+    let path: &Path = Path::new(path);
+    let cfg_bytes: Vec<u8> = ::std::fs::read(path.join("cfg.json")).expect("Failed to read input CFG");
+    let cfg: Cfg = ::serde_json_v8::from_slice(&cfg_bytes).expect("Failed to parse input CFG");
+
+    let (actual_avm1, map) = emit_cfg_with_map(&cfg).expect("Failed to convert CFG to AVM1");
+    assert_eq!(actual_avm1, emit_cfg(&cfg).unwrap());
+
+    let mut actions: Vec<(usize, &CfgLabel, usize, &cfg::Action)> = Vec::new();
+    collect_actions(&cfg, 0, &mut 0, &mut actions);
+    assert_eq!(map.actions.len(), actions.len());
+    // Entries are in emission order: function body actions come between the
+    // actions surrounding their definition.
+    assert!(map.actions.windows(2).all(|w| w[0].offset < w[1].offset));
+    assert!(map.blocks.windows(2).all(|w| w[0].range.start <= w[1].range.start));
+    assert!(map.functions.windows(2).all(|w| w[0].offset < w[1].offset));
+    for (scope, label, index, action) in actions {
+      let offset = map
+        .action_offset(scope, label, index)
+        .expect("every action must be mapped");
+      let (_, actual) = parse_action(&actual_avm1[offset..]).expect("mapped offset must point to an action");
+      match (raw::Action::try_from(action.clone()), actual) {
+        (Ok(raw::Action::Raw(expected)), _) => assert_eq!(actual_avm1[offset], expected.code),
+        (Ok(expected), actual) => assert_eq!(actual, expected),
+        (Err(FromCfgActionError::DefineFunction(expected)), raw::Action::DefineFunction(actual)) => {
+          assert_eq!(actual.name, expected.name)
         }
-        (CfgFlow::WaitForFrame2(l), CfgFlow::WaitForFrame2(r)) => {
-          label_eq(l.ready_target.as_ref(), r.ready_target.as_ref())
-            && label_eq(l.loading_target.as_ref(), r.loading_target.as_ref())
+        (Err(FromCfgActionError::DefineFunction2(expected)), raw::Action::DefineFunction2(actual)) => {
+          assert_eq!(actual.name, expected.name)
         }
-        (CfgFlow::With(l), CfgFlow::With(r)) => soft_cfg_equivalent(&l.body, &r.body, label_eq),
-        (l, r) => l == r,
-      };
-      if !flow_eq {
-        return false;
+        (_, actual) => panic!("unexpected action at mapped offset: {:?}", actual),
       }
     }
 
-    true
+    for function in map.functions.iter() {
+      let (rest, actual) = parse_action(&actual_avm1[function.offset..]).expect("function offset must be mapped");
+      let body_size = match actual {
+        raw::Action::DefineFunction(a) => a.body_size,
+        raw::Action::DefineFunction2(a) => a.body_size,
+        a => panic!("unexpected action at function offset: {:?}", a),
+      };
+      assert_eq!(function.body.start, actual_avm1.len() - rest.len());
+      assert_eq!(function.body.end - function.body.start, usize::from(body_size));
+    }
   }
 
-  fn action_equivalent(left: &cfg::Action, right: &cfg::Action) -> bool {
-    match (left, right) {
-      (cfg::Action::DefineFunction(l), cfg::Action::DefineFunction(r)) => {
-        l.name == r.name && l.parameters == r.parameters && hard_cfg_equivalent(&l.body, &r.body)
+  #[test_resources("../tests/avm1/[!.]*/*/")]
+  fn test_emit_cfg_listing(path: &str) {
+    let path: &Path = Path::new(path);
+    let cfg_bytes: Vec<u8> = ::std::fs::read(path.join("cfg.json")).expect("Failed to read input CFG");
+    let cfg: Cfg = ::serde_json_v8::from_slice(&cfg_bytes).expect("Failed to parse input CFG");
+
+    let (actual_avm1, listing) = emit_cfg_listing(&cfg).expect("Failed to convert CFG to AVM1");
+    assert_eq!(actual_avm1, emit_cfg(&cfg).unwrap());
+
+    // The listed actions cover the whole output, in order.
+    let listed: Vec<u8> = listing.actions().flat_map(|a| a.bytes.iter().cloned()).collect();
+    assert_eq!(listed, actual_avm1);
+
+    for action in listing.actions() {
+      if action.mnemonic == "Error" {
+        continue;
       }
-      (cfg::Action::DefineFunction2(l), cfg::Action::DefineFunction2(r)) => {
-        l.name == r.name
-          && l.register_count == r.register_count
-          && l.flags == r.flags
-          && l.parameters == r.parameters
-          && hard_cfg_equivalent(&l.body, &r.body)
+      let (_, actual) = parse_action(&actual_avm1[action.offset..]).expect("listed offset must point to an action");
+      assert_eq!(action.mnemonic, listing::mnemonic(&actual));
+      if let Some((_, target)) = action.target {
+        let delta = match actual {
+          raw::Action::If(a) => a.offset,
+          raw::Action::Jump(a) => a.offset,
+          a => panic!("unexpected action with a jump target: {:?}", a),
+        };
+        assert_eq!(target as isize, (action.offset + 5) as isize + isize::from(delta));
       }
-      (l, r) => l == r,
     }
   }
 
-  fn try_equivalent(
-    left: &cfg::Try,
-    right: &cfg::Try,
-    label_eq: &impl Fn(Option<&CfgLabel>, Option<&CfgLabel>) -> bool,
-  ) -> bool {
-    if !soft_cfg_equivalent(&left.r#try, &right.r#try, label_eq) {
-      return false;
+  #[test_resources("../tests/avm1/[!.]*/*/")]
+  fn test_emit_raw_actions(path: &str) {
+    let path: &Path = Path::new(path);
+    let expected: Vec<u8> = ::std::fs::read(path.join("main.avm1")).expect("Failed to read input AVM1");
+
+    let mut actions: Vec<raw::Action> = Vec::new();
+    let mut input: &[u8] = &expected;
+    while !input.is_empty() {
+      let (rest, action) = parse_action(input).expect("Failed to parse action");
+      actions.push(action);
+      input = rest;
     }
-    let catch_eq = match (left.catch.as_ref(), right.catch.as_ref()) {
-      (Some(l), Some(r)) => l.target == r.target && soft_cfg_equivalent(&l.body, &r.body, label_eq),
-      (l, r) => l == r,
+
+    let actual = emit_raw_actions(&actions).expect("Failed to emit raw actions");
+    assert_eq!(actual, expected);
+  }
+
+  #[test]
+  fn test_emit_cfg_with_options() {
+    let cfg = crate::compiler::compile(
+      r#"
+        trace(1 + 2 * 3);
+        if (x) {
+          trace("a");
+          trace("shared tail");
+        } else {
+          trace("b");
+          trace("shared tail");
+        }
+      "#,
+    )
+    .unwrap();
+    let none = emit_cfg_with_options(&cfg, &EmitOptions::default()).unwrap();
+    let size = emit_cfg_with_options(&cfg, &EmitOptions::optimize(opt::Level::Size)).unwrap();
+    let speed = emit_cfg_with_options(&cfg, &EmitOptions::optimize(opt::Level::Speed)).unwrap();
+    assert_eq!(none, emit_cfg(&cfg).unwrap());
+    assert!(speed.len() < none.len());
+    assert!(size.len() < speed.len());
+
+    let cfg = crate::compiler::compile("trace(undefined + 1);").unwrap();
+    let unknown = emit_cfg_with_options(&cfg, &EmitOptions::optimize(opt::Level::Size)).unwrap();
+    let options = EmitOptions {
+      swf_version: Some(6),
+      ..EmitOptions::optimize(opt::Level::Size)
     };
-    if !catch_eq {
-      return false;
-    }
-    match (left.finally.as_ref(), right.finally.as_ref()) {
-      (Some(l), Some(r)) => soft_cfg_equivalent(l, r, label_eq),
-      (l, r) => l == r,
-    }
+    assert!(emit_cfg_with_options(&cfg, &options).unwrap().len() < unknown.len());
+  }
+
+  #[test]
+  fn test_emit_raw_actions_invalid_jump() {
+    let actions = vec![
+      raw::Action::Jump(raw::Jump { offset: 1 }),
+      raw::Action::Push(raw::Push {
+        values: vec![PushValue::String(String::from("a"))],
+      }),
+      raw::Action::End,
+    ];
+    let err = emit_raw_actions(&actions).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert_eq!(
+      err.to_string(),
+      "invalid action 0 at offset 0: jump target (6) is not on an action boundary"
+    );
+    assert!(emit_raw_actions(&actions[..1]).is_err());
+    let actions = vec![raw::Action::Jump(raw::Jump { offset: 1 }), raw::Action::Stop];
+    assert_eq!(
+      emit_raw_actions(&actions).unwrap(),
+      vec![0x99, 0x02, 0x00, 0x01, 0x00, 0x07]
+    );
+  }
+
+  #[test]
+  fn test_emit_soft_cfg() {
+    let label = |l: &str| CfgLabel(String::from(l));
+    let block = |l: &str, flow: CfgFlow| cfg::CfgBlock {
+      label: label(l),
+      actions: vec![cfg::Action::Trace],
+      flow,
+    };
+    let soft = vec![
+      block(
+        "s0",
+        CfgFlow::If(cfg::If {
+          true_target: Some(label("after")),
+          false_target: Some(label("s1")),
+        }),
+      ),
+      block("s1", CfgFlow::Simple(cfg::Simple { next: None })),
+      block(
+        "s2",
+        CfgFlow::Simple(cfg::Simple {
+          next: Some(label("after")),
+        }),
+      ),
+    ];
+    let after = block(
+      "after",
+      CfgFlow::Simple(cfg::Simple {
+        next: Some(label("s2")),
+      }),
+    );
+    let whole = Cfg {
+      blocks: vec1::Vec1::try_from_vec([soft.clone(), vec![after.clone()]].concat()).unwrap(),
+    };
+
+    let soft = emit_soft_cfg(
+      &Cfg {
+        blocks: vec1::Vec1::try_from_vec(soft).unwrap(),
+      },
+      Some(&label("after")),
+    )
+    .unwrap();
+    assert_eq!(
+      soft.blocks.iter().map(|(l, _)| l.0.as_str()).collect::<Vec<_>>(),
+      vec!["s0", "s1", "s2"]
+    );
+    assert_eq!(
+      soft.jumps.iter().map(|j| j.target.clone()).collect::<Vec<_>>(),
+      vec![Some(label("after"))]
+    );
+
+    // Surround the soft CFG with a prefix and the `after` block.
+    let mut bytes: Vec<u8> = vec![0x07];
+    let start = bytes.len();
+    bytes.extend_from_slice(&soft.bytes);
+    let after_offset = bytes.len();
+    bytes.extend_from_slice(&[0x26, 0x99, 0x02, 0x00, 0x00, 0x00]);
+    bytes.push(0x00);
+    let s2_offset = start + soft.blocks[2].1;
+    let delta = offset_delta_i16(after_offset + 6, s2_offset).unwrap();
+    bytes[after_offset + 4..after_offset + 6].copy_from_slice(&delta.to_le_bytes());
+    soft.jumps[0].patch(&mut bytes, start, after_offset).unwrap();
+
+    let mut expected = emit_cfg(&whole).unwrap();
+    expected.insert(0, 0x07);
+    assert_eq!(bytes, expected);
   }
 
-  fn get_hard_cfg_labels<'a>(hard_cfg: &'a Cfg) -> Vec<&'a CfgLabel> {
-    let mut result: Vec<&'a CfgLabel> = Vec::new();
-
-    fn visit<'a>(cfg: &'a Cfg, result: &mut Vec<&'a CfgLabel>) {
-      for block in cfg.blocks.iter() {
-        result.push(&block.label);
-        match &block.flow {
-          CfgFlow::Try(ref flow) => {
-            visit(&flow.r#try, result);
-            if let Some(catch) = &flow.catch {
-              visit(&catch.body, result);
-            }
-            if let Some(finally) = &flow.finally {
-              visit(finally, result);
-            }
+  /// Collects the actions of `cfg` with their scope, in the order used by `EmitMap`.
+  fn collect_actions<'a>(
+    cfg: &'a Cfg,
+    scope: usize,
+    last_scope: &mut usize,
+    result: &mut Vec<(usize, &'a CfgLabel, usize, &'a cfg::Action)>,
+  ) {
+    for block in cfg.blocks.iter() {
+      for (index, action) in block.actions.iter().enumerate() {
+        result.push((scope, &block.label, index, action));
+        let body = match action {
+          cfg::Action::DefineFunction(f) => &f.body,
+          cfg::Action::DefineFunction2(f) => &f.body,
+          _ => continue,
+        };
+        *last_scope += 1;
+        let body_scope = *last_scope;
+        collect_actions(body, body_scope, last_scope, result);
+      }
+      match &block.flow {
+        CfgFlow::Try(ref flow) => {
+          collect_actions(&flow.r#try, scope, last_scope, result);
+          if let Some(catch) = &flow.catch {
+            collect_actions(&catch.body, scope, last_scope, result);
           }
-          CfgFlow::With(ref flow) => {
-            visit(&flow.body, result);
+          if let Some(finally) = &flow.finally {
+            collect_actions(finally, scope, last_scope, result);
           }
-          _ => {}
         }
+        CfgFlow::With(ref flow) => collect_actions(&flow.body, scope, last_scope, result),
+        _ => {}
       }
     }
-
-    visit(hard_cfg, &mut result);
-
-    result
   }
 }
@@ -0,0 +1,447 @@
+use crate::layout::{Layout, LayoutItem};
+use crate::{JumpTarget, RegionKind};
+use avm1_types::cfg::CfgLabel;
+use avm1_types::{raw, CatchTarget, PushValue};
+use std::fmt;
+use std::ops::Range;
+
+/// Maximum number of bytes displayed for a single action.
+const MAX_DISPLAYED_BYTES: usize = 8;
+
+/// Annotated disassembly of the bytes emitted for a control flow graph.
+///
+/// The listing is built from what the emitter wrote, without parsing the
+/// output again: block labels, jump targets and region boundaries are exact.
+/// Scopes are numbered like in `EmitMap`.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Listing {
+  pub lines: Vec<ListingLine>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+  feature = "serde",
+  derive(serde::Serialize, serde::Deserialize),
+  serde(tag = "type", rename_all = "PascalCase")
+)]
+pub enum ListingLine {
+  /// Start of a block.
+  Label {
+    scope: usize,
+    label: CfgLabel,
+  },
+  Action(ListingAction),
+  /// Start of the body of a function defined by the previous action.
+  FunctionStart {
+    scope: usize,
+    name: String,
+    body: Range<usize>,
+  },
+  FunctionEnd {
+    scope: usize,
+  },
+  /// Start of a region nested in a `Try` or `With` action.
+  RegionStart {
+    scope: usize,
+    kind: RegionKind,
+    range: Range<usize>,
+  },
+  RegionEnd {
+    scope: usize,
+    kind: RegionKind,
+  },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ListingAction {
+  pub scope: usize,
+  /// Label of the block emitting this action, `None` for the final `End` action.
+  pub label: Option<CfgLabel>,
+  /// Absolute offset of the action.
+  pub offset: usize,
+  /// Bytes of the action header and data, excluding function bodies.
+  pub bytes: Vec<u8>,
+  /// Name of the `raw::Action` variant.
+  pub mnemonic: String,
+  pub operands: String,
+  /// Target of `If` and `Jump` actions, with its absolute offset.
+  pub target: Option<(JumpTarget, usize)>,
+}
+
+impl Listing {
+  pub(crate) fn from_layout(layout: &Layout, bytes: &[u8]) -> Self {
+    let mut listing = Self::default();
+    listing.add_layout(layout, bytes, 0, &mut 0, 0);
+    listing
+  }
+
+  fn add_layout(&mut self, layout: &Layout, bytes: &[u8], scope: usize, last_scope: &mut usize, base: usize) {
+    for item in layout.items.iter() {
+      match item {
+        LayoutItem::Block { label, .. } => self.lines.push(ListingLine::Label {
+          scope,
+          label: label.clone(),
+        }),
+        LayoutItem::Action(record) => {
+          let offset = base + record.offset;
+          let target = record.target.as_ref().map(|target| {
+            let delta = match &record.action {
+              raw::Action::If(a) => a.offset,
+              raw::Action::Jump(a) => a.offset,
+              _ => 0,
+            };
+            let end = offset + record.size;
+            (target.clone(), (end as isize + isize::from(delta)) as usize)
+          });
+          self.lines.push(ListingLine::Action(ListingAction {
+            scope,
+            label: record.label.clone(),
+            offset,
+            bytes: bytes[offset..(offset + record.size)].to_vec(),
+            mnemonic: mnemonic(&record.action).to_string(),
+            operands: operands(&record.action),
+            target,
+          }));
+        }
+        LayoutItem::Function(function) => {
+          *last_scope += 1;
+          let body_scope = *last_scope;
+          let body_offset = base + function.body_offset;
+          self.lines.push(ListingLine::FunctionStart {
+            scope: body_scope,
+            name: function.name.clone(),
+            body: body_offset..(body_offset + function.body.size),
+          });
+          self.add_layout(&function.body, bytes, body_scope, last_scope, body_offset);
+          self.lines.push(ListingLine::FunctionEnd { scope: body_scope });
+        }
+        LayoutItem::RegionStart { kind, range } => self.lines.push(ListingLine::RegionStart {
+          scope,
+          kind: *kind,
+          range: (base + range.start)..(base + range.end),
+        }),
+        LayoutItem::RegionEnd { kind } => self.lines.push(ListingLine::RegionEnd { scope, kind: *kind }),
+      }
+    }
+  }
+
+  /// Returns the actions of the listing, in emission order.
+  pub fn actions(&self) -> impl Iterator<Item = &ListingAction> {
+    self.lines.iter().filter_map(|line| match line {
+      ListingLine::Action(action) => Some(action),
+      _ => None,
+    })
+  }
+}
+
+impl fmt::Display for Listing {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut depth: usize = 0;
+    for line in self.lines.iter() {
+      match line {
+        ListingLine::Label { label, .. } => writeln!(f, "{:indent$}{}:", "", label.0, indent = depth * 2)?,
+        ListingLine::Action(action) => {
+          let mut hex: Vec<String> = action
+            .bytes
+            .iter()
+            .take(MAX_DISPLAYED_BYTES)
+            .map(|b| format!("{:02x}", b))
+            .collect();
+          if action.bytes.len() > MAX_DISPLAYED_BYTES {
+            hex.push(String::from(".."));
+          }
+          let mut text = format!("{:<16} {}", action.mnemonic, action.operands);
+          if let Some((target, offset)) = &action.target {
+            match target {
+              JumpTarget::Label(label) => text.push_str(&format!(" -> {} ({:04x})", label.0, offset)),
+              JumpTarget::End => text.push_str(&format!(" -> <end> ({:04x})", offset)),
+            }
+          }
+          writeln!(
+            f,
+            "{:indent$}  {:04x}  {:<26} {}",
+            "",
+            action.offset,
+            hex.join(" "),
+            text.trim_end(),
+            indent = depth * 2
+          )?;
+        }
+        ListingLine::FunctionStart { name, body, .. } => {
+          let name = if name.is_empty() { "<anonymous>" } else { name.as_str() };
+          writeln!(
+            f,
+            "{:indent$}  function {} {{ ; {:04x}..{:04x}",
+            "",
+            name,
+            body.start,
+            body.end,
+            indent = depth * 2
+          )?;
+          depth += 2;
+        }
+        ListingLine::RegionStart { kind, range, .. } => {
+          let kind = match kind {
+            RegionKind::Try => "try",
+            RegionKind::Catch => "catch",
+            RegionKind::Finally => "finally",
+            RegionKind::With => "with",
+          };
+          writeln!(
+            f,
+            "{:indent$}  {} {{ ; {:04x}..{:04x}",
+            "",
+            kind,
+            range.start,
+            range.end,
+            indent = depth * 2
+          )?;
+          depth += 2;
+        }
+        ListingLine::FunctionEnd { .. } | ListingLine::RegionEnd { .. } => {
+          depth -= 2;
+          writeln!(f, "{:indent$}  }}", "", indent = depth * 2)?;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// Returns the name of the `raw::Action` variant of `action`.
+pub(crate) fn mnemonic(action: &raw::Action) -> &'static str {
+  use raw::Action::*;
+
+  match action {
+    Add => "Add",
+    Add2 => "Add2",
+    And => "And",
+    AsciiToChar => "AsciiToChar",
+    BitAnd => "BitAnd",
+    BitOr => "BitOr",
+    BitLShift => "BitLShift",
+    BitRShift => "BitRShift",
+    BitURShift => "BitURShift",
+    BitXor => "BitXor",
+    Call => "Call",
+    CallFunction => "CallFunction",
+    CallMethod => "CallMethod",
+    CharToAscii => "CharToAscii",
+    CastOp => "CastOp",
+    CloneSprite => "CloneSprite",
+    ConstantPool(_) => "ConstantPool",
+    Decrement => "Decrement",
+    DefineFunction(_) => "DefineFunction",
+    DefineFunction2(_) => "DefineFunction2",
+    DefineLocal => "DefineLocal",
+    DefineLocal2 => "DefineLocal2",
+    Delete => "Delete",
+    Delete2 => "Delete2",
+    Divide => "Divide",
+    EndDrag => "EndDrag",
+    Enumerate => "Enumerate",
+    Enumerate2 => "Enumerate2",
+    Equals => "Equals",
+    Equals2 => "Equals2",
+    Extends => "Extends",
+    FsCommand2 => "FsCommand2",
+    GetMember => "GetMember",
+    GetProperty => "GetProperty",
+    GetTime => "GetTime",
+    GetUrl(_) => "GetUrl",
+    GetUrl2(_) => "GetUrl2",
+    GetVariable => "GetVariable",
+    GotoFrame(_) => "GotoFrame",
+    GotoFrame2(_) => "GotoFrame2",
+    GotoLabel(_) => "GotoLabel",
+    Greater => "Greater",
+    ImplementsOp => "ImplementsOp",
+    Increment => "Increment",
+    InitArray => "InitArray",
+    InitObject => "InitObject",
+    InstanceOf => "InstanceOf",
+    Less => "Less",
+    Less2 => "Less2",
+    MbAsciiToChar => "MbAsciiToChar",
+    MbCharToAscii => "MbCharToAscii",
+    MbStringExtract => "MbStringExtract",
+    MbStringLength => "MbStringLength",
+    Modulo => "Modulo",
+    Multiply => "Multiply",
+    NewMethod => "NewMethod",
+    NewObject => "NewObject",
+    NextFrame => "NextFrame",
+    Not => "Not",
+    Or => "Or",
+    Play => "Play",
+    Pop => "Pop",
+    PrevFrame => "PrevFrame",
+    Push(_) => "Push",
+    PushDuplicate => "PushDuplicate",
+    RandomNumber => "RandomNumber",
+    Raw(_) => "Raw",
+    RemoveSprite => "RemoveSprite",
+    SetMember => "SetMember",
+    SetProperty => "SetProperty",
+    SetTarget(_) => "SetTarget",
+    SetTarget2 => "SetTarget2",
+    SetVariable => "SetVariable",
+    StackSwap => "StackSwap",
+    StartDrag => "StartDrag",
+    Stop => "Stop",
+    StopSounds => "StopSounds",
+    StoreRegister(_) => "StoreRegister",
+    StrictEquals => "StrictEquals",
+    StrictMode(_) => "StrictMode",
+    StringAdd => "StringAdd",
+    StringEquals => "StringEquals",
+    StringExtract => "StringExtract",
+    StringGreater => "StringGreater",
+    StringLength => "StringLength",
+    StringLess => "StringLess",
+    Subtract => "Subtract",
+    TargetPath => "TargetPath",
+    ToInteger => "ToInteger",
+    ToNumber => "ToNumber",
+    ToString => "ToString",
+    ToggleQuality => "ToggleQuality",
+    Trace => "Trace",
+    TypeOf => "TypeOf",
+    End => "End",
+    Jump(_) => "Jump",
+    If(_) => "If",
+    Throw => "Throw",
+    Return => "Return",
+    Try(_) => "Try",
+    WaitForFrame(_) => "WaitForFrame",
+    WaitForFrame2(_) => "WaitForFrame2",
+    With(_) => "With",
+    Error(_) => "Error",
+  }
+}
+
+fn operands(action: &raw::Action) -> String {
+  use raw::Action::*;
+
+  match action {
+    ConstantPool(a) => a.pool.iter().map(|s| format!("{:?}", s)).collect::<Vec<_>>().join(", "),
+    DefineFunction(a) => format!("{}({}) size={}", a.name, a.parameters.join(", "), a.body_size),
+    DefineFunction2(a) => {
+      let parameters: Vec<String> = a
+        .parameters
+        .iter()
+        .map(|p| match p.register {
+          0 => p.name.clone(),
+          r => format!("r:{}={}", r, p.name),
+        })
+        .collect();
+      format!(
+        "{}({}) registers={} flags={:?} size={}",
+        a.name,
+        parameters.join(", "),
+        a.register_count,
+        a.flags,
+        a.body_size
+      )
+    }
+    Error(_) => String::from("<invalid push>"),
+    GetUrl(a) => format!("{:?}, {:?}", a.url, a.target),
+    GetUrl2(a) => format!(
+      "method={:?} load_target={} load_variables={}",
+      a.method, a.load_target, a.load_variables
+    ),
+    GotoFrame(a) => a.frame.to_string(),
+    GotoFrame2(a) => format!("play={} scene_bias={}", a.play, a.scene_bias),
+    GotoLabel(a) => format!("{:?}", a.label),
+    If(a) => format!("{:+}", a.offset),
+    Jump(a) => format!("{:+}", a.offset),
+    Push(a) => a.values.iter().map(push_value).collect::<Vec<_>>().join(", "),
+    Raw(a) => {
+      let data: Vec<String> = a.data.iter().map(|b| format!("{:02x}", b)).collect();
+      format!("code=0x{:02x} data=[{}]", a.code, data.join(" "))
+    }
+    SetTarget(a) => format!("{:?}", a.target_name),
+    StoreRegister(a) => format!("r:{}", a.register),
+    StrictMode(a) => a.is_strict.to_string(),
+    Try(a) => {
+      let mut result = format!("try={}", a.r#try);
+      if let Some(catch) = &a.catch {
+        let target = match &catch.target {
+          CatchTarget::Register(r) => format!("r:{}", r),
+          CatchTarget::Variable(v) => format!("{:?}", v),
+        };
+        result.push_str(&format!(" catch({})={}", target, catch.size));
+      }
+      if let Some(finally) = a.finally {
+        result.push_str(&format!(" finally={}", finally));
+      }
+      result
+    }
+    WaitForFrame(a) => format!("frame={} skip={}", a.frame, a.skip),
+    WaitForFrame2(a) => format!("skip={}", a.skip),
+    With(a) => format!("size={}", a.size),
+    _ => String::new(),
+  }
+}
+
+/// Formats a pushed value: strings are quoted, registers are written `r:1`
+/// and constants `c:1`.
+pub(crate) fn push_value(value: &PushValue) -> String {
+  match value {
+    PushValue::Boolean(v) => v.to_string(),
+    PushValue::Constant(v) => format!("c:{}", v),
+    PushValue::Float32(v) => format!("{:?}f32", v),
+    PushValue::Float64(v) => format!("{:?}", v),
+    PushValue::Null => String::from("null"),
+    PushValue::Register(v) => format!("r:{}", v),
+    PushValue::Sint32(v) => v.to_string(),
+    PushValue::String(v) => format!("{:?}", v),
+    PushValue::Undefined => String::from("undefined"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use crate::emit_cfg_listing;
+  use avm1_types::cfg::{Cfg, CfgBlock, CfgFlow, CfgLabel, If, Simple};
+  use avm1_types::{cfg, raw, PushValue};
+  use vec1::vec1;
+
+  #[test]
+  fn test_listing_display() {
+    let cfg = Cfg {
+      blocks: vec1![
+        CfgBlock {
+          label: CfgLabel(String::from("start")),
+          actions: vec![cfg::Action::Push(raw::Push {
+            values: vec![PushValue::String(String::from("a")), PushValue::Register(1)],
+          })],
+          flow: CfgFlow::If(If {
+            true_target: Some(CfgLabel(String::from("start"))),
+            false_target: None,
+          }),
+        },
+        CfgBlock {
+          label: CfgLabel(String::from("unreachable")),
+          actions: vec![cfg::Action::Trace],
+          flow: CfgFlow::Simple(Simple { next: None }),
+        },
+      ],
+    };
+    let (bytes, listing) = emit_cfg_listing(&cfg).unwrap();
+    let expected = [
+      "start:",
+      "  0000  96 05 00 00 61 00 04 01    Push             \"a\", r:1",
+      "  0008  9d 02 00 f3 ff             If               -13 -> start (0000)",
+      "  000d  00                         End",
+      "unreachable:",
+      "  000e  26                         Trace",
+      "  000f  00                         End",
+      "",
+    ];
+    assert_eq!(listing.to_string(), expected.join("\n"));
+    assert_eq!(listing.actions().last().unwrap().offset + 1, bytes.len());
+  }
+}
@@ -0,0 +1,120 @@
+use crate::layout::{ActionRecord, Layout, LayoutItem};
+use avm1_types::cfg::CfgLabel;
+use std::ops::Range;
+
+/// Correspondence between a control flow graph and the bytes emitted for it.
+///
+/// Labels are only unique inside a hard CFG, so every entry is tagged with a
+/// scope: `0` for the top-level CFG, and `i + 1` for the body of `functions[i]`.
+/// All offsets are absolute offsets in the emitted bytes, including the
+/// offsets inside nested function bodies.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EmitMap {
+  /// Block ranges, in emission order.
+  pub blocks: Vec<BlockRange>,
+  /// Offset of every action from the CFG, in emission order.
+  pub actions: Vec<ActionOffset>,
+  /// Function definitions, in emission order (parents before children).
+  pub functions: Vec<FunctionRange>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct BlockRange {
+  pub scope: usize,
+  pub label: CfgLabel,
+  /// Bytes emitted for the block, including its flow. For blocks with a `Try`
+  /// or `With` flow, this includes the nested blocks.
+  pub range: Range<usize>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ActionOffset {
+  pub scope: usize,
+  pub label: CfgLabel,
+  /// Index of the action in `CfgBlock::actions`.
+  pub index: usize,
+  pub offset: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FunctionRange {
+  /// Scope of the function body.
+  pub scope: usize,
+  /// Scope containing the function definition.
+  pub parent: usize,
+  /// Function name, empty string if anonymous.
+  pub name: String,
+  /// Offset of the `DefineFunction` or `DefineFunction2` action.
+  pub offset: usize,
+  pub body: Range<usize>,
+}
+
+impl EmitMap {
+  pub(crate) fn from_layout(layout: &Layout) -> Self {
+    let mut map = Self::default();
+    map.add_layout(layout, 0, 0);
+    map
+  }
+
+  fn add_layout(&mut self, layout: &Layout, scope: usize, base: usize) {
+    for item in layout.items.iter() {
+      match item {
+        LayoutItem::Block { label, range } => self.blocks.push(BlockRange {
+          scope,
+          label: label.clone(),
+          range: (base + range.start)..(base + range.end),
+        }),
+        LayoutItem::Action(ActionRecord {
+          offset,
+          label: Some(label),
+          index: Some(index),
+          ..
+        }) => self.actions.push(ActionOffset {
+          scope,
+          label: label.clone(),
+          index: *index,
+          offset: base + offset,
+        }),
+        LayoutItem::Function(function) => {
+          let body_offset = base + function.body_offset;
+          self.functions.push(FunctionRange {
+            scope: self.functions.len() + 1,
+            parent: scope,
+            name: function.name.clone(),
+            offset: base + function.offset,
+            body: body_offset..(body_offset + function.body.size),
+          });
+          self.add_layout(&function.body, self.functions.len(), body_offset);
+        }
+        _ => {}
+      }
+    }
+  }
+
+  /// Returns the range of the block `label` in `scope`.
+  pub fn block(&self, scope: usize, label: &CfgLabel) -> Option<&BlockRange> {
+    self.blocks.iter().find(|b| b.scope == scope && &b.label == label)
+  }
+
+  /// Returns the offset of the action `index` of the block `label` in `scope`.
+  pub fn action_offset(&self, scope: usize, label: &CfgLabel, index: usize) -> Option<usize> {
+    self
+      .actions
+      .iter()
+      .find(|a| a.scope == scope && &a.label == label && a.index == index)
+      .map(|a| a.offset)
+  }
+
+  /// Returns the innermost block containing the byte at `offset`.
+  pub fn block_at(&self, offset: usize) -> Option<&BlockRange> {
+    self
+      .blocks
+      .iter()
+      .filter(|b| b.range.contains(&offset))
+      .min_by_key(|b| b.range.end - b.range.start)
+  }
+}
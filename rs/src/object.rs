@@ -0,0 +1,317 @@
+//! Relocatable fragments of AVM1 bytecode.
+//!
+//! A fragment is a top-level CFG emitted on its own: jumps to labels defined
+//! in the fragment are resolved, jumps to other labels are left as
+//! relocations. The [`link`] function concatenates fragments, patches the
+//! relocations against the labels exported by the fragments and appends the
+//! final `End` action. Only the labels designated by the caller of
+//! [`emit_fragment`] are exported.
+//!
+//! Control falling off the end of a fragment (including jumps to the `None`
+//! label) continues with the next fragment.
+
+use crate::label::{collect_labels, fresh_label};
+use crate::opt::{flow_targets, flow_targets_mut, regions, regions_mut};
+use crate::{emit_soft_cfg, offset_delta_i16};
+use avm1_types::cfg::{Cfg, CfgLabel};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+
+/// Emitted bytes of a CFG, with their unresolved jumps.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Fragment {
+  pub bytes: Vec<u8>,
+  /// Labels defined by the fragment, sorted by offset.
+  pub exports: Vec<Symbol>,
+  /// Jumps to labels missing from the fragment, sorted by offset.
+  pub relocations: Vec<Relocation>,
+}
+
+/// Label defined at `offset` in the bytes of its fragment.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Symbol {
+  pub label: CfgLabel,
+  pub offset: usize,
+}
+
+/// `If` or `Jump` action whose `i16` offset, stored at `offset` in the bytes
+/// of its fragment, must be patched to reach `target`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Relocation {
+  pub offset: usize,
+  pub target: CfgLabel,
+}
+
+/// Error returned by [`link`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum LinkError {
+  /// The label is exported by two fragments.
+  DuplicateSymbol(CfgLabel),
+  /// A relocation targets a label exported by no fragment.
+  UndefinedSymbol(CfgLabel),
+  /// A relocation targets a label too far away for an `i16` offset.
+  OffsetOutOfReach(CfgLabel),
+}
+
+impl fmt::Display for LinkError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      LinkError::DuplicateSymbol(label) => write!(f, "duplicate symbol: {}", label.0),
+      LinkError::UndefinedSymbol(label) => write!(f, "undefined symbol: {}", label.0),
+      LinkError::OffsetOutOfReach(label) => write!(f, "symbol out of reach: {}", label.0),
+    }
+  }
+}
+
+impl std::error::Error for LinkError {}
+
+/// Emits `value` as a relocatable fragment, exporting the labels `exports`.
+///
+/// Unlike [`emit_cfg`](crate::emit_cfg), no `End` action is appended and jumps
+/// to labels missing from `value` are recorded as relocations. Jumps to the
+/// `None` label reach the end of the fragment. Labels of `value` missing from
+/// `exports` stay local to the fragment, so fragments may reuse them.
+///
+/// Returns an `InvalidInput` error if an exported label is not defined by a
+/// block of `value`.
+pub fn emit_fragment(value: &Cfg, exports: &[CfgLabel]) -> io::Result<Fragment> {
+  let mut labels: HashSet<CfgLabel> = HashSet::new();
+  collect_labels(value, &mut labels);
+  collect_targets(value, &mut labels);
+  let end = fresh_label(&mut labels, "end");
+  let mut cfg = value.clone();
+  redirect_end(&mut cfg, &end);
+
+  let soft = emit_soft_cfg(&cfg, Some(&end))?;
+  let mut bytes = soft.bytes;
+  let end_offset = bytes.len();
+  let mut relocations: Vec<Relocation> = Vec::new();
+  for jump in soft.jumps {
+    match jump.target {
+      Some(target) if target != end => relocations.push(Relocation {
+        offset: jump.offset,
+        target,
+      }),
+      _ => {
+        jump.patch(&mut bytes, 0, end_offset).expect("TargetOffsetOutOfReach");
+      }
+    }
+  }
+
+  let mut symbols: Vec<Symbol> = Vec::with_capacity(exports.len());
+  for label in exports {
+    match soft.blocks.iter().find(|(l, _)| l == label) {
+      Some((_, offset)) => symbols.push(Symbol {
+        label: label.clone(),
+        offset: *offset,
+      }),
+      None => {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidInput,
+          format!("exported label is not defined: {}", label.0),
+        ))
+      }
+    }
+  }
+  symbols.sort_by(|l, r| l.offset.cmp(&r.offset).then_with(|| l.label.0.cmp(&r.label.0)));
+  symbols.dedup();
+  Ok(Fragment {
+    bytes,
+    exports: symbols,
+    relocations,
+  })
+}
+
+/// Adds the labels targeted by the flows of `cfg` and of its nested regions
+/// to `labels`.
+fn collect_targets(cfg: &Cfg, labels: &mut HashSet<CfgLabel>) {
+  for block in cfg.blocks.iter() {
+    labels.extend(flow_targets(&block.flow).into_iter().flatten().cloned());
+    for region in regions(&block.flow) {
+      collect_targets(region, labels);
+    }
+  }
+}
+
+/// Replaces the `None` targets of the flows of `cfg` and of its nested regions
+/// with `end`.
+fn redirect_end(cfg: &mut Cfg, end: &CfgLabel) {
+  for block in cfg.blocks.iter_mut() {
+    for target in flow_targets_mut(&mut block.flow) {
+      if target.is_none() {
+        *target = Some(end.clone());
+      }
+    }
+    for region in regions_mut(&mut block.flow) {
+      redirect_end(region, end);
+    }
+  }
+}
+
+/// Concatenates `fragments` into a complete action list ending with `End`.
+pub fn link(fragments: &[Fragment]) -> Result<Vec<u8>, LinkError> {
+  let mut symbols: HashMap<&CfgLabel, usize> = HashMap::new();
+  let mut starts: Vec<usize> = Vec::with_capacity(fragments.len());
+  let mut size: usize = 0;
+  for fragment in fragments {
+    starts.push(size);
+    for symbol in fragment.exports.iter() {
+      if symbols.insert(&symbol.label, size + symbol.offset).is_some() {
+        return Err(LinkError::DuplicateSymbol(symbol.label.clone()));
+      }
+    }
+    size += fragment.bytes.len();
+  }
+
+  let mut bytes: Vec<u8> = Vec::with_capacity(size + 1);
+  for (fragment, start) in fragments.iter().zip(starts) {
+    bytes.extend_from_slice(&fragment.bytes);
+    for relocation in fragment.relocations.iter() {
+      let target_offset = match symbols.get(&relocation.target) {
+        Some(target_offset) => *target_offset,
+        None => return Err(LinkError::UndefinedSymbol(relocation.target.clone())),
+      };
+      let offset = start + relocation.offset;
+      let delta = match offset_delta_i16(offset + 2, target_offset) {
+        Some(delta) => delta,
+        None => return Err(LinkError::OffsetOutOfReach(relocation.target.clone())),
+      };
+      bytes[offset..offset + 2].copy_from_slice(&delta.to_le_bytes());
+    }
+  }
+  // `End` action
+  bytes.push(0x00);
+  Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm::parse_asm;
+  use crate::emit_cfg;
+  use avm1_types::cfg::{CfgBlock, CfgFlow};
+  use avm1_types::{action, cfg, PushValue};
+  use vec1::vec1;
+
+  fn block(label: &str, message: &str, flow: CfgFlow) -> CfgBlock {
+    CfgBlock {
+      label: CfgLabel(label.to_string()),
+      actions: vec![
+        cfg::Action::Push(action::Push {
+          values: vec![PushValue::String(message.to_string())],
+        }),
+        cfg::Action::Trace,
+      ],
+      flow,
+    }
+  }
+
+  fn label(name: &str) -> CfgLabel {
+    CfgLabel(name.to_string())
+  }
+
+  fn simple(next: Option<&str>) -> CfgFlow {
+    CfgFlow::Simple(cfg::Simple {
+      next: next.map(|l| CfgLabel(l.to_string())),
+    })
+  }
+
+  fn branch(true_target: &str, false_target: &str) -> CfgFlow {
+    CfgFlow::If(cfg::If {
+      true_target: Some(CfgLabel(true_target.to_string())),
+      false_target: Some(CfgLabel(false_target.to_string())),
+    })
+  }
+
+  #[test]
+  fn link_matches_whole_emission() {
+    let first = Cfg {
+      blocks: vec1![block("a0", "a0", branch("b1", "a1")), block("a1", "a1", simple(None))],
+    };
+    let second = Cfg {
+      blocks: vec1![
+        block("b0", "b0", simple(Some("b1"))),
+        block("b1", "b1", branch("a0", "b2")),
+        block("b2", "b2", simple(None))
+      ],
+    };
+    let whole = Cfg {
+      blocks: vec1![
+        block("a0", "a0", branch("b1", "a1")),
+        block("a1", "a1", simple(Some("b0"))),
+        block("b0", "b0", simple(Some("b1"))),
+        block("b1", "b1", branch("a0", "b2")),
+        block("b2", "b2", simple(None)),
+      ],
+    };
+
+    let first = emit_fragment(&first, &[label("a0")]).unwrap();
+    let second = emit_fragment(&second, &[label("b1")]).unwrap();
+    assert_eq!(
+      first
+        .relocations
+        .iter()
+        .map(|r| r.target.0.as_str())
+        .collect::<Vec<_>>(),
+      vec!["b1"]
+    );
+    assert_eq!(
+      second.exports.iter().map(|s| s.label.0.as_str()).collect::<Vec<_>>(),
+      vec!["b1"]
+    );
+    assert_eq!(link(&[first, second]).unwrap(), emit_cfg(&whole).unwrap());
+  }
+
+  #[test]
+  fn link_asm_fragments() {
+    // Both fragments use the generated labels of `parse_asm`, which are not
+    // exported. Jumps to `end` continue with the next fragment.
+    let first = r#"
+        Push true
+        If end
+        Push "a"
+        Trace
+        Jump end
+      skip:
+        Push "b"
+        Trace
+    "#;
+    let second = r#"
+        Push "c"
+        Trace
+    "#;
+    let whole = format!("{}  next:\n{}", first.replace("end", "next"), second);
+
+    let first = emit_fragment(&parse_asm(first).unwrap(), &[]).unwrap();
+    let second = emit_fragment(&parse_asm(second).unwrap(), &[]).unwrap();
+    assert!(first.relocations.is_empty());
+    assert_eq!(
+      link(&[first, second]).unwrap(),
+      emit_cfg(&parse_asm(&whole).unwrap()).unwrap()
+    );
+  }
+
+  #[test]
+  fn link_errors() {
+    let first = Cfg {
+      blocks: vec1![block("a0", "a0", simple(Some("missing")))],
+    };
+    assert_eq!(
+      emit_fragment(&first, &[label("missing")]).unwrap_err().kind(),
+      io::ErrorKind::InvalidInput
+    );
+    let first = emit_fragment(&first, &[label("a0")]).unwrap();
+    assert_eq!(
+      link(std::slice::from_ref(&first)),
+      Err(LinkError::UndefinedSymbol(CfgLabel(String::from("missing"))))
+    );
+    assert_eq!(
+      link(&[first.clone(), first]),
+      Err(LinkError::DuplicateSymbol(CfgLabel(String::from("a0"))))
+    );
+  }
+}
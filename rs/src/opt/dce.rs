@@ -0,0 +1,176 @@
+//! Dead block and unreachable code elimination.
+
+use crate::opt::thread::thread_hard_cfg;
+use crate::opt::{flow_targets, function_bodies_mut, regions, regions_mut};
+use avm1_types::cfg::{Cfg, CfgBlock, CfgFlow, CfgLabel};
+use std::collections::{HashMap, HashSet};
+
+/// Summary of the changes made by [`eliminate_dead_code`].
+///
+/// Labels are only unique inside a hard CFG: blocks removed from different
+/// function bodies may have the same label.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeadCodeReport {
+  /// Removed blocks that no flow reaches, in emission order.
+  pub unreachable: Vec<CfgLabel>,
+  /// Removed blocks without actions, whose flows now skip them.
+  pub pass_through: Vec<CfgLabel>,
+}
+
+/// Returns a copy of `value` without the blocks that are never executed.
+///
+/// Flows first skip the blocks without actions and with a `Simple` flow, when
+/// the target of the skipped block is in the same region. The blocks reachable
+/// from the entry block are then kept: a `Try` or `With` flow reaches the first
+/// block of each of its regions (including the `catch` and `finally` bodies),
+/// other flows reach their targets. The first block of a region is removed
+/// with its region. Functions defined by the kept blocks are processed the
+/// same way.
+pub fn eliminate_dead_code(value: &Cfg) -> (Cfg, DeadCodeReport) {
+  let mut cfg = value.clone();
+  let mut report = DeadCodeReport::default();
+  eliminate_hard_cfg(&mut cfg, &mut report);
+  (cfg, report)
+}
+
+fn eliminate_hard_cfg(cfg: &mut Cfg, report: &mut DeadCodeReport) {
+  thread_hard_cfg(cfg);
+
+  let mut reachable: HashSet<CfgLabel> = HashSet::new();
+  let mut stack: Vec<&CfgLabel> = vec![&cfg.blocks.first().label];
+  let by_label: HashMap<&CfgLabel, &CfgBlock> = {
+    let mut by_label = HashMap::new();
+    collect_blocks(cfg, &mut by_label);
+    by_label
+  };
+  while let Some(label) = stack.pop() {
+    if !reachable.insert(label.clone()) {
+      continue;
+    }
+    let block = by_label[label];
+    stack.extend(
+      flow_targets(&block.flow)
+        .into_iter()
+        .flatten()
+        .filter(|l| by_label.contains_key(l)),
+    );
+    stack.extend(regions(&block.flow).into_iter().map(|r| &r.blocks.first().label));
+  }
+
+  remove_unreachable(cfg, &reachable, report);
+}
+
+fn remove_unreachable(cfg: &mut Cfg, reachable: &HashSet<CfgLabel>, report: &mut DeadCodeReport) {
+  for block in cfg.blocks.iter().filter(|b| !reachable.contains(&b.label)) {
+    match block.flow {
+      CfgFlow::Simple(_) if block.actions.is_empty() => report.pass_through.push(block.label.clone()),
+      _ => report.unreachable.push(block.label.clone()),
+    }
+  }
+  cfg
+    .blocks
+    .retain(|b| reachable.contains(&b.label))
+    .expect("the first block of a reachable CFG is reachable");
+  for block in cfg.blocks.iter_mut() {
+    for body in function_bodies_mut(&mut block.actions) {
+      eliminate_hard_cfg(body, report);
+    }
+    for region in regions_mut(&mut block.flow) {
+      remove_unreachable(region, reachable, report);
+    }
+  }
+}
+
+fn collect_blocks<'a>(cfg: &'a Cfg, by_label: &mut HashMap<&'a CfgLabel, &'a CfgBlock>) {
+  for block in cfg.blocks.iter() {
+    by_label.insert(&block.label, block);
+    for nested in regions(&block.flow) {
+      collect_blocks(nested, by_label);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm::parse_asm;
+  use crate::emit_cfg;
+
+  fn labels(labels: &[&str]) -> Vec<CfgLabel> {
+    labels.iter().map(|l| CfgLabel(l.to_string())).collect()
+  }
+
+  #[test]
+  fn removes_unreachable_and_pass_through_blocks() {
+    let cfg = parse_asm(
+      r#"
+        Push "x"
+        GetVariable
+        If a
+        Jump done
+      dead:
+        Push "dead"
+        Trace
+      a:
+      b:
+        Push "b"
+        Trace
+        Jump a
+      done:
+        Stop
+      "#,
+    )
+    .unwrap();
+    let (optimized, report) = eliminate_dead_code(&cfg);
+    assert_eq!(report.unreachable, labels(&["dead"]));
+    // The block of `Jump done` is skipped too.
+    assert_eq!(
+      report.pass_through,
+      vec![cfg.blocks[1].label.clone(), CfgLabel(String::from("a"))]
+    );
+    assert_eq!(
+      optimized.blocks.iter().map(|b| b.label.0.as_str()).collect::<Vec<_>>(),
+      vec![cfg.blocks.first().label.0.as_str(), "b", "done"]
+    );
+    assert!(emit_cfg(&optimized).unwrap().len() < emit_cfg(&cfg).unwrap().len());
+  }
+
+  #[test]
+  fn keeps_regions_and_frame_targets() {
+    let cfg = parse_asm(
+      r#"
+        WaitForFrame frame=1 loading=loading
+        try {
+          Push "try"
+          Throw
+        } catch(r:0) {
+          Push r:0
+          Trace
+        } finally {
+        empty:
+          Jump done
+        }
+      dead:
+        Stop
+      loading:
+        Push "loading"
+        Trace
+      done:
+        function () {
+          Jump end
+        unused:
+          Push "unused"
+          Trace
+        }
+      "#,
+    )
+    .unwrap();
+    let (optimized, report) = eliminate_dead_code(&cfg);
+    assert_eq!(report.unreachable, labels(&["dead", "unused"]));
+    assert_eq!(report.pass_through, labels(&[]));
+    let (again, report) = eliminate_dead_code(&optimized);
+    assert_eq!(report, DeadCodeReport::default());
+    assert_eq!(again, optimized);
+  }
+}
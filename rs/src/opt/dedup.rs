@@ -0,0 +1,316 @@
+//! Deduplication of identical function definitions.
+
+use crate::equivalence::function_equivalent;
+use crate::opt::{actions_size, regions};
+use avm1_types::cfg::{self, Cfg, CfgFlow};
+use avm1_types::raw::Push;
+use avm1_types::PushValue;
+use std::collections::{HashMap, HashSet};
+
+/// Summary of the changes made by [`deduplicate_functions`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DedupReport {
+  /// Variables holding a shared function, in definition order.
+  pub variables: Vec<String>,
+  /// Number of definitions replaced by a reference to a shared function,
+  /// without the definitions kept for each shared function.
+  pub replaced_definitions: usize,
+  /// Number of bytes saved by the rewrite.
+  pub bytes_saved: usize,
+}
+
+/// Returns a copy of `value` where identical anonymous function definitions
+/// share a single function object.
+///
+/// Two definitions are identical if they have the same parameters (and flags
+/// and register count for `DefineFunction2`) and their bodies only differ by
+/// their labels. The first definition of a shared function is kept and also
+/// stores the function in the variable `"{variable_prefix}{n}"`; the other
+/// definitions read this variable instead. Functions are only shared when it
+/// makes the emitted bytes smaller.
+///
+/// Only definitions in the top-level code, outside of `With` regions, are
+/// considered: their scope chain is the same wherever they are defined, so
+/// the shared function resolves variables the same way. This does not hold
+/// if the top-level code changes the current target (`SetTarget` and
+/// `SetTarget2`), which also changes where the shared variables are stored:
+/// such code is returned unchanged. The first definition
+/// must be in the entry block, so it runs before the definitions it replaces.
+/// Named definitions are kept as is, as they bind distinct functions to their
+/// names. Identity changes though: the former anonymous copies are now the
+/// same object.
+///
+/// `n` skips the variables whose name appears as a string in `value`, but
+/// names built at runtime may still collide with the shared variables.
+pub fn deduplicate_functions(value: &Cfg, variable_prefix: &str) -> (Cfg, DedupReport) {
+  let mut report = DedupReport::default();
+  if sets_target(value) {
+    return (value.clone(), report);
+  }
+
+  let mut definitions: Vec<&cfg::Action> = Vec::new();
+  collect_definitions(value, &mut definitions);
+  let entry_definitions = value.blocks.first().actions.iter().filter(|a| is_definition(a)).count();
+  let mut groups: Vec<Vec<usize>> = Vec::new();
+  for (i, definition) in definitions.iter().enumerate() {
+    match groups
+      .iter_mut()
+      .find(|g| function_equivalent(definitions[g[0]], definition))
+    {
+      Some(group) => group.push(i),
+      None => groups.push(vec![i]),
+    }
+  }
+
+  let mut strings: HashSet<String> = HashSet::new();
+  collect_strings(value, &mut strings);
+  let mut next_variable: usize = 0;
+
+  let mut replacements: HashMap<usize, Vec<cfg::Action>> = HashMap::new();
+  for group in groups.iter().filter(|g| g.len() > 1 && g[0] < entry_definitions) {
+    let mut variable = format!("{}{}", variable_prefix, next_variable);
+    while strings.contains(&variable) {
+      next_variable += 1;
+      variable = format!("{}{}", variable_prefix, next_variable);
+    }
+    let definition = definitions[group[0]].clone();
+    let shared = vec![
+      definition.clone(),
+      cfg::Action::PushDuplicate,
+      push(PushValue::String(variable.clone())),
+      cfg::Action::StackSwap,
+      cfg::Action::SetVariable,
+    ];
+    let reference = vec![push(PushValue::String(variable.clone())), cfg::Action::GetVariable];
+
+    let definition_size = size(&[definition]) as isize;
+    let saved: isize = (group.len() as isize - 1) * (definition_size - size(&reference) as isize)
+      - (size(&shared) as isize - definition_size);
+    if saved <= 0 {
+      continue;
+    }
+
+    next_variable += 1;
+    report.variables.push(variable);
+    report.replaced_definitions += group.len() - 1;
+    report.bytes_saved += saved as usize;
+    replacements.insert(group[0], shared);
+    replacements.extend(group[1..].iter().map(|&i| (i, reference.clone())));
+  }
+
+  let mut cfg = value.clone();
+  if !replacements.is_empty() {
+    rewrite_definitions(&mut cfg, &mut 0, &mut replacements);
+  }
+  (cfg, report)
+}
+
+/// Returns the size of `actions`, using the maximum size if it cannot be
+/// emitted so the rewrite is never considered beneficial.
+fn size(actions: &[cfg::Action]) -> usize {
+  actions_size(actions).unwrap_or(u16::MAX.into())
+}
+
+/// Collects the function definitions that may be shared, in emission order.
+fn collect_definitions<'a>(cfg: &'a Cfg, result: &mut Vec<&'a cfg::Action>) {
+  for block in cfg.blocks.iter() {
+    result.extend(block.actions.iter().filter(|a| is_definition(a)));
+    if let CfgFlow::Try(ref flow) = block.flow {
+      collect_definitions(&flow.r#try, result);
+      if let Some(catch) = flow.catch.as_ref() {
+        collect_definitions(&catch.body, result);
+      }
+      if let Some(finally) = flow.finally.as_ref() {
+        collect_definitions(finally, result);
+      }
+    }
+  }
+}
+
+/// Replaces the definitions in the same order as `collect_definitions`.
+fn rewrite_definitions(cfg: &mut Cfg, index: &mut usize, replacements: &mut HashMap<usize, Vec<cfg::Action>>) {
+  for block in cfg.blocks.iter_mut() {
+    let actions = std::mem::take(&mut block.actions);
+    for action in actions {
+      if !is_definition(&action) {
+        block.actions.push(action);
+        continue;
+      }
+      match replacements.remove(index) {
+        Some(replacement) => block.actions.extend(replacement),
+        None => block.actions.push(action),
+      }
+      *index += 1;
+    }
+    if let CfgFlow::Try(ref mut flow) = block.flow {
+      rewrite_definitions(&mut flow.r#try, index, replacements);
+      if let Some(catch) = flow.catch.as_mut() {
+        rewrite_definitions(&mut catch.body, index, replacements);
+      }
+      if let Some(finally) = flow.finally.as_mut() {
+        rewrite_definitions(finally, index, replacements);
+      }
+    }
+  }
+}
+
+/// Checks if `cfg` or its regions change the current target, without looking
+/// into function bodies.
+fn sets_target(cfg: &Cfg) -> bool {
+  cfg.blocks.iter().any(|block| {
+    block
+      .actions
+      .iter()
+      .any(|action| matches!(action, cfg::Action::SetTarget(_) | cfg::Action::SetTarget2))
+      || regions(&block.flow).into_iter().any(sets_target)
+  })
+}
+
+/// Checks if `action` defines an anonymous function.
+fn is_definition(action: &cfg::Action) -> bool {
+  match action {
+    cfg::Action::DefineFunction(f) => f.name.is_empty(),
+    cfg::Action::DefineFunction2(f) => f.name.is_empty(),
+    _ => false,
+  }
+}
+
+/// Collects the pushed strings and constants of `cfg`, including its regions
+/// and function bodies.
+fn collect_strings(cfg: &Cfg, result: &mut HashSet<String>) {
+  for block in cfg.blocks.iter() {
+    for action in block.actions.iter() {
+      match action {
+        cfg::Action::Push(push) => {
+          result.extend(push.values.iter().filter_map(|v| match v {
+            PushValue::String(s) => Some(s.clone()),
+            _ => None,
+          }));
+        }
+        cfg::Action::ConstantPool(pool) => result.extend(pool.pool.iter().cloned()),
+        cfg::Action::DefineFunction(f) => collect_strings(&f.body, result),
+        cfg::Action::DefineFunction2(f) => collect_strings(&f.body, result),
+        _ => {}
+      }
+    }
+    for region in regions(&block.flow) {
+      collect_strings(region, result);
+    }
+  }
+}
+
+fn push(value: PushValue) -> cfg::Action {
+  cfg::Action::Push(Push { values: vec![value] })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm::parse_asm;
+  use crate::compiler::compile;
+  use crate::emit_cfg;
+
+  fn count_definitions(cfg: &Cfg) -> usize {
+    let mut definitions = Vec::new();
+    collect_definitions(cfg, &mut definitions);
+    definitions.len()
+  }
+
+  #[test]
+  fn shares_identical_handlers() {
+    let source = r#"
+      a.onPress = function () { if (x) { trace("hello"); } else { trace("world"); } };
+      b.onPress = function () { if (x) { trace("hello"); } else { trace("world"); } };
+      c.onPress = function () { if (x) { trace("hello"); } else { trace("world"); } };
+      d.onPress = function (y) { if (x) { trace("hello"); } else { trace("world"); } };
+      trace("__f0");
+    "#;
+    let cfg = compile(source).unwrap();
+    let (optimized, report) = deduplicate_functions(&cfg, "__f");
+
+    // `__f0` is already used by the source.
+    assert_eq!(report.variables, vec![String::from("__f1")]);
+    assert_eq!(report.replaced_definitions, 2);
+    // The shared definition and the function with a different parameter.
+    assert_eq!(count_definitions(&optimized), 2);
+    let before = emit_cfg(&cfg).unwrap();
+    let after = emit_cfg(&optimized).unwrap();
+    assert_eq!(before.len() - after.len(), report.bytes_saved);
+  }
+
+  #[test]
+  fn keeps_named_unprofitable_and_scoped_definitions() {
+    let source = r#"
+      function A() { if (x) { trace("hello"); } else { trace("world"); } }
+      function B() { if (x) { trace("hello"); } else { trace("world"); } }
+    "#;
+    let cfg = compile(source).unwrap();
+    let (optimized, report) = deduplicate_functions(&cfg, "__f");
+    assert_eq!(report, DedupReport::default());
+    assert_eq!(optimized, cfg);
+
+    let cfg = compile("a.onPress = function () {}; b.onPress = function () {};").unwrap();
+    let (optimized, report) = deduplicate_functions(&cfg, "__f");
+    assert_eq!(report, DedupReport::default());
+    assert_eq!(optimized, cfg);
+
+    // The first definition does not run before the second one.
+    let source = r#"
+        Push true
+        If second
+        function () {
+          Push "long enough to be worth sharing"
+          Trace
+        }
+        Pop
+      second:
+        function () {
+          Push "long enough to be worth sharing"
+          Trace
+        }
+        Pop
+    "#;
+    let cfg = parse_asm(source).unwrap();
+    let (optimized, report) = deduplicate_functions(&cfg, "__f");
+    assert_eq!(report, DedupReport::default());
+    assert_eq!(optimized, cfg);
+
+    // The second definition runs in the scope of another target.
+    let source = r#"
+      function () {
+        Push "long enough to be worth sharing"
+        Trace
+      }
+      Pop
+      SetTarget "/mc"
+      function () {
+        Push "long enough to be worth sharing"
+        Trace
+      }
+      Pop
+      SetTarget ""
+    "#;
+    let cfg = parse_asm(source).unwrap();
+    let (optimized, report) = deduplicate_functions(&cfg, "__f");
+    assert_eq!(report, DedupReport::default());
+    assert_eq!(optimized, cfg);
+
+    let source = r#"
+      with {
+        function () {
+          Push "long enough to be worth sharing"
+          Trace
+        }
+        function () {
+          Push "long enough to be worth sharing"
+          Trace
+        }
+      }
+    "#;
+    let cfg = parse_asm(source).unwrap();
+    let (optimized, report) = deduplicate_functions(&cfg, "__f");
+    assert_eq!(report, DedupReport::default());
+    assert_eq!(optimized, cfg);
+  }
+}
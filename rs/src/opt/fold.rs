@@ -0,0 +1,581 @@
+//! Constant folding following the AVM1 conversion rules.
+//!
+//! The conversions of AVM1 depend on the SWF version of the movie running the
+//! code:
+//! - `undefined` and `null` convert to `0` before SWF 7, to `NaN` since;
+//! - `undefined` converts to `""` before SWF 7, to `"undefined"` since;
+//! - strings are truthy when they convert to a non-zero number before SWF 7,
+//!   when they are not empty since;
+//! - the SWF 4 actions (`Not`, `Less`, `Equals`, `And`, ...) push `1` or `0`
+//!   before SWF 5, booleans since;
+//! - `Divide` by zero pushes `"#ERROR#"` before SWF 5.
+//!
+//! Without a target version, only the operations whose result is the same in
+//! every version are folded.
+
+use crate::opt::{function_bodies_mut, number, number_value, push_fits, regions_mut};
+use avm1_types::cfg::{self, Cfg};
+use avm1_types::raw::Push;
+use avm1_types::PushValue;
+
+/// Options of [`fold_constants`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FoldOptions {
+  /// Version of the SWF movie running the code, if known.
+  pub swf_version: Option<u8>,
+}
+
+/// Summary of the changes made by [`fold_constants`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FoldReport {
+  /// Number of operations replaced by their result.
+  pub folded: usize,
+}
+
+/// Returns a copy of `value` where operations on pushed constants are
+/// replaced by their result.
+///
+/// Arithmetic, bitwise, comparison, logical and string operations are folded
+/// when all their operands are pushed constants (not registers nor constant
+/// pool entries) and their result does not depend on an unknown SWF version.
+/// Adjacent `Push` actions holding the operands are merged, unless the merged
+/// `Push` is too long to be emitted. Only plain decimal
+/// strings (such as `"-1.5e3"`) are converted to numbers, and only integers,
+/// simple decimals and non-finite numbers are converted to strings: other
+/// conversions are left to the player.
+pub fn fold_constants(value: &Cfg, options: &FoldOptions) -> (Cfg, FoldReport) {
+  let mut cfg = value.clone();
+  let mut report = FoldReport::default();
+  fold_cfg(&mut cfg, options.swf_version, &mut report);
+  (cfg, report)
+}
+
+fn fold_cfg(cfg: &mut Cfg, version: Option<u8>, report: &mut FoldReport) {
+  for block in cfg.blocks.iter_mut() {
+    fold_actions(&mut block.actions, version, report);
+    for body in function_bodies_mut(&mut block.actions) {
+      fold_cfg(body, version, report);
+    }
+    for region in regions_mut(&mut block.flow) {
+      fold_cfg(region, version, report);
+    }
+  }
+}
+
+fn fold_actions(actions: &mut Vec<cfg::Action>, version: Option<u8>, report: &mut FoldReport) {
+  let mut i: usize = 1;
+  while i < actions.len() {
+    match fold_at(actions, i, version) {
+      Some(push) => {
+        report.folded += 1;
+        // The result may be the operand of the next action.
+        i = push + 1;
+      }
+      None => i += 1,
+    }
+  }
+}
+
+/// Folds the operation `actions[i]` if its operands are pushed by the
+/// previous actions, and returns the index of the `Push` holding the result.
+fn fold_at(actions: &mut Vec<cfg::Action>, i: usize, version: Option<u8>) -> Option<usize> {
+  let arity = arity(&actions[i])?;
+  let mut push = i - 1;
+  let mut count = push_len(&actions[push])?;
+  while count < arity && push > 0 {
+    match push_len(&actions[push - 1]) {
+      Some(len) => {
+        push -= 1;
+        count += len;
+      }
+      None => break,
+    }
+  }
+  if count < arity {
+    return None;
+  }
+  let operands: Vec<PushValue> = actions[push..i]
+    .iter()
+    .flat_map(|a| match a {
+      cfg::Action::Push(p) => p.values.clone(),
+      _ => Vec::new(),
+    })
+    .collect();
+  let result = evaluate(&actions[i], &operands[operands.len() - arity..], version)?;
+  let mut values = operands;
+  values.truncate(values.len() - arity);
+  values.push(result);
+  if !push_fits(&values) {
+    return None;
+  }
+  actions.splice(push..=i, [cfg::Action::Push(Push { values })]);
+  Some(push)
+}
+
+fn push_len(action: &cfg::Action) -> Option<usize> {
+  match action {
+    cfg::Action::Push(p) => Some(p.values.len()),
+    _ => None,
+  }
+}
+
+fn arity(action: &cfg::Action) -> Option<usize> {
+  use cfg::Action::*;
+  match action {
+    Not | ToNumber | ToString | TypeOf | Increment | Decrement | StringLength => Some(1),
+    Add | Add2 | Subtract | Multiply | Divide | Modulo | BitAnd | BitOr | BitXor | BitLShift | BitRShift
+    | BitURShift | Less | Less2 | Greater | Equals | Equals2 | StrictEquals | And | Or | StringAdd | StringEquals
+    | StringLess | StringGreater => Some(2),
+    _ => None,
+  }
+}
+
+/// Returns the result of `action` on `operands`, or `None` if it cannot be
+/// computed without running the code or knowing the SWF version.
+pub(crate) fn evaluate(action: &cfg::Action, operands: &[PushValue], version: Option<u8>) -> Option<PushValue> {
+  use cfg::Action::*;
+  let value = |i: usize| -> Option<Value> { Value::from_push(&operands[i]) };
+  let num = |i: usize| -> Option<f64> { value(i)?.to_number(version) };
+  let string = |i: usize| -> Option<String> { value(i)?.to_string(version) };
+  let int = |i: usize| -> Option<i32> { Some(to_int32(num(i)?)) };
+  let result = match action {
+    Not => swf4_boolean(!value(0)?.to_boolean(version)?, version)?,
+    ToNumber => number_value(num(0)?),
+    ToString => PushValue::String(string(0)?),
+    TypeOf => PushValue::String(String::from(value(0)?.type_of())),
+    Increment => number_value(num(0)? + 1.0),
+    Decrement => number_value(num(0)? - 1.0),
+    StringLength => {
+      let s = string(0)?;
+      // Before SWF 6, the length is counted in bytes.
+      if !s.is_ascii() {
+        return None;
+      }
+      PushValue::Sint32(i32::try_from(s.len()).ok()?)
+    }
+    Add | Subtract | Multiply | Modulo => {
+      let (left, right) = (num(0)?, num(1)?);
+      number_value(match action {
+        Add => left + right,
+        Subtract => left - right,
+        Multiply => left * right,
+        _ => left % right,
+      })
+    }
+    Divide => {
+      let (left, right) = (num(0)?, num(1)?);
+      if right == 0.0 && version? < 5 {
+        PushValue::String(String::from("#ERROR#"))
+      } else {
+        number_value(left / right)
+      }
+    }
+    Add2 => {
+      let (left, right) = (value(0)?, value(1)?);
+      if matches!(left, Value::String(_)) || matches!(right, Value::String(_)) {
+        PushValue::String(left.to_string(version)? + &right.to_string(version)?)
+      } else {
+        number_value(left.to_number(version)? + right.to_number(version)?)
+      }
+    }
+    BitAnd => PushValue::Sint32(int(0)? & int(1)?),
+    BitOr => PushValue::Sint32(int(0)? | int(1)?),
+    BitXor => PushValue::Sint32(int(0)? ^ int(1)?),
+    BitLShift => PushValue::Sint32(int(0)?.wrapping_shl(int(1)? as u32 & 31)),
+    BitRShift => PushValue::Sint32(int(0)?.wrapping_shr(int(1)? as u32 & 31)),
+    BitURShift => number_value(f64::from((int(0)? as u32).wrapping_shr(int(1)? as u32 & 31))),
+    Less | Equals => {
+      let (left, right) = (num(0)?, num(1)?);
+      if left.is_nan() || right.is_nan() {
+        return None;
+      }
+      swf4_boolean(
+        if matches!(action, Less) {
+          left < right
+        } else {
+          left == right
+        },
+        version,
+      )?
+    }
+    Less2 => compare(value(0)?, value(1)?, version)?,
+    Greater => compare(value(1)?, value(0)?, version)?,
+    StrictEquals => PushValue::Boolean(value(0)?.strict_equals(&value(1)?)),
+    Equals2 => PushValue::Boolean(value(0)?.loose_equals(&value(1)?, version)?),
+    And => swf4_boolean(
+      value(0)?.to_boolean(version)? && value(1)?.to_boolean(version)?,
+      version,
+    )?,
+    Or => swf4_boolean(
+      value(0)?.to_boolean(version)? || value(1)?.to_boolean(version)?,
+      version,
+    )?,
+    StringAdd => PushValue::String(string(0)? + &string(1)?),
+    StringEquals => swf4_boolean(string(0)? == string(1)?, version)?,
+    StringLess => swf4_boolean(utf16_less(&string(0)?, &string(1)?), version)?,
+    StringGreater => PushValue::Boolean(utf16_less(&string(1)?, &string(0)?)),
+    _ => return None,
+  };
+  Some(result)
+}
+
+/// Constant operand of a folded operation.
+#[derive(Clone, Debug, PartialEq)]
+enum Value {
+  Undefined,
+  Null,
+  Boolean(bool),
+  Number(f64),
+  String(String),
+}
+
+impl Value {
+  fn from_push(value: &PushValue) -> Option<Self> {
+    match value {
+      PushValue::Undefined => Some(Value::Undefined),
+      PushValue::Null => Some(Value::Null),
+      PushValue::Boolean(v) => Some(Value::Boolean(*v)),
+      PushValue::String(v) => Some(Value::String(v.clone())),
+      v => number(v).map(Value::Number),
+    }
+  }
+
+  fn to_number(&self, version: Option<u8>) -> Option<f64> {
+    match self {
+      Value::Undefined | Value::Null => Some(if version? >= 7 { f64::NAN } else { 0.0 }),
+      Value::Boolean(v) => Some(if *v { 1.0 } else { 0.0 }),
+      Value::Number(v) => Some(*v),
+      Value::String(v) => parse_decimal(v),
+    }
+  }
+
+  fn to_string(&self, version: Option<u8>) -> Option<String> {
+    match self {
+      Value::Undefined => Some(String::from(if version? >= 7 { "undefined" } else { "" })),
+      Value::Null => Some(String::from("null")),
+      Value::Boolean(v) => Some(v.to_string()),
+      Value::Number(v) => format_number(*v),
+      Value::String(v) => Some(v.clone()),
+    }
+  }
+
+  fn to_boolean(&self, version: Option<u8>) -> Option<bool> {
+    match self {
+      Value::Undefined | Value::Null => Some(false),
+      Value::Boolean(v) => Some(*v),
+      Value::Number(v) => Some(*v != 0.0 && !v.is_nan()),
+      Value::String(v) => {
+        let by_content = !v.is_empty();
+        let by_number = parse_decimal(v).map(|n| n != 0.0 && !n.is_nan());
+        match version {
+          Some(version) if version >= 7 => Some(by_content),
+          Some(_) => by_number,
+          None => by_number.filter(|b| *b == by_content),
+        }
+      }
+    }
+  }
+
+  fn type_of(&self) -> &'static str {
+    match self {
+      Value::Undefined => "undefined",
+      Value::Null => "null",
+      Value::Boolean(_) => "boolean",
+      Value::Number(_) => "number",
+      Value::String(_) => "string",
+    }
+  }
+
+  fn strict_equals(&self, other: &Self) -> bool {
+    match (self, other) {
+      (Value::Number(l), Value::Number(r)) => l == r,
+      (l, r) => l == r,
+    }
+  }
+
+  fn loose_equals(&self, other: &Self, version: Option<u8>) -> Option<bool> {
+    match (self, other) {
+      (Value::Undefined | Value::Null, Value::Undefined | Value::Null) => Some(true),
+      (Value::Undefined | Value::Null, _) | (_, Value::Undefined | Value::Null) => Some(false),
+      (Value::Number(_), Value::String(_)) | (Value::String(_), Value::Number(_)) => {
+        Some(self.to_number(version)? == other.to_number(version)?)
+      }
+      (Value::Boolean(_), _) | (_, Value::Boolean(_))
+        if std::mem::discriminant(self) != std::mem::discriminant(other) =>
+      {
+        let (left, right) = (
+          Value::Number(self.to_number(version)?),
+          Value::Number(other.to_number(version)?),
+        );
+        left.loose_equals(&right, version)
+      }
+      (l, r) => Some(l.strict_equals(r)),
+    }
+  }
+}
+
+/// Pushes the result of a comparison, or `undefined` if a number is `NaN`.
+fn compare(left: Value, right: Value, version: Option<u8>) -> Option<PushValue> {
+  if let (Value::String(l), Value::String(r)) = (&left, &right) {
+    return Some(PushValue::Boolean(utf16_less(l, r)));
+  }
+  let (left, right) = (left.to_number(version)?, right.to_number(version)?);
+  if left.is_nan() || right.is_nan() {
+    Some(PushValue::Undefined)
+  } else {
+    Some(PushValue::Boolean(left < right))
+  }
+}
+
+/// Pushes the boolean result of a SWF 4 action.
+fn swf4_boolean(value: bool, version: Option<u8>) -> Option<PushValue> {
+  if version? >= 5 {
+    Some(PushValue::Boolean(value))
+  } else {
+    Some(PushValue::Sint32(i32::from(value)))
+  }
+}
+
+/// Compares strings by UTF-16 code units, as AVM1 does.
+fn utf16_less(left: &str, right: &str) -> bool {
+  left.encode_utf16().lt(right.encode_utf16())
+}
+
+/// ECMAScript `ToInt32`.
+fn to_int32(value: f64) -> i32 {
+  if !value.is_finite() {
+    return 0;
+  }
+  let value = value.trunc().rem_euclid(4294967296.0);
+  value as u32 as i32
+}
+
+/// Parses plain decimal numbers, such as `"1"`, `"-2.5"` or `"1e3"`.
+///
+/// Other strings (empty, with spaces, hexadecimal, ...) convert differently
+/// depending on the version and are not parsed.
+fn parse_decimal(value: &str) -> Option<f64> {
+  let digits = value.strip_prefix('-').unwrap_or(value);
+  let (mantissa, exponent) = match digits.find(['e', 'E']) {
+    Some(i) => (&digits[..i], Some(&digits[i + 1..])),
+    None => (digits, None),
+  };
+  let (int, frac) = match mantissa.find('.') {
+    Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+    None => (mantissa, ""),
+  };
+  let is_digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+  if int.is_empty() || !is_digits(int) || !is_digits(frac) {
+    return None;
+  }
+  if let Some(exponent) = exponent {
+    let exponent = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+    if exponent.is_empty() || !is_digits(exponent) {
+      return None;
+    }
+  }
+  value.parse().ok()
+}
+
+/// Formats numbers whose AVM1 string is unambiguous: integers below `1e15`,
+/// decimals with few digits and non-finite numbers.
+fn format_number(value: f64) -> Option<String> {
+  if value.is_nan() {
+    return Some(String::from("NaN"));
+  }
+  if value.is_infinite() {
+    return Some(String::from(if value > 0.0 { "Infinity" } else { "-Infinity" }));
+  }
+  if value.trunc() == value && value.abs() < 1e15 {
+    return Some(format!("{}", value as i64));
+  }
+  let formatted = value.to_string();
+  let significant = formatted.bytes().filter(|b| b.is_ascii_digit()).count();
+  if value.abs() >= 1e-4 && value.abs() < 1e15 && significant <= 15 && !formatted.contains('e') {
+    Some(formatted)
+  } else {
+    None
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fold(values: Vec<PushValue>, actions: Vec<cfg::Action>, swf_version: Option<u8>) -> Vec<cfg::Action> {
+    let mut all = vec![push(values)];
+    all.extend(actions);
+    fold_actions(&mut all, swf_version, &mut FoldReport::default());
+    all
+  }
+
+  fn folds_to(values: Vec<PushValue>, action: cfg::Action, swf_version: Option<u8>) -> Option<PushValue> {
+    match fold(values, vec![action], swf_version).as_slice() {
+      [cfg::Action::Push(push)] => push.values.last().cloned(),
+      _ => None,
+    }
+  }
+
+  fn push(values: Vec<PushValue>) -> cfg::Action {
+    cfg::Action::Push(Push { values })
+  }
+
+  fn s(value: &str) -> PushValue {
+    PushValue::String(String::from(value))
+  }
+
+  #[test]
+  fn arithmetic() {
+    use cfg::Action::*;
+    use PushValue::*;
+    let actions = fold(
+      vec![Sint32(1)],
+      vec![push(vec![Sint32(2), Sint32(3)]), Multiply, Add2],
+      None,
+    );
+    assert_eq!(actions, vec![push(vec![Sint32(7)])]);
+    let actions = fold(
+      vec![Sint32(1)],
+      vec![push(vec![Sint32(2)]), push(vec![Sint32(3)]), Add2, Add2],
+      None,
+    );
+    assert_eq!(actions, vec![push(vec![Sint32(6)])]);
+    assert_eq!(folds_to(vec![Float64(0.5), Sint32(2)], Multiply, None), Some(Sint32(1)));
+    assert_eq!(folds_to(vec![Sint32(7), Sint32(2)], Divide, None), Some(Float64(3.5)));
+    assert_eq!(folds_to(vec![Sint32(-7), Sint32(2)], Modulo, None), Some(Sint32(-1)));
+    assert_eq!(
+      folds_to(vec![Sint32(1), Sint32(0)], Divide, Some(4)),
+      Some(s("#ERROR#"))
+    );
+    assert_eq!(
+      folds_to(vec![Sint32(1), Sint32(0)], Divide, Some(5)),
+      Some(Float64(f64::INFINITY))
+    );
+    assert_eq!(folds_to(vec![Sint32(1), Sint32(0)], Divide, None), None);
+    assert_eq!(folds_to(vec![s("1.5"), Sint32(1)], Subtract, None), Some(Float64(0.5)));
+    assert_eq!(folds_to(vec![s(" 1"), Sint32(1)], Subtract, None), None);
+    assert_eq!(folds_to(vec![Undefined, Sint32(1)], Add2, Some(6)), Some(Sint32(1)));
+    assert!(matches!(folds_to(vec![Undefined, Sint32(1)], Add2, Some(7)), Some(Float64(v)) if v.is_nan()));
+    assert_eq!(folds_to(vec![Undefined, Sint32(1)], Add2, None), None);
+  }
+
+  #[test]
+  fn strings() {
+    use cfg::Action::*;
+    use PushValue::*;
+    assert_eq!(folds_to(vec![s("a"), Sint32(1)], Add2, None), Some(s("a1")));
+    assert_eq!(folds_to(vec![s("a"), Float64(0.25)], Add2, None), Some(s("a0.25")));
+    assert_eq!(folds_to(vec![s("a"), Float64(0.1 + 0.2)], Add2, None), None);
+    assert_eq!(folds_to(vec![s("a"), Boolean(true)], StringAdd, None), Some(s("atrue")));
+    assert_eq!(folds_to(vec![s("a"), Undefined], StringAdd, Some(6)), Some(s("a")));
+    assert_eq!(
+      folds_to(vec![s("a"), Undefined], StringAdd, Some(7)),
+      Some(s("aundefined"))
+    );
+    assert_eq!(folds_to(vec![s("a"), Undefined], StringAdd, None), None);
+    assert_eq!(folds_to(vec![s("abc")], StringLength, None), Some(Sint32(3)));
+    assert_eq!(folds_to(vec![Null], TypeOf, None), Some(s("null")));
+    assert_eq!(folds_to(vec![Float64(-0.0)], ToString, None), Some(s("0")));
+    assert_eq!(folds_to(vec![s("b"), s("a")], StringGreater, None), Some(Boolean(true)));
+  }
+
+  #[test]
+  fn bitwise() {
+    use cfg::Action::*;
+    use PushValue::*;
+    assert_eq!(
+      folds_to(vec![Float64(4294967297.0), Sint32(0)], BitOr, None),
+      Some(Sint32(1))
+    );
+    assert_eq!(
+      folds_to(vec![Float64(f64::NAN), Sint32(3)], BitOr, None),
+      Some(Sint32(3))
+    );
+    assert_eq!(folds_to(vec![Sint32(1), Sint32(33)], BitLShift, None), Some(Sint32(2)));
+    assert_eq!(folds_to(vec![Sint32(-8), Sint32(1)], BitRShift, None), Some(Sint32(-4)));
+    assert_eq!(
+      folds_to(vec![Sint32(-1), Sint32(0)], BitURShift, None),
+      Some(Float64(4294967295.0))
+    );
+    assert_eq!(folds_to(vec![Float64(-2.5), Sint32(-1)], BitXor, None), Some(Sint32(1)));
+  }
+
+  #[test]
+  fn comparisons_and_logic() {
+    use cfg::Action::*;
+    use PushValue::*;
+    assert_eq!(folds_to(vec![Sint32(1), Sint32(2)], Less2, None), Some(Boolean(true)));
+    assert_eq!(
+      folds_to(vec![Sint32(1), Float64(f64::NAN)], Less2, None),
+      Some(Undefined)
+    );
+    assert_eq!(folds_to(vec![s("10"), s("9")], Less2, None), Some(Boolean(true)));
+    assert_eq!(
+      folds_to(vec![Sint32(1), Sint32(2)], Greater, None),
+      Some(Boolean(false))
+    );
+    assert_eq!(
+      folds_to(vec![Sint32(1), Float64(1.0)], StrictEquals, None),
+      Some(Boolean(true))
+    );
+    assert_eq!(
+      folds_to(vec![Sint32(1), s("1")], StrictEquals, None),
+      Some(Boolean(false))
+    );
+    assert_eq!(folds_to(vec![Sint32(1), s("1")], Equals2, None), Some(Boolean(true)));
+    assert_eq!(folds_to(vec![Null, Undefined], Equals2, None), Some(Boolean(true)));
+    assert_eq!(
+      folds_to(vec![Boolean(true), s("1")], Equals2, None),
+      Some(Boolean(true))
+    );
+    assert_eq!(folds_to(vec![Sint32(1), Sint32(2)], Less, Some(4)), Some(Sint32(1)));
+    assert_eq!(folds_to(vec![Sint32(1), Sint32(2)], Less, Some(5)), Some(Boolean(true)));
+    assert_eq!(folds_to(vec![Sint32(1), Sint32(2)], Less, None), None);
+    // Strings are truthy if they convert to a non-zero number before SWF 7.
+    assert_eq!(folds_to(vec![s("0")], Not, Some(6)), Some(Boolean(true)));
+    assert_eq!(folds_to(vec![s("0")], Not, Some(7)), Some(Boolean(false)));
+    assert_eq!(folds_to(vec![s("abc")], Not, Some(6)), None);
+    assert_eq!(folds_to(vec![s("1"), Sint32(0)], And, Some(5)), Some(Boolean(false)));
+  }
+
+  #[test]
+  fn keeps_long_results_apart() {
+    use cfg::Action::*;
+    let long = s(&"x".repeat(40000));
+    let actions = fold(vec![long.clone()], vec![push(vec![long.clone()]), StringAdd], None);
+    assert_eq!(
+      actions,
+      vec![push(vec![long.clone()]), push(vec![long.clone()]), StringAdd]
+    );
+
+    let cfg = Cfg {
+      blocks: vec1::vec1![cfg::CfgBlock {
+        label: cfg::CfgLabel(String::from("l0")),
+        actions: vec![
+          push(vec![long.clone()]),
+          Trace,
+          push(vec![long.clone()]),
+          push(vec![long]),
+          StringAdd,
+          Trace,
+        ],
+        flow: cfg::CfgFlow::Simple(cfg::Simple { next: None }),
+      }],
+    };
+    let options = crate::EmitOptions::optimize(crate::opt::Level::Size);
+    assert_eq!(
+      crate::emit_cfg_with_options(&cfg, &options).unwrap(),
+      crate::emit_cfg(&cfg).unwrap()
+    );
+  }
+
+  #[test]
+  fn fold_cfg_blocks() {
+    let cfg = crate::compiler::compile("trace(1 + 2 * 3); trace(\"a\" + 1);").unwrap();
+    let (folded, report) = fold_constants(&cfg, &FoldOptions::default());
+    assert_eq!(report.folded, 3);
+    assert!(crate::emit_cfg(&folded).unwrap().len() < crate::emit_cfg(&cfg).unwrap().len());
+  }
+}
@@ -0,0 +1,179 @@
+//! Optimization passes rewriting a control flow graph before it is emitted.
+//!
+//! Each pass returns an optimized copy of its input with a report describing
+//! what was changed. [`optimize`] applies the passes selected by a preset
+//! [`Level`].
+
+pub mod dce;
+pub mod dedup;
+pub mod fold;
+pub mod peephole;
+pub mod tail_merge;
+pub mod thread;
+
+use avm1_types::cfg::{self, Cfg, CfgBlock, CfgFlow, CfgLabel};
+use avm1_types::PushValue;
+use vec1::vec1;
+
+/// Optimization preset selecting the passes applied by [`optimize`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Level {
+  /// No optimization: the CFG is emitted as is.
+  None,
+  /// Smallest output: constant folding, peephole rules, dead code elimination
+  /// (with jump threading) and tail merging.
+  Size,
+  /// Fewest executed actions: constant folding, peephole rules and dead code
+  /// elimination (with jump threading). Tails are not merged, as reaching a
+  /// shared tail may need an extra `Jump`.
+  Speed,
+}
+
+impl Default for Level {
+  fn default() -> Self {
+    Level::None
+  }
+}
+
+/// Returns a copy of `value` optimized with the passes selected by `level`.
+///
+/// `swf_version` is the version of the movie running the code, if known: it
+/// allows folding constants whose conversion depends on the version.
+///
+/// Passes changing the observable behavior, such as
+/// [`dedup::deduplicate_functions`] (function identity), are never applied.
+pub fn optimize(value: &Cfg, level: Level, swf_version: Option<u8>) -> Cfg {
+  if level == Level::None {
+    return value.clone();
+  }
+  let (cfg, _) = fold::fold_constants(value, &fold::FoldOptions { swf_version });
+  let (cfg, _) = peephole::optimize_peephole(&cfg, &peephole::PeepholeRules::default());
+  let (cfg, _) = dce::eliminate_dead_code(&cfg);
+  match level {
+    Level::Size => {
+      let (cfg, _) = tail_merge::merge_tails(&cfg);
+      // Merged tails may leave empty forwarding blocks.
+      dce::eliminate_dead_code(&cfg).0
+    }
+    _ => cfg,
+  }
+}
+
+/// Returns the number of bytes emitted for `actions`, or `None` if they
+/// cannot be emitted.
+pub(crate) fn actions_size(actions: &[cfg::Action]) -> Option<usize> {
+  let cfg = Cfg {
+    blocks: vec1![CfgBlock {
+      label: CfgLabel(String::new()),
+      actions: actions.to_vec(),
+      flow: CfgFlow::Simple(cfg::Simple { next: None }),
+    }],
+  };
+  // Exclude the final `End` action.
+  crate::emit_cfg(&cfg).ok().map(|bytes| bytes.len() - 1)
+}
+
+/// Checks if a `Push` action of `values` can be emitted: the length of an
+/// action is stored as a `u16`.
+pub(crate) fn push_fits(values: &[PushValue]) -> bool {
+  let size: usize = values
+    .iter()
+    .map(|value| match value {
+      PushValue::Null | PushValue::Undefined => 1,
+      PushValue::Boolean(_) | PushValue::Register(_) => 2,
+      PushValue::Constant(v) => {
+        if *v <= u16::from(u8::MAX) {
+          2
+        } else {
+          3
+        }
+      }
+      PushValue::Sint32(_) | PushValue::Float32(_) => 5,
+      PushValue::Float64(_) => 9,
+      // Type, bytes and null terminator
+      PushValue::String(v) => v.len() + 2,
+    })
+    .sum();
+  size <= usize::from(u16::MAX)
+}
+
+/// Returns the value of a pushed number.
+pub(crate) fn number(value: &PushValue) -> Option<f64> {
+  match value {
+    PushValue::Sint32(v) => Some(f64::from(*v)),
+    PushValue::Float32(v) => Some(f64::from(*v)),
+    PushValue::Float64(v) => Some(*v),
+    _ => None,
+  }
+}
+
+/// Returns the most compact push value for `value`.
+pub(crate) fn number_value(value: f64) -> PushValue {
+  let int = value as i32;
+  if f64::from(int) == value && !(value == 0.0 && value.is_sign_negative()) {
+    PushValue::Sint32(int)
+  } else {
+    PushValue::Float64(value)
+  }
+}
+
+/// Returns the bodies of the functions defined by `actions`.
+pub(crate) fn function_bodies_mut(actions: &mut [cfg::Action]) -> impl Iterator<Item = &mut Cfg> {
+  actions.iter_mut().filter_map(|action| match action {
+    cfg::Action::DefineFunction(f) => Some(&mut f.body),
+    cfg::Action::DefineFunction2(f) => Some(&mut f.body),
+    _ => None,
+  })
+}
+
+/// Returns the soft CFGs of the `Try` or `With` region started by `flow`.
+pub(crate) fn regions_mut(flow: &mut CfgFlow) -> Vec<&mut Cfg> {
+  match flow {
+    CfgFlow::Try(flow) => {
+      let flow = &mut **flow;
+      let mut regions = vec![&mut flow.r#try];
+      regions.extend(flow.catch.as_mut().map(|c| &mut c.body));
+      regions.extend(flow.finally.as_mut());
+      regions
+    }
+    CfgFlow::With(flow) => vec![&mut flow.body],
+    _ => Vec::new(),
+  }
+}
+
+/// Returns the soft CFGs of the `Try` or `With` region started by `flow`.
+pub(crate) fn regions(flow: &CfgFlow) -> Vec<&Cfg> {
+  match flow {
+    CfgFlow::Try(flow) => {
+      let mut regions = vec![&flow.r#try];
+      regions.extend(flow.catch.as_ref().map(|c| &c.body));
+      regions.extend(flow.finally.as_ref());
+      regions
+    }
+    CfgFlow::With(flow) => vec![&flow.body],
+    _ => Vec::new(),
+  }
+}
+
+/// Returns the jump targets of `flow` (`None` is the end of the hard CFG).
+pub(crate) fn flow_targets(flow: &CfgFlow) -> Vec<&Option<CfgLabel>> {
+  match flow {
+    CfgFlow::If(flow) => vec![&flow.true_target, &flow.false_target],
+    CfgFlow::Simple(flow) => vec![&flow.next],
+    CfgFlow::WaitForFrame(flow) => vec![&flow.ready_target, &flow.loading_target],
+    CfgFlow::WaitForFrame2(flow) => vec![&flow.ready_target, &flow.loading_target],
+    _ => Vec::new(),
+  }
+}
+
+/// Returns the jump targets of `flow` (`None` is the end of the hard CFG).
+pub(crate) fn flow_targets_mut(flow: &mut CfgFlow) -> Vec<&mut Option<CfgLabel>> {
+  match flow {
+    CfgFlow::If(flow) => vec![&mut flow.true_target, &mut flow.false_target],
+    CfgFlow::Simple(flow) => vec![&mut flow.next],
+    CfgFlow::WaitForFrame(flow) => vec![&mut flow.ready_target, &mut flow.loading_target],
+    CfgFlow::WaitForFrame2(flow) => vec![&mut flow.ready_target, &mut flow.loading_target],
+    _ => Vec::new(),
+  }
+}
@@ -0,0 +1,518 @@
+//! Rule-based peephole optimizer over the actions of each block.
+
+use crate::opt::{fold, function_bodies_mut, number, push_fits, regions_mut};
+use avm1_types::action::StoreRegister;
+use avm1_types::cfg::{self, Cfg, CfgFlow};
+use avm1_types::PushValue;
+
+/// Rules applied by [`optimize_peephole`], all enabled by default.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeepholeRules {
+  /// `Push a, b; Push c` becomes `Push a, b, c`, unless the merged `Push` is
+  /// too long to be emitted.
+  pub merge_pushes: bool,
+  /// `Push a, b; Pop` becomes `Push a`, and `Push a; Pop` disappears.
+  pub push_pop: bool,
+  /// `Not; Not` before an `If` flow disappears.
+  pub double_not: bool,
+  /// `StoreRegister r; Pop; Push r, a` becomes `StoreRegister r; Push a`.
+  pub store_register: bool,
+  /// `Push a, b; Add2` (also `Add`, `Subtract` and `Multiply`) becomes
+  /// `Push c` when `a` and `b` are numbers and `c` is finite.
+  pub fold_constants: bool,
+}
+
+impl PeepholeRules {
+  /// Returns rules with every rule disabled.
+  pub fn none() -> Self {
+    Self {
+      merge_pushes: false,
+      push_pop: false,
+      double_not: false,
+      store_register: false,
+      fold_constants: false,
+    }
+  }
+}
+
+impl Default for PeepholeRules {
+  fn default() -> Self {
+    Self {
+      merge_pushes: true,
+      push_pop: true,
+      double_not: true,
+      store_register: true,
+      fold_constants: true,
+    }
+  }
+}
+
+/// Number of rewrites made by each rule of [`optimize_peephole`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PeepholeReport {
+  pub merge_pushes: usize,
+  pub push_pop: usize,
+  pub double_not: usize,
+  pub store_register: usize,
+  pub fold_constants: usize,
+}
+
+/// Returns a copy of `value` where the enabled `rules` are applied to the
+/// actions of every block (including the blocks of function bodies), until
+/// none applies.
+///
+/// Rewrites stay inside a block: the actions of a block always run in
+/// sequence, so only the stack and registers they leave matter.
+pub fn optimize_peephole(value: &Cfg, rules: &PeepholeRules) -> (Cfg, PeepholeReport) {
+  let mut cfg = value.clone();
+  let mut report = PeepholeReport::default();
+  optimize_cfg(&mut cfg, rules, &mut report);
+  (cfg, report)
+}
+
+fn optimize_cfg(cfg: &mut Cfg, rules: &PeepholeRules, report: &mut PeepholeReport) {
+  for block in cfg.blocks.iter_mut() {
+    let before_if = matches!(block.flow, CfgFlow::If(_));
+    optimize_actions(&mut block.actions, before_if, rules, report);
+    for body in function_bodies_mut(&mut block.actions) {
+      optimize_cfg(body, rules, report);
+    }
+    for region in regions_mut(&mut block.flow) {
+      optimize_cfg(region, rules, report);
+    }
+  }
+}
+
+/// Applies `rules` to `actions` until none applies. `before_if` is set if the
+/// actions are followed by an `If` flow.
+pub(crate) fn optimize_actions(
+  actions: &mut Vec<cfg::Action>,
+  before_if: bool,
+  rules: &PeepholeRules,
+  report: &mut PeepholeReport,
+) {
+  let mut i: usize = 0;
+  while i < actions.len() {
+    let applied = (rules.merge_pushes && merge_pushes(actions, i, &mut report.merge_pushes))
+      || (rules.push_pop && push_pop(actions, i, &mut report.push_pop))
+      || (rules.double_not && before_if && double_not(actions, i, &mut report.double_not))
+      || (rules.store_register && store_register(actions, i, &mut report.store_register))
+      || (rules.fold_constants && fold_constants(actions, i, &mut report.fold_constants));
+    if applied {
+      // A rewrite may enable a rule starting at the previous action.
+      i = i.saturating_sub(1);
+    } else {
+      i += 1;
+    }
+  }
+}
+
+fn push_values(action: Option<&cfg::Action>) -> Option<&Vec<PushValue>> {
+  match action {
+    Some(cfg::Action::Push(push)) => Some(&push.values),
+    _ => None,
+  }
+}
+
+fn push_values_mut(action: &mut cfg::Action) -> &mut Vec<PushValue> {
+  match action {
+    cfg::Action::Push(push) => &mut push.values,
+    _ => unreachable!("expected a `Push` action"),
+  }
+}
+
+fn merge_pushes(actions: &mut Vec<cfg::Action>, i: usize, count: &mut usize) -> bool {
+  let (left, right) = match (push_values(actions.get(i)), push_values(actions.get(i + 1))) {
+    (Some(left), Some(right)) => (left, right),
+    _ => return false,
+  };
+  if !push_fits(&[left.as_slice(), right.as_slice()].concat()) {
+    return false;
+  }
+  let next = std::mem::take(push_values_mut(&mut actions[i + 1]));
+  push_values_mut(&mut actions[i]).extend(next);
+  actions.remove(i + 1);
+  *count += 1;
+  true
+}
+
+fn push_pop(actions: &mut Vec<cfg::Action>, i: usize, count: &mut usize) -> bool {
+  if push_values(actions.get(i)).is_none() || actions.get(i + 1) != Some(&cfg::Action::Pop) {
+    return false;
+  }
+  let values = push_values_mut(&mut actions[i]);
+  values.pop();
+  if values.is_empty() {
+    actions.drain(i..i + 2);
+  } else {
+    actions.remove(i + 1);
+  }
+  *count += 1;
+  true
+}
+
+fn double_not(actions: &mut Vec<cfg::Action>, i: usize, count: &mut usize) -> bool {
+  // `If` only checks whether the condition is truthy, as `Not` does.
+  if i + 2 != actions.len() || actions[i] != cfg::Action::Not || actions[i + 1] != cfg::Action::Not {
+    return false;
+  }
+  actions.truncate(i);
+  *count += 1;
+  true
+}
+
+fn store_register(actions: &mut Vec<cfg::Action>, i: usize, count: &mut usize) -> bool {
+  let register = match actions.get(i) {
+    Some(cfg::Action::StoreRegister(StoreRegister { register })) => *register,
+    _ => return false,
+  };
+  if actions.get(i + 1) != Some(&cfg::Action::Pop) {
+    return false;
+  }
+  match push_values(actions.get(i + 2)).and_then(|values| values.first()) {
+    Some(PushValue::Register(r)) if *r == register => {}
+    _ => return false,
+  }
+  let values = push_values_mut(&mut actions[i + 2]);
+  values.remove(0);
+  if values.is_empty() {
+    actions.drain(i + 1..i + 3);
+  } else {
+    actions.remove(i + 1);
+  }
+  *count += 1;
+  true
+}
+
+fn fold_constants(actions: &mut Vec<cfg::Action>, i: usize, count: &mut usize) -> bool {
+  let values = match push_values(actions.get(i)) {
+    Some(values) if values.len() >= 2 => values,
+    _ => return false,
+  };
+  let operands = &values[values.len() - 2..];
+  if operands.iter().any(|v| number(v).is_none()) {
+    return false;
+  }
+  let operation = match actions.get(i + 1) {
+    Some(operation @ (cfg::Action::Add | cfg::Action::Add2 | cfg::Action::Subtract | cfg::Action::Multiply)) => {
+      operation
+    }
+    _ => return false,
+  };
+  let result = match fold::evaluate(operation, operands, None) {
+    Some(result) if number(&result).map_or(false, f64::is_finite) => result,
+    _ => return false,
+  };
+  let values = push_values_mut(&mut actions[i]);
+  values.truncate(values.len() - 2);
+  values.push(result);
+  actions.remove(i + 1);
+  *count += 1;
+  true
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use avm1_types::action::Push;
+  use std::collections::HashMap;
+
+  /// Values of the simplified machine used for differential tests.
+  #[derive(Clone, Debug)]
+  enum Value {
+    Number(f64),
+    String(String),
+    Boolean(bool),
+    Undefined,
+  }
+
+  impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+      match (self, other) {
+        (Value::Number(l), Value::Number(r)) => l.to_bits() == r.to_bits() || (l.is_nan() && r.is_nan()),
+        (Value::String(l), Value::String(r)) => l == r,
+        (Value::Boolean(l), Value::Boolean(r)) => l == r,
+        (Value::Undefined, Value::Undefined) => true,
+        _ => false,
+      }
+    }
+  }
+
+  impl Value {
+    fn to_number(&self) -> f64 {
+      match self {
+        Value::Number(v) => *v,
+        Value::String(v) => v.parse().unwrap_or(f64::NAN),
+        Value::Boolean(v) => f64::from(u8::from(*v)),
+        Value::Undefined => f64::NAN,
+      }
+    }
+
+    fn truthy(&self) -> bool {
+      match self {
+        Value::Number(v) => *v != 0.0 && !v.is_nan(),
+        Value::String(v) => !v.is_empty(),
+        Value::Boolean(v) => *v,
+        Value::Undefined => false,
+      }
+    }
+  }
+
+  #[derive(Debug, Default, PartialEq)]
+  struct State {
+    stack: Vec<Value>,
+    registers: HashMap<u8, Value>,
+    output: Vec<Value>,
+  }
+
+  /// Runs the actions of a block on a stack holding values of previous blocks, then the condition of its `If` flow if `before_if` is set.
+  fn run(actions: &[cfg::Action], before_if: bool) -> State {
+    // Blocks may consume values pushed by their predecessors.
+    let mut state = State {
+      stack: (0..16).map(|i| Value::String(format!("in{}", i))).collect(),
+      ..State::default()
+    };
+    let pop = |state: &mut State| state.stack.pop().unwrap_or(Value::Undefined);
+    for action in actions {
+      match action {
+        cfg::Action::Push(push) => {
+          for value in push.values.iter() {
+            let value = match value {
+              PushValue::Sint32(v) => Value::Number(f64::from(*v)),
+              PushValue::Float64(v) => Value::Number(*v),
+              PushValue::String(v) => Value::String(v.clone()),
+              PushValue::Boolean(v) => Value::Boolean(*v),
+              PushValue::Register(r) => state.registers.get(r).cloned().unwrap_or(Value::Undefined),
+              _ => Value::Undefined,
+            };
+            state.stack.push(value);
+          }
+        }
+        cfg::Action::Pop => {
+          pop(&mut state);
+        }
+        cfg::Action::Not => {
+          let value = pop(&mut state);
+          state.stack.push(Value::Boolean(!value.truthy()));
+        }
+        cfg::Action::StoreRegister(store) => {
+          let value = state.stack.last().cloned().unwrap_or(Value::Undefined);
+          state.registers.insert(store.register, value);
+        }
+        cfg::Action::Trace => {
+          let value = pop(&mut state);
+          state.output.push(value);
+        }
+        cfg::Action::Add2 | cfg::Action::Add | cfg::Action::Subtract | cfg::Action::Multiply => {
+          let right = pop(&mut state);
+          let left = pop(&mut state);
+          let value = match (action, &left, &right) {
+            (cfg::Action::Add2, Value::String(l), r) => Value::String(format!("{}{:?}", l, r)),
+            (cfg::Action::Add2, l, Value::String(r)) => Value::String(format!("{:?}{}", l, r)),
+            (cfg::Action::Add | cfg::Action::Add2, l, r) => Value::Number(l.to_number() + r.to_number()),
+            (cfg::Action::Subtract, l, r) => Value::Number(l.to_number() - r.to_number()),
+            (_, l, r) => Value::Number(l.to_number() * r.to_number()),
+          };
+          state.stack.push(value);
+        }
+        action => panic!("unexpected action: {:?}", action),
+      }
+    }
+    if before_if {
+      let condition = pop(&mut state);
+      state.stack.push(Value::Boolean(condition.truthy()));
+    }
+    state
+  }
+
+  /// Xorshift generator, to produce the same action sequences on every run.
+  struct Rng(u64);
+
+  impl Rng {
+    fn next(&mut self, bound: usize) -> usize {
+      self.0 ^= self.0 << 13;
+      self.0 ^= self.0 >> 7;
+      self.0 ^= self.0 << 17;
+      (self.0 % bound as u64) as usize
+    }
+  }
+
+  fn random_push(rng: &mut Rng) -> cfg::Action {
+    let values = (0..1 + rng.next(3))
+      .map(|_| match rng.next(7) {
+        0 => PushValue::Sint32(rng.next(5) as i32 - 2),
+        1 => PushValue::Float64(rng.next(9) as f64 * 0.5 - 1.0),
+        2 => PushValue::Float64(-0.0),
+        3 => PushValue::String(String::from("s")),
+        4 => PushValue::Boolean(rng.next(2) == 0),
+        _ => PushValue::Register(rng.next(2) as u8),
+      })
+      .collect();
+    cfg::Action::Push(Push { values })
+  }
+
+  fn random_actions(rng: &mut Rng) -> Vec<cfg::Action> {
+    let mut actions = Vec::new();
+    for _ in 0..1 + rng.next(8) {
+      match rng.next(12) {
+        0..=2 => actions.push(random_push(rng)),
+        3 => actions.push(cfg::Action::Pop),
+        4 => actions.push(cfg::Action::Not),
+        5 => actions.push(cfg::Action::StoreRegister(StoreRegister {
+          register: rng.next(2) as u8,
+        })),
+        6 => actions.push(cfg::Action::Trace),
+        7 => actions.push(cfg::Action::Add2),
+        8 => actions.push(cfg::Action::Subtract),
+        9 => actions.push(cfg::Action::Multiply),
+        10 => {
+          // Common sequence of compiled assignments.
+          let register = rng.next(2) as u8;
+          actions.push(cfg::Action::StoreRegister(StoreRegister { register }));
+          actions.push(cfg::Action::Pop);
+          let mut push = random_push(rng);
+          push_values_mut(&mut push).insert(0, PushValue::Register(register));
+          actions.push(push);
+        }
+        _ => actions.extend([cfg::Action::Not, cfg::Action::Not]),
+      }
+    }
+    actions
+  }
+
+  fn all_rules() -> Vec<(&'static str, PeepholeRules)> {
+    vec![
+      (
+        "merge_pushes",
+        PeepholeRules {
+          merge_pushes: true,
+          ..PeepholeRules::none()
+        },
+      ),
+      (
+        "push_pop",
+        PeepholeRules {
+          push_pop: true,
+          ..PeepholeRules::none()
+        },
+      ),
+      (
+        "double_not",
+        PeepholeRules {
+          double_not: true,
+          ..PeepholeRules::none()
+        },
+      ),
+      (
+        "store_register",
+        PeepholeRules {
+          store_register: true,
+          ..PeepholeRules::none()
+        },
+      ),
+      (
+        "fold_constants",
+        PeepholeRules {
+          fold_constants: true,
+          ..PeepholeRules::none()
+        },
+      ),
+      ("all", PeepholeRules::default()),
+    ]
+  }
+
+  #[test]
+  fn differential() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    let mut applied: HashMap<&str, usize> = HashMap::new();
+    for _ in 0..5000 {
+      let actions = random_actions(&mut rng);
+      let before_if = rng.next(2) == 0;
+      let expected = run(&actions, before_if);
+      for (name, rules) in all_rules() {
+        let mut optimized = actions.clone();
+        let mut report = PeepholeReport::default();
+        optimize_actions(&mut optimized, before_if, &rules, &mut report);
+        assert_eq!(
+          run(&optimized, before_if),
+          expected,
+          "{}: {:?} -> {:?}",
+          name,
+          actions,
+          optimized
+        );
+        if optimized != actions {
+          *applied.entry(name).or_default() += 1;
+        }
+      }
+    }
+    for (name, _) in all_rules() {
+      assert!(
+        applied.get(name).copied().unwrap_or_default() > 10,
+        "rule {} rarely applies",
+        name
+      );
+    }
+  }
+
+  #[test]
+  fn rewrites() {
+    let push = |values: Vec<PushValue>| cfg::Action::Push(Push { values });
+    let actions = vec![
+      push(vec![PushValue::Sint32(1)]),
+      push(vec![PushValue::Sint32(2)]),
+      cfg::Action::Add2,
+      cfg::Action::StoreRegister(StoreRegister { register: 1 }),
+      cfg::Action::Pop,
+      push(vec![PushValue::Register(1), PushValue::String(String::from("x"))]),
+      cfg::Action::Pop,
+      cfg::Action::Not,
+      cfg::Action::Not,
+    ];
+    let mut optimized = actions;
+    let mut report = PeepholeReport::default();
+    optimize_actions(&mut optimized, true, &PeepholeRules::default(), &mut report);
+    assert_eq!(
+      optimized,
+      vec![
+        push(vec![PushValue::Sint32(3)]),
+        cfg::Action::StoreRegister(StoreRegister { register: 1 }),
+      ]
+    );
+    assert_eq!(
+      report,
+      PeepholeReport {
+        merge_pushes: 1,
+        push_pop: 1,
+        double_not: 1,
+        store_register: 1,
+        fold_constants: 1,
+      }
+    );
+  }
+
+  #[test]
+  fn keeps_long_pushes_apart() {
+    let long = PushValue::String("x".repeat(40000));
+    let push = |values: Vec<PushValue>| cfg::Action::Push(Push { values });
+    let cfg = Cfg {
+      blocks: vec1::vec1![cfg::CfgBlock {
+        label: cfg::CfgLabel(String::from("l0")),
+        actions: vec![
+          push(vec![long.clone()]),
+          cfg::Action::Trace,
+          push(vec![long.clone()]),
+          push(vec![long]),
+          cfg::Action::StringAdd,
+          cfg::Action::Trace,
+        ],
+        flow: CfgFlow::Simple(cfg::Simple { next: None }),
+      }],
+    };
+    let (optimized, report) = optimize_peephole(&cfg, &PeepholeRules::default());
+    assert_eq!(report, PeepholeReport::default());
+    assert_eq!(crate::emit_cfg(&optimized).unwrap(), crate::emit_cfg(&cfg).unwrap());
+  }
+}
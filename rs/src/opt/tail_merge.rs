@@ -0,0 +1,234 @@
+//! Tail block merging (cross-jumping).
+
+use crate::label::{collect_labels, fresh_label};
+use crate::opt::{actions_size, function_bodies_mut, regions_mut};
+use avm1_types::cfg::{self, Cfg, CfgBlock, CfgFlow, CfgLabel};
+use std::collections::HashSet;
+
+/// Size of the `Jump` action that may be needed to reach a shared tail.
+const JUMP_SIZE: usize = 5;
+
+/// Summary of the changes made by [`merge_tails`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TailMergeReport {
+  /// Labels of the blocks holding a shared tail, in creation order.
+  pub tails: Vec<CfgLabel>,
+  /// Number of actions removed from the predecessors of the shared tails.
+  pub removed_actions: usize,
+}
+
+/// Returns a copy of `value` where blocks ending with the same actions and
+/// continuing to the same block share these actions.
+///
+/// Among the blocks of a single region with a `Simple` flow to the same
+/// target, the trailing actions common to several blocks are moved to a
+/// shared block continuing to the target, and the blocks continue to it
+/// instead. If one of the blocks only contains the common actions, it becomes
+/// the shared block. The shared block is placed after the last of the merged
+/// blocks, so that one keeps falling through.
+///
+/// Tails are only merged when they are larger than the `Jump` action that may
+/// be needed to reach them, so the emitted bytes never grow.
+pub fn merge_tails(value: &Cfg) -> (Cfg, TailMergeReport) {
+  let mut cfg = value.clone();
+  let mut report = TailMergeReport::default();
+  merge_hard_cfg(&mut cfg, &mut report);
+  (cfg, report)
+}
+
+fn merge_hard_cfg(cfg: &mut Cfg, report: &mut TailMergeReport) {
+  let mut labels: HashSet<CfgLabel> = HashSet::new();
+  collect_labels(cfg, &mut labels);
+  merge_soft_cfg(cfg, &mut labels, report);
+}
+
+fn merge_soft_cfg(cfg: &mut Cfg, labels: &mut HashSet<CfgLabel>, report: &mut TailMergeReport) {
+  while merge_once(cfg, labels, report) {}
+  for block in cfg.blocks.iter_mut() {
+    for body in function_bodies_mut(&mut block.actions) {
+      merge_hard_cfg(body, report);
+    }
+    for region in regions_mut(&mut block.flow) {
+      merge_soft_cfg(region, labels, report);
+    }
+  }
+}
+
+/// Merges the tails of one group of blocks, returns `false` if there is none.
+fn merge_once(cfg: &mut Cfg, labels: &mut HashSet<CfgLabel>, report: &mut TailMergeReport) -> bool {
+  let (members, len) = match find_group(cfg) {
+    Some(group) => group,
+    None => return false,
+  };
+  let last = *members.last().unwrap();
+  let next = match &cfg.blocks[last].flow {
+    CfgFlow::Simple(flow) => flow.next.clone(),
+    _ => unreachable!("merged blocks have a simple flow"),
+  };
+
+  let tail_index = members.iter().copied().find(|&i| cfg.blocks[i].actions.len() == len);
+  let tail_label = match tail_index {
+    Some(i) => cfg.blocks[i].label.clone(),
+    None => {
+      let block = &cfg.blocks[last];
+      let tail = CfgBlock {
+        label: fresh_label(labels, &format!("{}_tail", block.label.0)),
+        actions: block.actions[block.actions.len() - len..].to_vec(),
+        flow: CfgFlow::Simple(cfg::Simple { next }),
+      };
+      let label = tail.label.clone();
+      cfg.blocks.insert(last + 1, tail);
+      label
+    }
+  };
+
+  for &i in members.iter().filter(|&&i| Some(i) != tail_index) {
+    let block = &mut cfg.blocks[i];
+    block.actions.truncate(block.actions.len() - len);
+    block.flow = CfgFlow::Simple(cfg::Simple {
+      next: Some(tail_label.clone()),
+    });
+    report.removed_actions += len;
+  }
+  report.tails.push(tail_label);
+  true
+}
+
+/// Finds blocks continuing to the same target with a common tail worth
+/// sharing. Returns their indices and the number of common actions.
+fn find_group(cfg: &Cfg) -> Option<(Vec<usize>, usize)> {
+  let mut groups: Vec<Vec<usize>> = Vec::new();
+  for (i, block) in cfg.blocks.iter().enumerate() {
+    let (flow, action) = match (&block.flow, block.actions.last()) {
+      (CfgFlow::Simple(flow), Some(action)) => (flow, action),
+      _ => continue,
+    };
+    let group = groups.iter_mut().find(|g| {
+      let other = &cfg.blocks[g[0]];
+      other.actions.last() == Some(action) && matches!(&other.flow, CfgFlow::Simple(f) if f.next == flow.next)
+    });
+    match group {
+      Some(group) => group.push(i),
+      None => groups.push(vec![i]),
+    }
+  }
+
+  groups.into_iter().filter(|g| g.len() > 1).find_map(|group| {
+    let first = &cfg.blocks[group[0]].actions;
+    let len = group
+      .iter()
+      .map(|&i| common_suffix_len(first, &cfg.blocks[i].actions))
+      .min()
+      .unwrap();
+    let size = actions_size(&first[first.len() - len..])?;
+    if size > JUMP_SIZE {
+      Some((group, len))
+    } else {
+      None
+    }
+  })
+}
+
+fn common_suffix_len(left: &[cfg::Action], right: &[cfg::Action]) -> usize {
+  left
+    .iter()
+    .rev()
+    .zip(right.iter().rev())
+    .take_while(|(l, r)| l == r)
+    .count()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm::parse_asm;
+  use crate::emit_cfg;
+
+  #[test]
+  fn merges_common_tail() {
+    let cfg = parse_asm(
+      r#"
+        Push "x"
+        GetVariable
+        If other
+        Push "one"
+        Trace
+        Push "shared tail"
+        Trace
+        Jump done
+      other:
+        Push "two"
+        Trace
+        Push "shared tail"
+        Trace
+      done:
+        Stop
+      "#,
+    )
+    .unwrap();
+    let (merged, report) = merge_tails(&cfg);
+    assert_eq!(report.tails, vec![CfgLabel(String::from("other_tail"))]);
+    assert_eq!(report.removed_actions, 6);
+    assert_eq!(merged.blocks.len(), cfg.blocks.len() + 1);
+    assert_eq!(
+      emit_cfg(&cfg).unwrap().len() - emit_cfg(&merged).unwrap().len(),
+      actions_size(&cfg.blocks[2].actions[1..]).unwrap()
+    );
+  }
+
+  #[test]
+  fn reuses_block_made_of_the_tail() {
+    let cfg = parse_asm(
+      r#"
+        Push "x"
+        GetVariable
+        If other
+        Push "shared tail"
+        Trace
+        Jump done
+      other:
+        Push "two"
+        Trace
+        Push "shared tail"
+        Trace
+      done:
+        Stop
+      "#,
+    )
+    .unwrap();
+    let (merged, report) = merge_tails(&cfg);
+    assert_eq!(report.tails, vec![cfg.blocks[1].label.clone()]);
+    assert_eq!(merged.blocks.len(), cfg.blocks.len());
+    assert_eq!(merged.blocks[2].actions.len(), 2);
+    assert!(emit_cfg(&merged).unwrap().len() < emit_cfg(&cfg).unwrap().len());
+  }
+
+  #[test]
+  fn keeps_short_tails_and_distinct_targets() {
+    let cfg = parse_asm(
+      r#"
+        Push "x"
+        GetVariable
+        If other
+        Push "one"
+        Trace
+        Jump done
+      other:
+        Push "two"
+        Trace
+      done:
+        Push "same", "tail"
+        Trace
+        Jump other
+      last:
+        Push "same", "tail"
+        Trace
+      "#,
+    )
+    .unwrap();
+    let (merged, report) = merge_tails(&cfg);
+    assert_eq!(report, TailMergeReport::default());
+    assert_eq!(merged, cfg);
+  }
+}
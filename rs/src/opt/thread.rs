@@ -0,0 +1,207 @@
+//! Jump threading through empty forwarding blocks.
+
+use crate::opt::{flow_targets_mut, function_bodies_mut, regions, regions_mut};
+use avm1_types::cfg::{Cfg, CfgFlow, CfgLabel};
+use std::collections::{HashMap, HashSet};
+
+/// Summary of the changes made by [`thread_jumps`].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ThreadReport {
+  /// Number of flow targets retargeted to skip forwarding blocks.
+  pub retargeted_edges: usize,
+}
+
+/// Returns a copy of `value` where flows skip the forwarding blocks: blocks
+/// without actions and with a `Simple` flow.
+///
+/// Every target of `Simple`, `If` and `WaitForFrame` flows pointing at a
+/// forwarding block is replaced by the first block of the chain that is not a
+/// forwarding block. A chain stops at a forwarding block whose target is in a
+/// different region (a `Try` or `With` body, or the top level), so flows never
+/// enter a region they did not already enter. Loops of forwarding blocks are
+/// left as is. The forwarding blocks themselves are kept, see
+/// [`eliminate_dead_code`](crate::opt::dce::eliminate_dead_code) to remove them:
+/// until then, a block falling through to a skipped block needs a `Jump`.
+pub fn thread_jumps(value: &Cfg) -> (Cfg, ThreadReport) {
+  let mut cfg = value.clone();
+  let mut report = ThreadReport::default();
+  thread_functions(&mut cfg, &mut report);
+  report.retargeted_edges += thread_hard_cfg(&mut cfg);
+  (cfg, report)
+}
+
+fn thread_functions(cfg: &mut Cfg, report: &mut ThreadReport) {
+  for block in cfg.blocks.iter_mut() {
+    for body in function_bodies_mut(&mut block.actions) {
+      thread_functions(body, report);
+      report.retargeted_edges += thread_hard_cfg(body);
+    }
+    for region in regions_mut(&mut block.flow) {
+      thread_functions(region, report);
+    }
+  }
+}
+
+/// Threads the jumps of `cfg`, without the functions it defines. Returns the
+/// number of retargeted edges.
+pub(crate) fn thread_hard_cfg(cfg: &mut Cfg) -> usize {
+  let mut blocks: HashMap<CfgLabel, BlockInfo> = HashMap::new();
+  index_blocks(cfg, 0, &mut 0, &mut blocks);
+  skip_forwarding(cfg, &blocks)
+}
+
+struct BlockInfo {
+  /// Index of the soft CFG containing the block, `0` for the hard CFG.
+  region: usize,
+  /// Target of the block if it has no actions and a `Simple` flow.
+  forward: Option<Option<CfgLabel>>,
+}
+
+fn index_blocks(cfg: &Cfg, region: usize, last_region: &mut usize, blocks: &mut HashMap<CfgLabel, BlockInfo>) {
+  for block in cfg.blocks.iter() {
+    let forward = match &block.flow {
+      CfgFlow::Simple(flow) if block.actions.is_empty() => Some(flow.next.clone()),
+      _ => None,
+    };
+    blocks.insert(block.label.clone(), BlockInfo { region, forward });
+    for nested in regions(&block.flow) {
+      *last_region += 1;
+      index_blocks(nested, *last_region, last_region, blocks);
+    }
+  }
+}
+
+/// Retargets the flows of `cfg` to skip forwarding blocks, returns the number
+/// of retargeted edges.
+fn skip_forwarding(cfg: &mut Cfg, blocks: &HashMap<CfgLabel, BlockInfo>) -> usize {
+  let mut retargeted: usize = 0;
+  for block in cfg.blocks.iter_mut() {
+    for target in flow_targets_mut(&mut block.flow) {
+      let resolved = resolve(target, blocks);
+      if resolved != *target {
+        *target = resolved;
+        retargeted += 1;
+      }
+    }
+    for region in regions_mut(&mut block.flow) {
+      retargeted += skip_forwarding(region, blocks);
+    }
+  }
+  retargeted
+}
+
+/// Follows forwarding blocks from `target`, without leaving their region.
+fn resolve(target: &Option<CfgLabel>, blocks: &HashMap<CfgLabel, BlockInfo>) -> Option<CfgLabel> {
+  let mut current = target.clone();
+  let mut seen: HashSet<CfgLabel> = HashSet::new();
+  while let Some(label) = current.as_ref() {
+    let (info, next) = match blocks.get(label) {
+      Some(
+        info @ BlockInfo {
+          forward: Some(next), ..
+        },
+      ) => (info, next),
+      _ => break,
+    };
+    // `None` is the end of the hard CFG, only reachable without leaving a region from the top level.
+    let next_region = match next {
+      Some(next) => blocks.get(next).map(|i| i.region),
+      None => Some(0),
+    };
+    if next_region != Some(info.region) || !seen.insert(label.clone()) {
+      break;
+    }
+    current = next.clone();
+  }
+  current
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::asm::parse_asm;
+
+  fn targets(cfg: &Cfg) -> Vec<Option<String>> {
+    let mut targets = Vec::new();
+    for block in cfg.blocks.iter() {
+      for target in crate::opt::flow_targets(&block.flow) {
+        targets.push(target.as_ref().map(|l| l.0.clone()));
+      }
+    }
+    targets
+  }
+
+  #[test]
+  fn threads_chains() {
+    let cfg = parse_asm(
+      r#"
+        Push "x"
+        GetVariable
+        If a
+        Jump b
+      a:
+        Jump b
+      b:
+      c:
+        Jump done
+      loop:
+        Jump loop
+      done:
+        Stop
+      "#,
+    )
+    .unwrap();
+    let (threaded, report) = thread_jumps(&cfg);
+    assert_eq!(
+      targets(&threaded),
+      vec![
+        Some(String::from("done")),
+        Some(String::from("done")),
+        Some(String::from("done")),
+        Some(String::from("done")),
+        Some(String::from("done")),
+        Some(String::from("done")),
+        Some(String::from("loop")),
+        None,
+      ]
+    );
+    assert_eq!(report.retargeted_edges, 5);
+  }
+
+  #[test]
+  fn respects_region_boundaries() {
+    let cfg = parse_asm(
+      r#"
+        Push "x"
+        GetVariable
+        If out
+        with {
+          Push "inside"
+          Trace
+          If tail
+          Push "again"
+          Trace
+        tail:
+          Jump out
+        }
+      out:
+        Jump done
+      done:
+        Stop
+      "#,
+    )
+    .unwrap();
+    let (threaded, report) = thread_jumps(&cfg);
+    assert_eq!(report.retargeted_edges, 2);
+    let with = match &threaded.blocks[1].flow {
+      CfgFlow::With(with) => with,
+      flow => panic!("unexpected flow: {:?}", flow),
+    };
+    // `tail` jumps out of the region: it is not skipped, but its own target is.
+    let targets = targets(&with.body);
+    assert_eq!(targets[0], Some(String::from("tail")));
+    assert_eq!(targets[2], Some(String::from("tail")));
+    assert_eq!(targets[3], Some(String::from("done")));
+  }
+}
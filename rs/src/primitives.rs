@@ -12,6 +12,10 @@ pub fn emit_le_i16<W: io::Write + ?Sized>(writer: &mut W, value: i16) -> io::Res
   writer.write_all(&value.to_le_bytes())
 }
 
+pub fn emit_le_u32<W: io::Write + ?Sized>(writer: &mut W, value: u32) -> io::Result<()> {
+  writer.write_all(&value.to_le_bytes())
+}
+
 pub fn emit_le_i32<W: io::Write + ?Sized>(writer: &mut W, value: i32) -> io::Result<()> {
   writer.write_all(&value.to_le_bytes())
 }
@@ -0,0 +1,231 @@
+//! Generation of SWD files, read by the Flash debug player to map bytecode
+//! offsets to source lines.
+//!
+//! A SWD file starts with the `FWD` signature and the SWF version, followed by
+//! a list of tags. Each tag starts with its kind as a `u32`:
+//!
+//! - `0` (script): `id: u32`, `bitmap: u32`, `name: CString`, `source: CString`
+//! - `1` (offset): `script: u32`, `line: u32`, `offset: u32`
+//! - `2` (breakpoint): `offset: u32`
+//! - `3` (id): 16 bytes, matching the `DebugID` tag of the SWF file
+//!
+//! Offsets are relative to the start of the bytes returned by `emit_cfg`,
+//! which is the start of the action list of the `DoAction` (or `DoInitAction`)
+//! tag containing them.
+
+use crate::map::EmitMap;
+use crate::primitives::{emit_c_string, emit_le_u32, emit_u8};
+use avm1_types::cfg::{Cfg, CfgLabel};
+use std::convert::TryFrom;
+use std::io;
+use std::io::Write;
+
+const TAG_SCRIPT: u32 = 0;
+const TAG_OFFSET: u32 = 1;
+const TAG_BREAKPOINT: u32 = 2;
+const TAG_ID: u32 = 3;
+
+/// Debug information attached to a CFG.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DebugInfo {
+  /// SWF version of the file containing the actions.
+  pub swf_version: u8,
+  /// Identifier shared with the `DebugID` tag of the SWF file.
+  pub id: [u8; 16],
+  pub scripts: Vec<SourceScript>,
+  /// Source positions of the actions of the CFG. Actions without a position
+  /// are not mapped to any line.
+  pub positions: Vec<ActionPosition>,
+  /// Lines where the debugger should break.
+  pub breakpoints: Vec<SourcePosition>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceScript {
+  pub id: u32,
+  /// Flags stored with the script, usually `0`.
+  pub bitmap: u32,
+  /// Name displayed by the debugger, usually the path of the source file.
+  pub name: String,
+  pub source: String,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourcePosition {
+  /// Id of the `SourceScript`.
+  pub script: u32,
+  /// One-based line number.
+  pub line: u32,
+}
+
+/// Source position of the action `index` of the block `label` in `scope`.
+///
+/// Scopes are numbered like in `EmitMap`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ActionPosition {
+  pub scope: usize,
+  pub label: CfgLabel,
+  pub index: usize,
+  pub position: SourcePosition,
+}
+
+/// Emits `value` and returns the emitted bytes with the corresponding SWD file.
+///
+/// Breakpoints are placed on the first action of their line; breakpoints on
+/// lines without any action are ignored. Returns an `InvalidInput` error if a
+/// position refers to a missing action or script.
+pub fn emit_cfg_with_swd(value: &Cfg, debug: &DebugInfo) -> io::Result<(Vec<u8>, Vec<u8>)> {
+  let (bytes, map) = crate::emit_cfg_with_map(value)?;
+  let swd = emit_swd(&map, debug)?;
+  Ok((bytes, swd))
+}
+
+/// Emits the SWD file for bytes emitted with the map `map`.
+pub fn emit_swd(map: &EmitMap, debug: &DebugInfo) -> io::Result<Vec<u8>> {
+  let mut offsets: Vec<(usize, SourcePosition)> = Vec::with_capacity(debug.positions.len());
+  for action in debug.positions.iter() {
+    if !debug.scripts.iter().any(|s| s.id == action.position.script) {
+      return Err(invalid_input(format!("unknown script id: {}", action.position.script)));
+    }
+    let offset = map
+      .action_offset(action.scope, &action.label, action.index)
+      .ok_or_else(|| {
+        invalid_input(format!(
+          "action not found: scope {}, label {}, index {}",
+          action.scope, action.label.0, action.index
+        ))
+      })?;
+    offsets.push((offset, action.position));
+  }
+  offsets.sort();
+
+  let mut writer: Vec<u8> = Vec::new();
+  writer.write_all(b"FWD")?;
+  emit_u8(&mut writer, debug.swf_version)?;
+
+  emit_le_u32(&mut writer, TAG_ID)?;
+  writer.write_all(&debug.id)?;
+
+  for script in debug.scripts.iter() {
+    emit_le_u32(&mut writer, TAG_SCRIPT)?;
+    emit_le_u32(&mut writer, script.id)?;
+    emit_le_u32(&mut writer, script.bitmap)?;
+    emit_c_string(&mut writer, &script.name)?;
+    emit_c_string(&mut writer, &script.source)?;
+  }
+
+  for (offset, position) in offsets.iter() {
+    emit_le_u32(&mut writer, TAG_OFFSET)?;
+    emit_le_u32(&mut writer, position.script)?;
+    emit_le_u32(&mut writer, position.line)?;
+    emit_le_u32(&mut writer, to_u32(*offset)?)?;
+  }
+
+  for breakpoint in debug.breakpoints.iter() {
+    if let Some((offset, _)) = offsets.iter().find(|(_, p)| p == breakpoint) {
+      emit_le_u32(&mut writer, TAG_BREAKPOINT)?;
+      emit_le_u32(&mut writer, to_u32(*offset)?)?;
+    }
+  }
+
+  Ok(writer)
+}
+
+fn to_u32(offset: usize) -> io::Result<u32> {
+  u32::try_from(offset).map_err(|_| invalid_input(format!("offset out of range: {}", offset)))
+}
+
+fn invalid_input(message: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use avm1_types::cfg::{CfgBlock, CfgFlow, Simple};
+  use avm1_types::{cfg, raw, PushValue};
+  use vec1::vec1;
+
+  fn position(label: &str, index: usize, line: u32) -> ActionPosition {
+    ActionPosition {
+      scope: 0,
+      label: CfgLabel(label.to_string()),
+      index,
+      position: SourcePosition { script: 1, line },
+    }
+  }
+
+  #[test]
+  fn test_emit_cfg_with_swd() {
+    let cfg = Cfg {
+      blocks: vec1![CfgBlock {
+        label: CfgLabel(String::from("l0")),
+        actions: vec![
+          cfg::Action::Push(raw::Push {
+            values: vec![PushValue::String(String::from("a"))],
+          }),
+          cfg::Action::Trace,
+          cfg::Action::Stop,
+        ],
+        flow: CfgFlow::Simple(Simple { next: None }),
+      }],
+    };
+    let debug = DebugInfo {
+      swf_version: 7,
+      id: [0xab; 16],
+      scripts: vec![SourceScript {
+        id: 1,
+        bitmap: 0,
+        name: String::from("a.as"),
+        source: String::from("trace(\"a\");\nstop();"),
+      }],
+      positions: vec![position("l0", 2, 2), position("l0", 0, 1), position("l0", 1, 1)],
+      breakpoints: vec![
+        SourcePosition { script: 1, line: 2 },
+        SourcePosition { script: 1, line: 3 },
+      ],
+    };
+    let (bytes, swd) = emit_cfg_with_swd(&cfg, &debug).unwrap();
+    assert_eq!(bytes, crate::emit_cfg(&cfg).unwrap());
+
+    let mut expected: Vec<u8> = b"FWD\x07".to_vec();
+    expected.extend([3, 0, 0, 0]);
+    expected.extend([0xab; 16]);
+    expected.extend([0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    expected.extend(b"a.as\0trace(\"a\");\nstop();\0");
+    expected.extend([1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0]);
+    expected.extend([1, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 6, 0, 0, 0]);
+    expected.extend([1, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 7, 0, 0, 0]);
+    expected.extend([2, 0, 0, 0, 7, 0, 0, 0]);
+    assert_eq!(swd, expected);
+  }
+
+  #[test]
+  fn test_unknown_action() {
+    let cfg = Cfg {
+      blocks: vec1![CfgBlock {
+        label: CfgLabel(String::from("l0")),
+        actions: Vec::new(),
+        flow: CfgFlow::Simple(Simple { next: None }),
+      }],
+    };
+    let debug = DebugInfo {
+      swf_version: 7,
+      id: [0; 16],
+      scripts: vec![SourceScript {
+        id: 1,
+        bitmap: 0,
+        name: String::from("a.as"),
+        source: String::new(),
+      }],
+      positions: vec![position("l0", 0, 1)],
+      breakpoints: Vec::new(),
+    };
+    let err = emit_cfg_with_swd(&cfg, &debug).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+  }
+}
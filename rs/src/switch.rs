@@ -0,0 +1,395 @@
+//! Lowering of `switch` dispatches to plain blocks.
+//!
+//! AVM1 has no computed jump: a switch compares the scrutinee with each case.
+//! [`Switch::lower`] produces either a linear chain of `StrictEquals`/`If`
+//! blocks, or a balanced binary search on the case values when they are all
+//! numbers. The binary search takes about `log2(n) + 2` comparisons instead of
+//! up to `n`, but needs about 1.4 times the bytes of the linear chain: it adds
+//! `Less2` comparisons to the `StrictEquals` of every case.
+
+use avm1_types::action::Push;
+use avm1_types::cfg::{self, CfgBlock, CfgFlow, CfgLabel};
+use avm1_types::PushValue;
+
+/// Maximum number of cases compared linearly at the leaves of a binary search.
+const BINARY_SEARCH_LEAF_CASES: usize = 3;
+
+/// Dispatch to the label of the first case strictly equal to the value of a register.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Switch {
+  pub scrutinee_register: u8,
+  pub cases: Vec<(PushValue, CfgLabel)>,
+  /// Target if no case matches, `None` for the end of the function.
+  pub default: Option<CfgLabel>,
+}
+
+/// What [`SwitchStrategy::Auto`] optimizes for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SwitchGoal {
+  /// Fewest emitted bytes.
+  Size,
+  /// Fewest comparisons on average to reach a case.
+  Speed,
+}
+
+/// Lowering strategy of a [`Switch`].
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum SwitchStrategy {
+  /// Strategy selected by [`Switch::strategy`] for the goal.
+  Auto(SwitchGoal),
+  /// Compare the cases in order.
+  Linear,
+  /// Balanced binary search, if all the cases are numbers (linear chain otherwise).
+  BinarySearch,
+}
+
+impl Switch {
+  /// Returns the strategy used for `SwitchStrategy::Auto(goal)`: `Linear` or
+  /// `BinarySearch`.
+  ///
+  /// The linear chain is always the smallest. For speed, the binary search is
+  /// used if all the cases are numbers and it needs fewer comparisons than the
+  /// linear chain to reach a case, on average over the cases.
+  pub fn strategy(&self, goal: SwitchGoal) -> SwitchStrategy {
+    let cases = match (goal, self.numeric_cases()) {
+      (SwitchGoal::Speed, Some(cases)) => cases,
+      _ => return SwitchStrategy::Linear,
+    };
+    // Comparisons to reach each case, summed over the cases.
+    let linear: usize = (1..=self.cases.len()).sum();
+    let search = binary_search_comparisons(cases.len(), 0);
+    // Compare the averages: `search / cases.len()` and `linear / self.cases.len()`.
+    if search * self.cases.len() < linear * cases.len() {
+      SwitchStrategy::BinarySearch
+    } else {
+      SwitchStrategy::Linear
+    }
+  }
+
+  /// Lowers the switch with `SwitchStrategy::Auto(SwitchGoal::Speed)`.
+  ///
+  /// See [`Switch::lower_with`].
+  pub fn lower(&self, label: CfgLabel) -> Vec<CfgBlock> {
+    self.lower_with(label, SwitchStrategy::Auto(SwitchGoal::Speed))
+  }
+
+  /// Lowers the switch to blocks, the first one is labeled `label`.
+  ///
+  /// The other blocks are labeled `{label}_{n}`: these labels must not be used
+  /// elsewhere in the function. The scrutinee register is left untouched.
+  pub fn lower_with(&self, label: CfgLabel, strategy: SwitchStrategy) -> Vec<CfgBlock> {
+    let strategy = match strategy {
+      SwitchStrategy::Auto(goal) => self.strategy(goal),
+      strategy => strategy,
+    };
+    let mut lowering = Lowering {
+      register: self.scrutinee_register,
+      label,
+      next_id: 0,
+      blocks: Vec::new(),
+    };
+    let entry = lowering.label.clone();
+    match (strategy, self.numeric_cases()) {
+      (SwitchStrategy::BinarySearch, Some(cases)) => lowering.binary_search(entry, &cases, &self.default),
+      _ => {
+        let cases: Vec<(PushValue, &CfgLabel)> = self.cases.iter().map(|(v, l)| (v.clone(), l)).collect();
+        lowering.linear(entry, &cases, &self.default)
+      }
+    }
+    lowering.blocks
+  }
+
+  /// Returns the cases sorted by value if they are all numbers, keeping the
+  /// first case of duplicated values. `NaN` cases never match and are dropped.
+  fn numeric_cases(&self) -> Option<Vec<(f64, PushValue, &CfgLabel)>> {
+    let mut cases: Vec<(f64, PushValue, &CfgLabel)> = Vec::with_capacity(self.cases.len());
+    for (value, target) in &self.cases {
+      let number = match value {
+        PushValue::Sint32(v) => f64::from(*v),
+        PushValue::Float32(v) => f64::from(*v),
+        PushValue::Float64(v) => *v,
+        _ => return None,
+      };
+      // `0` and `-0` are strictly equal
+      if !number.is_nan() && !cases.iter().any(|(n, _, _)| *n == number) {
+        cases.push((number, value.clone(), target));
+      }
+    }
+    cases.sort_by(|(a, _, _), (b, _, _)| a.partial_cmp(b).unwrap());
+    Some(cases)
+  }
+}
+
+/// Returns the comparisons needed by a binary search on `cases` cases to reach
+/// each case, summed over the cases, starting at `depth` comparisons.
+fn binary_search_comparisons(cases: usize, depth: usize) -> usize {
+  if cases <= BINARY_SEARCH_LEAF_CASES {
+    // Linear chain at the leaves
+    return (1..=cases).map(|i| depth + i).sum();
+  }
+  let mid = cases / 2;
+  binary_search_comparisons(mid, depth + 1) + binary_search_comparisons(cases - mid, depth + 1)
+}
+
+struct Lowering {
+  register: u8,
+  label: CfgLabel,
+  next_id: usize,
+  blocks: Vec<CfgBlock>,
+}
+
+impl Lowering {
+  fn new_label(&mut self) -> CfgLabel {
+    let label = CfgLabel(format!("{}_{}", self.label.0, self.next_id));
+    self.next_id += 1;
+    label
+  }
+
+  /// Adds a block comparing the scrutinee with `value`.
+  fn compare(&mut self, label: CfgLabel, value: PushValue, action: cfg::Action, targets: (CfgLabel, Option<CfgLabel>)) {
+    self.blocks.push(CfgBlock {
+      label,
+      actions: vec![
+        cfg::Action::Push(Push {
+          values: vec![PushValue::Register(self.register), value],
+        }),
+        action,
+      ],
+      flow: CfgFlow::If(cfg::If {
+        true_target: Some(targets.0),
+        false_target: targets.1,
+      }),
+    });
+  }
+
+  fn linear(&mut self, entry: CfgLabel, cases: &[(PushValue, &CfgLabel)], default: &Option<CfgLabel>) {
+    if cases.is_empty() {
+      self.blocks.push(CfgBlock {
+        label: entry,
+        actions: Vec::new(),
+        flow: CfgFlow::Simple(cfg::Simple { next: default.clone() }),
+      });
+      return;
+    }
+    let mut label = entry;
+    for (i, (value, target)) in cases.iter().enumerate() {
+      let next = if i + 1 < cases.len() {
+        Some(self.new_label())
+      } else {
+        default.clone()
+      };
+      self.compare(
+        label,
+        value.clone(),
+        cfg::Action::StrictEquals,
+        ((*target).clone(), next.clone()),
+      );
+      label = match next {
+        Some(next) => next,
+        None => break,
+      };
+    }
+  }
+
+  fn binary_search(&mut self, entry: CfgLabel, cases: &[(f64, PushValue, &CfgLabel)], default: &Option<CfgLabel>) {
+    if cases.len() <= BINARY_SEARCH_LEAF_CASES {
+      let cases: Vec<(PushValue, &CfgLabel)> = cases.iter().map(|(_, v, l)| (v.clone(), *l)).collect();
+      return self.linear(entry, &cases, default);
+    }
+    // Values lower than the pivot go to the first half
+    let mid = cases.len() / 2;
+    let (lower, upper) = (self.new_label(), self.new_label());
+    self.compare(
+      entry,
+      cases[mid].1.clone(),
+      cfg::Action::Less2,
+      (lower.clone(), Some(upper.clone())),
+    );
+    self.binary_search(upper, &cases[mid..], default);
+    self.binary_search(lower, &cases[..mid], default);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use avm1_types::cfg::Cfg;
+  use std::collections::HashMap;
+  use vec1::Vec1;
+
+  fn label(name: &str) -> CfgLabel {
+    CfgLabel(String::from(name))
+  }
+
+  fn switch(values: Vec<PushValue>) -> Switch {
+    Switch {
+      scrutinee_register: 1,
+      cases: values
+        .into_iter()
+        .enumerate()
+        .map(|(i, v)| (v, label(&format!("case{}", i))))
+        .collect(),
+      default: Some(label("default")),
+    }
+  }
+
+  /// Runs the dispatch blocks with `scrutinee` in the register, returns the
+  /// target reached (following empty blocks).
+  fn dispatch(blocks: &[CfgBlock], scrutinee: &PushValue) -> Option<CfgLabel> {
+    let as_number = |value: &PushValue| match value {
+      PushValue::Sint32(v) => Some(f64::from(*v)),
+      PushValue::Float32(v) => Some(f64::from(*v)),
+      PushValue::Float64(v) => Some(*v),
+      _ => None,
+    };
+    let by_label: HashMap<&CfgLabel, &CfgBlock> = blocks.iter().map(|b| (&b.label, b)).collect();
+    let mut block = &blocks[0];
+    loop {
+      let result = match (block.actions.as_slice(), &block.flow) {
+        ([], CfgFlow::Simple(_)) => true,
+        ([cfg::Action::Push(push), action], CfgFlow::If(_)) => {
+          assert_eq!(push.values[0], PushValue::Register(1));
+          let value = &push.values[1];
+          match action {
+            cfg::Action::StrictEquals => match (as_number(scrutinee), as_number(value)) {
+              (Some(a), Some(b)) => a == b,
+              _ => scrutinee == value,
+            },
+            cfg::Action::Less2 => as_number(scrutinee).unwrap() < as_number(value).unwrap(),
+            action => panic!("unexpected action: {:?}", action),
+          }
+        }
+        // Not a dispatch block
+        _ => return Some(block.label.clone()),
+      };
+      let target = match &block.flow {
+        CfgFlow::If(flow) if result => &flow.true_target,
+        CfgFlow::If(flow) => &flow.false_target,
+        CfgFlow::Simple(flow) => &flow.next,
+        _ => unreachable!(),
+      };
+      match target.as_ref().and_then(|t| by_label.get(t)) {
+        Some(next) => block = next,
+        None => return target.clone(),
+      }
+    }
+  }
+
+  #[test]
+  fn test_linear_strings() {
+    let value = switch(vec![
+      PushValue::String(String::from("a")),
+      PushValue::String(String::from("b")),
+      PushValue::String(String::from("a")),
+    ]);
+    assert_eq!(value.strategy(SwitchGoal::Speed), SwitchStrategy::Linear);
+    let blocks = value.lower(label("s"));
+    let labels: Vec<&str> = blocks.iter().map(|b| b.label.0.as_str()).collect();
+    assert_eq!(labels, ["s", "s_0", "s_1"]);
+    let string = |v: &str| PushValue::String(String::from(v));
+    assert_eq!(dispatch(&blocks, &string("a")), Some(label("case0")));
+    assert_eq!(dispatch(&blocks, &string("b")), Some(label("case1")));
+    assert_eq!(dispatch(&blocks, &string("c")), Some(label("default")));
+  }
+
+  #[test]
+  fn test_binary_search() {
+    let values: Vec<PushValue> = (0..20)
+      .map(|i| match i % 3 {
+        0 => PushValue::Sint32(i * 7 - 50),
+        1 => PushValue::Float64(f64::from(i) * 7.0 - 50.0),
+        _ => PushValue::Float32(i as f32 * 7.0 - 50.0),
+      })
+      .chain([PushValue::Float64(f64::NAN), PushValue::Sint32(-50)])
+      .collect();
+    let value = switch(values);
+    assert_eq!(value.strategy(SwitchGoal::Speed), SwitchStrategy::BinarySearch);
+    assert_eq!(value.strategy(SwitchGoal::Size), SwitchStrategy::Linear);
+    let linear = value.lower_with(label("s"), SwitchStrategy::Linear);
+    let search = value.lower(label("s"));
+    assert!(search.iter().any(|b| b.actions.contains(&cfg::Action::Less2)));
+    for scrutinee in -60..100 {
+      let scrutinee = PushValue::Sint32(scrutinee);
+      let expected = dispatch(&linear, &scrutinee);
+      assert_eq!(dispatch(&search, &scrutinee), expected, "{:?}", scrutinee);
+    }
+    assert_eq!(dispatch(&search, &PushValue::Sint32(-50)), Some(label("case0")));
+    assert_eq!(dispatch(&search, &PushValue::Sint32(-43)), Some(label("case1")));
+    assert_eq!(dispatch(&search, &PushValue::Sint32(-42)), Some(label("default")));
+  }
+
+  #[test]
+  fn test_strategy_goals() {
+    let numbers = |n: i32| switch((0..n).map(PushValue::Sint32).collect());
+    // 2.5 comparisons on average either way
+    assert_eq!(numbers(4).strategy(SwitchGoal::Speed), SwitchStrategy::Linear);
+    // 2.8 comparisons on average instead of 3
+    assert_eq!(numbers(5).strategy(SwitchGoal::Speed), SwitchStrategy::BinarySearch);
+    for n in 1..40 {
+      let value = numbers(n);
+      assert_eq!(value.strategy(SwitchGoal::Size), SwitchStrategy::Linear);
+      let size = |strategy: SwitchStrategy| -> usize {
+        let blocks = value.lower_with(label("s"), strategy);
+        blocks
+          .iter()
+          .map(|b| crate::opt::actions_size(&b.actions).unwrap())
+          .sum::<usize>()
+      };
+      assert!(size(SwitchStrategy::Linear) <= size(SwitchStrategy::BinarySearch));
+    }
+  }
+
+  #[test]
+  fn test_mixed_cases_fall_back_to_linear() {
+    let mut values: Vec<PushValue> = (0..10).map(PushValue::Sint32).collect();
+    values.push(PushValue::Null);
+    let value = switch(values);
+    assert_eq!(value.strategy(SwitchGoal::Speed), SwitchStrategy::Linear);
+    let blocks = value.lower_with(label("s"), SwitchStrategy::BinarySearch);
+    assert_eq!(blocks.len(), 11);
+    assert!(blocks.iter().all(|b| b.actions[1] == cfg::Action::StrictEquals));
+  }
+
+  #[test]
+  fn test_emit_lowered_switch() {
+    for strategy in [SwitchStrategy::Linear, SwitchStrategy::BinarySearch] {
+      let value = Switch {
+        default: None,
+        ..switch((0..9).map(PushValue::Sint32).collect())
+      };
+      let mut blocks = value.lower_with(label("s"), strategy);
+      for i in 0..9 {
+        blocks.push(CfgBlock {
+          label: label(&format!("case{}", i)),
+          actions: vec![
+            cfg::Action::Push(Push {
+              values: vec![PushValue::Sint32(i)],
+            }),
+            cfg::Action::Trace,
+          ],
+          flow: CfgFlow::Simple(cfg::Simple { next: None }),
+        });
+      }
+      let cfg = Cfg {
+        blocks: Vec1::try_from_vec(blocks).unwrap(),
+      };
+      let parsed = avm1_parser::parse_cfg(&crate::emit_cfg(&cfg).unwrap());
+      for scrutinee in -2..11 {
+        let target = dispatch(&parsed.blocks, &PushValue::Sint32(scrutinee));
+        let traced = target.map(|target| {
+          let block = parsed.blocks.iter().find(|b| b.label == target).unwrap();
+          block.actions[0].clone()
+        });
+        let expected = Some(scrutinee).filter(|i| (0..9).contains(i)).map(|i| {
+          cfg::Action::Push(Push {
+            values: vec![PushValue::Sint32(i)],
+          })
+        });
+        assert_eq!(traced, expected, "{:?} {}", strategy, scrutinee);
+      }
+    }
+  }
+}